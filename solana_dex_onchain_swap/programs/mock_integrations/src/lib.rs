@@ -0,0 +1,230 @@
+// The `#[program]` expansion still calls `AccountInfo::realloc` for the IDL account.
+#![allow(deprecated)]
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program::{invoke, invoke_signed},
+};
+
+declare_id!("5khBNLPBqSPmwYeB6rCMsa5mw3dNci5PEeQ9Nr5rYjJ");
+
+// Test-only program standing in for the external programs the DEX talks to.
+#[program]
+pub mod mock_integrations {
+    use super::*;
+
+    pub fn init_record(_ctx: Context<InitRecord>) -> Result<()> {
+        Ok(())
+    }
+
+    // Liquidity callback target: stores the last notification it received
+    pub fn on_liquidity_event(
+        ctx: Context<OnLiquidityEvent>,
+        data: LiquidityCallbackData,
+    ) -> Result<()> {
+        let record = &mut ctx.accounts.record;
+        record.pair = ctx.accounts.pair.key();
+        record.sender = data.sender;
+        record.amount0 = data.amount0;
+        record.amount1 = data.amount1;
+        record.liquidity = data.liquidity;
+        record.is_add = data.is_add;
+        record.count += 1;
+        Ok(())
+    }
+
+    // Pair controller: forwards a prebuilt DEX instruction, signing as the
+    // `[b"controller", pair]` PDA. Remaining accounts are the instruction's
+    // accounts in order.
+    pub fn controller_invoke<'info>(
+        ctx: Context<'_, '_, '_, 'info, ControllerInvoke<'info>>,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let signer_key = ctx.accounts.controller_signer.key();
+        let accounts = ctx
+            .remaining_accounts
+            .iter()
+            .map(|account| AccountMeta {
+                pubkey: account.key(),
+                is_signer: account.is_signer || account.key() == signer_key,
+                is_writable: account.is_writable,
+            })
+            .collect();
+
+        let mut account_infos = ctx.remaining_accounts.to_vec();
+        account_infos.push(ctx.accounts.dex_program.to_account_info());
+
+        let pair_key = ctx.accounts.pair.key();
+        let seeds: &[&[u8]] = &[b"controller", pair_key.as_ref(), &[ctx.bumps.controller_signer]];
+        invoke_signed(
+            &Instruction {
+                program_id: ctx.accounts.dex_program.key(),
+                accounts,
+                data,
+            },
+            &account_infos,
+            &[seeds],
+        )?;
+        Ok(())
+    }
+
+    // Flash swap borrower: repays the little-endian u64 at the front of
+    // `data.data` from `repay_from` into `vault`, under the sender's signature
+    // forwarded by the DEX. Repaying too little lets the DEX's check fail.
+    pub fn on_flash_swap(ctx: Context<OnFlashSwap>, data: FlashSwapCallbackData) -> Result<()> {
+        let amount = data
+            .data
+            .get(..8)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_le_bytes)
+            .unwrap_or_default();
+
+        // SPL Token `Transfer`: tag 3 followed by the amount
+        let mut ix_data = vec![3u8];
+        ix_data.extend_from_slice(&amount.to_le_bytes());
+        invoke(
+            &Instruction {
+                program_id: ctx.accounts.token_program.key(),
+                accounts: vec![
+                    AccountMeta::new(ctx.accounts.repay_from.key(), false),
+                    AccountMeta::new(ctx.accounts.vault.key(), false),
+                    AccountMeta::new_readonly(ctx.accounts.sender.key(), true),
+                ],
+                data: ix_data,
+            },
+            &[
+                ctx.accounts.repay_from.to_account_info(),
+                ctx.accounts.vault.to_account_info(),
+                ctx.accounts.sender.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    // External price feed: `price_x64` is token0 in token1, Q64.64
+    pub fn init_mock_oracle(ctx: Context<InitMockOracle>, price_x64: u128) -> Result<()> {
+        ctx.accounts.oracle.price_x64 = price_x64;
+        Ok(())
+    }
+
+    pub fn set_mock_oracle_price(ctx: Context<SetMockOraclePrice>, price_x64: u128) -> Result<()> {
+        ctx.accounts.oracle.price_x64 = price_x64;
+        Ok(())
+    }
+}
+
+// Mirrors `solana_dex::LiquidityCallbackData`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct LiquidityCallbackData {
+    pub pair: Pubkey,
+    pub sender: Pubkey,
+    pub amount0: u64,
+    pub amount1: u64,
+    pub liquidity: u64,
+    pub is_add: bool,
+}
+
+// Mirrors `solana_dex::FlashSwapCallbackData`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct FlashSwapCallbackData {
+    pub pair: Pubkey,
+    pub sender: Pubkey,
+    pub amount0_out: u64,
+    pub amount1_out: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Accounts)]
+pub struct InitRecord<'info> {
+    #[account(init, payer = payer, space = CallbackRecord::LEN)]
+    pub record: Account<'info, CallbackRecord>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OnLiquidityEvent<'info> {
+    /// CHECK: The pair that triggered the callback
+    pub pair: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub record: Account<'info, CallbackRecord>,
+}
+
+#[derive(Accounts)]
+pub struct OnFlashSwap<'info> {
+    /// CHECK: The pair that lent the tokens
+    pub pair: UncheckedAccount<'info>,
+    /// CHECK: Sender's token account the repayment comes from
+    #[account(mut)]
+    pub repay_from: UncheckedAccount<'info>,
+    /// CHECK: Pair vault being repaid
+    #[account(mut)]
+    pub vault: UncheckedAccount<'info>,
+    pub sender: Signer<'info>,
+    /// CHECK: SPL Token program
+    #[account(executable)]
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ControllerInvoke<'info> {
+    /// CHECK: The pair being managed; only used to derive the signer
+    pub pair: UncheckedAccount<'info>,
+    /// CHECK: Signing PDA, validated by seeds
+    #[account(seeds = [b"controller", pair.key().as_ref()], bump)]
+    pub controller_signer: UncheckedAccount<'info>,
+    /// CHECK: The DEX program being invoked
+    #[account(executable)]
+    pub dex_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitMockOracle<'info> {
+    #[account(init, payer = payer, space = MockOracle::LEN)]
+    pub oracle: Account<'info, MockOracle>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetMockOraclePrice<'info> {
+    #[account(mut)]
+    pub oracle: Account<'info, MockOracle>,
+}
+
+#[account]
+pub struct CallbackRecord {
+    pub pair: Pubkey,
+    pub sender: Pubkey,
+    pub amount0: u64,
+    pub amount1: u64,
+    pub liquidity: u64,
+    pub is_add: bool,
+    pub count: u64,
+}
+
+impl CallbackRecord {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // pair
+        32 + // sender
+        8 + // amount0
+        8 + // amount1
+        8 + // liquidity
+        1 + // is_add
+        8; // count
+}
+
+// Layout matches what `solana_dex` reads: discriminator, then the price
+#[account]
+pub struct MockOracle {
+    pub price_x64: u128,
+}
+
+impl MockOracle {
+    pub const LEN: usize = 8 + // discriminator
+        16; // price_x64
+}
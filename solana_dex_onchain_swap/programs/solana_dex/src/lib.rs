@@ -1,22 +1,130 @@
+// The `#[program]` expansion still calls `AccountInfo::realloc` for the IDL account.
+#![allow(deprecated)]
+
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{
+    compute_units::sol_remaining_compute_units, instruction::Instruction, program::invoke,
+    program_option::COption,
+};
 use anchor_spl::{
-    token_interface::{Mint, TokenAccount, TokenInterface},
-    token,
+    associated_token::AssociatedToken,
+    token::accessor,
+    token_interface::{
+        self, spl_token_2022::instruction::AuthorityType, Mint, TokenAccount, TokenInterface,
+    },
 };
 
 declare_id!("JCCQmki6kdXWrFoc5kkQ3vYAnUNkcidccXNsm8WEoJGS"); // Replace with your actual program ID
 
+// Swap fees are expressed in basis points of the input amount
+pub const FEE_DENOMINATOR: u128 = 10_000;
+pub const DEFAULT_FEE_BPS: u16 = 30;
+pub const MAX_FEE_BPS: u16 = 1_000;
+pub const MAX_FEE_TIERS: usize = 4;
+
+// Pairs `set_fees_batch` updates per call; with the factory, owner and
+// program ids this stays inside a legacy transaction's 1232-byte limit
+pub const MAX_FEE_BATCH_PAIRS: usize = 20;
+
+// Routed swaps take [pair, token0_account, token1_account, authority,
+// token0_mint, token1_mint] per hop from `remaining_accounts`
+pub const ROUTE_HOP_ACCOUNTS: usize = 6;
+pub const MAX_ROUTE_HOPS: usize = 3;
+
+// Batch swaps take the route hop accounts plus the sender's [token_in,
+// token_out] per swap from `remaining_accounts`
+pub const BATCH_SWAP_ACCOUNTS: usize = ROUTE_HOP_ACCOUNTS + 2;
+pub const MAX_BATCH_SWAPS: usize = 4;
+
+// Migrations take [pair, token0_account, token1_account, lp_mint,
+// liquidity_from, authority, fee_to_account] per source pair from
+// `remaining_accounts`
+pub const MIGRATION_SOURCE_ACCOUNTS: usize = 7;
+pub const MAX_MIGRATION_SOURCES: usize = 3;
+
+// Bit positions in the `get_pair_flags` bitmask; a set bit means the feature
+// is active on the pair
+pub const PAIR_FLAG_INITIALIZED: u32 = 1 << 0;
+pub const PAIR_FLAG_LOCKED: u32 = 1 << 1;
+pub const PAIR_FLAG_ORACLE_ENABLED: u32 = 1 << 2;
+pub const PAIR_FLAG_CALLBACK: u32 = 1 << 3;
+pub const PAIR_FLAG_CONTROLLED: u32 = 1 << 4;
+pub const PAIR_FLAG_FEE_TIERS: u32 = 1 << 5;
+pub const PAIR_FLAG_COOLDOWN: u32 = 1 << 6;
+pub const PAIR_FLAG_MAX_OUT: u32 = 1 << 7;
+pub const PAIR_FLAG_MAX_RATIO: u32 = 1 << 8;
+pub const PAIR_FLAG_MIN_TRADE: u32 = 1 << 9;
+pub const PAIR_FLAG_DEPOSIT_BOUNDS: u32 = 1 << 10;
+pub const PAIR_FLAG_PAUSED: u32 = 1 << 11;
+pub const PAIR_FLAG_EVENTS_THROTTLED: u32 = 1 << 12;
+pub const PAIR_FLAG_FROZEN: u32 = 1 << 13;
+pub const PAIR_FLAG_DEPOSIT_DEVIATION: u32 = 1 << 14;
+
+// LP permanently locked on the first deposit for a pair of 6-decimal tokens;
+// see `minimum_liquidity_for` for how it scales with other decimals. The
+// locked LP is never redeemable: once every other LP exits, the dust it backs
+// stays in the vaults and swaps are refused until someone deposits again.
+pub const MINIMUM_LIQUIDITY: u64 = 1_000;
+pub const MINIMUM_LIQUIDITY_BASE_DECIMALS: u8 = 6;
+
+// Roles reported by `verify_pair_account`
+pub const ACCOUNT_ROLE_NONE: u8 = 0;
+pub const ACCOUNT_ROLE_VAULT0: u8 = 1;
+pub const ACCOUNT_ROLE_VAULT1: u8 = 2;
+pub const ACCOUNT_ROLE_AUTHORITY: u8 = 3;
+pub const ACCOUNT_ROLE_LP_MINT: u8 = 4;
+
+// Fixed-point scale for a reward pool's accumulated reward per staked LP
+pub const REWARD_PRECISION: u128 = 1_000_000_000_000;
+
+// Delay between queueing and executing a protocol fee withdrawal (2 days)
+pub const PROTOCOL_FEE_WITHDRAWAL_DELAY: i64 = 2 * 24 * 60 * 60;
+
+// Cap on the share of collected protocol fee LP paid to the keeper (1%)
+pub const MAX_KEEPER_BOUNTY_BPS: u16 = 100;
+
+// Compute units a swap needs from its pre-checks through the final event,
+// including both token transfers
+pub const SWAP_COMPUTE_UNITS: u64 = 60_000;
+
+// Snapshots per `PairArchive` chunk account, and the number of slots a
+// pair's archive cycles through (64 chunks) before overwriting the oldest
+pub const PAIR_ARCHIVE_CHUNK_LEN: usize = 16;
+pub const MAX_PAIR_ARCHIVE_SNAPSHOTS: u64 = 1_024;
+
+// Oracle observations a pair keeps, one per update that advances the clock.
+// Kept small so the whole pair still fits in `dump_pair_state` return data.
+pub const MAX_ORACLE_OBSERVATIONS: usize = 4;
+
+// Stretch of history `configure_pair_with_oracle_seed` back-fills at the
+// seed price
+pub const ORACLE_SEED_PERIOD_SECONDS: u32 = 60 * 60;
+
 #[program]
 pub mod solana_dex {
     use super::*;
 
-    pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
+    // `migration_authority` alone may run `repair_bumps` and hand its role
+    // on; `owner` keeps the day-to-day settings and the other recovery tools
+    pub fn initialize(ctx: Context<Initialize>, migration_authority: Pubkey) -> Result<()> {
         let factory = &mut ctx.accounts.factory;
         factory.owner = ctx.accounts.owner.key();
+        factory.migration_authority = migration_authority;
         factory.pair_count = 0;
         factory.fee_to = Pubkey::default();
         factory.fee_on = false;
         factory.last_pair = Pubkey::default();
+        factory.discount_mint = Pubkey::default();
+        factory.discount_threshold = 0;
+        factory.discount_fee_bps = 0;
+        factory.pending_owner = Pubkey::default();
+        factory.promo_fee_until = 0;
+        factory.promo_fee_bps = 0;
+        factory.reject_lp_pair_tokens = true;
+        factory.keeper_bounty_bps = 0;
+        factory.lp_burn_address = Pubkey::default();
+        factory.fee_numerator = 1;
+        factory.fee_denominator = 6;
         Ok(())
     }
 
@@ -32,9 +140,20 @@ pub mod solana_dex {
         Ok(())
     }
 
-    // Step 2: Create pair account and LP mint
+    // Step 2: Create pair account and LP mint (PDA `[b"lp_mint", pair]`)
     pub fn create_pair_account(ctx: Context<CreatePairAccount>) -> Result<()> {
+        // LP-of-LP pairs are refused unless the factory allows them
+        if ctx.accounts.factory.reject_lp_pair_tokens {
+            require!(
+                ctx.accounts.token0_lp_marker.data_is_empty()
+                    && ctx.accounts.token1_lp_marker.data_is_empty(),
+                DexError::LpMintAsPairToken
+            );
+        }
+        ctx.accounts.lp_mint_marker.pair = ctx.accounts.pair.key();
+
         let pair = &mut ctx.accounts.pair;
+        pair.factory = ctx.accounts.factory.key();
         pair.bump = ctx.bumps.pair;
         pair.authority_bump = ctx.bumps.authority;
         
@@ -44,18 +163,191 @@ pub mod solana_dex {
         Ok(())
     }
 
+    // Recovery for a pair whose `configure_pair` never ran: closes the pair and
+    // its LP mint marker and returns their rent to the factory owner. The LP
+    // mint itself can't be closed and stays behind, empty.
+    pub fn force_close_unconfigured_pair(ctx: Context<ForceCloseUnconfiguredPair>) -> Result<()> {
+        emit!(UnconfiguredPairClosedEvent {
+            pair: ctx.accounts.pair.key(),
+            lp_mint: ctx.accounts.lp_mint.key(),
+        });
+
+        Ok(())
+    }
+
+    // Reclaims the rent of a configured pair that never took a deposit (or was
+    // emptied): closes the pair and its LP mint marker to the factory owner and
+    // takes it off the factory's count. The LP mint stays behind, empty. An
+    // evacuated pair handed its LP mint and marker on, so it closes without.
+    pub fn close_pair(ctx: Context<ClosePair>) -> Result<()> {
+        require!(
+            ctx.accounts.lp_mint_marker.is_some() == (ctx.accounts.pair.lp_mint != Pubkey::default()),
+            DexError::InvalidLpMint
+        );
+        let factory = &mut ctx.accounts.factory;
+        factory.pair_count = factory.pair_count.saturating_sub(1);
+
+        emit!(PairClosedEvent {
+            pair: ctx.accounts.pair.key(),
+            pair_count: factory.pair_count,
+        });
+
+        Ok(())
+    }
+
+    // Emergency replacement of a pair: moves both vaults' whole balances into
+    // an empty `new_pair` over the same tokens and hands it the old LP mint,
+    // mint authority, supply and marker included, so every outstanding LP
+    // token redeems against the new pair. The old pair is left paused and
+    // empty, ready for `close_pair`.
+    pub fn evacuate_pair(ctx: Context<EvacuatePair>) -> Result<()> {
+        require!(!ctx.accounts.new_pair.locked, DexError::Locked);
+        lock_pair(&mut ctx.accounts.old_pair)?;
+        update_cumulative_prices(&mut ctx.accounts.old_pair)?;
+        update_cumulative_prices(&mut ctx.accounts.new_pair)?;
+
+        let old_pair_key = ctx.accounts.old_pair.key();
+        let authority_bump = ctx.accounts.old_pair.authority_bump;
+        let amount0 = ctx.accounts.old_token0_account.amount;
+        let amount1 = ctx.accounts.old_token1_account.amount;
+        transfer_from_vault(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.old_token0_account.to_account_info(),
+            &ctx.accounts.token0_mint,
+            ctx.accounts.new_token0_account.to_account_info(),
+            ctx.accounts.old_authority.to_account_info(),
+            old_pair_key,
+            authority_bump,
+            amount0,
+        )?;
+        transfer_from_vault(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.old_token1_account.to_account_info(),
+            &ctx.accounts.token1_mint,
+            ctx.accounts.new_token1_account.to_account_info(),
+            ctx.accounts.old_authority.to_account_info(),
+            old_pair_key,
+            authority_bump,
+            amount1,
+        )?;
+        // A transfer fee lands less than was sent
+        ctx.accounts.new_token0_account.reload()?;
+        ctx.accounts.new_token1_account.reload()?;
+        let (received0, received1) = (
+            ctx.accounts.new_token0_account.amount,
+            ctx.accounts.new_token1_account.amount,
+        );
+
+        let authority_seeds = &[b"authority".as_ref(), old_pair_key.as_ref(), &[authority_bump]];
+        token_interface::set_authority(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token_interface::SetAuthority {
+                    current_authority: ctx.accounts.old_authority.to_account_info(),
+                    account_or_mint: ctx.accounts.lp_mint.to_account_info(),
+                },
+                &[authority_seeds],
+            ),
+            AuthorityType::MintTokens,
+            Some(ctx.accounts.new_authority.key()),
+        )?;
+
+        // The burned minimum stays part of the supply, and `k_last` moves over
+        // so the protocol fee keeps accruing from the same base
+        let old_pair = &mut ctx.accounts.old_pair;
+        let new_pair = &mut ctx.accounts.new_pair;
+        new_pair.reserve0 = received0;
+        new_pair.reserve1 = received1;
+        new_pair.total_supply = old_pair.total_supply;
+        new_pair.lp_mint = old_pair.lp_mint;
+        new_pair.k_last = old_pair.k_last;
+        new_pair.minimum_liquidity = old_pair.minimum_liquidity;
+
+        old_pair.reserve0 = 0;
+        old_pair.reserve1 = 0;
+        old_pair.total_supply = 0;
+        old_pair.k_last = 0;
+        old_pair.lp_mint = Pubkey::default();
+        old_pair.pair_paused = true;
+        ctx.accounts.lp_mint_marker.pair = new_pair.key();
+
+        emit!(PairEvacuatedEvent {
+            old_pair: old_pair_key,
+            new_pair: new_pair.key(),
+            amount0,
+            amount1,
+            total_supply: new_pair.total_supply,
+        });
+
+        old_pair.locked = false;
+        Ok(())
+    }
+
+    // Recovery tool for the migration authority: recomputes the canonical pair
+    // and authority bumps and stores them if either stored value is off. The
+    // pair PDA may have been derived from either mint order, so both are tried.
+    pub fn repair_bumps(ctx: Context<RepairBumps>) -> Result<()> {
+        let pair_key = ctx.accounts.pair.key();
+        let pair = &mut ctx.accounts.pair;
+        let bump = [[pair.token0, pair.token1], [pair.token1, pair.token0]]
+            .iter()
+            .map(|[first, second]| {
+                Pubkey::find_program_address(&[b"pair", first.as_ref(), second.as_ref()], ctx.program_id)
+            })
+            .find(|(address, _)| *address == pair_key)
+            .map(|(_, bump)| bump)
+            .ok_or(DexError::InvalidPairAddress)?;
+        let (_, authority_bump) =
+            Pubkey::find_program_address(&[b"authority", pair_key.as_ref()], ctx.program_id);
+
+        // Rewriting a healthy pair would only hide a caller's mistake
+        require!(
+            bump != pair.bump || authority_bump != pair.authority_bump,
+            DexError::BumpsAlreadyValid
+        );
+
+        emit!(BumpsRepairedEvent {
+            pair: pair_key,
+            old_bump: pair.bump,
+            new_bump: bump,
+            old_authority_bump: pair.authority_bump,
+            new_authority_bump: authority_bump,
+        });
+        pair.bump = bump;
+        pair.authority_bump = authority_bump;
+
+        Ok(())
+    }
+
     // Step 3: Configure the pair with actual data
-    pub fn configure_pair(ctx: Context<ConfigurePair>) -> Result<()> {
+    // `max_ratio` bounds how lopsided a swap may leave the reserves (0 = disabled)
+    // `reserve_cap0`/`reserve_cap1` freeze deposits once a reserve reaches them (0 = disabled)
+    pub fn configure_pair(
+        ctx: Context<ConfigurePair>,
+        max_ratio: u64,
+        reserve_cap0: u64,
+        reserve_cap1: u64,
+    ) -> Result<()> {
         // Ensure the pair is not already initialized
         require!(!ctx.accounts.pair.is_initialized, DexError::PairAlreadyInitialized);
 
         // Determine which token is token0 and which is token1
-        let (token0, token1) = if ctx.accounts.token0.key() < ctx.accounts.token1.key() {
-            (ctx.accounts.token0.key(), ctx.accounts.token1.key())
+        let (token0, token1, decimals0, decimals1) = if ctx.accounts.token0.key() < ctx.accounts.token1.key() {
+            (ctx.accounts.token0.key(), ctx.accounts.token1.key(), ctx.accounts.token0.decimals, ctx.accounts.token1.decimals)
         } else {
-            (ctx.accounts.token1.key(), ctx.accounts.token0.key())
+            (ctx.accounts.token1.key(), ctx.accounts.token0.key(), ctx.accounts.token1.decimals, ctx.accounts.token0.decimals)
         };
 
+        // The pair must have been created at the PDA of the sorted mints, or
+        // its stored token order would disagree with its seeds
+        let sorted_pair = Pubkey::create_program_address(
+            &[b"pair", token0.as_ref(), token1.as_ref(), &[ctx.accounts.pair.bump]],
+            ctx.program_id,
+        )
+        .map_err(|_| error!(DexError::TokensNotSorted))?;
+        require_keys_eq!(ctx.accounts.pair.key(), sorted_pair, DexError::TokensNotSorted);
+        let minimum_liquidity = minimum_liquidity_for(decimals0, decimals1)?;
+
         // Initialize the pair account
         let pair = &mut ctx.accounts.pair;
         pair.factory = ctx.accounts.factory.key();
@@ -67,6 +359,18 @@ pub mod solana_dex {
         pair.token1_account = ctx.accounts.token1_account.key();
         pair.lp_mint = ctx.accounts.lp_mint.key();
         pair.total_supply = 0;
+        pair.fee_bps = DEFAULT_FEE_BPS;
+        pair.max_ratio = max_ratio;
+        pair.token0_decimals = decimals0;
+        pair.token1_decimals = decimals1;
+        pair.minimum_liquidity = minimum_liquidity;
+        pair.tick_size_x64 = 1;
+        pair.max_deposit_liquidity = u64::MAX;
+        pair.emit_events = true;
+        pair.reserve_cap0 = reserve_cap0;
+        pair.reserve_cap1 = reserve_cap1;
+        pair.created_at = Clock::get()?.unix_timestamp;
+        pair.creator = ctx.accounts.sender.key();
         pair.is_initialized = true;
 
         // Update the factory with the new pair
@@ -80,20 +384,38 @@ pub mod solana_dex {
             token1,
             pair: ctx.accounts.pair.key(),
             pair_count: factory.pair_count,
+            created_at: ctx.accounts.pair.created_at,
+            creator: ctx.accounts.pair.creator,
         });
 
         Ok(())
     }
 
-    pub fn add_liquidity(
-        ctx: Context<AddLiquidity>,
+    pub fn add_liquidity<'info>(
+        ctx: Context<'_, '_, '_, 'info, AddLiquidity<'info>>,
         amount0_desired: u128,
         amount1_desired: u128,
         amount0_min: u128,
         amount1_min: u128,
+        deadline: i64,
     ) -> Result<()> {
         // Ensure pair is initialized
         require!(ctx.accounts.pair.is_initialized, DexError::PairNotInitialized);
+        require!(
+            amount0_desired > 0 && amount1_desired > 0,
+            DexError::InsufficientInputAmount
+        );
+        require!(!ctx.accounts.factory.paused, DexError::Paused);
+        require!(!ctx.accounts.pair.pair_paused, DexError::PairPaused);
+        require!(!ctx.accounts.pair.frozen, DexError::PairFrozen);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now <= deadline, DexError::Expired);
+        lock_pair(&mut ctx.accounts.pair)?;
+        update_cumulative_prices(&mut ctx.accounts.pair)?;
+        ctx.accounts.pair.check_deposit_deviation(now as u32)?;
+
+        // Protocol fee first, so the deposit is priced against the diluted supply
+        ctx.accounts.mint_fee()?;
     
         // Get current reserves
         let reserve0 = ctx.accounts.pair.reserve0;
@@ -101,166 +423,19 @@ pub mod solana_dex {
         let total_supply = ctx.accounts.pair.total_supply;
     
         // Calculate liquidity amounts
-        let (amount0, amount1, liquidity) = if reserve0 == 0 && reserve1 == 0 {
-            // First liquidity provision
-            // Use the full amounts provided but ensure they don't exceed u64::MAX
-            let amount0 = u64::try_from(amount0_desired)
-                .map_err(|_| error!(DexError::AmountOverflow))?;
-            let amount1 = u64::try_from(amount1_desired)
-                .map_err(|_| error!(DexError::AmountOverflow))?;
-    
-            // Initial liquidity is the geometric mean of the amounts
-            let initial_liquidity = sqrt(
-                (amount0 as u128).checked_mul(amount1 as u128).unwrap()
-            ) as u64;
-    
-            // Enforce minimum liquidity
-            let liquidity = initial_liquidity.checked_sub(1000).unwrap_or(0);
-    
-            // Minimum liquidity check
-            require!(liquidity > 0, DexError::InsufficientLiquidityMinted);
-    
-            (amount0, amount1, liquidity)
-        } else {
-            // Not the first provision, calculate based on existing reserves
-            let amount1_optimal = amount0_desired
-                .checked_mul(reserve1 as u128)
-                .unwrap()
-                .checked_div(reserve0 as u128)
-                .unwrap();
-    
-            if amount1_optimal <= amount1_desired {
-                // amount1_optimal is the binding amount
-                require!(
-                    amount1_optimal >= amount1_min,
-                    DexError::InsufficientAmount
-                );
-    
-                let liquidity = amount0_desired
-                    .checked_mul(total_supply as u128)
-                    .unwrap()
-                    .checked_div(reserve0 as u128)
-                    .unwrap();
-    
-                // Convert to u64 for actual token transfers
-                let amount0_u64 = u64::try_from(amount0_desired)
-                    .map_err(|_| error!(DexError::AmountOverflow))?;
-                let amount1_u64 = u64::try_from(amount1_optimal)
-                    .map_err(|_| error!(DexError::AmountOverflow))?;
-                let liquidity_u64 = u64::try_from(liquidity)
-                    .map_err(|_| error!(DexError::AmountOverflow))?;
-    
-                (amount0_u64, amount1_u64, liquidity_u64)
-            } else {
-                // amount0_optimal is the binding amount
-                let amount0_optimal = amount1_desired
-                    .checked_mul(reserve0 as u128)
-                    .unwrap()
-                    .checked_div(reserve1 as u128)
-                    .unwrap();
-    
-                require!(
-                    amount0_optimal >= amount0_min,
-                    DexError::InsufficientAmount
-                );
-    
-                let liquidity = amount1_desired
-                    .checked_mul(total_supply as u128)
-                    .unwrap()
-                    .checked_div(reserve1 as u128)
-                    .unwrap();
-    
-                // Convert to u64 for actual token transfers
-                let amount0_u64 = u64::try_from(amount0_optimal)
-                    .map_err(|_| error!(DexError::AmountOverflow))?;
-                let amount1_u64 = u64::try_from(amount1_desired)
-                    .map_err(|_| error!(DexError::AmountOverflow))?;
-                let liquidity_u64 = u64::try_from(liquidity)
-                    .map_err(|_| error!(DexError::AmountOverflow))?;
-    
-                (amount0_u64, amount1_u64, liquidity_u64)
-            }
-        };
-    
-        // Ensure minimum liquidity amounts
-        require!(
-            amount0 as u128 >= amount0_min && amount1 as u128 >= amount1_min,
-            DexError::InsufficientAmount
-        );
-    
-        // Transfer tokens from user to pair
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                token::Transfer {
-                    from: ctx.accounts.user_token0.to_account_info(),
-                    to: ctx.accounts.token0_account.to_account_info(),
-                    authority: ctx.accounts.sender.to_account_info(),
-                },
-            ),
-            amount0,
-        )?;
-    
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                token::Transfer {
-                    from: ctx.accounts.user_token1.to_account_info(),
-                    to: ctx.accounts.token1_account.to_account_info(),
-                    authority: ctx.accounts.sender.to_account_info(),
-                },
-            ),
-            amount1,
-        )?;
-        
-        // Mint LP tokens to user
-        let pair_key = ctx.accounts.pair.key();
-        let authority_seeds = &[
-            b"authority".as_ref(),
-            pair_key.as_ref(),
-            &[ctx.accounts.pair.authority_bump],
-        ];
-    
-        // If this is the first deposit, mint minimum liquidity to burn account
-        if reserve0 == 0 && reserve1 == 0 {
-            // Mint minimum liquidity to burn address
-            token::mint_to(
-                CpiContext::new_with_signer(
-                    ctx.accounts.token_program.to_account_info(),
-                    token::MintTo {
-                        mint: ctx.accounts.lp_mint.to_account_info(),
-                        to: ctx.accounts.burn_account.to_account_info(),
-                        authority: ctx.accounts.authority.to_account_info(),
-                    },
-                    &[authority_seeds],
-                ),
-                1000, // Minimum liquidity
-            )?;
-        }
-    
-        // Mint LP tokens to user
-        token::mint_to(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                token::MintTo {
-                    mint: ctx.accounts.lp_mint.to_account_info(),
-                    to: ctx.accounts.liquidity_to.to_account_info(),
-                    authority: ctx.accounts.authority.to_account_info(),
-                },
-                &[authority_seeds],
-            ),
-            liquidity,
+        let (amount0, amount1, liquidity) = compute_liquidity(
+            reserve0,
+            reserve1,
+            total_supply,
+            ctx.accounts.pair.minimum_liquidity,
+            ctx.accounts.pair.normalized_decimals(),
+            amount0_desired,
+            amount1_desired,
+            amount0_min,
+            amount1_min,
         )?;
     
-        // Update pair account
-        ctx.accounts.pair.reserve0 = reserve0.checked_add(amount0).unwrap();
-        ctx.accounts.pair.reserve1 = reserve1.checked_add(amount1).unwrap();
-        ctx.accounts.pair.total_supply = total_supply.checked_add(liquidity).unwrap();
-    
-        // If this is the first deposit, add minimum liquidity to total supply
-        if reserve0 == 0 && reserve1 == 0 {
-            ctx.accounts.pair.total_supply = ctx.accounts.pair.total_supply.checked_add(1000).unwrap();
-        }
+        let (amount0, amount1, liquidity) = ctx.accounts.deposit_liquidity(amount0, amount1, liquidity)?;
     
         // Emit event
         emit!(LiquidityAddedEvent {
@@ -268,501 +443,5353 @@ pub mod solana_dex {
             amount0,
             amount1,
             liquidity,
+            reserve0_after: ctx.accounts.pair.reserve0,
+            reserve1_after: ctx.accounts.pair.reserve1,
         });
-    
+
+        invoke_liquidity_callback(
+            &ctx.accounts.pair,
+            ctx.remaining_accounts,
+            LiquidityCallbackData {
+                pair: ctx.accounts.pair.key(),
+                sender: ctx.accounts.sender.key(),
+                amount0,
+                amount1,
+                liquidity,
+                is_add: true,
+            },
+        )?;
+
+        ctx.accounts.pair.locked = false;
         Ok(())
     }
 
-    pub fn remove_liquidity(
-        ctx: Context<RemoveLiquidity>,
-        liquidity: u128,
+    // Deposit sized by total value in token0 terms: half is supplied as token0
+    // and the other half as token1 at the current reserve ratio. Unlike a zap
+    // nothing is swapped, the caller must already hold both tokens.
+    pub fn add_liquidity_by_value<'info>(
+        ctx: Context<'_, '_, '_, 'info, AddLiquidity<'info>>,
+        total_value_in_token0: u128,
         amount0_min: u128,
         amount1_min: u128,
+        deadline: i64,
     ) -> Result<()> {
-        // Ensure pair is initialized
         require!(ctx.accounts.pair.is_initialized, DexError::PairNotInitialized);
-    
-        // Get current reserves and total supply
+        require!(total_value_in_token0 > 0, DexError::InsufficientInputAmount);
+        require!(!ctx.accounts.factory.paused, DexError::Paused);
+        require!(!ctx.accounts.pair.pair_paused, DexError::PairPaused);
+        require!(!ctx.accounts.pair.frozen, DexError::PairFrozen);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now <= deadline, DexError::Expired);
+        lock_pair(&mut ctx.accounts.pair)?;
+        update_cumulative_prices(&mut ctx.accounts.pair)?;
+        ctx.accounts.pair.check_deposit_deviation(now as u32)?;
+
+        ctx.accounts.mint_fee()?;
+
         let reserve0 = ctx.accounts.pair.reserve0;
         let reserve1 = ctx.accounts.pair.reserve1;
         let total_supply = ctx.accounts.pair.total_supply;
-    
-        // Convert liquidity to u64 since that's what token operations require
-        let liquidity_u64 = u64::try_from(liquidity)
-            .map_err(|_| error!(DexError::AmountOverflow))?;
-    
-        // Calculate token amounts based on proportion of liquidity
-        let amount0 = liquidity
-            .checked_mul(reserve0 as u128)
-            .unwrap()
-            .checked_div(total_supply as u128)
-            .unwrap();
-    
-        let amount1 = liquidity
+
+        // The split needs a price, so the pool must already be seeded
+        require!(reserve0 > 0 && reserve1 > 0, DexError::InsufficientLiquidity);
+
+        let amount0_desired = total_value_in_token0 / 2;
+        let amount1_desired = amount0_desired
             .checked_mul(reserve1 as u128)
-            .unwrap()
-            .checked_div(total_supply as u128)
-            .unwrap();
-    
-        // Ensure minimum amounts are met
+            .ok_or(DexError::AmountOverflow)?
+            .checked_div(reserve0 as u128)
+            .ok_or(DexError::InsufficientLiquidity)?;
         require!(
-            amount0 >= amount0_min && amount1 >= amount1_min,
+            amount0_desired > 0 && amount1_desired > 0,
             DexError::InsufficientAmount
         );
-    
-        // Convert to u64 for token operations
-        let amount0_u64 = u64::try_from(amount0)
-            .map_err(|_| error!(DexError::AmountOverflow))?;
-        let amount1_u64 = u64::try_from(amount1)
-            .map_err(|_| error!(DexError::AmountOverflow))?;
-    
-        // Burn LP tokens first
-        token::burn(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                token::Burn {
-                    mint: ctx.accounts.lp_mint.to_account_info(),
-                    from: ctx.accounts.liquidity_from.to_account_info(),
-                    authority: ctx.accounts.sender.to_account_info(),
-                },
-            ),
-            liquidity_u64,
-        )?;
-    
-        // Transfer tokens to user
-        let pair_key = ctx.accounts.pair.key();
-        let authority_seeds = &[
-            b"authority".as_ref(),
-            pair_key.as_ref(),
-            &[ctx.accounts.pair.authority_bump],
-        ];
-    
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                token::Transfer {
-                    from: ctx.accounts.token0_account.to_account_info(),
-                    to: ctx.accounts.token0_to.to_account_info(),
-                    authority: ctx.accounts.authority.to_account_info(),
-                },
-                &[authority_seeds],
-            ),
-            amount0_u64,
-        )?;
-    
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                token::Transfer {
-                    from: ctx.accounts.token1_account.to_account_info(),
-                    to: ctx.accounts.token1_to.to_account_info(),
-                    authority: ctx.accounts.authority.to_account_info(),
-                },
-                &[authority_seeds],
-            ),
-            amount1_u64,
+
+        let (amount0, amount1, liquidity) = compute_liquidity(
+            reserve0,
+            reserve1,
+            total_supply,
+            ctx.accounts.pair.minimum_liquidity,
+            ctx.accounts.pair.normalized_decimals(),
+            amount0_desired,
+            amount1_desired,
+            amount0_min,
+            amount1_min,
         )?;
-    
-        // Update pair account
-        ctx.accounts.pair.reserve0 = reserve0.checked_sub(amount0_u64).unwrap();
-        ctx.accounts.pair.reserve1 = reserve1.checked_sub(amount1_u64).unwrap();
-        ctx.accounts.pair.total_supply = total_supply.checked_sub(liquidity_u64).unwrap();
-    
-        // Emit event
-        emit!(LiquidityRemovedEvent {
+
+        let (amount0, amount1, liquidity) = ctx.accounts.deposit_liquidity(amount0, amount1, liquidity)?;
+
+        emit!(LiquidityAddedEvent {
             sender: ctx.accounts.sender.key(),
-            amount0: amount0_u64,
-            amount1: amount1_u64,
-            liquidity: liquidity_u64,
+            amount0,
+            amount1,
+            liquidity,
+            reserve0_after: ctx.accounts.pair.reserve0,
+            reserve1_after: ctx.accounts.pair.reserve1,
         });
-    
+
+        invoke_liquidity_callback(
+            &ctx.accounts.pair,
+            ctx.remaining_accounts,
+            LiquidityCallbackData {
+                pair: ctx.accounts.pair.key(),
+                sender: ctx.accounts.sender.key(),
+                amount0,
+                amount1,
+                liquidity,
+                is_add: true,
+            },
+        )?;
+
+        ctx.accounts.pair.locked = false;
         Ok(())
     }
 
-    pub fn swap(
-        ctx: Context<Swap>,
+    // Swaps `swap_fraction_bps` of `amount_in` (of mint `token_in`) and
+    // deposits the rest together with the swap output at the post-swap
+    // reserves. Unlike a zap the split is the caller's choice, so whatever one
+    // side can't pair with stays in the wallet. Pairs with a cooldown or an
+    // external oracle are refused, as the accounts can't carry their extras.
+    #[allow(clippy::too_many_arguments)]
+    pub fn swap_and_add_liquidity<'info>(
+        ctx: Context<'_, '_, '_, 'info, AddLiquidity<'info>>,
         amount_in: u128,
+        token_in: Pubkey,
+        swap_fraction_bps: u16,
         amount_out_min: u128,
+        amount0_min: u128,
+        amount1_min: u128,
+        deadline: i64,
     ) -> Result<()> {
-        // Ensure pair is initialized
-        require!(ctx.accounts.pair.is_initialized, DexError::PairNotInitialized);
-    
-        // Get current reserves and determine input/output token accounts
-        let (reserve_in, reserve_out, is_token0_in) = if ctx.accounts.token_in.mint.eq(&ctx.accounts.pair.token0) {
-            (ctx.accounts.pair.reserve0, ctx.accounts.pair.reserve1, true)
-        } else if ctx.accounts.token_in.mint.eq(&ctx.accounts.pair.token1) {
-            (ctx.accounts.pair.reserve1, ctx.accounts.pair.reserve0, false)
-        } else {
-            return err!(DexError::InvalidTokenAccount);
-        };
-    
-        // Convert amount_in to u64 for token operations
-        let amount_in_u64 = u64::try_from(amount_in)
-            .map_err(|_| error!(DexError::AmountOverflow))?;
-    
-        // Calculate amount out with fee (0.3% fee = multiply by 997 / 1000)
-        let amount_in_with_fee = amount_in.checked_mul(997).unwrap();
-    
-        // Calculate amount out based on constant product formula (k = x * y)
-        let numerator = amount_in_with_fee.checked_mul(reserve_out as u128).unwrap();
-        let denominator = (reserve_in as u128).checked_mul(1000).unwrap().checked_add(amount_in_with_fee).unwrap();
-        let amount_out = numerator.checked_div(denominator).unwrap();
-    
-        // Ensure minimum output amount is met
         require!(
-            amount_out >= amount_out_min,
-            DexError::InsufficientOutputAmount
+            swap_fraction_bps as u128 <= FEE_DENOMINATOR,
+            DexError::InvalidSwapFraction
         );
-    
-        // Convert amount_out to u64 for token operations
-        let amount_out_u64 = u64::try_from(amount_out)
+        let is_token0_in = token_in == ctx.accounts.pair.token0;
+        require!(is_token0_in || token_in == ctx.accounts.pair.token1, DexError::InvalidTokenAccount);
+
+        swap_and_deposit(
+            ctx,
+            amount_in,
+            is_token0_in,
+            Some(swap_fraction_bps),
+            amount_out_min,
+            amount0_min,
+            amount1_min,
+            0,
+            deadline,
+        )
+    }
+
+    // One-sided deposit: swaps the share of `amount_in` that balances the
+    // remainder against the swap output at the post-swap reserves, then
+    // deposits both. Only what the deposit pairs up is pulled in, so rounding
+    // dust never leaves the wallet. Same restrictions as `swap_and_add_liquidity`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn zap_in<'info>(
+        ctx: Context<'_, '_, '_, 'info, AddLiquidity<'info>>,
+        amount_in: u128,
+        is_token0: bool,
+        amount_out_min: u128,
+        amount0_min: u128,
+        amount1_min: u128,
+        min_liquidity: u128,
+        deadline: i64,
+    ) -> Result<()> {
+        swap_and_deposit(
+            ctx,
+            amount_in,
+            is_token0,
+            None,
+            amount_out_min,
+            amount0_min,
+            amount1_min,
+            min_liquidity,
+            deadline,
+        )
+    }
+
+    // Preview of `add_liquidity`, emitted and also returned through the
+    // instruction's return data: same amount selection and LP math, no
+    // transfers
+    pub fn quote_add_liquidity(
+        ctx: Context<PairView>,
+        amount0_desired: u128,
+        amount1_desired: u128,
+    ) -> Result<AddLiquidityQuote> {
+        let pair = &ctx.accounts.pair;
+        require!(pair.is_initialized, DexError::PairNotInitialized);
+
+        let (amount0, amount1, liquidity) = compute_liquidity(
+            pair.reserve0,
+            pair.reserve1,
+            pair.total_supply,
+            pair.minimum_liquidity,
+            pair.normalized_decimals(),
+            amount0_desired,
+            amount1_desired,
+            0,
+            0,
+        )?;
+
+        emit!(AddLiquidityPreviewEvent {
+            amount0,
+            amount1,
+            liquidity,
+        });
+
+        Ok(AddLiquidityQuote {
+            amount0,
+            amount1,
+            liquidity,
+        })
+    }
+
+    // Preview of `remove_liquidity` through the instruction's return data: the
+    // amounts burning `liquidity` pays out, with the same integer math
+    // and the protocol fee that burn would mint first, so it fails where the
+    // real withdrawal would
+    pub fn quote_remove_liquidity(ctx: Context<FactoryPairView>, liquidity: u128) -> Result<RemoveLiquidityQuote> {
+        let pair = &ctx.accounts.pair;
+        require!(pair.is_initialized, DexError::PairNotInitialized);
+        require!(liquidity > 0, DexError::InsufficientInputAmount);
+
+        let reserve0 = pair.reserve0;
+        let reserve1 = pair.reserve1;
+        let total_supply = pair
+            .total_supply
+            .checked_add(pair.accrued_protocol_fee(&ctx.accounts.factory)?)
+            .ok_or(DexError::AmountOverflow)?;
+
+        require!(total_supply > 0, DexError::InsufficientLiquidity);
+        let amount0 = liquidity
+            .checked_mul(reserve0 as u128)
+            .ok_or(DexError::AmountOverflow)?
+            .checked_div(total_supply as u128)
+            .ok_or(DexError::InsufficientLiquidity)?;
+        let amount1 = liquidity
+            .checked_mul(reserve1 as u128)
+            .ok_or(DexError::AmountOverflow)?
+            .checked_div(total_supply as u128)
+            .ok_or(DexError::InsufficientLiquidity)?;
+        check_withdrawal_rounding(liquidity, reserve0, total_supply, amount0)?;
+        check_withdrawal_rounding(liquidity, reserve1, total_supply, amount1)?;
+
+        Ok(RemoveLiquidityQuote {
+            amount0: u64::try_from(amount0).map_err(|_| error!(DexError::AmountOverflow))?,
+            amount1: u64::try_from(amount1).map_err(|_| error!(DexError::AmountOverflow))?,
+        })
+    }
+
+    // `quote_remove_liquidity` plus the LP the pending protocol fee will mint
+    // ahead of the burn, which the quote already dilutes the supply by
+    pub fn my_redeemable(ctx: Context<FactoryPairView>, liquidity: u128) -> Result<RedeemableAmounts> {
+        let protocol_fee_liquidity = ctx.accounts.pair.accrued_protocol_fee(&ctx.accounts.factory)?;
+        let RemoveLiquidityQuote { amount0, amount1 } = quote_remove_liquidity(ctx, liquidity)?;
+
+        Ok(RedeemableAmounts {
+            amount0,
+            amount1,
+            protocol_fee_liquidity,
+        })
+    }
+
+    // Exact-in quote returned through the instruction's return data, using the
+    // same integer math as `swap` so a simulation matches the real trade.
+    // Promotions and holder discounts are signer/factory-level and not applied.
+    pub fn get_amount_out(ctx: Context<PairView>, amount_in: u128, token_in: Pubkey) -> Result<u128> {
+        let pair = &ctx.accounts.pair;
+        let (reserve_in, reserve_out) = if token_in == pair.token0 {
+            (pair.reserve0, pair.reserve1)
+        } else if token_in == pair.token1 {
+            (pair.reserve1, pair.reserve0)
+        } else {
+            return err!(DexError::InvalidTokenAccount);
+        };
+
+        let amount_in_u64 = u64::try_from(amount_in)
+            .map_err(|_| error!(DexError::AmountOverflow))?;
+        amount_out_for(amount_in, reserve_in, reserve_out, pair.swap_fee_bps(amount_in_u64))
+    }
+
+    // Exact-out quote: the smallest input that makes `swap` pay at least
+    // `amount_out` of `token_out`, emitted and also returned through the
+    // instruction's return data.
+    pub fn get_amount_in(ctx: Context<PairView>, amount_out: u64, token_out: Pubkey) -> Result<u64> {
+        let pair = &ctx.accounts.pair;
+        let (reserve_in, reserve_out) = if token_out == pair.token1 {
+            (pair.reserve0, pair.reserve1)
+        } else if token_out == pair.token0 {
+            (pair.reserve1, pair.reserve0)
+        } else {
+            return err!(DexError::InvalidTokenAccount);
+        };
+        require!(amount_out > 0, DexError::InsufficientOutputAmount);
+        require!(amount_out < reserve_out, DexError::InsufficientLiquidity);
+
+        let amount_in = pair.amount_in_for_exact_out(amount_out, reserve_in, reserve_out, |fee_bps| fee_bps)?;
+
+        emit!(AmountInEvent { amount_in });
+
+        Ok(amount_in)
+    }
+
+    // Read-side helper for UIs: the spot price of token0 in token1 (Q64.64)
+    // floored to the pair's tick size, emitted and also returned through the
+    // instruction's return data. Swap math never uses it.
+    pub fn spot_price_ticked(ctx: Context<PairView>) -> Result<TickedPrice> {
+        let pair = &ctx.accounts.pair;
+        require!(pair.reserve0 > 0 && pair.reserve1 > 0, DexError::InsufficientLiquidity);
+
+        let tick = pair.tick_size_x64.max(1);
+        let raw_price_x64 = ((pair.reserve1 as u128) << 64) / pair.reserve0 as u128;
+        let price_x64 = raw_price_x64 - raw_price_x64 % tick;
+
+        emit!(TickedPriceEvent { price_x64, tick });
+
+        Ok(TickedPrice { price_x64, tick })
+    }
+
+    // Keeper planning tool: the swap that moves the pool's price of token0 (in
+    // token1, Q64.64) to `external_price_x64`. Target reserves come from the
+    // current k curve; the input is then grossed up for the swap fee, which
+    // overshoots by roughly fee * amount_in / reserve_in. Emitted and also
+    // returned through the instruction's return data.
+    pub fn price_after_external_move(ctx: Context<PairView>, external_price_x64: u128) -> Result<ArbitrageQuote> {
+        let pair = &ctx.accounts.pair;
+        require!(pair.reserve0 > 0 && pair.reserve1 > 0, DexError::InsufficientLiquidity);
+        require!(external_price_x64 > 0, DexError::InvalidPrice);
+
+        // At price p on the same curve: reserve0 = sqrt(k / p), reserve1 = sqrt(k * p).
+        // sqrt(p) is Q32.32, so shift by 32 to get back to token units.
+        let reserve0 = pair.reserve0 as u128;
+        let reserve1 = pair.reserve1 as u128;
+        let root_k = sqrt(reserve0 * reserve1);
+        let root_price = sqrt(external_price_x64);
+        let target0 = (root_k << 32) / root_price;
+        let target1 = root_k.checked_mul(root_price).ok_or(DexError::AmountOverflow)? >> 32;
+
+        let zero_for_one = target0 > reserve0;
+        let (reserve_in, target_in, reserve_out, target_out) = if zero_for_one {
+            (reserve0, target0, reserve1, target1)
+        } else {
+            (reserve1, target1, reserve0, target0)
+        };
+        let net_amount_in = u64::try_from(target_in.saturating_sub(reserve_in))
+            .map_err(|_| error!(DexError::AmountOverflow))?;
+        let amount_out = reserve_out.saturating_sub(target_out) as u64;
+
+        let fee_bps = pair.swap_fee_bps(net_amount_in) as u128;
+        let amount_in = (net_amount_in as u128 * FEE_DENOMINATOR)
+            .div_ceil(FEE_DENOMINATOR - fee_bps);
+        let amount_in = u64::try_from(amount_in).map_err(|_| error!(DexError::AmountOverflow))?;
+
+        emit!(ArbitrageQuoteEvent {
+            pair: pair.key(),
+            zero_for_one,
+            amount_in,
+            amount_out,
+        });
+
+        Ok(ArbitrageQuote {
+            zero_for_one,
+            amount_in,
+            amount_out,
+        })
+    }
+
+    // Classifies `candidate` for CPI consumers: 0 = unrelated, 1 = vault0,
+    // 2 = vault1, 3 = pair authority, 4 = LP mint. Emitted and also returned
+    // through the instruction's return data.
+    pub fn verify_pair_account(ctx: Context<PairView>, candidate: Pubkey) -> Result<u8> {
+        let pair = &ctx.accounts.pair;
+        let pair_key = pair.key();
+        let authority = Pubkey::create_program_address(
+            &[b"authority".as_ref(), pair_key.as_ref(), &[pair.authority_bump]],
+            ctx.program_id,
+        )
+        .map_err(|_| error!(DexError::PairNotInitialized))?;
+
+        let role = if candidate == pair.token0_account {
+            ACCOUNT_ROLE_VAULT0
+        } else if candidate == pair.token1_account {
+            ACCOUNT_ROLE_VAULT1
+        } else if candidate == authority {
+            ACCOUNT_ROLE_AUTHORITY
+        } else if candidate == pair.lp_mint {
+            ACCOUNT_ROLE_LP_MINT
+        } else {
+            ACCOUNT_ROLE_NONE
+        };
+
+        emit!(AccountRoleEvent {
+            pair: pair_key,
+            candidate,
+            role,
+        });
+
+        Ok(role)
+    }
+
+    // Every on/off setting of a pair in one word; see the PAIR_FLAG_* bits.
+    // Emitted and also returned through the instruction's return data.
+    pub fn get_pair_flags(ctx: Context<PairView>) -> Result<u32> {
+        let flags = ctx.accounts.pair.flags();
+
+        emit!(PairFlagsEvent { flags });
+
+        Ok(flags)
+    }
+
+    // Average prices (Q64.64) since the newest observation at least `window`
+    // seconds old, up to now. The returned window is the span actually
+    // averaged, which may be longer than asked for.
+    pub fn consult(ctx: Context<PairView>, window: u32) -> Result<OracleConsult> {
+        let pair = &ctx.accounts.pair;
+        let now = Clock::get()?.unix_timestamp as u32;
+        let observation = pair
+            .observation_before(now, window)
+            .ok_or(DexError::OracleHistoryTooShort)?;
+        let elapsed = now.wrapping_sub(observation.timestamp);
+        let (price0_cumulative, price1_cumulative) = pair.cumulative_prices_at(now);
+
+        Ok(OracleConsult {
+            price0_average_x64: price0_cumulative.wrapping_sub(observation.price0_cumulative) / elapsed as u128,
+            price1_average_x64: price1_cumulative.wrapping_sub(observation.price1_cumulative) / elapsed as u128,
+            window: elapsed,
+        })
+    }
+
+    // Average sqrt(reserve0 * reserve1) over the same span `consult` would
+    // use, so consumers can discount prices from thin pools
+    pub fn consult_liquidity(ctx: Context<PairView>, window: u32) -> Result<LiquidityConsult> {
+        let pair = &ctx.accounts.pair;
+        let now = Clock::get()?.unix_timestamp as u32;
+        let observation = pair
+            .observation_before(now, window)
+            .ok_or(DexError::OracleHistoryTooShort)?;
+        let elapsed = now.wrapping_sub(observation.timestamp);
+
+        Ok(LiquidityConsult {
+            liquidity_average: pair
+                .liquidity_cumulative_at(now)
+                .wrapping_sub(observation.liquidity_cumulative)
+                / elapsed as u128,
+            window: elapsed,
+        })
+    }
+
+    // How close the reserves sit to the pair's swap caps. The ratio is the
+    // larger reserve over the smaller in bps (10_000 = balanced); a disabled
+    // cap reports u64::MAX headroom. There's no per-pair reserve change cap,
+    // so only `max_ratio` and `max_out_bps` are covered. Emitted and also
+    // returned through the instruction's return data.
+    pub fn pair_utilization(ctx: Context<PairView>) -> Result<PairUtilization> {
+        let pair = &ctx.accounts.pair;
+        require!(pair.reserve0 > 0 && pair.reserve1 > 0, DexError::InsufficientLiquidity);
+
+        let (larger, smaller) = if pair.reserve0 >= pair.reserve1 {
+            (pair.reserve0 as u128, pair.reserve1 as u128)
+        } else {
+            (pair.reserve1 as u128, pair.reserve0 as u128)
+        };
+        let ratio_bps = larger * FEE_DENOMINATOR / smaller;
+        let ratio_headroom_bps = if pair.max_ratio > 0 {
+            (pair.max_ratio as u128 * FEE_DENOMINATOR).saturating_sub(ratio_bps)
+        } else {
+            u64::MAX as u128
+        };
+
+        // Largest output a single swap may take from each reserve right now
+        let max_out = |reserve: u64| {
+            if pair.max_out_bps > 0 {
+                (reserve as u128 * pair.max_out_bps as u128 / FEE_DENOMINATOR) as u64
+            } else {
+                reserve - 1
+            }
+        };
+
+        let utilization = PairUtilization {
+            reserve0: pair.reserve0,
+            reserve1: pair.reserve1,
+            ratio_bps: u64::try_from(ratio_bps).unwrap_or(u64::MAX),
+            max_ratio: pair.max_ratio,
+            ratio_headroom_bps: u64::try_from(ratio_headroom_bps).unwrap_or(u64::MAX),
+            max_out_bps: pair.max_out_bps,
+            max_amount0_out: max_out(pair.reserve0),
+            max_amount1_out: max_out(pair.reserve1),
+        };
+
+        emit!(UtilizationEvent {
+            pair: pair.key(),
+            reserve0: utilization.reserve0,
+            reserve1: utilization.reserve1,
+            ratio_bps: utilization.ratio_bps,
+            max_ratio: utilization.max_ratio,
+            ratio_headroom_bps: utilization.ratio_headroom_bps,
+            max_out_bps: utilization.max_out_bps,
+            max_amount0_out: utilization.max_amount0_out,
+            max_amount1_out: utilization.max_amount1_out,
+        });
+
+        Ok(utilization)
+    }
+
+    // Derives the pair PDA for two mints given in either order, sorted the
+    // same way `configure_pair` stores them, and reports whether the pair
+    // passed in (which must be that PDA) holds an initialized pair
+    pub fn find_pair(ctx: Context<FindPair>, token_a: Pubkey, token_b: Pubkey) -> Result<PairLookup> {
+        require_keys_neq!(token_a, token_b, DexError::IdenticalTokens);
+        let (token0, token1) = if token_a < token_b {
+            (token_a, token_b)
+        } else {
+            (token_b, token_a)
+        };
+        let (pair, _) =
+            Pubkey::find_program_address(&[b"pair", token0.as_ref(), token1.as_ref()], ctx.program_id);
+
+        let account = &ctx.accounts.pair;
+        require_keys_eq!(account.key(), pair, DexError::InvalidPairAddress);
+        let is_initialized = account.owner == ctx.program_id
+            && PairAccount::try_deserialize(&mut &account.try_borrow_data()?[..])
+                .is_ok_and(|state| state.is_initialized);
+
+        Ok(PairLookup { pair, is_initialized })
+    }
+
+    // Diagnostic snapshot: the stored pair state alongside the live vault
+    // balances and LP mint supply, so desyncs show up in a single event and
+    // the same snapshot comes back as return data
+    pub fn dump_pair_state(ctx: Context<PairDump>) -> Result<PairStateDump> {
+        emit!(PairStateDumpEvent {
+            pair: ctx.accounts.pair.key(),
+            state: (*ctx.accounts.pair).clone(),
+            vault0_balance: ctx.accounts.token0_account.amount,
+            vault1_balance: ctx.accounts.token1_account.amount,
+            lp_mint_supply: ctx.accounts.lp_mint.supply,
+        });
+
+        Ok(PairStateDump {
+            state: (*ctx.accounts.pair).clone(),
+            vault0_balance: ctx.accounts.token0_account.amount,
+            vault1_balance: ctx.accounts.token1_account.amount,
+            lp_mint_supply: ctx.accounts.lp_mint.supply,
+        })
+    }
+
+    pub fn remove_liquidity<'info>(
+        ctx: Context<'_, '_, '_, 'info, RemoveLiquidity<'info>>,
+        liquidity: u128,
+        amount0_min: u128,
+        amount1_min: u128,
+        deadline: i64,
+    ) -> Result<()> {
+        // Ensure pair is initialized
+        require!(ctx.accounts.pair.is_initialized, DexError::PairNotInitialized);
+        require!(liquidity > 0, DexError::InsufficientInputAmount);
+        require!(Clock::get()?.unix_timestamp <= deadline, DexError::Expired);
+        lock_pair(&mut ctx.accounts.pair)?;
+        update_cumulative_prices(&mut ctx.accounts.pair)?;
+
+        ctx.accounts.mint_fee()?;
+    
+        // Get current reserves and total supply
+        let reserve0 = ctx.accounts.pair.reserve0;
+        let reserve1 = ctx.accounts.pair.reserve1;
+        let total_supply = ctx.accounts.pair.total_supply;
+    
+        // Convert liquidity to u64 since that's what token operations require
+        let liquidity_u64 = u64::try_from(liquidity)
             .map_err(|_| error!(DexError::AmountOverflow))?;
     
-        // Ensure amount_out is positive and reserves are sufficient
-        require!(amount_out_u64 > 0, DexError::InsufficientOutputAmount);
-        require!(amount_out_u64 <= reserve_out, DexError::InsufficientLiquidity);
+        // Calculate token amounts based on proportion of liquidity
+        require!(total_supply > 0, DexError::InsufficientLiquidity);
+        let amount0 = liquidity
+            .checked_mul(reserve0 as u128)
+            .ok_or(DexError::AmountOverflow)?
+            .checked_div(total_supply as u128)
+            .ok_or(DexError::InsufficientLiquidity)?;
     
-        // Transfer tokens from user to pool
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                token::Transfer {
-                    from: ctx.accounts.token_in.to_account_info(),
-                    to: if is_token0_in {
-                        ctx.accounts.token0_account.to_account_info()
-                    } else {
-                        ctx.accounts.token1_account.to_account_info()
-                    },
-                    authority: ctx.accounts.sender.to_account_info(),
-                },
-            ),
-            amount_in_u64,
-        )?;
+        let amount1 = liquidity
+            .checked_mul(reserve1 as u128)
+            .ok_or(DexError::AmountOverflow)?
+            .checked_div(total_supply as u128)
+            .ok_or(DexError::InsufficientLiquidity)?;
+        check_withdrawal_rounding(liquidity, reserve0, total_supply, amount0)?;
+        check_withdrawal_rounding(liquidity, reserve1, total_supply, amount1)?;
     
-        // Transfer tokens from pool to user
-        let pair_key = ctx.accounts.pair.key();
-        let authority_seeds = &[
-            b"authority".as_ref(),
-            pair_key.as_ref(),
-            &[ctx.accounts.pair.authority_bump],
-        ];
+        // Ensure minimum amounts are met
+        require!(
+            amount0 >= amount0_min && amount1 >= amount1_min,
+            DexError::InsufficientAmount
+        );
     
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                token::Transfer {
-                    from: if is_token0_in {
-                        ctx.accounts.token1_account.to_account_info()
-                    } else {
-                        ctx.accounts.token0_account.to_account_info()
-                    },
-                    to: ctx.accounts.token_out.to_account_info(),
-                    authority: ctx.accounts.authority.to_account_info(),
-                },
-                &[authority_seeds],
-            ),
-            amount_out_u64,
+        // Convert to u64 for token operations
+        let amount0_u64 = u64::try_from(amount0)
+            .map_err(|_| error!(DexError::AmountOverflow))?;
+        let amount1_u64 = u64::try_from(amount1)
+            .map_err(|_| error!(DexError::AmountOverflow))?;
+    
+        ctx.accounts.withdraw_liquidity(liquidity_u64, amount0_u64, amount1_u64)?;
+        ctx.accounts.pair.update_k_last(ctx.accounts.factory.fee_on);
+    
+        // Emit event
+        emit!(LiquidityRemovedEvent {
+            sender: ctx.accounts.sender.key(),
+            amount0: amount0_u64,
+            amount1: amount1_u64,
+            liquidity: liquidity_u64,
+            reserve0_after: ctx.accounts.pair.reserve0,
+            reserve1_after: ctx.accounts.pair.reserve1,
+        });
+
+        invoke_liquidity_callback(
+            &ctx.accounts.pair,
+            ctx.remaining_accounts,
+            LiquidityCallbackData {
+                pair: ctx.accounts.pair.key(),
+                sender: ctx.accounts.sender.key(),
+                amount0: amount0_u64,
+                amount1: amount1_u64,
+                liquidity: liquidity_u64,
+                is_add: false,
+            },
         )?;
+
+        ctx.accounts.pair.locked = false;
+        Ok(())
+    }
+
+    // `remove_liquidity` for users missing an output ATA: whichever of the
+    // sender's token0/token1 ATAs doesn't exist yet is created first, with the
+    // sender paying its rent, so exiting takes a single transaction.
+    pub fn remove_liquidity_init_accounts<'info>(
+        ctx: Context<'_, '_, '_, 'info, RemoveLiquidityInitAccounts<'info>>,
+        liquidity: u128,
+        amount0_min: u128,
+        amount1_min: u128,
+        deadline: i64,
+    ) -> Result<()> {
+        remove_liquidity(
+            Context::new(
+                ctx.program_id,
+                &mut ctx.accounts.remove,
+                ctx.remaining_accounts,
+                ctx.bumps.remove,
+            ),
+            liquidity,
+            amount0_min,
+            amount1_min,
+            deadline,
+        )
+    }
+
+    // Proportional exit that skips the pool-level guards of `remove_liquidity`
+    // (caps and the liquidity callback), so LPs can always redeem their share
+    // of whatever reserves remain; the caller's own minimums and deadline
+    // still apply. The protocol fee is still minted first and `k_last`
+    // snapshotted after, so an exit through here neither loses the fee
+    // accrued so far nor skips charging later growth.
+    pub fn emergency_remove_liquidity(
+        ctx: Context<RemoveLiquidity>,
+        liquidity: u128,
+        amount0_min: u128,
+        amount1_min: u128,
+        deadline: i64,
+    ) -> Result<()> {
+        // Ensure pair is initialized
+        require!(ctx.accounts.pair.is_initialized, DexError::PairNotInitialized);
+        require!(liquidity > 0, DexError::InsufficientInputAmount);
+        require!(Clock::get()?.unix_timestamp <= deadline, DexError::Expired);
+        lock_pair(&mut ctx.accounts.pair)?;
+        update_cumulative_prices(&mut ctx.accounts.pair)?;
+
+        ctx.accounts.mint_fee()?;
     
-        // Update reserves
-        if is_token0_in {
-            ctx.accounts.pair.reserve0 = reserve_in.checked_add(amount_in_u64).unwrap();
-            ctx.accounts.pair.reserve1 = reserve_out.checked_sub(amount_out_u64).unwrap();
-        } else {
-            ctx.accounts.pair.reserve1 = reserve_in.checked_add(amount_in_u64).unwrap();
-            ctx.accounts.pair.reserve0 = reserve_out.checked_sub(amount_out_u64).unwrap();
-        }
+        let reserve0 = ctx.accounts.pair.reserve0;
+        let reserve1 = ctx.accounts.pair.reserve1;
+        let total_supply = ctx.accounts.pair.total_supply;
     
-        // Verify k is not decreased (protects against price manipulation)
-        let new_reserve0 = ctx.accounts.pair.reserve0 as u128;
-        let new_reserve1 = ctx.accounts.pair.reserve1 as u128;
-        let old_k = (reserve_in as u128).checked_mul(reserve_out as u128).unwrap();
-        let new_k = new_reserve0.checked_mul(new_reserve1).unwrap();
-        
-        require!(new_k >= old_k, DexError::K);
+        let liquidity_u64 = u64::try_from(liquidity)
+            .map_err(|_| error!(DexError::AmountOverflow))?;
     
-        // Emit swap event
-        emit!(SwapEvent {
+        // Same proportional share as `remove_liquidity`
+        let amount0 = liquidity
+            .checked_mul(reserve0 as u128)
+            .ok_or(DexError::AmountOverflow)?
+            .checked_div(total_supply as u128)
+            .ok_or(DexError::InsufficientLiquidity)?;
+    
+        let amount1 = liquidity
+            .checked_mul(reserve1 as u128)
+            .ok_or(DexError::AmountOverflow)?
+            .checked_div(total_supply as u128)
+            .ok_or(DexError::InsufficientLiquidity)?;
+        require!(
+            amount0 >= amount0_min && amount1 >= amount1_min,
+            DexError::InsufficientAmount
+        );
+    
+        let amount0_u64 = u64::try_from(amount0)
+            .map_err(|_| error!(DexError::AmountOverflow))?;
+        let amount1_u64 = u64::try_from(amount1)
+            .map_err(|_| error!(DexError::AmountOverflow))?;
+    
+        ctx.accounts.withdraw_liquidity(liquidity_u64, amount0_u64, amount1_u64)?;
+        ctx.accounts.pair.update_k_last(ctx.accounts.factory.fee_on);
+    
+        emit!(EmergencyLiquidityRemovedEvent {
+            pair: ctx.accounts.pair.key(),
             sender: ctx.accounts.sender.key(),
-            amount_in: amount_in_u64,
-            amount_out: amount_out_u64,
-            is_token0_in,
+            amount0: amount0_u64,
+            amount1: amount1_u64,
+            liquidity: liquidity_u64,
         });
-    
+
+        ctx.accounts.pair.locked = false;
         Ok(())
     }
 
-}
+    // `max_price_impact_bps` caps how far the execution price, fee included,
+    // may fall below the pre-trade mid price (0 = no cap)
+    pub fn swap(
+        ctx: Context<Swap>,
+        amount_in: u128,
+        amount_out_min: u128,
+        deadline: i64,
+        max_price_impact_bps: u16,
+    ) -> Result<()> {
+        // A zero input would only burn compute on the checks and transfers
+        require!(amount_in > 0, DexError::InsufficientInputAmount);
+        ctx.accounts.execute_swap(
+            SwapAmount::ExactIn {
+                amount_in,
+                amount_out_min,
+                max_price_impact_bps,
+            },
+            deadline,
+        )
+    }
+
+    // "Receive exactly `amount_out`": charges the smallest input that covers it
+    // under the same fee rules as `swap`, failing if that exceeds `amount_in_max`
+    pub fn swap_tokens_for_exact_tokens(
+        ctx: Context<Swap>,
+        amount_out: u128,
+        amount_in_max: u128,
+        deadline: i64,
+    ) -> Result<()> {
+        ctx.accounts.execute_swap(
+            SwapAmount::ExactOut {
+                amount_out,
+                amount_in_max,
+            },
+            deadline,
+        )
+    }
+
+    // Routes `amount_in` through up to MAX_ROUTE_HOPS pairs (e.g. A->B->C when
+    // A/C has no pool). Each hop's output is paid straight into the next
+    // pair's vault and only the final output is held to `amount_out_min`.
+    // Pairs with a cooldown or an external oracle can't be routed through.
+    pub fn swap_exact_tokens_for_tokens<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SwapExactTokensForTokens<'info>>,
+        amount_in: u128,
+        amount_out_min: u128,
+        deadline: i64,
+    ) -> Result<()> {
+        route_exact_in(ctx, amount_in, amount_out_min, deadline, false)
+    }
+
+    // `swap_exact_tokens_for_tokens` for a path a relayer may have chosen: with
+    // `verify_hops` set, every hop must also end with k no lower than before
+    // and both reserves still backed by its vault balances
+    pub fn swap_route_checked<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SwapExactTokensForTokens<'info>>,
+        amount_in: u128,
+        amount_out_min: u128,
+        deadline: i64,
+        verify_hops: bool,
+    ) -> Result<()> {
+        route_exact_in(ctx, amount_in, amount_out_min, deadline, verify_hops)
+    }
+
+    // Independent exact-in swaps on unrelated pairs in one transaction, each
+    // held to its own `amount_out_min`; one failing leg reverts them all.
+    // Legs run in order, so a pair listed twice sees the earlier leg's trade.
+    // Same pair restrictions as routed swaps.
+    pub fn batch_swap<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BatchSwap<'info>>,
+        swaps: Vec<SwapParams>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.factory.paused, DexError::Paused);
+        let leg_accounts = ctx.remaining_accounts.chunks_exact(BATCH_SWAP_ACCOUNTS);
+        require!(
+            leg_accounts.remainder().is_empty()
+                && leg_accounts.len() == swaps.len()
+                && (1..=MAX_BATCH_SWAPS).contains(&swaps.len()),
+            DexError::InvalidBatchSwap
+        );
+
+        let now = Clock::get()?.unix_timestamp;
+        let factory = &ctx.accounts.factory;
+        let promo_fee_bps = (now < factory.promo_fee_until).then_some(factory.promo_fee_bps);
+        let sender = ctx.accounts.sender.key();
+
+        for (leg, params) in leg_accounts.zip(&swaps) {
+            require!(params.amount_in > 0, DexError::InsufficientInputAmount);
+            let amount_in = u64::try_from(params.amount_in)
+                .map_err(|_| error!(DexError::AmountOverflow))?;
+
+            let mut pair = load_hop_pair(&leg[..ROUTE_HOP_ACCOUNTS], &factory.key(), ctx.program_id)?;
+            let (vault0, vault1, authority) = (&leg[1], &leg[2], &leg[3]);
+            let mint0 = InterfaceAccount::<Mint>::try_from(&leg[4])?;
+            let mint1 = InterfaceAccount::<Mint>::try_from(&leg[5])?;
+            let token_in = InterfaceAccount::<TokenAccount>::try_from(&leg[6])?;
+            let token_out = InterfaceAccount::<TokenAccount>::try_from(&leg[7])?;
+            require_keys_eq!(token_in.owner, sender, DexError::InvalidTokenOwner);
+            require_keys_eq!(token_out.owner, sender, DexError::InvalidTokenOwner);
+            let is_token0_in = token_in.mint == pair.token0;
+            require!(is_token0_in || token_in.mint == pair.token1, DexError::InvalidTokenAccount);
+            let (vault_in, vault_out) = if is_token0_in { (vault0, vault1) } else { (vault1, vault0) };
+            let (mint_in, mint_out) = if is_token0_in { (&mint0, &mint1) } else { (&mint1, &mint0) };
+            require_keys_eq!(token_out.mint, mint_out.key(), DexError::InvalidTokenAccount);
+
+            lock_pair(&mut pair)?;
+            update_cumulative_prices(&mut pair)?;
+
+            // Priced on what reached the vault, as in `route_exact_in`
+            let vault_before = accessor::amount(vault_in)?;
+            transfer_from_user(
+                ctx.accounts.token_program.to_account_info(),
+                token_in.to_account_info(),
+                mint_in,
+                vault_in.clone(),
+                ctx.accounts.sender.to_account_info(),
+                amount_in,
+            )?;
+            let received_in = accessor::amount(vault_in)?
+                .checked_sub(vault_before)
+                .ok_or(DexError::AmountOverflow)?;
+            let amount_out = swap_hop(&mut pair, is_token0_in, received_in, promo_fee_bps, sender)?;
+            require!(
+                amount_out as u128 >= params.amount_out_min,
+                DexError::InsufficientOutputAmount
+            );
+            transfer_from_vault(
+                ctx.accounts.token_program.to_account_info(),
+                vault_out.clone(),
+                mint_out,
+                token_out.to_account_info(),
+                authority.clone(),
+                pair.key(),
+                pair.authority_bump,
+                amount_out,
+            )?;
+
+            pair.locked = false;
+            pair.exit(ctx.program_id)?;
+        }
+
+        Ok(())
+    }
+
+    // Uniswap V2 style flash swap: `amount_out` of the recipient's mint is paid
+    // out first, then `callback_program` gets `on_flash_swap` with `data` and
+    // the remaining accounts. By the time it returns the vaults must hold
+    // enough, net of the fee on whatever was paid in, that k didn't shrink.
+    pub fn flash_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, FlashSwap<'info>>,
+        amount_out: u128,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.factory.paused, DexError::Paused);
+        require!(!ctx.accounts.pair.pair_paused, DexError::PairPaused);
+        require!(amount_out > 0, DexError::InsufficientOutputAmount);
+        lock_pair(&mut ctx.accounts.pair)?;
+        update_cumulative_prices(&mut ctx.accounts.pair)?;
+        require!(
+            ctx.accounts.pair.total_supply > ctx.accounts.pair.minimum_liquidity,
+            DexError::PoolDrained
+        );
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts
+            .pair
+            .check_cooldown(ctx.accounts.trader_state.as_deref_mut(), now)?;
+
+        let amount_out = u64::try_from(amount_out).map_err(|_| error!(DexError::AmountOverflow))?;
+        let is_token0_out = ctx.accounts.recipient.mint == ctx.accounts.pair.token0;
+        let (reserve0, reserve1) = (ctx.accounts.pair.reserve0, ctx.accounts.pair.reserve1);
+        let (amount0_out, amount1_out, vault_out, mint_out) = if is_token0_out {
+            (amount_out, 0, ctx.accounts.token0_account.to_account_info(), &ctx.accounts.token0_mint)
+        } else {
+            (0, amount_out, ctx.accounts.token1_account.to_account_info(), &ctx.accounts.token1_mint)
+        };
+        require!(
+            amount0_out < reserve0 && amount1_out < reserve1,
+            DexError::InsufficientLiquidity
+        );
+
+        transfer_from_vault(
+            ctx.accounts.token_program.to_account_info(),
+            vault_out,
+            mint_out,
+            ctx.accounts.recipient.to_account_info(),
+            ctx.accounts.authority.to_account_info(),
+            ctx.accounts.pair.key(),
+            ctx.accounts.pair.authority_bump,
+            amount_out,
+        )?;
+
+        invoke_flash_swap_callback(
+            &ctx.accounts.pair,
+            &ctx.accounts.callback_program,
+            ctx.remaining_accounts,
+            FlashSwapCallbackData {
+                pair: ctx.accounts.pair.key(),
+                sender: ctx.accounts.sender.key(),
+                amount0_out,
+                amount1_out,
+                data,
+            },
+        )?;
+
+        ctx.accounts.token0_account.reload()?;
+        ctx.accounts.token1_account.reload()?;
+        let balance0 = ctx.accounts.token0_account.amount;
+        let balance1 = ctx.accounts.token1_account.amount;
+        let amount0_in = balance0.saturating_sub(reserve0 - amount0_out);
+        let amount1_in = balance1.saturating_sub(reserve1 - amount1_out);
+
+        // k on the balances with the fee taken off what came in, scaled by
+        // FEE_DENOMINATOR^2 like the reserves it's compared against. Each side
+        // pays the fee a swap of that size would, promotion and discount
+        // included.
+        let adjust_fee = ctx.accounts.factory.fee_adjustment(
+            now,
+            ctx.accounts.discount_token.as_ref(),
+            ctx.accounts.sender.key(),
+        )?;
+        let fee0_bps = adjust_fee(ctx.accounts.pair.swap_fee_bps(amount0_in)) as u128;
+        let fee1_bps = adjust_fee(ctx.accounts.pair.swap_fee_bps(amount1_in)) as u128;
+        let adjusted0 = (balance0 as u128)
+            .checked_mul(FEE_DENOMINATOR)
+            .and_then(|b| b.checked_sub((amount0_in as u128) * fee0_bps))
+            .ok_or(DexError::AmountOverflow)?;
+        let adjusted1 = (balance1 as u128)
+            .checked_mul(FEE_DENOMINATOR)
+            .and_then(|b| b.checked_sub((amount1_in as u128) * fee1_bps))
+            .ok_or(DexError::AmountOverflow)?;
+        let new_k = adjusted0.checked_mul(adjusted1).ok_or(DexError::AmountOverflow)?;
+        let old_k = (reserve0 as u128)
+            .checked_mul(reserve1 as u128)
+            .and_then(|k| k.checked_mul(FEE_DENOMINATOR * FEE_DENOMINATOR))
+            .ok_or(DexError::AmountOverflow)?;
+        require!(new_k >= old_k, DexError::FlashRepaymentFailed);
+
+        // A repayment in the other token makes the net flow a swap, held to
+        // the same per-swap limits; repaying the borrowed side is just a loan
+        let pair = &mut ctx.accounts.pair;
+        if balance0 < reserve0 {
+            pair.check_trade_limits(reserve1, reserve0, balance1 - reserve1, reserve0 - balance0)?;
+        } else if balance1 < reserve1 {
+            pair.check_trade_limits(reserve0, reserve1, balance0 - reserve0, reserve1 - balance1)?;
+        }
+        pair.reserve0 = balance0;
+        pair.reserve1 = balance1;
+        pair.check_oracle_deviation(ctx.accounts.external_oracle.as_ref())?;
+        let pair_key = pair.key();
+        pair.check_reserve_caps(pair_key);
+
+        emit!(FlashSwapEvent {
+            pair: pair.key(),
+            sender: ctx.accounts.sender.key(),
+            amount0_out,
+            amount1_out,
+            amount0_in,
+            amount1_in,
+            reserve0_after: balance0,
+            reserve1_after: balance1,
+        });
+
+        pair.locked = false;
+        Ok(())
+    }
+
+    // Permissionless: overwrites the reserves with the vault balances, folding
+    // any tokens sent straight to the vaults into the pool
+    pub fn sync(ctx: Context<Sync>) -> Result<()> {
+        sync_reserves(
+            &mut ctx.accounts.pair,
+            &ctx.accounts.token0_account,
+            &ctx.accounts.token1_account,
+        )
+    }
+
+    // Gifts tokens to the pool's LPs: the donation lands in the vaults and is
+    // synced into the reserves in the same instruction, so k grows and every
+    // LP token is backed by more without any LP being minted.
+    pub fn donate(ctx: Context<Donate>, amount0: u64, amount1: u64) -> Result<()> {
+        require!(amount0 > 0 || amount1 > 0, DexError::InsufficientInputAmount);
+        // With no LP outstanding the first depositor would take the gift
+        require!(ctx.accounts.pair.total_supply > 0, DexError::InsufficientLiquidity);
+
+        let accounts = ctx.accounts;
+        for (amount, from, mint, to) in [
+            (amount0, &accounts.donor_token0, &accounts.token0_mint, &accounts.token0_account),
+            (amount1, &accounts.donor_token1, &accounts.token1_mint, &accounts.token1_account),
+        ] {
+            if amount > 0 {
+                transfer_from_user(
+                    accounts.token_program.to_account_info(),
+                    from.to_account_info(),
+                    mint,
+                    to.to_account_info(),
+                    accounts.donor.to_account_info(),
+                    amount,
+                )?;
+            }
+        }
+
+        accounts.token0_account.reload()?;
+        accounts.token1_account.reload()?;
+        sync_reserves(&mut accounts.pair, &accounts.token0_account, &accounts.token1_account)?;
+
+        emit!(DonationEvent {
+            pair: accounts.pair.key(),
+            donor: accounts.donor.key(),
+            amount0,
+            amount1,
+            reserve0_after: accounts.pair.reserve0,
+            reserve1_after: accounts.pair.reserve1,
+        });
+
+        Ok(())
+    }
+
+    // Permissionless counterpart to `sync`: pays whatever the vaults hold above
+    // the reserves out to `to`, leaving the reserves untouched
+    pub fn skim(ctx: Context<Skim>, to: Pubkey) -> Result<()> {
+        let accounts = &ctx.accounts;
+        require_keys_eq!(accounts.token0_to.owner, to, DexError::InvalidTokenOwner);
+        require_keys_eq!(accounts.token1_to.owner, to, DexError::InvalidTokenOwner);
+
+        // A vault below its reserve has nothing to skim
+        let amount0 = accounts.token0_account.amount.saturating_sub(accounts.pair.reserve0);
+        let amount1 = accounts.token1_account.amount.saturating_sub(accounts.pair.reserve1);
+
+        let pair_key = accounts.pair.key();
+        if amount0 > 0 {
+            transfer_from_vault(
+                accounts.token_program.to_account_info(),
+                accounts.token0_account.to_account_info(),
+                &accounts.token0_mint,
+                accounts.token0_to.to_account_info(),
+                accounts.authority.to_account_info(),
+                pair_key,
+                accounts.pair.authority_bump,
+                amount0,
+            )?;
+        }
+
+        if amount1 > 0 {
+            transfer_from_vault(
+                accounts.token_program.to_account_info(),
+                accounts.token1_account.to_account_info(),
+                &accounts.token1_mint,
+                accounts.token1_to.to_account_info(),
+                accounts.authority.to_account_info(),
+                pair_key,
+                accounts.pair.authority_bump,
+                amount1,
+            )?;
+        }
+
+        emit!(SkimEvent {
+            pair: pair_key,
+            to,
+            amount0,
+            amount1,
+        });
+
+        Ok(())
+    }
+
+    // Appends the pair's reserves and supply to its on-chain history. Snapshots
+    // are stored PAIR_ARCHIVE_CHUNK_LEN to a `PairArchive` PDA, the next chunk
+    // being created (at the caller's expense) once the current one is full.
+    // The archive is a ring: past MAX_PAIR_ARCHIVE_SNAPSHOTS the oldest slot
+    // is overwritten, so anyone can keep calling this and no one can fill it.
+    pub fn archive_snapshot(ctx: Context<ArchiveSnapshot>) -> Result<()> {
+        let pair = &mut ctx.accounts.pair;
+        let index = pair.archive_snapshot_count;
+        let slot = index % MAX_PAIR_ARCHIVE_SNAPSHOTS;
+
+        let snapshot = PairSnapshot {
+            reserve0: pair.reserve0,
+            reserve1: pair.reserve1,
+            total_supply: pair.total_supply,
+            timestamp: Clock::get()?.unix_timestamp,
+        };
+        let archive = &mut ctx.accounts.archive;
+        archive.pair = pair.key();
+        archive.chunk_index = slot / PAIR_ARCHIVE_CHUNK_LEN as u64;
+        archive.snapshots[(slot % PAIR_ARCHIVE_CHUNK_LEN as u64) as usize] = snapshot;
+        archive.count = (archive.count + 1).min(PAIR_ARCHIVE_CHUNK_LEN as u8);
+        pair.archive_snapshot_count = index + 1;
+
+        emit!(PairSnapshotArchivedEvent {
+            pair: pair.key(),
+            index,
+            reserve0: snapshot.reserve0,
+            reserve1: snapshot.reserve1,
+            total_supply: snapshot.total_supply,
+            timestamp: snapshot.timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Creates the pair's program-owned LP account that receives protocol fees.
+    // From then on every fee mint lands there instead of in fee_to's account,
+    // and it can only be set once, so the destination can't be redirected later.
+    pub fn init_protocol_fee_account(ctx: Context<InitProtocolFeeAccount>) -> Result<()> {
+        let pair = &mut ctx.accounts.pair;
+        require!(
+            pair.protocol_fee_account == Pubkey::default(),
+            DexError::ProtocolFeeAccountAlreadySet
+        );
+        pair.protocol_fee_account = ctx.accounts.protocol_fee_account.key();
+
+        emit!(ProtocolFeeAccountInitializedEvent {
+            pair: pair.key(),
+            protocol_fee_account: pair.protocol_fee_account,
+        });
+
+        Ok(())
+    }
+
+    // Step 1 of a fee withdrawal: announce the amount and destination,
+    // executable after the delay. Both are fixed until it executes.
+    pub fn queue_protocol_fee_withdrawal(
+        ctx: Context<QueueProtocolFeeWithdrawal>,
+        amount: u64,
+    ) -> Result<()> {
+        let pair = &mut ctx.accounts.pair;
+        require!(
+            pair.protocol_fee_account != Pubkey::default(),
+            DexError::ProtocolFeeAccountNotSet
+        );
+        require!(amount > 0, DexError::InsufficientAmount);
+
+        let unlock_at = Clock::get()?
+            .unix_timestamp
+            .checked_add(PROTOCOL_FEE_WITHDRAWAL_DELAY)
+            .ok_or(DexError::AmountOverflow)?;
+        pair.fee_withdrawal_amount = amount;
+        pair.fee_withdrawal_unlock_at = unlock_at;
+        pair.fee_withdrawal_destination = ctx.accounts.destination.key();
+
+        emit!(ProtocolFeeWithdrawalQueuedEvent {
+            pair: pair.key(),
+            destination: pair.fee_withdrawal_destination,
+            amount,
+            unlock_at,
+        });
+
+        Ok(())
+    }
+
+    // Step 2 of a fee withdrawal: move the queued LP to the queued destination
+    // once the timelock expired
+    pub fn withdraw_protocol_fees(ctx: Context<WithdrawProtocolFees>) -> Result<()> {
+        let amount = ctx.accounts.pair.fee_withdrawal_amount;
+        require!(amount > 0, DexError::InsufficientAmount);
+        require!(
+            Clock::get()?.unix_timestamp >= ctx.accounts.pair.fee_withdrawal_unlock_at,
+            DexError::FeeWithdrawalLocked
+        );
+
+        let pair_key = ctx.accounts.pair.key();
+        transfer_from_vault(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.protocol_fee_account.to_account_info(),
+            &ctx.accounts.lp_mint,
+            ctx.accounts.destination.to_account_info(),
+            ctx.accounts.authority.to_account_info(),
+            pair_key,
+            ctx.accounts.pair.authority_bump,
+            amount,
+        )?;
+
+        let pair = &mut ctx.accounts.pair;
+        pair.fee_withdrawal_amount = 0;
+        pair.fee_withdrawal_unlock_at = 0;
+        pair.fee_withdrawal_destination = Pubkey::default();
+
+        emit!(ProtocolFeesWithdrawnEvent {
+            pair: pair_key,
+            destination: ctx.accounts.destination.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    // Turns fee_to's LP into permanent protocol-owned liquidity: the whole
+    // balance moves to a vault held by the pair authority that no instruction
+    // withdraws from. Unlike the burned minimum it is tracked per pair and
+    // keeps growing with every reinvestment.
+    pub fn reinvest_protocol_fees(ctx: Context<ReinvestProtocolFees>) -> Result<()> {
+        let amount = ctx.accounts.fee_to_account.amount;
+        require!(amount > 0, DexError::InsufficientInputAmount);
+
+        transfer_from_user(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.fee_to_account.to_account_info(),
+            &ctx.accounts.lp_mint,
+            ctx.accounts.protocol_liquidity_vault.to_account_info(),
+            ctx.accounts.fee_to.to_account_info(),
+            amount,
+        )?;
+
+        let pair = &mut ctx.accounts.pair;
+        pair.protocol_liquidity = pair
+            .protocol_liquidity
+            .checked_add(amount)
+            .ok_or(DexError::AmountOverflow)?;
+
+        emit!(ProtocolLiquidityReinvestedEvent {
+            pair: pair.key(),
+            amount,
+            protocol_liquidity: pair.protocol_liquidity,
+        });
+
+        Ok(())
+    }
+
+    // Permissionless: realises the protocol fee accrued since the last liquidity
+    // event instead of waiting for one. The caller keeps `keeper_bounty_bps` of
+    // the minted LP and the rest goes to the pair's protocol fee account, or
+    // to fee_to while the pair has none.
+    pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let fee_on = accounts.factory.fee_on;
+        let liquidity = accounts.pair.accrued_protocol_fee(&accounts.factory)?;
+        require!(liquidity > 0, DexError::NoProtocolFeeAccrued);
+
+        let bounty = u64::try_from(
+            liquidity as u128 * accounts.factory.keeper_bounty_bps as u128 / FEE_DENOMINATOR,
+        )
+        .map_err(|_| error!(DexError::AmountOverflow))?;
+        let fee_to_amount = liquidity - bounty;
+
+        let pair_key = accounts.pair.key();
+        for (to, amount) in [
+            (accounts.fee_to_account.to_account_info(), fee_to_amount),
+            (accounts.keeper_lp.to_account_info(), bounty),
+        ] {
+            if amount > 0 {
+                mint_lp(
+                    accounts.token_program.to_account_info(),
+                    accounts.lp_mint.to_account_info(),
+                    to,
+                    accounts.authority.to_account_info(),
+                    pair_key,
+                    accounts.pair.authority_bump,
+                    amount,
+                )?;
+            }
+        }
+
+        let pair = &mut accounts.pair;
+        pair.total_supply = pair
+            .total_supply
+            .checked_add(liquidity)
+            .ok_or(DexError::AmountOverflow)?;
+        pair.update_k_last(fee_on);
+
+        emit!(ProtocolFeesCollectedEvent {
+            pair: pair_key,
+            keeper: accounts.keeper.key(),
+            fee_to_amount,
+            bounty,
+        });
+
+        Ok(())
+    }
+
+    // Sets (or clears with `None`) the program notified after liquidity changes
+    pub fn set_callback_program(
+        ctx: Context<SetPairConfig>,
+        callback_program: Option<Pubkey>,
+    ) -> Result<()> {
+        let pair = &mut ctx.accounts.pair;
+        pair.callback_program = callback_program;
+
+        emit!(CallbackProgramUpdatedEvent {
+            pair: pair.key(),
+            callback_program,
+        });
+
+        Ok(())
+    }
+
+    // Replaces the pair's size-based fee schedule. Tiers must have strictly
+    // increasing thresholds; an empty list falls back to the base fee_bps.
+    pub fn set_fee_tiers(ctx: Context<SetPairConfig>, tiers: Vec<FeeTier>) -> Result<()> {
+        require!(tiers.len() <= MAX_FEE_TIERS, DexError::TooManyFeeTiers);
+        require!(
+            tiers.windows(2).all(|w| w[0].threshold < w[1].threshold),
+            DexError::FeeTiersNotSorted
+        );
+        require!(
+            tiers.iter().all(|tier| tier.fee_bps <= MAX_FEE_BPS),
+            DexError::InvalidFee
+        );
+
+        let pair = &mut ctx.accounts.pair;
+        pair.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        pair.fee_tiers[..tiers.len()].copy_from_slice(&tiers);
+        pair.fee_tier_count = tiers.len() as u8;
+
+        emit!(FeeTiersUpdatedEvent {
+            pair: pair.key(),
+            tiers,
+        });
+
+        Ok(())
+    }
+
+    // Sets the pair's base fee, per-swap output cap (bps of the output
+    // reserve, 0 = uncapped) and minimum swap input together
+    pub fn configure_pair_fees(
+        ctx: Context<SetPairConfig>,
+        fee_bps: u16,
+        max_out_bps: u16,
+        min_trade_amount: u64,
+    ) -> Result<()> {
+        require!(fee_bps <= MAX_FEE_BPS, DexError::InvalidFee);
+        require!(
+            max_out_bps as u128 <= FEE_DENOMINATOR,
+            DexError::InvalidMaxOutput
+        );
+
+        let pair = &mut ctx.accounts.pair;
+        pair.fee_bps = fee_bps;
+        pair.max_out_bps = max_out_bps;
+        pair.min_trade_amount = min_trade_amount;
+
+        emit!(PairFeesConfiguredEvent {
+            pair: pair.key(),
+            fee_bps,
+            max_out_bps,
+            min_trade_amount,
+        });
+
+        Ok(())
+    }
+
+    // Retunes just the pair's base fee
+    pub fn set_pair_fee(ctx: Context<SetPairConfig>, fee_bps: u16) -> Result<()> {
+        require!(fee_bps <= MAX_FEE_BPS, DexError::InvalidFee);
+
+        let pair = &mut ctx.accounts.pair;
+        let old_fee_bps = pair.fee_bps;
+        pair.fee_bps = fee_bps;
+
+        emit!(PairFeeChangedEvent {
+            pair: pair.key(),
+            old_fee_bps,
+            new_fee_bps: fee_bps,
+        });
+
+        Ok(())
+    }
+
+    // Minimum seconds between swaps from the same signer on this pair (0 disables)
+    pub fn set_swap_cooldown(ctx: Context<SetPairConfig>, swap_cooldown_seconds: u32) -> Result<()> {
+        let pair = &mut ctx.accounts.pair;
+        pair.swap_cooldown_seconds = swap_cooldown_seconds;
+
+        emit!(SwapCooldownUpdatedEvent {
+            pair: pair.key(),
+            swap_cooldown_seconds,
+        });
+
+        Ok(())
+    }
+
+    // Display increment for `spot_price_ticked`, in Q64.64 (1 = no rounding)
+    pub fn set_tick_size(ctx: Context<SetPairConfig>, tick_size_x64: u128) -> Result<()> {
+        require!(tick_size_x64 > 0, DexError::InvalidPrice);
+        let pair = &mut ctx.accounts.pair;
+        pair.tick_size_x64 = tick_size_x64;
+
+        emit!(TickSizeUpdatedEvent {
+            pair: pair.key(),
+            tick_size_x64,
+        });
+
+        Ok(())
+    }
+
+    // Takes one pair out of service (swaps and deposits) without touching the
+    // factory-wide switch; withdrawals stay open
+    pub fn toggle_pair_paused(ctx: Context<SetPairConfig>, paused: bool) -> Result<()> {
+        let pair = &mut ctx.accounts.pair;
+        pair.pair_paused = paused;
+
+        emit!(PairPausedEvent {
+            pair: pair.key(),
+            paused,
+        });
+
+        Ok(())
+    }
+
+    // Moves the reserve caps and lifts a freeze they triggered; the pair
+    // freezes again at once if a reserve is still at or above its new cap
+    pub fn set_reserve_caps(ctx: Context<SetPairConfig>, reserve_cap0: u64, reserve_cap1: u64) -> Result<()> {
+        let pair = &mut ctx.accounts.pair;
+        pair.reserve_cap0 = reserve_cap0;
+        pair.reserve_cap1 = reserve_cap1;
+        pair.frozen = false;
+
+        emit!(ReserveCapsUpdatedEvent {
+            pair: pair.key(),
+            reserve_cap0,
+            reserve_cap1,
+        });
+
+        let pair_key = pair.key();
+        pair.check_reserve_caps(pair_key);
+
+        Ok(())
+    }
+
+    // Trades indexability for compute on hot pools: with `emit_events` off a
+    // swap logs nothing and only adds to running totals, flushed as one
+    // `SwapSummaryEvent` every `summary_interval` swaps. Reserves and the
+    // oracle still update on every swap. Pending totals are flushed here.
+    pub fn set_event_mode(
+        ctx: Context<SetPairConfig>,
+        emit_events: bool,
+        summary_interval: u16,
+    ) -> Result<()> {
+        require!(emit_events || summary_interval > 0, DexError::InvalidSummaryInterval);
+        let pair_key = ctx.accounts.pair.key();
+        let pair = &mut ctx.accounts.pair;
+        if pair.summary_swap_count > 0 {
+            pair.flush_swap_summary(pair_key);
+        }
+        pair.emit_events = emit_events;
+        pair.summary_interval = summary_interval;
+
+        emit!(EventModeUpdatedEvent {
+            pair: pair_key,
+            emit_events,
+            summary_interval,
+        });
+
+        Ok(())
+    }
+
+    // Before the first deposit only: takes the initial LP as the geometric
+    // mean of whole-token amounts at the smaller decimals, so a 18/6 decimal
+    // pair issues LP like a 6/6 one. The locked minimum follows the LP's
+    // decimals either way.
+    pub fn set_decimals_normalization(ctx: Context<SetPairConfig>, enabled: bool) -> Result<()> {
+        let pair = &mut ctx.accounts.pair;
+        require!(pair.total_supply == 0, DexError::PairNotEmpty);
+        let lp_decimals = pair.token0_decimals.min(pair.token1_decimals);
+        pair.minimum_liquidity = if enabled {
+            minimum_liquidity_for(lp_decimals, lp_decimals)?
+        } else {
+            minimum_liquidity_for(pair.token0_decimals, pair.token1_decimals)?
+        };
+        pair.normalize_decimals = enabled;
+
+        emit!(DecimalsNormalizationUpdatedEvent {
+            pair: pair.key(),
+            enabled,
+            minimum_liquidity: pair.minimum_liquidity,
+        });
+
+        Ok(())
+    }
+
+    // Bounds the LP a single deposit may mint, for pools with position-size
+    // limits. 0 and u64::MAX leave the respective side open.
+    pub fn set_deposit_bounds(
+        ctx: Context<SetPairConfig>,
+        min_deposit_liquidity: u64,
+        max_deposit_liquidity: u64,
+    ) -> Result<()> {
+        require!(
+            min_deposit_liquidity <= max_deposit_liquidity,
+            DexError::InvalidDepositBounds
+        );
+        let pair = &mut ctx.accounts.pair;
+        pair.min_deposit_liquidity = min_deposit_liquidity;
+        pair.max_deposit_liquidity = max_deposit_liquidity;
+
+        emit!(DepositBoundsUpdatedEvent {
+            pair: pair.key(),
+            min_deposit_liquidity,
+            max_deposit_liquidity,
+        });
+
+        Ok(())
+    }
+
+    // Points swaps at an external price feed: every swap must leave the pool
+    // price within `max_oracle_deviation_bps` of it. `None` turns the check off.
+    pub fn set_external_oracle(
+        ctx: Context<SetPairConfig>,
+        external_oracle: Option<Pubkey>,
+        max_oracle_deviation_bps: u16,
+    ) -> Result<()> {
+        if external_oracle.is_some() {
+            require!(
+                max_oracle_deviation_bps > 0 && max_oracle_deviation_bps as u128 <= FEE_DENOMINATOR,
+                DexError::InvalidOracleDeviation
+            );
+        }
+        let pair = &mut ctx.accounts.pair;
+        pair.external_oracle = external_oracle;
+        pair.max_oracle_deviation_bps = max_oracle_deviation_bps;
+
+        emit!(ExternalOracleUpdatedEvent {
+            pair: pair.key(),
+            external_oracle,
+            max_oracle_deviation_bps,
+        });
+
+        Ok(())
+    }
+
+    // Rejects plain deposits while the spot price is more than
+    // `max_deposit_deviation_bps` away from the pair's own TWAP; 0 turns the
+    // check off
+    pub fn set_deposit_deviation(ctx: Context<SetPairConfig>, max_deposit_deviation_bps: u16) -> Result<()> {
+        require!(
+            max_deposit_deviation_bps as u128 <= FEE_DENOMINATOR,
+            DexError::InvalidDepositDeviation
+        );
+        let pair = &mut ctx.accounts.pair;
+        pair.max_deposit_deviation_bps = max_deposit_deviation_bps;
+
+        emit!(DepositDeviationUpdatedEvent {
+            pair: pair.key(),
+            max_deposit_deviation_bps,
+        });
+
+        Ok(())
+    }
+
+    // Back-fills the oracle as if the pool had traded at `seed_price_x64`
+    // (token1 per token0, Q64.64) for ORACLE_SEED_PERIOD_SECONDS up to now, so
+    // early consults and the deposit guard have a sensible anchor; the depth
+    // over that period is taken to be the current one. Nothing
+    // checks the seed against the market: it trusts whoever configures the
+    // pair. Meant to run right after the first deposit (the same transaction
+    // works); once the oracle has history past that deposit it's refused.
+    pub fn configure_pair_with_oracle_seed(ctx: Context<SetPairConfig>, seed_price_x64: u128) -> Result<()> {
+        require!(seed_price_x64 > 0, DexError::InvalidSeedPrice);
+        let pair = &mut ctx.accounts.pair;
+        require!(pair.reserve0 > 0 && pair.reserve1 > 0, DexError::InsufficientLiquidity);
+        require!(pair.observation_count <= 1, DexError::OracleAlreadyStarted);
+
+        let now = Clock::get()?.unix_timestamp as u32;
+        let period = ORACLE_SEED_PERIOD_SECONDS as u128;
+        // 2^128 / price, short by at most one unit
+        let price1_x64 = u128::MAX / seed_price_x64;
+        pair.observations[0] = OracleObservation {
+            timestamp: now.wrapping_sub(ORACLE_SEED_PERIOD_SECONDS),
+            ..Default::default()
+        };
+        pair.observation_index = 0;
+        pair.observation_count = 1;
+        pair.price0_cumulative_last = seed_price_x64.wrapping_mul(period);
+        pair.price1_cumulative_last = price1_x64.wrapping_mul(period);
+        pair.liquidity_cumulative_last = sqrt(pair.reserve0 as u128 * pair.reserve1 as u128) * period;
+        pair.block_timestamp_last = now;
+        pair.record_observation();
+
+        emit!(OracleSeededEvent {
+            pair: pair.key(),
+            seed_price_x64,
+            price0_cumulative_last: pair.price0_cumulative_last,
+            price1_cumulative_last: pair.price1_cumulative_last,
+            block_timestamp_last: now,
+        });
+
+        Ok(())
+    }
+
+    // Per-signer swap record, required for swaps on pairs with a cooldown
+    pub fn init_trader_state(ctx: Context<InitTraderState>) -> Result<()> {
+        ctx.accounts.trader_state.last_swap_at = 0;
+        Ok(())
+    }
+
+    // Hands the pair's settings to an external controller program (or back to
+    // the factory owner with `None`). While set, only the controller's
+    // `[b"controller", pair]` PDA can sign SetPairConfig instructions.
+    pub fn set_controller(ctx: Context<SetPairConfig>, controller: Option<Pubkey>) -> Result<()> {
+        let pair = &mut ctx.accounts.pair;
+        pair.controller = controller;
+
+        emit!(ControllerUpdatedEvent {
+            pair: pair.key(),
+            controller,
+        });
+
+        Ok(())
+    }
+
+    // Sets the base fee on every pair passed (writable) in `remaining_accounts`.
+    // Pairs managed by a controller must be updated through it instead.
+    pub fn set_fees_batch<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SetFactoryConfig<'info>>,
+        fee_bps: u16,
+    ) -> Result<()> {
+        require!(fee_bps <= MAX_FEE_BPS, DexError::InvalidFee);
+        require!(
+            ctx.remaining_accounts.len() <= MAX_FEE_BATCH_PAIRS,
+            DexError::TooManyPairs
+        );
+
+        let factory_key = ctx.accounts.factory.key();
+        for info in ctx.remaining_accounts {
+            let mut pair = Account::<PairAccount>::try_from(info)?;
+            require_keys_eq!(pair.factory, factory_key, DexError::InvalidPairFactory);
+            require!(pair.controller.is_none(), DexError::NotController);
+
+            let old_fee_bps = pair.fee_bps;
+            pair.fee_bps = fee_bps;
+            pair.exit(ctx.program_id)?;
+
+            emit!(PairFeeChangedEvent {
+                pair: info.key(),
+                old_fee_bps,
+                new_fee_bps: fee_bps,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Wallet whose LP accounts receive the protocol fee while `fee_on` is set
+    pub fn set_fee_to(ctx: Context<SetFactoryConfig>, fee_to: Pubkey) -> Result<()> {
+        let factory = &mut ctx.accounts.factory;
+        require!(
+            !factory.fee_on || fee_to != Pubkey::default(),
+            DexError::FeeToNotSet
+        );
+        factory.fee_to = fee_to;
+
+        emit!(FeeToUpdatedEvent { fee_to });
+
+        Ok(())
+    }
+
+    // Owner of the account that must receive the locked minimum liquidity on
+    // a pair's first deposit. Defaults to the all-zero key, which nobody can
+    // sign for, so the lock can't be redeemed.
+    pub fn set_lp_burn_address(ctx: Context<SetFactoryConfig>, lp_burn_address: Pubkey) -> Result<()> {
+        let factory = &mut ctx.accounts.factory;
+        factory.lp_burn_address = lp_burn_address;
+
+        emit!(LpBurnAddressUpdatedEvent { lp_burn_address });
+
+        Ok(())
+    }
+
+    // Protocol fee switch. Each pair starts accruing from its next liquidity
+    // event, since `k_last` is only snapshotted while the fee is on.
+    pub fn set_fee_on(ctx: Context<SetFactoryConfig>, enabled: bool) -> Result<()> {
+        let factory = &mut ctx.accounts.factory;
+        require!(
+            !enabled || factory.fee_to != Pubkey::default(),
+            DexError::FeeToNotSet
+        );
+        factory.fee_on = enabled;
+
+        emit!(FeeSwitchToggledEvent { enabled });
+
+        Ok(())
+    }
+
+    // The protocol's cut of the LP fee growth, numerator / denominator. It
+    // applies to growth not yet minted as well, so a change counts from the
+    // last `k_last` snapshot of each pair.
+    pub fn set_protocol_fee_fraction(
+        ctx: Context<SetFactoryConfig>,
+        fee_numerator: u8,
+        fee_denominator: u8,
+    ) -> Result<()> {
+        require!(
+            fee_denominator > 0 && fee_numerator < fee_denominator,
+            DexError::InvalidFee
+        );
+        let factory = &mut ctx.accounts.factory;
+        factory.fee_numerator = fee_numerator;
+        factory.fee_denominator = fee_denominator;
+
+        emit!(ProtocolFeeFractionUpdatedEvent {
+            fee_numerator,
+            fee_denominator,
+        });
+
+        Ok(())
+    }
+
+    // Share of each `collect_protocol_fees` paid to the caller, in bps
+    pub fn set_keeper_bounty(ctx: Context<SetFactoryConfig>, keeper_bounty_bps: u16) -> Result<()> {
+        require!(
+            keeper_bounty_bps <= MAX_KEEPER_BOUNTY_BPS,
+            DexError::InvalidKeeperBounty
+        );
+        ctx.accounts.factory.keeper_bounty_bps = keeper_bounty_bps;
+
+        emit!(KeeperBountyUpdatedEvent { keeper_bounty_bps });
+
+        Ok(())
+    }
+
+    // Configure the fee discount for holders of `discount_mint`. Passing the
+    // default pubkey disables the discount.
+    pub fn set_fee_discount(
+        ctx: Context<SetFactoryConfig>,
+        discount_mint: Pubkey,
+        discount_threshold: u64,
+        discount_fee_bps: u16,
+    ) -> Result<()> {
+        require!(discount_fee_bps <= MAX_FEE_BPS, DexError::InvalidFee);
+
+        let factory = &mut ctx.accounts.factory;
+        factory.discount_mint = discount_mint;
+        factory.discount_threshold = discount_threshold;
+        factory.discount_fee_bps = discount_fee_bps;
+
+        emit!(FeeDiscountUpdatedEvent {
+            discount_mint,
+            discount_threshold,
+            discount_fee_bps,
+        });
+
+        Ok(())
+    }
+
+    // Every pair swaps at `promo_fee_bps` until `promo_fee_until`, then falls
+    // back to its own fee schedule without another transaction
+    pub fn start_promo(ctx: Context<SetFactoryConfig>, promo_fee_bps: u16, promo_fee_until: i64) -> Result<()> {
+        require!(promo_fee_bps <= MAX_FEE_BPS, DexError::InvalidFee);
+
+        let factory = &mut ctx.accounts.factory;
+        factory.promo_fee_bps = promo_fee_bps;
+        factory.promo_fee_until = promo_fee_until;
+
+        emit!(PromoStartedEvent {
+            promo_fee_bps,
+            promo_fee_until,
+        });
+
+        Ok(())
+    }
+
+    // Whether pairs may use another pair's LP mint as one of their tokens
+    pub fn set_lp_token_policy(ctx: Context<SetFactoryConfig>, reject_lp_pair_tokens: bool) -> Result<()> {
+        ctx.accounts.factory.reject_lp_pair_tokens = reject_lp_pair_tokens;
+
+        emit!(LpTokenPolicyUpdatedEvent {
+            reject_lp_pair_tokens,
+        });
+
+        Ok(())
+    }
+
+    // Emergency stop for every pair: swaps and deposits are refused while set,
+    // withdrawals stay open so LPs can always exit
+    pub fn set_paused(ctx: Context<SetFactoryConfig>, paused: bool) -> Result<()> {
+        ctx.accounts.factory.paused = paused;
+
+        emit!(PausedEvent { paused });
+
+        Ok(())
+    }
+
+    // Ownership moves in two steps: the current owner names a pending owner,
+    // who must then accept. Until then the current owner can cancel.
+    pub fn transfer_ownership(ctx: Context<SetFactoryConfig>, new_owner: Pubkey) -> Result<()> {
+        let factory = &mut ctx.accounts.factory;
+        factory.pending_owner = new_owner;
+
+        emit!(OwnershipTransferStartedEvent {
+            factory: factory.key(),
+            owner: factory.owner,
+            pending_owner: new_owner,
+        });
+
+        Ok(())
+    }
+
+    pub fn accept_ownership(ctx: Context<AcceptOwnership>) -> Result<()> {
+        let factory = &mut ctx.accounts.factory;
+        let previous_owner = factory.owner;
+        factory.owner = factory.pending_owner;
+        factory.pending_owner = Pubkey::default();
+
+        emit!(OwnershipTransferAcceptedEvent {
+            factory: factory.key(),
+            previous_owner,
+            new_owner: factory.owner,
+        });
+
+        Ok(())
+    }
+
+    pub fn cancel_ownership_transfer(ctx: Context<SetFactoryConfig>) -> Result<()> {
+        let factory = &mut ctx.accounts.factory;
+        require_keys_neq!(factory.pending_owner, Pubkey::default(), DexError::NoPendingOwner);
+
+        let pending_owner = factory.pending_owner;
+        factory.pending_owner = Pubkey::default();
+
+        emit!(OwnershipTransferCancelledEvent {
+            factory: factory.key(),
+            pending_owner,
+        });
+
+        Ok(())
+    }
+
+    // Hands migration rights to a new key. Only the current migration
+    // authority can do this, the owner has no say over it.
+    pub fn set_migration_authority(
+        ctx: Context<SetMigrationAuthority>,
+        new_migration_authority: Pubkey,
+    ) -> Result<()> {
+        let factory = &mut ctx.accounts.factory;
+        let old_migration_authority = factory.migration_authority;
+        factory.migration_authority = new_migration_authority;
+
+        emit!(MigrationAuthorityUpdatedEvent {
+            factory: factory.key(),
+            old_migration_authority,
+            new_migration_authority,
+        });
+
+        Ok(())
+    }
+
+    // Moves LP positions from up to MAX_MIGRATION_SOURCES pairs into another
+    // pair of the same tokens in one transaction, burning `liquidity[i]` of the
+    // i-th source listed in `remaining_accounts`. Each source is withdrawn and
+    // the destination deposited into under the same guards as
+    // `remove_liquidity` and `add_liquidity`. The withdrawn tokens pass through
+    // the user's accounts; whatever the destination's ratio doesn't take stays
+    // there.
+    pub fn migrate_liquidity<'info>(
+        ctx: Context<'_, '_, 'info, 'info, MigrateLiquidity<'info>>,
+        liquidity: Vec<u128>,
+        min_liquidity_out: u128,
+        deadline: i64,
+    ) -> Result<()> {
+        let source_accounts = ctx.remaining_accounts.chunks_exact(MIGRATION_SOURCE_ACCOUNTS);
+        require!(
+            source_accounts.remainder().is_empty()
+                && source_accounts.len() == liquidity.len()
+                && (1..=MAX_MIGRATION_SOURCES).contains(&liquidity.len()),
+            DexError::InvalidMigration
+        );
+
+        let program_id = ctx.program_id;
+        let accounts = ctx.accounts;
+        require!(!accounts.factory.paused, DexError::Paused);
+        require!(!accounts.dest_pair.pair_paused, DexError::PairPaused);
+        require!(!accounts.dest_pair.frozen, DexError::PairFrozen);
+        require!(Clock::get()?.unix_timestamp <= deadline, DexError::Expired);
+        lock_pair(&mut accounts.dest_pair)?;
+        update_cumulative_prices(&mut accounts.dest_pair)?;
+
+        // Withdraw the proportional share from each source. What the user
+        // actually received (net of any transfer fee) is what gets deposited.
+        let (user0_before, user1_before) = (accounts.user_token0.amount, accounts.user_token1.amount);
+        let mut source_pairs = Vec::with_capacity(liquidity.len());
+        let mut liquidity_burned = Vec::with_capacity(liquidity.len());
+        let (mut amount0_removed, mut amount1_removed) = (0u64, 0u64);
+        for (source, &liquidity) in source_accounts.zip(&liquidity) {
+            require!(liquidity > 0, DexError::InsufficientInputAmount);
+            let (vault0, vault1, lp_mint, liquidity_from, authority) =
+                (&source[1], &source[2], &source[3], &source[4], &source[5]);
+            let (mut pair, fee_to_account) = load_migration_source(
+                source,
+                &accounts.factory,
+                &accounts.dest_pair,
+                accounts.sender.key(),
+                program_id,
+            )?;
+            lock_pair(&mut pair)?;
+            update_cumulative_prices(&mut pair)?;
+
+            mint_fee(
+                &accounts.factory,
+                &mut pair,
+                fee_to_account.as_ref(),
+                lp_mint.clone(),
+                authority.clone(),
+                accounts.token_program.to_account_info(),
+            )?;
+
+            let (reserve0, reserve1, total_supply) = (pair.reserve0, pair.reserve1, pair.total_supply);
+            require!(total_supply > 0, DexError::InsufficientLiquidity);
+            let liquidity_u64 = u64::try_from(liquidity)
+                .map_err(|_| error!(DexError::AmountOverflow))?;
+            let amount0 = liquidity
+                .checked_mul(reserve0 as u128)
+                .ok_or(DexError::AmountOverflow)?
+                / total_supply as u128;
+            let amount1 = liquidity
+                .checked_mul(reserve1 as u128)
+                .ok_or(DexError::AmountOverflow)?
+                / total_supply as u128;
+            check_withdrawal_rounding(liquidity, reserve0, total_supply, amount0)?;
+            check_withdrawal_rounding(liquidity, reserve1, total_supply, amount1)?;
+            let amount0 = u64::try_from(amount0).map_err(|_| error!(DexError::AmountOverflow))?;
+            let amount1 = u64::try_from(amount1).map_err(|_| error!(DexError::AmountOverflow))?;
+            require!(
+                amount0 <= reserve0
+                    && amount1 <= reserve1
+                    && amount0 <= accessor::amount(vault0)?
+                    && amount1 <= accessor::amount(vault1)?,
+                DexError::ReserveDesync
+            );
+
+            token_interface::burn(
+                CpiContext::new(
+                    accounts.token_program.to_account_info(),
+                    token_interface::Burn {
+                        mint: lp_mint.clone(),
+                        from: liquidity_from.clone(),
+                        authority: accounts.sender.to_account_info(),
+                    },
+                ),
+                liquidity_u64,
+            )?;
+            for (vault, mint, to, amount) in [
+                (vault0, &accounts.token0_mint, &accounts.user_token0, amount0),
+                (vault1, &accounts.token1_mint, &accounts.user_token1, amount1),
+            ] {
+                transfer_from_vault(
+                    accounts.token_program.to_account_info(),
+                    vault.clone(),
+                    mint,
+                    to.to_account_info(),
+                    authority.clone(),
+                    pair.key(),
+                    pair.authority_bump,
+                    amount,
+                )?;
+            }
+
+            pair.reserve0 = reserve0 - amount0;
+            pair.reserve1 = reserve1 - amount1;
+            pair.total_supply = total_supply - liquidity_u64;
+            pair.update_k_last(accounts.factory.fee_on);
+            pair.locked = false;
+            pair.exit(program_id)?;
+
+            source_pairs.push(pair.key());
+            liquidity_burned.push(liquidity_u64);
+            amount0_removed = amount0_removed.checked_add(amount0).ok_or(DexError::AmountOverflow)?;
+            amount1_removed = amount1_removed.checked_add(amount1).ok_or(DexError::AmountOverflow)?;
+        }
+        accounts.user_token0.reload()?;
+        accounts.user_token1.reload()?;
+        let withdrawn0 = accounts
+            .user_token0
+            .amount
+            .checked_sub(user0_before)
+            .ok_or(DexError::AmountOverflow)?;
+        let withdrawn1 = accounts
+            .user_token1
+            .amount
+            .checked_sub(user1_before)
+            .ok_or(DexError::AmountOverflow)?;
+
+        // Deposit at the destination's ratio; it must already be seeded so the
+        // migration never sets its initial price
+        mint_fee(
+            &accounts.factory,
+            &mut accounts.dest_pair,
+            accounts.fee_to_account.as_deref(),
+            accounts.dest_lp_mint.to_account_info(),
+            accounts.dest_authority.to_account_info(),
+            accounts.token_program.to_account_info(),
+        )?;
+        let dest_reserve0 = accounts.dest_pair.reserve0;
+        let dest_reserve1 = accounts.dest_pair.reserve1;
+        let dest_supply = accounts.dest_pair.total_supply;
+        require!(
+            dest_reserve0 > 0 && dest_reserve1 > 0,
+            DexError::InsufficientLiquidity
+        );
+
+        let (amount0_added, amount1_added, liquidity_minted) = compute_liquidity(
+            dest_reserve0,
+            dest_reserve1,
+            dest_supply,
+            accounts.dest_pair.minimum_liquidity,
+            accounts.dest_pair.normalized_decimals(),
+            withdrawn0 as u128,
+            withdrawn1 as u128,
+            0,
+            0,
+        )?;
+
+        let (vault0_before, vault1_before) =
+            (accounts.dest_token0_account.amount, accounts.dest_token1_account.amount);
+        for (from, mint, to, amount) in [
+            (&accounts.user_token0, &accounts.token0_mint, &accounts.dest_token0_account, amount0_added),
+            (&accounts.user_token1, &accounts.token1_mint, &accounts.dest_token1_account, amount1_added),
+        ] {
+            transfer_from_user(
+                accounts.token_program.to_account_info(),
+                from.to_account_info(),
+                mint,
+                to.to_account_info(),
+                accounts.sender.to_account_info(),
+                amount,
+            )?;
+        }
+        accounts.dest_token0_account.reload()?;
+        accounts.dest_token1_account.reload()?;
+        let received0 = accounts
+            .dest_token0_account
+            .amount
+            .checked_sub(vault0_before)
+            .ok_or(DexError::AmountOverflow)?;
+        let received1 = accounts
+            .dest_token1_account
+            .amount
+            .checked_sub(vault1_before)
+            .ok_or(DexError::AmountOverflow)?;
+        let liquidity_minted = accounts.dest_pair.received_liquidity(
+            amount0_added,
+            amount1_added,
+            received0,
+            received1,
+            liquidity_minted,
+        )?;
+        require!(
+            liquidity_minted as u128 >= min_liquidity_out,
+            DexError::InsufficientLiquidityMinted
+        );
+
+        mint_lp(
+            accounts.token_program.to_account_info(),
+            accounts.dest_lp_mint.to_account_info(),
+            accounts.liquidity_to.to_account_info(),
+            accounts.dest_authority.to_account_info(),
+            accounts.dest_pair.key(),
+            accounts.dest_pair.authority_bump,
+            liquidity_minted,
+        )?;
+
+        let dest_pair_key = accounts.dest_pair.key();
+        let dest_pair = &mut accounts.dest_pair;
+        dest_pair.reserve0 = dest_reserve0
+            .checked_add(received0)
+            .ok_or(DexError::AmountOverflow)?;
+        dest_pair.reserve1 = dest_reserve1
+            .checked_add(received1)
+            .ok_or(DexError::AmountOverflow)?;
+        dest_pair.total_supply = dest_supply
+            .checked_add(liquidity_minted)
+            .ok_or(DexError::AmountOverflow)?;
+        dest_pair.update_k_last(accounts.factory.fee_on);
+        dest_pair.check_reserve_caps(dest_pair_key);
+
+        emit!(LiquidityMigratedEvent {
+            source_pairs,
+            dest_pair: dest_pair_key,
+            sender: accounts.sender.key(),
+            liquidity_burned,
+            amount0_removed,
+            amount1_removed,
+            amount0_added: received0,
+            amount1_added: received1,
+            liquidity_minted,
+        });
+
+        accounts.dest_pair.locked = false;
+        Ok(())
+    }
+
+    // Built-in LP farm: stakers of the pair's LP earn `reward_rate` reward
+    // tokens per second, split pro rata. Rewards are paid from the reward
+    // vault, which anyone can top up with plain transfers.
+    pub fn init_reward_pool(ctx: Context<InitRewardPool>, reward_rate: u64) -> Result<()> {
+        let reward_pool = &mut ctx.accounts.reward_pool;
+        reward_pool.pair = ctx.accounts.pair.key();
+        reward_pool.reward_mint = ctx.accounts.reward_mint.key();
+        reward_pool.staked_lp_vault = ctx.accounts.staked_lp_vault.key();
+        reward_pool.reward_vault = ctx.accounts.reward_vault.key();
+        reward_pool.reward_rate = reward_rate;
+        reward_pool.reward_per_share = 0;
+        reward_pool.last_update_at = Clock::get()?.unix_timestamp;
+        reward_pool.total_staked = 0;
+
+        emit!(RewardPoolInitializedEvent {
+            pair: reward_pool.pair,
+            reward_mint: reward_pool.reward_mint,
+            reward_rate,
+        });
+
+        Ok(())
+    }
+
+    pub fn stake_lp(ctx: Context<StakeLp>, amount: u64) -> Result<()> {
+        require!(amount > 0, DexError::InsufficientAmount);
+
+        let reward_pool = &mut ctx.accounts.reward_pool;
+        let position = &mut ctx.accounts.position;
+        reward_pool.update(Clock::get()?.unix_timestamp)?;
+        position.accrue(reward_pool)?;
+
+        transfer_from_user(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.user_lp.to_account_info(),
+            &ctx.accounts.lp_mint,
+            ctx.accounts.staked_lp_vault.to_account_info(),
+            ctx.accounts.sender.to_account_info(),
+            amount,
+        )?;
+
+        position.owner = ctx.accounts.sender.key();
+        position.reward_pool = reward_pool.key();
+        position.amount = position.amount.checked_add(amount).ok_or(DexError::AmountOverflow)?;
+        reward_pool.total_staked = reward_pool
+            .total_staked
+            .checked_add(amount)
+            .ok_or(DexError::AmountOverflow)?;
+        position.reset_reward_debt(reward_pool)?;
+
+        emit!(LpStakedEvent {
+            pair: reward_pool.pair,
+            owner: position.owner,
+            amount,
+            total_staked: reward_pool.total_staked,
+        });
+
+        Ok(())
+    }
+
+    pub fn unstake_lp(ctx: Context<UnstakeLp>, amount: u64) -> Result<()> {
+        let reward_pool = &mut ctx.accounts.reward_pool;
+        let position = &mut ctx.accounts.position;
+        require!(amount > 0 && amount <= position.amount, DexError::InsufficientStake);
+
+        reward_pool.update(Clock::get()?.unix_timestamp)?;
+        position.accrue(reward_pool)?;
+
+        transfer_from_vault(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.staked_lp_vault.to_account_info(),
+            &ctx.accounts.lp_mint,
+            ctx.accounts.user_lp.to_account_info(),
+            ctx.accounts.authority.to_account_info(),
+            ctx.accounts.pair.key(),
+            ctx.accounts.pair.authority_bump,
+            amount,
+        )?;
+
+        position.amount -= amount;
+        reward_pool.total_staked -= amount;
+        position.reset_reward_debt(reward_pool)?;
+
+        emit!(LpUnstakedEvent {
+            pair: reward_pool.pair,
+            owner: position.owner,
+            amount,
+            total_staked: reward_pool.total_staked,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+        let reward_pool = &mut ctx.accounts.reward_pool;
+        let position = &mut ctx.accounts.position;
+        reward_pool.update(Clock::get()?.unix_timestamp)?;
+        position.accrue(reward_pool)?;
+        position.reset_reward_debt(reward_pool)?;
+
+        let amount = position.pending_rewards;
+        require!(
+            ctx.accounts.reward_vault.amount >= amount,
+            DexError::InsufficientRewards
+        );
+        position.pending_rewards = 0;
+
+        transfer_from_vault(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.reward_vault.to_account_info(),
+            &ctx.accounts.reward_mint,
+            ctx.accounts.user_reward.to_account_info(),
+            ctx.accounts.authority.to_account_info(),
+            ctx.accounts.pair.key(),
+            ctx.accounts.pair.authority_bump,
+            amount,
+        )?;
+
+        emit!(RewardsClaimedEvent {
+            pair: reward_pool.pair,
+            owner: position.owner,
+            amount,
+        });
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = Factory::LEN
+    )]
+    pub factory: Account<'info, Factory>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+// Step 1: Create token accounts only
+#[derive(Accounts)]
+pub struct CreateTokenAccounts<'info> {
+    // Remove the factory to save stack space
+    
+    /// CHECK: This is a token mint
+    pub token0: UncheckedAccount<'info>,
+    
+    /// CHECK: This is a token mint
+    pub token1: UncheckedAccount<'info>,
+    
+    /// CHECK: This is the authority PDA
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            pair_pda.key().as_ref()
+        ],
+        bump
+    )]
+    pub authority: UncheckedAccount<'info>,
+    
+    /// CHECK: This is a PDA for the pair, used only for the authority derivation
+    #[account(
+        seeds = [
+            b"pair".as_ref(),
+            token0.key().as_ref(),
+            token1.key().as_ref()
+        ],
+        bump
+    )]
+    pub pair_pda: UncheckedAccount<'info>,
+    
+    #[account(
+        init,
+        payer = sender,
+        token::mint = token0,
+        token::authority = authority,
+    )]
+    pub token0_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        init,
+        payer = sender,
+        token::mint = token1,
+        token::authority = authority,
+    )]
+    pub token1_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+// Step 2: Create pair account and LP mint
+#[derive(Accounts)]
+pub struct CreatePairAccount<'info> {
+    #[account(
+        mut,
+        has_one = owner @ DexError::NotFactoryOwner,
+    )]
+    pub factory: Account<'info, Factory>,
+    
+    #[account(
+        init,
+        payer = sender,
+        space = PairAccount::LEN,
+        seeds = [
+            b"pair".as_ref(),
+            token0.key().as_ref(),
+            token1.key().as_ref()
+        ],
+        bump
+    )]
+    pub pair: Account<'info, PairAccount>,
+    
+    /// CHECK: This is a token mint and is validated by the token program
+    pub token0: InterfaceAccount<'info, Mint>,
+    
+    /// CHECK: This is a token mint and is validated by the token program
+    pub token1: InterfaceAccount<'info, Mint>,
+    
+    // Derived from the pair, so every pair has exactly one LP mint address
+    #[account(
+        init,
+        payer = sender,
+        seeds = [
+            b"lp_mint".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump,
+        mint::decimals = 8,
+        mint::authority = authority,
+    )]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    // Marks `lp_mint` as an LP mint of this program
+    #[account(
+        init,
+        payer = sender,
+        space = LpMintMarker::LEN,
+        seeds = [
+            b"lp_mint".as_ref(),
+            lp_mint.key().as_ref()
+        ],
+        bump
+    )]
+    pub lp_mint_marker: Box<Account<'info, LpMintMarker>>,
+
+    /// CHECK: Marker PDA for token0; only checked for existence
+    #[account(
+        seeds = [
+            b"lp_mint".as_ref(),
+            token0.key().as_ref()
+        ],
+        bump
+    )]
+    pub token0_lp_marker: UncheckedAccount<'info>,
+
+    /// CHECK: Marker PDA for token1; only checked for existence
+    #[account(
+        seeds = [
+            b"lp_mint".as_ref(),
+            token1.key().as_ref()
+        ],
+        bump
+    )]
+    pub token1_lp_marker: UncheckedAccount<'info>,
+    
+    /// CHECK: This is the PDA authority for the pair
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump
+    )]
+    pub authority: UncheckedAccount<'info>,
+    
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    
+    /// CHECK: Factory owner required for authorization
+    pub owner: UncheckedAccount<'info>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct ForceCloseUnconfiguredPair<'info> {
+    #[account(has_one = owner @ DexError::NotFactoryOwner)]
+    pub factory: Account<'info, Factory>,
+
+    #[account(
+        mut,
+        close = owner,
+        constraint = !pair.is_initialized @ DexError::PairAlreadyInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    /// CHECK: Only used to derive the marker; it's the pair's LP mint PDA
+    #[account(
+        seeds = [
+            b"lp_mint".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump
+    )]
+    pub lp_mint: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        close = owner,
+        seeds = [
+            b"lp_mint".as_ref(),
+            lp_mint.key().as_ref()
+        ],
+        bump,
+        constraint = lp_mint_marker.pair == pair.key() @ DexError::InvalidLpMint,
+    )]
+    pub lp_mint_marker: Account<'info, LpMintMarker>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePair<'info> {
+    #[account(
+        mut,
+        has_one = owner @ DexError::NotFactoryOwner,
+    )]
+    pub factory: Account<'info, Factory>,
+
+    #[account(
+        mut,
+        close = owner,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = pair.reserve0 == 0 && pair.reserve1 == 0 && pair.total_supply == 0 @ DexError::PairNotEmpty,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    /// CHECK: Only used to derive the marker; it's the pair's LP mint PDA
+    #[account(
+        seeds = [
+            b"lp_mint".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump
+    )]
+    pub lp_mint: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        close = owner,
+        seeds = [
+            b"lp_mint".as_ref(),
+            lp_mint.key().as_ref()
+        ],
+        bump,
+        constraint = lp_mint_marker.pair == pair.key() @ DexError::InvalidLpMint,
+    )]
+    pub lp_mint_marker: Option<Account<'info, LpMintMarker>>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[event]
+pub struct PairClosedEvent {
+    pub pair: Pubkey,
+    pub pair_count: u64,
+}
+
+#[derive(Accounts)]
+pub struct EvacuatePair<'info> {
+    #[account(has_one = owner @ DexError::NotFactoryOwner)]
+    pub factory: Account<'info, Factory>,
+
+    #[account(
+        mut,
+        constraint = old_pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = old_pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = old_pair.token0_account == old_token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = old_pair.token1_account == old_token1_account.key() @ DexError::InvalidTokenAccount,
+        constraint = old_pair.lp_mint == lp_mint.key() @ DexError::InvalidLpMint,
+    )]
+    pub old_pair: Account<'info, PairAccount>,
+
+    #[account(mut)]
+    pub old_token0_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub old_token1_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA authority of the old pair; signs the transfers out
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            old_pair.key().as_ref()
+        ],
+        bump = old_pair.authority_bump
+    )]
+    pub old_authority: UncheckedAccount<'info>,
+
+    // Same tokens, never deposited into
+    #[account(
+        mut,
+        constraint = new_pair.key() != old_pair.key() @ DexError::IdenticalPairs,
+        constraint = new_pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = new_pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = new_pair.token0 == old_pair.token0 && new_pair.token1 == old_pair.token1 @ DexError::MismatchedPairTokens,
+        constraint = new_pair.reserve0 == 0 && new_pair.reserve1 == 0 && new_pair.total_supply == 0 @ DexError::PairNotEmpty,
+        constraint = new_pair.token0_account == new_token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = new_pair.token1_account == new_token1_account.key() @ DexError::InvalidTokenAccount,
+    )]
+    pub new_pair: Account<'info, PairAccount>,
+
+    #[account(mut)]
+    pub new_token0_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub new_token1_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA authority of the new pair; becomes the LP mint authority
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            new_pair.key().as_ref()
+        ],
+        bump = new_pair.authority_bump
+    )]
+    pub new_authority: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    // Re-pointed at the new pair along with the mint
+    #[account(
+        mut,
+        seeds = [
+            b"lp_mint".as_ref(),
+            lp_mint.key().as_ref()
+        ],
+        bump,
+        constraint = lp_mint_marker.pair == old_pair.key() @ DexError::InvalidLpMint,
+    )]
+    pub lp_mint_marker: Account<'info, LpMintMarker>,
+
+    #[account(address = old_pair.token0 @ DexError::InvalidTokenAccount)]
+    pub token0_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = old_pair.token1 @ DexError::InvalidTokenAccount)]
+    pub token1_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub owner: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event]
+pub struct PairEvacuatedEvent {
+    pub old_pair: Pubkey,
+    pub new_pair: Pubkey,
+    pub amount0: u64,
+    pub amount1: u64,
+    pub total_supply: u64,
+}
+
+// Step 3: Configure the pair
+#[derive(Accounts)]
+pub struct ConfigurePair<'info> {
+    #[account(
+        mut,
+        has_one = owner @ DexError::NotFactoryOwner,
+    )]
+    pub factory: Account<'info, Factory>,
+    
+    #[account(mut)]
+    pub pair: Account<'info, PairAccount>,
+    
+    pub token0: InterfaceAccount<'info, Mint>,
+    
+    pub token1: InterfaceAccount<'info, Mint>,
+    
+    // Only the pair's authority may mint LP, and nobody may freeze it
+    #[account(
+        seeds = [
+            b"lp_mint".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump,
+        constraint = lp_mint.mint_authority == COption::Some(authority.key()) @ DexError::InvalidLpMintAuthority,
+        constraint = lp_mint.freeze_authority.is_none() @ DexError::InvalidLpMintAuthority,
+    )]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: This is the PDA authority for the pair; it must own both vaults
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump = pair.authority_bump
+    )]
+    pub authority: UncheckedAccount<'info>,
+    
+    // Vaults line up with the sorted tokens, as stored by the handler
+    #[account(
+        constraint = token0_account.owner == authority.key() @ DexError::InvalidTokenAccount,
+        constraint = token0_account.mint == token0.key().min(token1.key()) @ DexError::InvalidTokenAccount,
+    )]
+    pub token0_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        constraint = token1_account.owner == authority.key() @ DexError::InvalidTokenAccount,
+        constraint = token1_account.mint == token0.key().max(token1.key()) @ DexError::InvalidTokenAccount,
+    )]
+    pub token1_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    
+    /// CHECK: Factory owner required for authorization
+    pub owner: UncheckedAccount<'info>,
+}
+
+#[account]
+pub struct Factory {
+    pub owner: Pubkey,
+    pub pair_count: u64,
+    pub fee_to: Pubkey,
+    pub fee_on: bool,
+    pub last_pair: Pubkey,
+    pub discount_mint: Pubkey,
+    pub discount_threshold: u64,
+    pub discount_fee_bps: u16,
+    pub pending_owner: Pubkey,
+    pub promo_fee_until: i64,
+    pub promo_fee_bps: u16,
+    pub reject_lp_pair_tokens: bool,
+    pub keeper_bounty_bps: u16,
+    pub paused: bool,
+    pub migration_authority: Pubkey,
+    pub lp_burn_address: Pubkey,
+    pub fee_numerator: u8,
+    pub fee_denominator: u8,
+}
+
+impl Factory {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // owner pubkey
+        8 + // pair_count
+        32 + // fee_to pubkey
+        1 + // fee_on boolean
+        32 + // last_pair pubkey
+        32 + // discount_mint pubkey
+        8 + // discount_threshold
+        2 + // discount_fee_bps
+        32 + // pending_owner pubkey
+        8 + // promo_fee_until
+        2 + // promo_fee_bps
+        1 + // reject_lp_pair_tokens
+        2 + // keeper_bounty_bps
+        1 + // paused
+        32 + // migration_authority pubkey
+        32 + // lp_burn_address pubkey
+        1 + // fee_numerator
+        1; // fee_denominator
+
+    // Factory-wide overrides of a pair's swap fee: a running promotion
+    // replaces it, and holders of enough of the discount token pay at most
+    // the discounted fee
+    fn fee_adjustment(
+        &self,
+        now: i64,
+        discount_token: Option<&InterfaceAccount<TokenAccount>>,
+        sender: Pubkey,
+    ) -> Result<impl Fn(u16) -> u16 + Copy> {
+        let promo_fee_bps = (now < self.promo_fee_until).then_some(self.promo_fee_bps);
+        let mut discount_fee_bps = None;
+        if let Some(discount_token) = discount_token {
+            require_keys_neq!(self.discount_mint, Pubkey::default(), DexError::InvalidTokenAccount);
+            require_keys_eq!(discount_token.mint, self.discount_mint, DexError::InvalidTokenAccount);
+            require_keys_eq!(discount_token.owner, sender, DexError::InvalidTokenOwner);
+            if discount_token.amount >= self.discount_threshold {
+                discount_fee_bps = Some(self.discount_fee_bps);
+            }
+        }
+        Ok(move |fee_bps: u16| {
+            let fee_bps = promo_fee_bps.unwrap_or(fee_bps);
+            discount_fee_bps.map_or(fee_bps, |discount| fee_bps.min(discount))
+        })
+    }
+}
+
+#[account]
+pub struct PairAccount {
+    pub factory: Pubkey,
+    pub token0: Pubkey,
+    pub token1: Pubkey,
+    pub reserve0: u64,
+    pub reserve1: u64,
+    pub token0_account: Pubkey,
+    pub token1_account: Pubkey,
+    pub lp_mint: Pubkey,
+    pub total_supply: u64,
+    pub bump: u8,
+    pub authority_bump: u8,
+    pub is_initialized: bool,
+    pub protocol_fee_account: Pubkey,
+    pub fee_withdrawal_amount: u64,
+    pub fee_withdrawal_unlock_at: i64,
+    pub callback_program: Option<Pubkey>,
+    pub fee_bps: u16,
+    pub fee_tiers: [FeeTier; MAX_FEE_TIERS],
+    pub fee_tier_count: u8,
+    pub max_ratio: u64,
+    pub token0_decimals: u8,
+    pub token1_decimals: u8,
+    pub minimum_liquidity: u64,
+    pub controller: Option<Pubkey>,
+    pub swap_cooldown_seconds: u32,
+    pub max_out_bps: u16,
+    pub min_trade_amount: u64,
+    pub k_last: u128,
+    pub tick_size_x64: u128,
+    pub external_oracle: Option<Pubkey>,
+    pub max_oracle_deviation_bps: u16,
+    pub locked: bool,
+    pub price0_cumulative_last: u128,
+    pub price1_cumulative_last: u128,
+    pub block_timestamp_last: u32,
+    pub min_deposit_liquidity: u64,
+    pub max_deposit_liquidity: u64,
+    pub pair_paused: bool,
+    pub archive_snapshot_count: u64,
+    pub protocol_liquidity: u64,
+    pub emit_events: bool,
+    pub summary_interval: u16,
+    pub summary_swap_count: u16,
+    pub summary_amount0_in: u64,
+    pub summary_amount1_in: u64,
+    pub summary_amount0_out: u64,
+    pub summary_amount1_out: u64,
+    pub normalize_decimals: bool,
+    pub reserve_cap0: u64,
+    pub reserve_cap1: u64,
+    pub frozen: bool,
+    pub created_at: i64,
+    pub creator: Pubkey,
+    pub max_deposit_deviation_bps: u16,
+    pub observations: [OracleObservation; MAX_ORACLE_OBSERVATIONS],
+    pub observation_index: u8,
+    pub observation_count: u8,
+    pub liquidity_cumulative_last: u128,
+    pub fee_withdrawal_destination: Pubkey,
+}
+
+impl PairAccount {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // factory
+        32 + // token0
+        32 + // token1
+        8 + // reserve0
+        8 + // reserve1
+        32 + // token0_account
+        32 + // token1_account
+        32 + // lp_mint
+        8 + // total_supply
+        1 + // bump
+        1 + // authority_bump
+        1 + // is_initialized
+        32 + // protocol_fee_account
+        8 + // fee_withdrawal_amount
+        8 + // fee_withdrawal_unlock_at
+        1 + 32 + // callback_program
+        2 + // fee_bps
+        FeeTier::LEN * MAX_FEE_TIERS + // fee_tiers
+        1 + // fee_tier_count
+        8 + // max_ratio
+        1 + // token0_decimals
+        1 + // token1_decimals
+        8 + // minimum_liquidity
+        1 + 32 + // controller
+        4 + // swap_cooldown_seconds
+        2 + // max_out_bps
+        8 + // min_trade_amount
+        16 + // k_last
+        16 + // tick_size_x64
+        1 + 32 + // external_oracle
+        2 + // max_oracle_deviation_bps
+        1 + // locked
+        16 + // price0_cumulative_last
+        16 + // price1_cumulative_last
+        4 + // block_timestamp_last
+        8 + // min_deposit_liquidity
+        8 + // max_deposit_liquidity
+        1 + // pair_paused
+        8 + // archive_snapshot_count
+        8 + // protocol_liquidity
+        1 + // emit_events
+        2 + // summary_interval
+        2 + // summary_swap_count
+        8 + // summary_amount0_in
+        8 + // summary_amount1_in
+        8 + // summary_amount0_out
+        8 + // summary_amount1_out
+        1 + // normalize_decimals
+        8 + // reserve_cap0
+        8 + // reserve_cap1
+        1 + // frozen
+        8 + // created_at
+        32 + // creator
+        2 + // max_deposit_deviation_bps
+        OracleObservation::LEN * MAX_ORACLE_OBSERVATIONS + // observations
+        1 + // observation_index
+        1 + // observation_count
+        16 + // liquidity_cumulative_last
+        32; // fee_withdrawal_destination
+
+    // Fee for a swap of `amount_in`: the highest tier whose threshold the
+    // amount reaches, otherwise the pair's base fee.
+    pub fn swap_fee_bps(&self, amount_in: u64) -> u16 {
+        self.fee_tiers[..self.fee_tier_count as usize]
+            .iter()
+            .rev()
+            .find(|tier| amount_in >= tier.threshold)
+            .map_or(self.fee_bps, |tier| tier.fee_bps)
+    }
+
+    // Smallest input that buys `amount_out`, where `adjust` maps the schedule's
+    // fee to the fee actually charged (identity for plain quotes). The tier
+    // depends on the input, so every fee in the schedule is tried and the
+    // cheapest input that is charged at most that fee wins.
+    fn amount_in_for_exact_out(
+        &self,
+        amount_out: u64,
+        reserve_in: u64,
+        reserve_out: u64,
+        adjust: impl Fn(u16) -> u16,
+    ) -> Result<u64> {
+        let tiers = &self.fee_tiers[..self.fee_tier_count as usize];
+        let mut amount_in: Option<u64> = None;
+        for fee_bps in std::iter::once(self.fee_bps).chain(tiers.iter().map(|tier| tier.fee_bps)) {
+            let fee_bps = adjust(fee_bps);
+            let candidate = amount_in_for(amount_out, reserve_in, reserve_out, fee_bps)?;
+            if adjust(self.swap_fee_bps(candidate)) <= fee_bps {
+                amount_in = Some(amount_in.map_or(candidate, |best| best.min(candidate)));
+            }
+        }
+        amount_in.ok_or(error!(DexError::InsufficientLiquidity))
+    }
+
+    // Per-trade guards shared by direct and routed swaps: the minimum trade
+    // size, a non-empty output the reserve can cover, and the pair's optional
+    // output share and reserve ratio caps.
+    // Pairs with a cooldown only let each signer swap once per window; a
+    // trader state passed on a pair without one still records the swap
+    fn check_cooldown(&self, trader_state: Option<&mut TraderState>, now: i64) -> Result<()> {
+        let cooldown = self.swap_cooldown_seconds as i64;
+        if cooldown > 0 || trader_state.is_some() {
+            let trader_state = trader_state.ok_or(DexError::TraderStateRequired)?;
+            require!(
+                now >= trader_state.last_swap_at.saturating_add(cooldown),
+                DexError::CooldownActive
+            );
+            trader_state.last_swap_at = now;
+        }
+        Ok(())
+    }
+
+    fn check_trade_limits(
+        &self,
+        reserve_in: u64,
+        reserve_out: u64,
+        amount_in: u64,
+        amount_out: u64,
+    ) -> Result<()> {
+        require!(amount_in >= self.min_trade_amount, DexError::TradeTooSmall);
+
+        // Ensure amount_out is positive and the output reserve keeps at least
+        // one unit, so the pair stays priceable
+        require!(amount_out > 0, DexError::InsufficientOutputAmount);
+        require!(amount_out < reserve_out, DexError::InsufficientLiquidity);
+
+        // Cap a single swap's output to a share of the output reserve
+        let max_out_bps = self.max_out_bps as u128;
+        if max_out_bps > 0 {
+            require!(
+                amount_out as u128 * FEE_DENOMINATOR <= reserve_out as u128 * max_out_bps,
+                DexError::MaxOutputExceeded
+            );
+        }
+
+        // Reject swaps that would skew the reserves beyond the configured ratio
+        let max_ratio = self.max_ratio as u128;
+        if max_ratio > 0 {
+            let new_reserve_in = reserve_in as u128 + amount_in as u128;
+            let new_reserve_out = (reserve_out - amount_out) as u128;
+            require!(
+                new_reserve_in <= new_reserve_out.saturating_mul(max_ratio)
+                    && new_reserve_out <= new_reserve_in.saturating_mul(max_ratio),
+                DexError::RatioOutOfBounds
+            );
+        }
+
+        Ok(())
+    }
+
+    // Logs a swap: as its own `SwapEvent`, or with events off, folded into the
+    // running totals that go out as a summary every `summary_interval` swaps
+    fn record_swap(&mut self, pair: Pubkey, event: SwapEvent) {
+        if self.emit_events {
+            emit!(event);
+            return;
+        }
+
+        let (amount_in, amount_out) = if event.is_token0_in {
+            (&mut self.summary_amount0_in, &mut self.summary_amount1_out)
+        } else {
+            (&mut self.summary_amount1_in, &mut self.summary_amount0_out)
+        };
+        *amount_in = amount_in.saturating_add(event.amount_in);
+        *amount_out = amount_out.saturating_add(event.amount_out);
+        self.summary_swap_count += 1;
+        if self.summary_swap_count >= self.summary_interval {
+            self.flush_swap_summary(pair);
+        }
+    }
+
+    // Freezes deposits once either reserve has reached its cap. Swaps and
+    // withdrawals carry on, so only the owner lifting the caps reopens inflows.
+    fn check_reserve_caps(&mut self, pair: Pubkey) {
+        if self.frozen {
+            return;
+        }
+        let reached = |reserve: u64, cap: u64| cap > 0 && reserve >= cap;
+        if reached(self.reserve0, self.reserve_cap0) || reached(self.reserve1, self.reserve_cap1) {
+            self.frozen = true;
+            emit!(ReserveCapReachedEvent {
+                pair,
+                reserve0: self.reserve0,
+                reserve1: self.reserve1,
+                reserve_cap0: self.reserve_cap0,
+                reserve_cap1: self.reserve_cap1,
+            });
+        }
+    }
+
+    fn flush_swap_summary(&mut self, pair: Pubkey) {
+        emit!(SwapSummaryEvent {
+            pair,
+            swap_count: self.summary_swap_count,
+            amount0_in: self.summary_amount0_in,
+            amount1_in: self.summary_amount1_in,
+            amount0_out: self.summary_amount0_out,
+            amount1_out: self.summary_amount1_out,
+            reserve0_after: self.reserve0,
+            reserve1_after: self.reserve1,
+        });
+        self.summary_swap_count = 0;
+        self.summary_amount0_in = 0;
+        self.summary_amount1_in = 0;
+        self.summary_amount0_out = 0;
+        self.summary_amount1_out = 0;
+    }
+
+    // Decimals the first deposit's geometric mean is normalised with, if any
+    pub fn normalized_decimals(&self) -> Option<(u8, u8)> {
+        self.normalize_decimals
+            .then_some((self.token0_decimals, self.token1_decimals))
+    }
+
+    pub fn flags(&self) -> u32 {
+        [
+            (self.is_initialized, PAIR_FLAG_INITIALIZED),
+            (self.locked, PAIR_FLAG_LOCKED),
+            (self.external_oracle.is_some(), PAIR_FLAG_ORACLE_ENABLED),
+            (self.callback_program.is_some(), PAIR_FLAG_CALLBACK),
+            (self.controller.is_some(), PAIR_FLAG_CONTROLLED),
+            (self.fee_tier_count > 0, PAIR_FLAG_FEE_TIERS),
+            (self.swap_cooldown_seconds > 0, PAIR_FLAG_COOLDOWN),
+            (self.max_out_bps > 0, PAIR_FLAG_MAX_OUT),
+            (self.max_ratio > 0, PAIR_FLAG_MAX_RATIO),
+            (self.min_trade_amount > 0, PAIR_FLAG_MIN_TRADE),
+            (
+                self.min_deposit_liquidity > 0 || self.max_deposit_liquidity < u64::MAX,
+                PAIR_FLAG_DEPOSIT_BOUNDS,
+            ),
+            (self.pair_paused, PAIR_FLAG_PAUSED),
+            (!self.emit_events, PAIR_FLAG_EVENTS_THROTTLED),
+            (self.frozen, PAIR_FLAG_FROZEN),
+            (self.max_deposit_deviation_bps > 0, PAIR_FLAG_DEPOSIT_DEVIATION),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, bit)| flags | bit)
+    }
+
+    // LP owed to the protocol for the sqrt(k) growth since `k_last`: the
+    // factory's fee_numerator / fee_denominator of it (1/6 by default, as in
+    // Uniswap V2). Zero while the fee is off or before the first snapshot.
+    fn accrued_protocol_fee(&self, factory: &Factory) -> Result<u64> {
+        if !factory.fee_on || self.k_last == 0 || factory.fee_numerator == 0 {
+            return Ok(0);
+        }
+
+        let root_k = sqrt(self.reserve0 as u128 * self.reserve1 as u128);
+        let root_k_last = sqrt(self.k_last);
+        if root_k <= root_k_last {
+            return Ok(0);
+        }
+
+        // Minting `liquidity` must leave the protocol that fraction of the
+        // growth: supply * growth * n / (root_k * (d - n) + root_k_last * n)
+        let (fee_numerator, fee_denominator) = (factory.fee_numerator as u128, factory.fee_denominator as u128);
+        let numerator = (self.total_supply as u128)
+            .checked_mul(root_k - root_k_last)
+            .and_then(|value| value.checked_mul(fee_numerator))
+            .ok_or(DexError::AmountOverflow)?;
+        let denominator = root_k
+            .checked_mul(fee_denominator - fee_numerator)
+            .and_then(|value| value.checked_add(root_k_last.checked_mul(fee_numerator)?))
+            .ok_or(DexError::AmountOverflow)?;
+        u64::try_from(numerator / denominator).map_err(|_| error!(DexError::AmountOverflow))
+    }
+
+    // LP for a deposit quoted at `liquidity` for `amount0`/`amount1` of which
+    // only `received0`/`received1` reached the vaults (a mint with a transfer
+    // fee), held to the pair's deposit bounds. Reserves and supply must still
+    // be the pre-deposit values.
+    fn received_liquidity(
+        &self,
+        amount0: u64,
+        amount1: u64,
+        received0: u64,
+        received1: u64,
+        liquidity: u64,
+    ) -> Result<u64> {
+        let liquidity = if received0 < amount0 || received1 < amount1 {
+            let (_, _, received_liquidity) = compute_liquidity(
+                self.reserve0,
+                self.reserve1,
+                self.total_supply,
+                self.minimum_liquidity,
+                self.normalized_decimals(),
+                received0 as u128,
+                received1 as u128,
+                0,
+                0,
+            )?;
+            received_liquidity.min(liquidity)
+        } else {
+            liquidity
+        };
+        require!(liquidity > 0, DexError::InsufficientLiquidityMinted);
+        require!(
+            liquidity >= self.min_deposit_liquidity && liquidity <= self.max_deposit_liquidity,
+            DexError::DepositOutOfBounds
+        );
+        Ok(liquidity)
+    }
+
+    // A pool trading away from the external price is likely depegged or
+    // manipulated; checked on the reserves a trade left behind
+    fn check_oracle_deviation(&self, external_oracle: Option<&UncheckedAccount>) -> Result<()> {
+        let Some(oracle_key) = self.external_oracle else {
+            return Ok(());
+        };
+        let oracle = external_oracle
+            .filter(|oracle| oracle.key() == oracle_key)
+            .ok_or(DexError::InvalidOracleAccount)?;
+        let oracle_price_x64 = external_oracle_price_x64(oracle)?;
+        let pool_price_x64 = ((self.reserve1 as u128) << 64) / self.reserve0 as u128;
+        let deviation = pool_price_x64.abs_diff(oracle_price_x64);
+        require!(
+            deviation.saturating_mul(FEE_DENOMINATOR)
+                <= oracle_price_x64.saturating_mul(self.max_oracle_deviation_bps as u128),
+            DexError::OracleDeviationTooHigh
+        );
+        Ok(())
+    }
+
+    // Cumulative prices as of `now`, carrying the current price forward from
+    // the last update without storing anything
+    fn cumulative_prices_at(&self, now: u32) -> (u128, u128) {
+        if self.block_timestamp_last == 0 || self.reserve0 == 0 || self.reserve1 == 0 {
+            return (self.price0_cumulative_last, self.price1_cumulative_last);
+        }
+        let time_elapsed = now.wrapping_sub(self.block_timestamp_last) as u128;
+        let price0_x64 = ((self.reserve1 as u128) << 64) / self.reserve0 as u128;
+        let price1_x64 = ((self.reserve0 as u128) << 64) / self.reserve1 as u128;
+        (
+            self.price0_cumulative_last
+                .wrapping_add(price0_x64.wrapping_mul(time_elapsed)),
+            self.price1_cumulative_last
+                .wrapping_add(price1_x64.wrapping_mul(time_elapsed)),
+        )
+    }
+
+    // Time-weighted depth as of `now`: sqrt(reserve0 * reserve1) times the
+    // seconds it held, carried forward from the last update like the prices
+    fn liquidity_cumulative_at(&self, now: u32) -> u128 {
+        if self.block_timestamp_last == 0 {
+            return self.liquidity_cumulative_last;
+        }
+        let time_elapsed = now.wrapping_sub(self.block_timestamp_last) as u128;
+        let root_k = sqrt(self.reserve0 as u128 * self.reserve1 as u128);
+        self.liquidity_cumulative_last
+            .wrapping_add(root_k.wrapping_mul(time_elapsed))
+    }
+
+    // Newest observation at least `window` seconds (and at least one second)
+    // before `now`
+    fn observation_before(&self, now: u32, window: u32) -> Option<&OracleObservation> {
+        (0..self.observation_count as usize)
+            .map(|age| {
+                &self.observations[(self.observation_index as usize + MAX_ORACLE_OBSERVATIONS - age)
+                    % MAX_ORACLE_OBSERVATIONS]
+            })
+            .find(|observation| now.wrapping_sub(observation.timestamp) >= window.max(1))
+    }
+
+    fn oldest_observation(&self) -> Option<&OracleObservation> {
+        if self.observation_count == 0 {
+            return None;
+        }
+        let slot = (self.observation_index as usize + 1 + MAX_ORACLE_OBSERVATIONS
+            - self.observation_count as usize)
+            % MAX_ORACLE_OBSERVATIONS;
+        Some(&self.observations[slot])
+    }
+
+    // Stores the accumulators as they stand now, overwriting the oldest slot
+    // once the ring is full
+    fn record_observation(&mut self) {
+        let slot = if self.observation_count == 0 {
+            0
+        } else {
+            (self.observation_index as usize + 1) % MAX_ORACLE_OBSERVATIONS
+        };
+        self.observations[slot] = OracleObservation {
+            timestamp: self.block_timestamp_last,
+            price0_cumulative: self.price0_cumulative_last,
+            price1_cumulative: self.price1_cumulative_last,
+            liquidity_cumulative: self.liquidity_cumulative_last,
+        };
+        self.observation_index = slot as u8;
+        self.observation_count = (self.observation_count + 1).min(MAX_ORACLE_OBSERVATIONS as u8);
+    }
+
+    // Deposits must find the spot price within `max_deposit_deviation_bps` of
+    // the TWAP over the stored observations, so an LP isn't lured into a
+    // freshly skewed ratio. Off at 0 and until the history spans some time.
+    fn check_deposit_deviation(&self, now: u32) -> Result<()> {
+        if self.max_deposit_deviation_bps == 0 || self.reserve0 == 0 || self.reserve1 == 0 {
+            return Ok(());
+        }
+        let Some(oldest) = self.oldest_observation() else {
+            return Ok(());
+        };
+        let window = now.wrapping_sub(oldest.timestamp);
+        if window == 0 {
+            return Ok(());
+        }
+        let (price0_cumulative, _) = self.cumulative_prices_at(now);
+        let twap_x64 = price0_cumulative.wrapping_sub(oldest.price0_cumulative) / window as u128;
+        let spot_x64 = ((self.reserve1 as u128) << 64) / self.reserve0 as u128;
+        require!(
+            spot_x64.abs_diff(twap_x64).saturating_mul(FEE_DENOMINATOR)
+                <= twap_x64.saturating_mul(self.max_deposit_deviation_bps as u128),
+            DexError::PriceDeviationTooHigh
+        );
+        Ok(())
+    }
+
+    // Snapshot of k after a mint or burn, the baseline for the next protocol
+    // fee. Cleared while the fee is off so re-enabling it doesn't charge for
+    // growth from the disabled period.
+    fn update_k_last(&mut self, fee_on: bool) {
+        self.k_last = if fee_on {
+            self.reserve0 as u128 * self.reserve1 as u128
+        } else {
+            0
+        };
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeTier {
+    pub threshold: u64,
+    pub fee_bps: u16,
+}
+
+impl FeeTier {
+    pub const LEN: usize = 8 + // threshold
+        2; // fee_bps
+}
+
+// Cumulative prices and depth as they stood at `timestamp`; the difference
+// between two observations over the time between them is the TWAP
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct OracleObservation {
+    pub timestamp: u32,
+    pub price0_cumulative: u128,
+    pub price1_cumulative: u128,
+    pub liquidity_cumulative: u128,
+}
+
+impl OracleObservation {
+    pub const LEN: usize = 4 + // timestamp
+        16 + // price0_cumulative
+        16 + // price1_cumulative
+        16; // liquidity_cumulative
+}
+
+#[event]
+pub struct UnconfiguredPairClosedEvent {
+    pub pair: Pubkey,
+    pub lp_mint: Pubkey,
+}
+
+#[derive(Accounts)]
+pub struct RepairBumps<'info> {
+    #[account(has_one = migration_authority @ DexError::NotMigrationAuthority)]
+    pub factory: Account<'info, Factory>,
+
+    #[account(
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    pub migration_authority: Signer<'info>,
+}
+
+#[event]
+pub struct BumpsRepairedEvent {
+    pub pair: Pubkey,
+    pub old_bump: u8,
+    pub new_bump: u8,
+    pub old_authority_bump: u8,
+    pub new_authority_bump: u8,
+}
+
+#[event]
+pub struct PairCreatedEvent {
+    pub token0: Pubkey,
+    pub token1: Pubkey,
+    pub pair: Pubkey,
+    pub pair_count: u64,
+    pub created_at: i64,
+    pub creator: Pubkey,
+}
+#[derive(Accounts)]
+pub struct AddLiquidity<'info> {
+    #[account(
+        mut,
+        has_one = owner @ DexError::NotFactoryOwner,
+    )]
+    pub factory: Account<'info, Factory>,
+    
+    #[account(
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.lp_mint == lp_mint.key() @ DexError::InvalidLpMint,
+    )]
+    pub pair: Account<'info, PairAccount>,
+    
+    #[account(mut)]
+    pub token0_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(mut)]
+    pub token1_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pair.token0 @ DexError::InvalidTokenAccount)]
+    pub token0_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pair.token1 @ DexError::InvalidTokenAccount)]
+    pub token1_mint: Box<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = user_token0.mint == pair.token0 @ DexError::InvalidTokenAccount,
+        constraint = user_token0.owner == sender.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub user_token0: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = user_token1.mint == pair.token1 @ DexError::InvalidTokenAccount,
+        constraint = user_token1.owner == sender.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub user_token1: InterfaceAccount<'info, TokenAccount>,
+    
+    // Only the pair authority may mint or freeze LP
+    #[account(
+        mut,
+        constraint = lp_mint.mint_authority == COption::Some(authority.key()) @ DexError::InvalidLpMint,
+        constraint = lp_mint.freeze_authority.is_none()
+            || lp_mint.freeze_authority == COption::Some(authority.key()) @ DexError::InvalidLpMint,
+    )]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        mut,
+        constraint = liquidity_to.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
+        constraint = liquidity_to.owner == sender.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub liquidity_to: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = burn_account.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
+        constraint = burn_account.owner == factory.lp_burn_address @ DexError::InvalidTokenOwner,
+    )]
+    pub burn_account: InterfaceAccount<'info, TokenAccount>,
+
+    // LP account of `factory.fee_to`, or the pair's protocol fee account once
+    // one is set; needed whenever a protocol fee is due
+    #[account(
+        mut,
+        constraint = fee_to_account.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
+        constraint = fee_to_account.key() == pair.protocol_fee_account
+            || (pair.protocol_fee_account == Pubkey::default() && fee_to_account.owner == factory.fee_to)
+            @ DexError::InvalidTokenOwner,
+    )]
+    pub fee_to_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    /// CHECK: This is the PDA authority for the pair
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump = pair.authority_bump
+    )]
+    pub authority: UncheckedAccount<'info>,
+    
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    
+    /// CHECK: Factory owner required for authorization
+    pub owner: UncheckedAccount<'info>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+impl<'info> AddLiquidity<'info> {
+    fn mint_fee(&mut self) -> Result<()> {
+        mint_fee(
+            &self.factory,
+            &mut self.pair,
+            self.fee_to_account.as_ref(),
+            self.lp_mint.to_account_info(),
+            self.authority.to_account_info(),
+            self.token_program.to_account_info(),
+        )
+    }
+
+    // Swap leg of `swap_and_add_liquidity`: priced and limited like a plain
+    // swap, with the output paid to the sender's other token account.
+    fn deposit_swap_fee_bps(&self, amount_in: u64, now: i64) -> u16 {
+        let factory = &self.factory;
+        let promo_fee_bps = (now < factory.promo_fee_until).then_some(factory.promo_fee_bps);
+        promo_fee_bps.unwrap_or(self.pair.swap_fee_bps(amount_in))
+    }
+
+    // Largest part of a one-sided `amount_in` to swap such that the rest
+    // still covers the swap output at the post-swap reserves, found by
+    // bisection over the same pricing `swap_for_deposit` applies
+    fn zap_swap_amount(&self, amount_in: u64, is_token0_in: bool, now: i64) -> Result<u64> {
+        let (reserve_in, reserve_out) = if is_token0_in {
+            (self.pair.reserve0, self.pair.reserve1)
+        } else {
+            (self.pair.reserve1, self.pair.reserve0)
+        };
+
+        let (mut low, mut high) = (0, amount_in);
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            let fee_bps = self.deposit_swap_fee_bps(mid, now);
+            let amount_out = amount_out_for(mid as u128, reserve_in, reserve_out, fee_bps)?;
+            let kept_value = ((amount_in - mid) as u128)
+                .checked_mul(reserve_out as u128 - amount_out)
+                .ok_or(DexError::AmountOverflow)?;
+            let out_value = amount_out
+                .checked_mul(reserve_in as u128 + mid as u128)
+                .ok_or(DexError::AmountOverflow)?;
+            if kept_value >= out_value {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+
+        Ok(low)
+    }
+
+    fn swap_for_deposit(&mut self, amount_in: u64, is_token0_in: bool, now: i64) -> Result<u64> {
+        if amount_in == 0 {
+            return Ok(0);
+        }
+        require!(
+            self.pair.total_supply > self.pair.minimum_liquidity,
+            DexError::PoolDrained
+        );
+
+        let (reserve_in, reserve_out) = if is_token0_in {
+            (self.pair.reserve0, self.pair.reserve1)
+        } else {
+            (self.pair.reserve1, self.pair.reserve0)
+        };
+        let fee_bps = self.deposit_swap_fee_bps(amount_in, now);
+        let amount_out = u64::try_from(amount_out_for(amount_in as u128, reserve_in, reserve_out, fee_bps)?)
+            .map_err(|_| error!(DexError::AmountOverflow))?;
+        self.pair
+            .check_trade_limits(reserve_in, reserve_out, amount_in, amount_out)?;
+        check_fee_charged(reserve_in, reserve_out, amount_in, amount_out, fee_bps)?;
+
+        let (user_in, user_out, vault_in, vault_out, mint_in, mint_out) = if is_token0_in {
+            (&self.user_token0, &self.user_token1, &self.token0_account, &self.token1_account, &self.token0_mint, &self.token1_mint)
+        } else {
+            (&self.user_token1, &self.user_token0, &self.token1_account, &self.token0_account, &self.token1_mint, &self.token0_mint)
+        };
+        let vault_in_before = accessor::amount(&vault_in.to_account_info())?;
+        transfer_from_user(
+            self.token_program.to_account_info(),
+            user_in.to_account_info(),
+            mint_in,
+            vault_in.to_account_info(),
+            self.sender.to_account_info(),
+            amount_in,
+        )?;
+        transfer_from_vault(
+            self.token_program.to_account_info(),
+            vault_out.to_account_info(),
+            mint_out,
+            user_out.to_account_info(),
+            self.authority.to_account_info(),
+            self.pair.key(),
+            self.pair.authority_bump,
+            amount_out,
+        )?;
+
+        // As in `execute_swap`, only what reached the vault is booked, and a
+        // transfer fee is absorbed only while k still grows
+        let received_in = accessor::amount(&vault_in.to_account_info())?
+            .checked_sub(vault_in_before)
+            .ok_or(DexError::AmountOverflow)?;
+        let new_reserve_in = reserve_in.checked_add(received_in).ok_or(DexError::AmountOverflow)?;
+        let new_reserve_out = reserve_out - amount_out;
+        require!(
+            received_in == amount_in
+                || new_reserve_in as u128 * new_reserve_out as u128 > reserve_in as u128 * reserve_out as u128,
+            DexError::TransferFeeUnsupported
+        );
+        if is_token0_in {
+            self.pair.reserve0 = new_reserve_in;
+            self.pair.reserve1 = new_reserve_out;
+        } else {
+            self.pair.reserve1 = new_reserve_in;
+            self.pair.reserve0 = new_reserve_out;
+        }
+
+        let pair_key = self.pair.key();
+        let (reserve0_after, reserve1_after) = (self.pair.reserve0, self.pair.reserve1);
+        self.pair.record_swap(
+            pair_key,
+            SwapEvent {
+                sender: self.sender.key(),
+                amount_in,
+                amount_out,
+                is_token0_in,
+                reserve0_after,
+                reserve1_after,
+            },
+        );
+
+        Ok(amount_out)
+    }
+
+    // Pulls both amounts into the vaults, mints the LP (plus the locked
+    // minimum on the first deposit) and grows the reserves.
+    // Returns the amounts that actually reached the vaults and the LP minted
+    // for them, which is less than `liquidity` if a mint took a transfer fee
+    fn deposit_liquidity(&mut self, amount0: u64, amount1: u64, liquidity: u64) -> Result<(u64, u64, u64)> {
+        let reserve0 = self.pair.reserve0;
+        let reserve1 = self.pair.reserve1;
+        let total_supply = self.pair.total_supply;
+        // A swap earlier in the instruction may have moved the vaults since
+        // they were loaded
+        self.token0_account.reload()?;
+        self.token1_account.reload()?;
+        let balance0_before = self.token0_account.amount;
+        let balance1_before = self.token1_account.amount;
+
+        // Transfer tokens from user to pair
+        transfer_from_user(
+            self.token_program.to_account_info(),
+            self.user_token0.to_account_info(),
+            &self.token0_mint,
+            self.token0_account.to_account_info(),
+            self.sender.to_account_info(),
+            amount0,
+        )?;
+    
+        transfer_from_user(
+            self.token_program.to_account_info(),
+            self.user_token1.to_account_info(),
+            &self.token1_mint,
+            self.token1_account.to_account_info(),
+            self.sender.to_account_info(),
+            amount1,
+        )?;
+
+        self.token0_account.reload()?;
+        self.token1_account.reload()?;
+        let received0 = self
+            .token0_account
+            .amount
+            .checked_sub(balance0_before)
+            .ok_or(DexError::AmountOverflow)?;
+        let received1 = self
+            .token1_account
+            .amount
+            .checked_sub(balance1_before)
+            .ok_or(DexError::AmountOverflow)?;
+        let liquidity = self.pair.received_liquidity(amount0, amount1, received0, received1, liquidity)?;
+        
+        // Mint LP tokens to user
+        let pair_key = self.pair.key();
+        let authority_seeds = &[
+            b"authority".as_ref(),
+            pair_key.as_ref(),
+            &[self.pair.authority_bump],
+        ];
+    
+        // If this is the first deposit, mint minimum liquidity to burn account
+        if reserve0 == 0 && reserve1 == 0 {
+            // Mint minimum liquidity to burn address
+            token_interface::mint_to(
+                CpiContext::new_with_signer(
+                    self.token_program.to_account_info(),
+                    token_interface::MintTo {
+                        mint: self.lp_mint.to_account_info(),
+                        to: self.burn_account.to_account_info(),
+                        authority: self.authority.to_account_info(),
+                    },
+                    &[authority_seeds],
+                ),
+                self.pair.minimum_liquidity,
+            )?;
+        }
+    
+        // Mint LP tokens to user
+        token_interface::mint_to(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                token_interface::MintTo {
+                    mint: self.lp_mint.to_account_info(),
+                    to: self.liquidity_to.to_account_info(),
+                    authority: self.authority.to_account_info(),
+                },
+                &[authority_seeds],
+            ),
+            liquidity,
+        )?;
+    
+        // Update pair account
+        self.pair.reserve0 = reserve0.checked_add(received0).ok_or(DexError::AmountOverflow)?;
+        self.pair.reserve1 = reserve1.checked_add(received1).ok_or(DexError::AmountOverflow)?;
+        self.pair.total_supply = total_supply.checked_add(liquidity).ok_or(DexError::AmountOverflow)?;
+    
+        // If this is the first deposit, add minimum liquidity to total supply
+        if reserve0 == 0 && reserve1 == 0 {
+            self.pair.total_supply = self.pair.total_supply.checked_add(self.pair.minimum_liquidity).ok_or(DexError::AmountOverflow)?;
+        }
+        self.pair.update_k_last(self.factory.fee_on);
+        self.pair.check_reserve_caps(pair_key);
+
+        Ok((received0, received1, liquidity))
+    }
+}
+
+// Add this event
+#[event]
+pub struct LiquidityAddedEvent {
+    pub sender: Pubkey,
+    pub amount0: u64,
+    pub amount1: u64,
+    pub liquidity: u64,
+    pub reserve0_after: u64,
+    pub reserve1_after: u64,
+}
+
+// Read-only access to a pair for quote/preview instructions
+#[derive(Accounts)]
+pub struct PairView<'info> {
+    pub pair: Account<'info, PairAccount>,
+}
+
+// `PairView` for quotes that depend on factory settings
+#[derive(Accounts)]
+pub struct FactoryPairView<'info> {
+    pub factory: Account<'info, Factory>,
+
+    #[account(
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+    )]
+    pub pair: Account<'info, PairAccount>,
+}
+
+// Return data of `quote_remove_liquidity`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct RemoveLiquidityQuote {
+    pub amount0: u64,
+    pub amount1: u64,
+}
+
+// Return data of `my_redeemable`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct RedeemableAmounts {
+    pub amount0: u64,
+    pub amount1: u64,
+    // LP the pending protocol fee will mint, already counted in the supply
+    pub protocol_fee_liquidity: u64,
+}
+
+#[event]
+pub struct UtilizationEvent {
+    pub pair: Pubkey,
+    pub reserve0: u64,
+    pub reserve1: u64,
+    pub ratio_bps: u64,
+    pub max_ratio: u64,
+    pub ratio_headroom_bps: u64,
+    pub max_out_bps: u16,
+    pub max_amount0_out: u64,
+    pub max_amount1_out: u64,
+}
+
+// Return data of `pair_utilization`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PairUtilization {
+    pub reserve0: u64,
+    pub reserve1: u64,
+    pub ratio_bps: u64,
+    pub max_ratio: u64,
+    pub ratio_headroom_bps: u64,
+    pub max_out_bps: u16,
+    pub max_amount0_out: u64,
+    pub max_amount1_out: u64,
+}
+
+#[derive(Accounts)]
+pub struct FindPair<'info> {
+    /// CHECK: May not exist yet; only its address and data are inspected
+    pub pair: UncheckedAccount<'info>,
+}
+
+// Return data of `find_pair`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PairLookup {
+    pub pair: Pubkey,
+    pub is_initialized: bool,
+}
+
+#[event]
+pub struct PairFlagsEvent {
+    pub flags: u32,
+}
+
+// Return data of `consult`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct OracleConsult {
+    pub price0_average_x64: u128,
+    pub price1_average_x64: u128,
+    pub window: u32,
+}
+
+// Return data of `consult_liquidity`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct LiquidityConsult {
+    pub liquidity_average: u128,
+    pub window: u32,
+}
+
+#[event]
+pub struct TickedPriceEvent {
+    pub price_x64: u128,
+    pub tick: u128,
+}
+
+// Return data of `spot_price_ticked`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct TickedPrice {
+    pub price_x64: u128,
+    pub tick: u128,
+}
+
+#[event]
+pub struct ExternalOracleUpdatedEvent {
+    pub pair: Pubkey,
+    pub external_oracle: Option<Pubkey>,
+    pub max_oracle_deviation_bps: u16,
+}
+
+#[event]
+pub struct TickSizeUpdatedEvent {
+    pub pair: Pubkey,
+    pub tick_size_x64: u128,
+}
+
+#[event]
+pub struct PairPausedEvent {
+    pub pair: Pubkey,
+    pub paused: bool,
+}
+
+#[event]
+pub struct ReserveCapsUpdatedEvent {
+    pub pair: Pubkey,
+    pub reserve_cap0: u64,
+    pub reserve_cap1: u64,
+}
+
+#[event]
+pub struct ReserveCapReachedEvent {
+    pub pair: Pubkey,
+    pub reserve0: u64,
+    pub reserve1: u64,
+    pub reserve_cap0: u64,
+    pub reserve_cap1: u64,
+}
+
+#[event]
+pub struct DecimalsNormalizationUpdatedEvent {
+    pub pair: Pubkey,
+    pub enabled: bool,
+    pub minimum_liquidity: u64,
+}
+
+#[event]
+pub struct EventModeUpdatedEvent {
+    pub pair: Pubkey,
+    pub emit_events: bool,
+    pub summary_interval: u16,
+}
+
+// Totals of the swaps since the last summary on a pair with events off
+#[event]
+pub struct SwapSummaryEvent {
+    pub pair: Pubkey,
+    pub swap_count: u16,
+    pub amount0_in: u64,
+    pub amount1_in: u64,
+    pub amount0_out: u64,
+    pub amount1_out: u64,
+    pub reserve0_after: u64,
+    pub reserve1_after: u64,
+}
+
+#[event]
+pub struct DepositBoundsUpdatedEvent {
+    pub pair: Pubkey,
+    pub min_deposit_liquidity: u64,
+    pub max_deposit_liquidity: u64,
+}
+
+#[event]
+pub struct DepositDeviationUpdatedEvent {
+    pub pair: Pubkey,
+    pub max_deposit_deviation_bps: u16,
+}
+
+#[event]
+pub struct OracleSeededEvent {
+    pub pair: Pubkey,
+    pub seed_price_x64: u128,
+    pub price0_cumulative_last: u128,
+    pub price1_cumulative_last: u128,
+    pub block_timestamp_last: u32,
+}
+
+#[event]
+pub struct AmountInEvent {
+    pub amount_in: u64,
+}
+
+#[event]
+pub struct AddLiquidityPreviewEvent {
+    pub amount0: u64,
+    pub amount1: u64,
+    pub liquidity: u64,
+}
+
+// Return data of `quote_add_liquidity`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct AddLiquidityQuote {
+    pub amount0: u64,
+    pub amount1: u64,
+    pub liquidity: u64,
+}
+
+#[event]
+pub struct AccountRoleEvent {
+    pub pair: Pubkey,
+    pub candidate: Pubkey,
+    pub role: u8,
+}
+
+#[event]
+pub struct ArbitrageQuoteEvent {
+    pub pair: Pubkey,
+    pub zero_for_one: bool,
+    pub amount_in: u64,
+    pub amount_out: u64,
+}
+
+// Return data of `price_after_external_move`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ArbitrageQuote {
+    pub zero_for_one: bool,
+    pub amount_in: u64,
+    pub amount_out: u64,
+}
+
+#[derive(Accounts)]
+pub struct PairDump<'info> {
+    #[account(
+        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.lp_mint == lp_mint.key() @ DexError::InvalidLpMint,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    pub token0_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token1_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+}
+
+// Stored pair state is embedded whole (~900 bytes), well inside the log limit
+#[event]
+pub struct PairStateDumpEvent {
+    pub pair: Pubkey,
+    pub state: PairAccount,
+    pub vault0_balance: u64,
+    pub vault1_balance: u64,
+    pub lp_mint_supply: u64,
+}
+
+// Return data of `dump_pair_state`. The stored pair state is embedded whole
+// (~900 bytes), inside the 1024-byte return data limit.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PairStateDump {
+    pub state: PairAccount,
+    pub vault0_balance: u64,
+    pub vault1_balance: u64,
+    pub lp_mint_supply: u64,
+}
+
+// Add this accounts struct
+#[derive(Accounts)]
+pub struct RemoveLiquidity<'info> {
+    #[account(
+        mut,
+        has_one = owner @ DexError::NotFactoryOwner,
+    )]
+    pub factory: Account<'info, Factory>,
+    
+    #[account(
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.lp_mint == lp_mint.key() @ DexError::InvalidLpMint,
+    )]
+    pub pair: Account<'info, PairAccount>,
+    
+    #[account(mut)]
+    pub token0_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(mut)]
+    pub token1_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pair.token0 @ DexError::InvalidTokenAccount)]
+    pub token0_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pair.token1 @ DexError::InvalidTokenAccount)]
+    pub token1_mint: Box<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = token0_to.mint == pair.token0 @ DexError::InvalidTokenAccount,
+        constraint = token0_to.owner == sender.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub token0_to: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = token1_to.mint == pair.token1 @ DexError::InvalidTokenAccount,
+        constraint = token1_to.owner == sender.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub token1_to: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(mut)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+    
+    #[account(
+        mut,
+        constraint = liquidity_from.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
+        constraint = liquidity_from.owner == sender.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub liquidity_from: InterfaceAccount<'info, TokenAccount>,
+
+    // LP account of `factory.fee_to`, or the pair's protocol fee account once
+    // one is set; needed whenever a protocol fee is due
+    #[account(
+        mut,
+        constraint = fee_to_account.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
+        constraint = fee_to_account.key() == pair.protocol_fee_account
+            || (pair.protocol_fee_account == Pubkey::default() && fee_to_account.owner == factory.fee_to)
+            @ DexError::InvalidTokenOwner,
+    )]
+    pub fee_to_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    
+    /// CHECK: This is the PDA authority for the pair
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump = pair.authority_bump
+    )]
+    pub authority: UncheckedAccount<'info>,
+    
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    
+    /// CHECK: Factory owner required for authorization
+    pub owner: UncheckedAccount<'info>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+impl<'info> RemoveLiquidity<'info> {
+    fn mint_fee(&mut self) -> Result<()> {
+        mint_fee(
+            &self.factory,
+            &mut self.pair,
+            self.fee_to_account.as_ref(),
+            self.lp_mint.to_account_info(),
+            self.authority.to_account_info(),
+            self.token_program.to_account_info(),
+        )
+    }
+
+    // Burns the LP tokens, pays out both sides and shrinks the reserves.
+    // Snapshotting `k_last` is left to the caller, after its `mint_fee`.
+    fn withdraw_liquidity(&mut self, liquidity_u64: u64, amount0_u64: u64, amount1_u64: u64) -> Result<()> {
+        let reserve0 = self.pair.reserve0;
+        let reserve1 = self.pair.reserve1;
+        let total_supply = self.pair.total_supply;
+
+        // Payouts are proportional, so exceeding a reserve means the stored
+        // state no longer matches the LP supply
+        require!(
+            amount0_u64 <= reserve0 && amount1_u64 <= reserve1,
+            DexError::ReserveDesync
+        );
+
+        // Stored reserves above the vault balances would otherwise fail deep
+        // inside the token program
+        require!(
+            amount0_u64 <= self.token0_account.amount && amount1_u64 <= self.token1_account.amount,
+            DexError::ReserveDesync
+        );
+
+        // Burn LP tokens first
+        token_interface::burn(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                token_interface::Burn {
+                    mint: self.lp_mint.to_account_info(),
+                    from: self.liquidity_from.to_account_info(),
+                    authority: self.sender.to_account_info(),
+                },
+            ),
+            liquidity_u64,
+        )?;
+    
+        // Transfer tokens to user
+        let pair_key = self.pair.key();
+        transfer_from_vault(
+            self.token_program.to_account_info(),
+            self.token0_account.to_account_info(),
+            &self.token0_mint,
+            self.token0_to.to_account_info(),
+            self.authority.to_account_info(),
+            pair_key,
+            self.pair.authority_bump,
+            amount0_u64,
+        )?;
+    
+        transfer_from_vault(
+            self.token_program.to_account_info(),
+            self.token1_account.to_account_info(),
+            &self.token1_mint,
+            self.token1_to.to_account_info(),
+            self.authority.to_account_info(),
+            pair_key,
+            self.pair.authority_bump,
+            amount1_u64,
+        )?;
+    
+        // Update pair account
+        self.pair.reserve0 = reserve0
+            .checked_sub(amount0_u64)
+            .ok_or(error!(DexError::InsufficientLiquidity))?;
+        self.pair.reserve1 = reserve1
+            .checked_sub(amount1_u64)
+            .ok_or(error!(DexError::InsufficientLiquidity))?;
+        self.pair.total_supply = total_supply
+            .checked_sub(liquidity_u64)
+            .ok_or(error!(DexError::InsufficientLiquidity))?;
+
+        Ok(())
+    }
+}
+
+// Add this event
+#[event]
+pub struct LiquidityRemovedEvent {
+    pub sender: Pubkey,
+    pub amount0: u64,
+    pub amount1: u64,
+    pub liquidity: u64,
+    pub reserve0_after: u64,
+    pub reserve1_after: u64,
+}
+
+#[event]
+pub struct EmergencyLiquidityRemovedEvent {
+    pub pair: Pubkey,
+    pub sender: Pubkey,
+    pub amount0: u64,
+    pub amount1: u64,
+    pub liquidity: u64,
+}
+
+// The output ATAs are created before `remove` is deserialized, which then
+// validates them against the pair like any other `remove_liquidity` call
+#[derive(Accounts)]
+pub struct RemoveLiquidityInitAccounts<'info> {
+    pub outputs: InitOutputAccounts<'info>,
+    pub remove: RemoveLiquidity<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitOutputAccounts<'info> {
+    pub token0_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token1_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(mut)]
+    pub sender: Signer<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = sender,
+        associated_token::mint = token0_mint,
+        associated_token::authority = sender,
+        associated_token::token_program = token_program,
+    )]
+    pub token0_to: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed,
+        payer = sender,
+        associated_token::mint = token1_mint,
+        associated_token::authority = sender,
+        associated_token::token_program = token_program,
+    )]
+    pub token1_to: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+// Add this accounts struct
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    pub factory: Account<'info, Factory>,
+
+    #[account(
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
+    )]
+    pub pair: Account<'info, PairAccount>,
+    
+    #[account(mut)]
+    pub token0_account: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(mut)]
+    pub token1_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pair.token0 @ DexError::InvalidTokenAccount)]
+    pub token0_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pair.token1 @ DexError::InvalidTokenAccount)]
+    pub token1_mint: Box<InterfaceAccount<'info, Mint>>,
+    
+    #[account(
+        mut,
+        constraint = token_in.owner == sender.key() @ DexError::InvalidTokenOwner,
+        constraint = (token_in.mint == pair.token0 || token_in.mint == pair.token1) @ DexError::InvalidTokenAccount,
+    )]
+    pub token_in: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        constraint = token_out.owner == sender.key() @ DexError::InvalidTokenOwner,
+        constraint = (token_out.mint == pair.token0 || token_out.mint == pair.token1) @ DexError::InvalidTokenAccount,
+        constraint = token_out.mint != token_in.mint @ DexError::IdenticalTokens,
+    )]
+    pub token_out: InterfaceAccount<'info, TokenAccount>,
+
+    // Sender's balance of the factory discount mint, if claiming the discount
+    pub discount_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    // Sender's last swap on this pair; required when the pair has a cooldown
+    #[account(
+        mut,
+        seeds = [b"trader", pair.key().as_ref(), sender.key().as_ref()],
+        bump,
+    )]
+    pub trader_state: Option<Account<'info, TraderState>>,
+
+    /// CHECK: Price feed for pairs with an external oracle; matched against
+    /// `pair.external_oracle` and read by `external_oracle_price_x64`
+    pub external_oracle: Option<UncheckedAccount<'info>>,
+    
+    /// CHECK: This is the PDA authority for the pair
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump = pair.authority_bump
+    )]
+    pub authority: UncheckedAccount<'info>,
+    
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+impl<'info> Swap<'info> {
+    // Shared by both swap directions: validates the trade, moves the tokens
+    // and checks k. `amount` fixes either the input or the output side.
+    fn execute_swap(&mut self, amount: SwapAmount, deadline: i64) -> Result<()> {
+        // Ensure pair is initialized
+        require!(self.pair.is_initialized, DexError::PairNotInitialized);
+        require!(!self.factory.paused, DexError::Paused);
+        require!(!self.pair.pair_paused, DexError::PairPaused);
+        lock_pair(&mut self.pair)?;
+        update_cumulative_prices(&mut self.pair)?;
+
+        // Deep in a CPI chain the budget may run out between the two transfers;
+        // failing here keeps the rejection clean for the calling program
+        require!(
+            sol_remaining_compute_units() >= SWAP_COMPUTE_UNITS,
+            DexError::InsufficientComputeBudget
+        );
+
+        // A transaction that sat unprocessed past its deadline would trade at a stale price
+        let now = Clock::get()?.unix_timestamp;
+        require!(now <= deadline, DexError::Expired);
+    
+        // A pair storing the same mint twice can't tell its sides apart
+        require_keys_neq!(
+            self.pair.token0,
+            self.pair.token1,
+            DexError::InvalidPairMints
+        );
+    
+        // Only the locked minimum is left: the dust reserves aren't a market
+        require!(
+            self.pair.total_supply > self.pair.minimum_liquidity,
+            DexError::PoolDrained
+        );
+    
+        // Get current reserves and determine input/output token accounts.
+        // The account constraints already pin token_in to one of the pair mints.
+        let is_token0_in = self.token_in.mint == self.pair.token0;
+        let (reserve_in, reserve_out) = if is_token0_in {
+            (self.pair.reserve0, self.pair.reserve1)
+        } else {
+            (self.pair.reserve1, self.pair.reserve0)
+        };
+
+        // No input can ever drain the whole output reserve
+        let amount_out_floor = match amount {
+            SwapAmount::ExactIn { amount_out_min, .. } => amount_out_min,
+            SwapAmount::ExactOut { amount_out, .. } => amount_out,
+        };
+        require!(
+            amount_out_floor < reserve_out as u128,
+            DexError::ImpossibleOutputMinimum
+        );
+
+        self.pair.check_cooldown(self.trader_state.as_deref_mut(), now)?;
+
+        // Promotion and holder discount
+        let adjust_fee = self
+            .factory
+            .fee_adjustment(now, self.discount_token.as_ref(), self.sender.key())?;
+
+        let (amount_in_u64, amount_out) = match amount {
+            SwapAmount::ExactIn {
+                amount_in,
+                amount_out_min,
+                max_price_impact_bps,
+            } => {
+                // Convert amount_in to u64 for token operations
+                let amount_in_u64 = u64::try_from(amount_in)
+                    .map_err(|_| error!(DexError::AmountOverflow))?;
+
+                // Fee depends on the trade size when the pair has a tier schedule
+                let fee_bps = adjust_fee(self.pair.swap_fee_bps(amount_in_u64));
+
+                // Calculate amount out based on constant product formula (k = x * y)
+                let amount_out = amount_out_for(amount_in, reserve_in, reserve_out, fee_bps)?;
+
+                // Ensure minimum output amount is met
+                require!(
+                    amount_out >= amount_out_min,
+                    DexError::InsufficientOutputAmount
+                );
+                check_price_impact(reserve_in, reserve_out, amount_in, amount_out, max_price_impact_bps)?;
+                (amount_in_u64, amount_out)
+            }
+            SwapAmount::ExactOut {
+                amount_out,
+                amount_in_max,
+            } => {
+                // Below the reserve, so it fits in a u64
+                let amount_in = self.pair.amount_in_for_exact_out(
+                    amount_out as u64,
+                    reserve_in,
+                    reserve_out,
+                    adjust_fee,
+                )?;
+                require!(
+                    amount_in as u128 <= amount_in_max,
+                    DexError::ExcessiveInputAmount
+                );
+                (amount_in, amount_out)
+            }
+        };
+    
+        // Fee actually charged, after any promotion or holder discount
+        let fee_bps = adjust_fee(self.pair.swap_fee_bps(amount_in_u64));
+
+        // Convert amount_out to u64 for token operations
+        let amount_out_u64 = u64::try_from(amount_out)
+            .map_err(|_| error!(DexError::AmountOverflow))?;
+        self.pair
+            .check_trade_limits(reserve_in, reserve_out, amount_in_u64, amount_out_u64)?;
+        check_fee_charged(reserve_in, reserve_out, amount_in_u64, amount_out_u64, fee_bps)?;
+    
+        // Transfer tokens from user to pool
+        let (vault_in, mint_in, mint_out) = if is_token0_in {
+            (&mut self.token0_account, &self.token0_mint, &self.token1_mint)
+        } else {
+            (&mut self.token1_account, &self.token1_mint, &self.token0_mint)
+        };
+        let vault_in_before = vault_in.amount;
+        transfer_from_user(
+            self.token_program.to_account_info(),
+            self.token_in.to_account_info(),
+            mint_in,
+            vault_in.to_account_info(),
+            self.sender.to_account_info(),
+            amount_in_u64,
+        )?;
+
+        // A mint that takes a cut on transfer credits the vault less than was
+        // sent; only what arrived goes into the reserve
+        vault_in.reload()?;
+        let received_in = vault_in
+            .amount
+            .checked_sub(vault_in_before)
+            .ok_or(DexError::AmountOverflow)?;
+    
+        // Transfer tokens from pool to user
+        let pair_key = self.pair.key();
+        transfer_from_vault(
+            self.token_program.to_account_info(),
+            if is_token0_in {
+                self.token1_account.to_account_info()
+            } else {
+                self.token0_account.to_account_info()
+            },
+            mint_out,
+            self.token_out.to_account_info(),
+            self.authority.to_account_info(),
+            pair_key,
+            self.pair.authority_bump,
+            amount_out_u64,
+        )?;
+    
+        // Update reserves
+        if is_token0_in {
+            self.pair.reserve0 = reserve_in.checked_add(received_in).ok_or(DexError::AmountOverflow)?;
+            self.pair.reserve1 = reserve_out.checked_sub(amount_out_u64).ok_or(DexError::AmountOverflow)?;
+        } else {
+            self.pair.reserve1 = reserve_in.checked_add(received_in).ok_or(DexError::AmountOverflow)?;
+            self.pair.reserve0 = reserve_out.checked_sub(amount_out_u64).ok_or(DexError::AmountOverflow)?;
+        }
+    
+        // Verify k is not decreased (protects against price manipulation)
+        let new_reserve0 = self.pair.reserve0 as u128;
+        let new_reserve1 = self.pair.reserve1 as u128;
+        let old_k = (reserve_in as u128).checked_mul(reserve_out as u128).ok_or(DexError::AmountOverflow)?;
+        let new_k = new_reserve0.checked_mul(new_reserve1).ok_or(DexError::AmountOverflow)?;
+
+        // The output was priced on the full input, so a transfer fee can only
+        // be absorbed while k still grows as it should
+        if received_in < amount_in_u64 {
+            require!(
+                new_k > old_k || (fee_bps == 0 && new_k == old_k),
+                DexError::TransferFeeUnsupported
+            );
+        }
+        
+        // The transaction reverts, but its logs keep the inputs for a post-mortem
+        if new_k < old_k {
+            emit!(KViolationEvent {
+                old_k,
+                new_k,
+                reserve_in,
+                reserve_out,
+                amount_in: amount_in_u64,
+                amount_out: amount_out_u64,
+            });
+            return err!(DexError::K);
+        }
+        // A charged fee always grows k; an unchanged k means it was bypassed.
+        // Only a fee-free swap may leave k exactly where it was.
+        require!(fee_bps == 0 || new_k > old_k, DexError::KNotIncreased);
+
+        self.pair.check_oracle_deviation(self.external_oracle.as_ref())?;
+    
+        // Emit swap event
+        let (reserve0_after, reserve1_after) = (self.pair.reserve0, self.pair.reserve1);
+        self.pair.record_swap(
+            pair_key,
+            SwapEvent {
+                sender: self.sender.key(),
+                amount_in: amount_in_u64,
+                amount_out: amount_out_u64,
+                is_token0_in,
+                reserve0_after,
+                reserve1_after,
+            },
+        );
+        self.pair.check_reserve_caps(pair_key);
+
+        self.pair.locked = false;
+        Ok(())
+    }
+}
+
+enum SwapAmount {
+    ExactIn { amount_in: u128, amount_out_min: u128, max_price_impact_bps: u16 },
+    ExactOut { amount_out: u128, amount_in_max: u128 },
+}
+
+#[event]
+pub struct OracleUpdatedEvent {
+    pub pair: Pubkey,
+    pub price0_cumulative_last: u128,
+    pub price1_cumulative_last: u128,
+    pub liquidity_cumulative_last: u128,
+    pub block_timestamp_last: u32,
+}
+
+// Add this event
+#[event]
+pub struct SwapEvent {
+    pub sender: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub is_token0_in: bool,
+    pub reserve0_after: u64,
+    pub reserve1_after: u64,
+}
+
+#[event]
+pub struct KViolationEvent {
+    pub old_k: u128,
+    pub new_k: u128,
+    pub reserve_in: u64,
+    pub reserve_out: u64,
+    pub amount_in: u64,
+    pub amount_out: u64,
+}
+
+#[derive(Accounts)]
+pub struct SwapExactTokensForTokens<'info> {
+    pub factory: Account<'info, Factory>,
+
+    #[account(
+        mut,
+        constraint = token_in.owner == sender.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub token_in: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = token_out.owner == sender.key() @ DexError::InvalidTokenOwner,
+        constraint = token_out.mint != token_in.mint @ DexError::IdenticalTokens,
+    )]
+    pub token_out: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub sender: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct BatchSwap<'info> {
+    pub factory: Account<'info, Factory>,
+
+    #[account(mut)]
+    pub sender: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// One leg of `batch_swap`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SwapParams {
+    pub amount_in: u128,
+    pub amount_out_min: u128,
+}
+
+#[derive(Accounts)]
+pub struct FlashSwap<'info> {
+    pub factory: Account<'info, Factory>,
+
+    #[account(
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    #[account(mut)]
+    pub token0_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub token1_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pair.token0 @ DexError::InvalidTokenAccount)]
+    pub token0_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pair.token1 @ DexError::InvalidTokenAccount)]
+    pub token1_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    // Receives the borrowed side; its mint picks which token is lent
+    #[account(
+        mut,
+        constraint = (recipient.mint == pair.token0 || recipient.mint == pair.token1) @ DexError::InvalidTokenAccount,
+    )]
+    pub recipient: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: This is the PDA authority for the pair
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump = pair.authority_bump
+    )]
+    pub authority: UncheckedAccount<'info>,
+
+    /// CHECK: Borrower program called with `on_flash_swap`; it has to repay
+    /// the vaults before returning
+    #[account(executable)]
+    pub callback_program: UncheckedAccount<'info>,
+
+    // Sender's balance of the factory discount mint, if claiming the discount
+    pub discount_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    // Sender's last swap on this pair; required when the pair has a cooldown
+    #[account(
+        mut,
+        seeds = [b"trader", pair.key().as_ref(), sender.key().as_ref()],
+        bump,
+    )]
+    pub trader_state: Option<Account<'info, TraderState>>,
+
+    /// CHECK: Price feed for pairs with an external oracle; matched against
+    /// `pair.external_oracle` and read by `external_oracle_price_x64`
+    pub external_oracle: Option<UncheckedAccount<'info>>,
+
+    #[account(mut)]
+    pub sender: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// Payload passed to the borrower's `on_flash_swap`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct FlashSwapCallbackData {
+    pub pair: Pubkey,
+    pub sender: Pubkey,
+    pub amount0_out: u64,
+    pub amount1_out: u64,
+    pub data: Vec<u8>,
+}
+
+#[event]
+pub struct FlashSwapEvent {
+    pub pair: Pubkey,
+    pub sender: Pubkey,
+    pub amount0_out: u64,
+    pub amount1_out: u64,
+    pub amount0_in: u64,
+    pub amount1_in: u64,
+    pub reserve0_after: u64,
+    pub reserve1_after: u64,
+}
+
+#[derive(Accounts)]
+pub struct Sync<'info> {
+    #[account(
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    pub token0_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token1_account: InterfaceAccount<'info, TokenAccount>,
+}
+
+#[event]
+pub struct SyncEvent {
+    pub reserve0_before: u64,
+    pub reserve1_before: u64,
+    pub reserve0_after: u64,
+    pub reserve1_after: u64,
+    // -1 when the vault lost tokens, 0 when unchanged, 1 for a donation
+    pub delta0_sign: i8,
+    pub delta1_sign: i8,
+}
+
+#[derive(Accounts)]
+pub struct Donate<'info> {
+    #[account(
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    #[account(mut)]
+    pub token0_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub token1_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pair.token0 @ DexError::InvalidTokenAccount)]
+    pub token0_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pair.token1 @ DexError::InvalidTokenAccount)]
+    pub token1_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        constraint = donor_token0.mint == pair.token0 @ DexError::InvalidTokenAccount,
+        constraint = donor_token0.owner == donor.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub donor_token0: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = donor_token1.mint == pair.token1 @ DexError::InvalidTokenAccount,
+        constraint = donor_token1.owner == donor.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub donor_token1: InterfaceAccount<'info, TokenAccount>,
+
+    pub donor: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event]
+pub struct DonationEvent {
+    pub pair: Pubkey,
+    pub donor: Pubkey,
+    pub amount0: u64,
+    pub amount1: u64,
+    pub reserve0_after: u64,
+    pub reserve1_after: u64,
+}
+
+#[derive(Accounts)]
+pub struct Skim<'info> {
+    #[account(
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    #[account(mut)]
+    pub token0_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub token1_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pair.token0 @ DexError::InvalidTokenAccount)]
+    pub token0_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pair.token1 @ DexError::InvalidTokenAccount)]
+    pub token1_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        constraint = token0_to.mint == pair.token0 @ DexError::InvalidTokenAccount,
+    )]
+    pub token0_to: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = token1_to.mint == pair.token1 @ DexError::InvalidTokenAccount,
+    )]
+    pub token1_to: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: This is the PDA authority for the pair
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump = pair.authority_bump
+    )]
+    pub authority: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event]
+pub struct SkimEvent {
+    pub pair: Pubkey,
+    pub to: Pubkey,
+    pub amount0: u64,
+    pub amount1: u64,
+}
+
+#[derive(Accounts)]
+pub struct ArchiveSnapshot<'info> {
+    #[account(
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = keeper,
+        space = PairArchive::LEN,
+        seeds = [
+            b"archive".as_ref(),
+            pair.key().as_ref(),
+            &(pair.archive_snapshot_count % MAX_PAIR_ARCHIVE_SNAPSHOTS / PAIR_ARCHIVE_CHUNK_LEN as u64).to_le_bytes(),
+        ],
+        bump,
+    )]
+    pub archive: Account<'info, PairArchive>,
+
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+pub struct PairArchive {
+    pub pair: Pubkey,
+    pub chunk_index: u64,
+    pub count: u8,
+    pub snapshots: [PairSnapshot; PAIR_ARCHIVE_CHUNK_LEN],
+}
+
+impl PairArchive {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // pair
+        8 + // chunk_index
+        1 + // count
+        PairSnapshot::LEN * PAIR_ARCHIVE_CHUNK_LEN; // snapshots
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PairSnapshot {
+    pub reserve0: u64,
+    pub reserve1: u64,
+    pub total_supply: u64,
+    pub timestamp: i64,
+}
+
+impl PairSnapshot {
+    pub const LEN: usize = 8 + // reserve0
+        8 + // reserve1
+        8 + // total_supply
+        8; // timestamp
+}
+
+#[event]
+pub struct PairSnapshotArchivedEvent {
+    pub pair: Pubkey,
+    pub index: u64,
+    pub reserve0: u64,
+    pub reserve1: u64,
+    pub total_supply: u64,
+    pub timestamp: i64,
+}
+
+#[derive(Accounts)]
+pub struct InitTraderState<'info> {
+    pub pair: Account<'info, PairAccount>,
+
+    #[account(
+        init,
+        payer = sender,
+        space = TraderState::LEN,
+        seeds = [b"trader", pair.key().as_ref(), sender.key().as_ref()],
+        bump,
+    )]
+    pub trader_state: Account<'info, TraderState>,
+
+    #[account(mut)]
+    pub sender: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+pub struct TraderState {
+    pub last_swap_at: i64,
+}
+
+impl TraderState {
+    pub const LEN: usize = 8 + // discriminator
+        8; // last_swap_at
+}
+
+#[event]
+pub struct PairFeesConfiguredEvent {
+    pub pair: Pubkey,
+    pub fee_bps: u16,
+    pub max_out_bps: u16,
+    pub min_trade_amount: u64,
+}
+
+#[event]
+pub struct SwapCooldownUpdatedEvent {
+    pub pair: Pubkey,
+    pub swap_cooldown_seconds: u32,
+}
+
+#[derive(Accounts)]
+pub struct InitProtocolFeeAccount<'info> {
+    #[account(has_one = owner @ DexError::NotFactoryOwner)]
+    pub factory: Account<'info, Factory>,
+
+    #[account(
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = pair.lp_mint == lp_mint.key() @ DexError::InvalidLpMint,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = owner,
+        seeds = [
+            b"protocol_fee".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump,
+        token::mint = lp_mint,
+        token::authority = authority,
+    )]
+    pub protocol_fee_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: This is the PDA authority for the pair
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump = pair.authority_bump
+    )]
+    pub authority: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CollectProtocolFees<'info> {
+    pub factory: Account<'info, Factory>,
+
+    #[account(
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = pair.lp_mint == lp_mint.key() @ DexError::InvalidLpMint,
+    )]
+    pub pair: Account<'info, PairAccount>,
+
+    #[account(mut)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
 
-#[derive(Accounts)]
-pub struct Initialize<'info> {
+    // The pair's protocol fee account once one is set, so changing fee_to
+    // can't redirect fees already routed there; fee_to's LP account before
     #[account(
-        init,
-        payer = owner,
-        space = Factory::LEN
+        mut,
+        constraint = fee_to_account.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
+        constraint = fee_to_account.key() == pair.protocol_fee_account
+            || (pair.protocol_fee_account == Pubkey::default() && fee_to_account.owner == factory.fee_to)
+            @ DexError::InvalidTokenOwner,
     )]
-    pub factory: Account<'info, Factory>,
-    #[account(mut)]
-    pub owner: Signer<'info>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+    pub fee_to_account: InterfaceAccount<'info, TokenAccount>,
 
-// Step 1: Create token accounts only
-#[derive(Accounts)]
-pub struct CreateTokenAccounts<'info> {
-    // Remove the factory to save stack space
-    
-    /// CHECK: This is a token mint
-    pub token0: UncheckedAccount<'info>,
-    
-    /// CHECK: This is a token mint
-    pub token1: UncheckedAccount<'info>,
-    
-    /// CHECK: This is the authority PDA
+    #[account(
+        mut,
+        constraint = keeper_lp.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
+        constraint = keeper_lp.owner == keeper.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub keeper_lp: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: This is the PDA authority for the pair
     #[account(
         seeds = [
             b"authority".as_ref(),
-            pair_pda.key().as_ref()
+            pair.key().as_ref()
         ],
-        bump
+        bump = pair.authority_bump
     )]
     pub authority: UncheckedAccount<'info>,
-    
-    /// CHECK: This is a PDA for the pair, used only for the authority derivation
+
+    pub keeper: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ReinvestProtocolFees<'info> {
     #[account(
-        seeds = [
-            b"pair".as_ref(),
-            token0.key().as_ref(),
-            token1.key().as_ref()
-        ],
-        bump
+        constraint = factory.fee_to == fee_to.key() @ DexError::NotFeeTo,
     )]
-    pub pair_pda: UncheckedAccount<'info>,
-    
+    pub factory: Account<'info, Factory>,
+
     #[account(
-        init,
-        payer = sender,
-        token::mint = token0,
-        token::authority = authority,
+        mut,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = pair.lp_mint == lp_mint.key() @ DexError::InvalidLpMint,
     )]
-    pub token0_account: InterfaceAccount<'info, TokenAccount>,
-    
+    pub pair: Account<'info, PairAccount>,
+
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
     #[account(
-        init,
-        payer = sender,
-        token::mint = token1,
+        mut,
+        constraint = fee_to_account.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
+        constraint = fee_to_account.owner == fee_to.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub fee_to_account: InterfaceAccount<'info, TokenAccount>,
+
+    // Held by the pair authority, which never signs a transfer out of it
+    #[account(
+        init_if_needed,
+        payer = fee_to,
+        seeds = [b"protocol_liquidity".as_ref(), pair.key().as_ref()],
+        bump,
+        token::mint = lp_mint,
         token::authority = authority,
     )]
-    pub token1_account: InterfaceAccount<'info, TokenAccount>,
-    
+    pub protocol_liquidity_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: This is the PDA authority for the pair
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            pair.key().as_ref()
+        ],
+        bump = pair.authority_bump
+    )]
+    pub authority: UncheckedAccount<'info>,
+
     #[account(mut)]
-    pub sender: Signer<'info>,
-    
+    pub fee_to: Signer<'info>,
+
     pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
-// Step 2: Create pair account and LP mint
+#[event]
+pub struct ProtocolLiquidityReinvestedEvent {
+    pub pair: Pubkey,
+    pub amount: u64,
+    pub protocol_liquidity: u64,
+}
+
 #[derive(Accounts)]
-pub struct CreatePairAccount<'info> {
+pub struct QueueProtocolFeeWithdrawal<'info> {
+    #[account(has_one = owner @ DexError::NotFactoryOwner)]
+    pub factory: Account<'info, Factory>,
+
     #[account(
         mut,
-        has_one = owner @ DexError::NotFactoryOwner,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
     )]
+    pub pair: Account<'info, PairAccount>,
+
+    #[account(constraint = destination.mint == pair.lp_mint @ DexError::InvalidTokenAccount)]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawProtocolFees<'info> {
+    #[account(has_one = owner @ DexError::NotFactoryOwner)]
     pub factory: Account<'info, Factory>,
-    
+
     #[account(
-        init,
-        payer = sender,
-        space = PairAccount::LEN,
-        seeds = [
-            b"pair".as_ref(),
-            token0.key().as_ref(),
-            token1.key().as_ref()
-        ],
-        bump
+        mut,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = pair.protocol_fee_account == protocol_fee_account.key() @ DexError::ProtocolFeeAccountNotSet,
     )]
     pub pair: Account<'info, PairAccount>,
-    
-    /// CHECK: This is a token mint and is validated by the token program
-    pub token0: InterfaceAccount<'info, Mint>,
-    
-    /// CHECK: This is a token mint and is validated by the token program
-    pub token1: InterfaceAccount<'info, Mint>,
-    
+
+    #[account(mut)]
+    pub protocol_fee_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pair.lp_mint @ DexError::InvalidLpMint)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
     #[account(
-        init,
-        payer = sender,
-        mint::decimals = 8,
-        mint::authority = authority,
+        mut,
+        address = pair.fee_withdrawal_destination @ DexError::InvalidFeeWithdrawalDestination,
+        constraint = destination.mint == pair.lp_mint @ DexError::InvalidTokenAccount,
     )]
-    pub lp_mint: InterfaceAccount<'info, Mint>,
-    
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+
     /// CHECK: This is the PDA authority for the pair
     #[account(
         seeds = [
             b"authority".as_ref(),
             pair.key().as_ref()
         ],
-        bump
+        bump = pair.authority_bump
     )]
     pub authority: UncheckedAccount<'info>,
-    
-    #[account(mut)]
-    pub sender: Signer<'info>,
-    
-    /// CHECK: Factory owner required for authorization
-    pub owner: UncheckedAccount<'info>,
-    
+
+    pub owner: Signer<'info>,
+
     pub token_program: Interface<'info, TokenInterface>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
-// Step 3: Configure the pair
+#[event]
+pub struct ProtocolFeeAccountInitializedEvent {
+    pub pair: Pubkey,
+    pub protocol_fee_account: Pubkey,
+}
+
+#[event]
+pub struct ProtocolFeeWithdrawalQueuedEvent {
+    pub pair: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+    pub unlock_at: i64,
+}
+
+#[event]
+pub struct ProtocolFeesWithdrawnEvent {
+    pub pair: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+}
+
+// Factory-wide settings changed by the factory owner
+#[derive(Accounts)]
+pub struct SetFactoryConfig<'info> {
+    #[account(
+        mut,
+        has_one = owner @ DexError::NotFactoryOwner,
+    )]
+    pub factory: Account<'info, Factory>,
+
+    pub owner: Signer<'info>,
+}
+
+#[event]
+pub struct PairFeeChangedEvent {
+    pub pair: Pubkey,
+    pub old_fee_bps: u16,
+    pub new_fee_bps: u16,
+}
+
+#[event]
+pub struct FeeToUpdatedEvent {
+    pub fee_to: Pubkey,
+}
+
+#[event]
+pub struct LpBurnAddressUpdatedEvent {
+    pub lp_burn_address: Pubkey,
+}
+
+#[event]
+pub struct ProtocolFeeFractionUpdatedEvent {
+    pub fee_numerator: u8,
+    pub fee_denominator: u8,
+}
+
+#[event]
+pub struct FeeSwitchToggledEvent {
+    pub enabled: bool,
+}
+
+#[event]
+pub struct KeeperBountyUpdatedEvent {
+    pub keeper_bounty_bps: u16,
+}
+
+#[event]
+pub struct ProtocolFeesCollectedEvent {
+    pub pair: Pubkey,
+    pub keeper: Pubkey,
+    pub fee_to_amount: u64,
+    pub bounty: u64,
+}
+
+#[event]
+pub struct ProtocolFeeMintedEvent {
+    pub pair: Pubkey,
+    pub fee_to_account: Pubkey,
+    pub liquidity: u64,
+    pub k_last: u128,
+    pub k: u128,
+}
+
+#[event]
+pub struct FeeDiscountUpdatedEvent {
+    pub discount_mint: Pubkey,
+    pub discount_threshold: u64,
+    pub discount_fee_bps: u16,
+}
+
+#[account]
+pub struct LpMintMarker {
+    pub pair: Pubkey,
+}
+
+impl LpMintMarker {
+    pub const LEN: usize = 8 + // discriminator
+        32; // pair
+}
+
+#[event]
+pub struct LpTokenPolicyUpdatedEvent {
+    pub reject_lp_pair_tokens: bool,
+}
+
+#[event]
+pub struct PausedEvent {
+    pub paused: bool,
+}
+
+#[event]
+pub struct PromoStartedEvent {
+    pub promo_fee_bps: u16,
+    pub promo_fee_until: i64,
+}
+
+#[derive(Accounts)]
+pub struct AcceptOwnership<'info> {
+    #[account(
+        mut,
+        constraint = factory.pending_owner != Pubkey::default() @ DexError::NoPendingOwner,
+        constraint = factory.pending_owner == pending_owner.key() @ DexError::NotPendingOwner,
+    )]
+    pub factory: Account<'info, Factory>,
+
+    pub pending_owner: Signer<'info>,
+}
+
+#[event]
+pub struct OwnershipTransferStartedEvent {
+    pub factory: Pubkey,
+    pub owner: Pubkey,
+    pub pending_owner: Pubkey,
+}
+
+#[event]
+pub struct OwnershipTransferAcceptedEvent {
+    pub factory: Pubkey,
+    pub previous_owner: Pubkey,
+    pub new_owner: Pubkey,
+}
+
+#[event]
+pub struct OwnershipTransferCancelledEvent {
+    pub factory: Pubkey,
+    pub pending_owner: Pubkey,
+}
+
+#[derive(Accounts)]
+pub struct SetMigrationAuthority<'info> {
+    #[account(
+        mut,
+        has_one = migration_authority @ DexError::NotMigrationAuthority,
+    )]
+    pub factory: Account<'info, Factory>,
+
+    pub migration_authority: Signer<'info>,
+}
+
+#[event]
+pub struct MigrationAuthorityUpdatedEvent {
+    pub factory: Pubkey,
+    pub old_migration_authority: Pubkey,
+    pub new_migration_authority: Pubkey,
+}
+
+// Owner-gated update of a single pair's settings. Pairs with a controller
+// take the controller's signing PDA in place of the factory owner.
 #[derive(Accounts)]
-pub struct ConfigurePair<'info> {
+pub struct SetPairConfig<'info> {
+    pub factory: Account<'info, Factory>,
+
     #[account(
         mut,
-        has_one = owner @ DexError::NotFactoryOwner,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
     )]
-    pub factory: Account<'info, Factory>,
-    
-    #[account(mut)]
     pub pair: Account<'info, PairAccount>,
-    
-    /// CHECK: This is a token mint
-    pub token0: UncheckedAccount<'info>,
-    
-    /// CHECK: This is a token mint
-    pub token1: UncheckedAccount<'info>,
-    
-    pub lp_mint: InterfaceAccount<'info, Mint>,
-    
-    pub token0_account: InterfaceAccount<'info, TokenAccount>,
-    
-    pub token1_account: InterfaceAccount<'info, TokenAccount>,
-    
-    #[account(mut)]
-    pub sender: Signer<'info>,
-    
-    /// CHECK: Factory owner required for authorization
-    pub owner: UncheckedAccount<'info>,
-}
 
-#[account]
-pub struct Factory {
-    pub owner: Pubkey,
-    pub pair_count: u64,
-    pub fee_to: Pubkey,
-    pub fee_on: bool,
-    pub last_pair: Pubkey,
+    #[account(
+        constraint = pair.controller.is_some() || factory.owner == owner.key() @ DexError::NotFactoryOwner,
+        constraint = !matches!(pair.controller, Some(controller) if controller_signer(&pair.key(), &controller) != owner.key()) @ DexError::NotController,
+    )]
+    pub owner: Signer<'info>,
 }
 
-impl Factory {
-    pub const LEN: usize = 8 + // discriminator
-        32 + // owner pubkey
-        8 + // pair_count
-        32 + // fee_to pubkey
-        1 + // fee_on boolean
-        32; // last_pair pubkey
+#[event]
+pub struct ControllerUpdatedEvent {
+    pub pair: Pubkey,
+    pub controller: Option<Pubkey>,
 }
 
-#[account]
-pub struct PairAccount {
-    pub factory: Pubkey,
-    pub token0: Pubkey,
-    pub token1: Pubkey,
-    pub reserve0: u64,
-    pub reserve1: u64,
-    pub token0_account: Pubkey,
-    pub token1_account: Pubkey,
-    pub lp_mint: Pubkey,
-    pub total_supply: u64,
-    pub bump: u8,
-    pub authority_bump: u8,
-    pub is_initialized: bool,
+#[event]
+pub struct CallbackProgramUpdatedEvent {
+    pub pair: Pubkey,
+    pub callback_program: Option<Pubkey>,
 }
 
-impl PairAccount {
-    pub const LEN: usize = 8 + // discriminator
-        32 + // factory
-        32 + // token0
-        32 + // token1
-        8 + // reserve0
-        8 + // reserve1
-        32 + // token0_account
-        32 + // token1_account
-        32 + // lp_mint
-        8 + // total_supply
-        1 + // bump
-        1 + // authority_bump
-        1; // is_initialized
+#[event]
+pub struct FeeTiersUpdatedEvent {
+    pub pair: Pubkey,
+    pub tiers: Vec<FeeTier>,
 }
 
-#[event]
-pub struct PairCreatedEvent {
-    pub token0: Pubkey,
-    pub token1: Pubkey,
+// Payload passed to a pair's callback program after a liquidity change
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct LiquidityCallbackData {
     pub pair: Pubkey,
-    pub pair_count: u64,
+    pub sender: Pubkey,
+    pub amount0: u64,
+    pub amount1: u64,
+    pub liquidity: u64,
+    pub is_add: bool,
 }
+
+// Source pairs come in `remaining_accounts`, see `migrate_liquidity`
 #[derive(Accounts)]
-pub struct AddLiquidity<'info> {
+pub struct MigrateLiquidity<'info> {
+    pub factory: Box<Account<'info, Factory>>,
+
     #[account(
         mut,
-        has_one = owner @ DexError::NotFactoryOwner,
+        constraint = dest_pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = dest_pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = dest_pair.token0_account == dest_token0_account.key() @ DexError::InvalidTokenAccount,
+        constraint = dest_pair.token1_account == dest_token1_account.key() @ DexError::InvalidTokenAccount,
+        constraint = dest_pair.lp_mint == dest_lp_mint.key() @ DexError::InvalidLpMint,
     )]
-    pub factory: Account<'info, Factory>,
-    
+    pub dest_pair: Box<Account<'info, PairAccount>>,
+
+    #[account(mut)]
+    pub dest_token0_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub dest_token1_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub dest_lp_mint: Box<InterfaceAccount<'info, Mint>>,
+
     #[account(
         mut,
-        constraint = pair.is_initialized @ DexError::PairNotInitialized,
-        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
-        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
-        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
-        constraint = pair.lp_mint == lp_mint.key() @ DexError::InvalidLpMint,
+        constraint = liquidity_to.mint == dest_lp_mint.key() @ DexError::InvalidTokenAccount,
+        constraint = liquidity_to.owner == sender.key() @ DexError::InvalidTokenOwner,
     )]
-    pub pair: Account<'info, PairAccount>,
-    
-    #[account(mut)]
-    pub token0_account: InterfaceAccount<'info, TokenAccount>,
-    
-    #[account(mut)]
-    pub token1_account: InterfaceAccount<'info, TokenAccount>,
-    
+    pub liquidity_to: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: This is the PDA authority for the destination pair
+    #[account(
+        seeds = [
+            b"authority".as_ref(),
+            dest_pair.key().as_ref()
+        ],
+        bump = dest_pair.authority_bump
+    )]
+    pub dest_authority: UncheckedAccount<'info>,
+
+    // The destination's protocol fee account, as in `add_liquidity`
     #[account(
         mut,
-        constraint = user_token0.mint == pair.token0 @ DexError::InvalidTokenAccount,
+        constraint = fee_to_account.mint == dest_lp_mint.key() @ DexError::InvalidTokenAccount,
+        constraint = fee_to_account.key() == dest_pair.protocol_fee_account
+            || (dest_pair.protocol_fee_account == Pubkey::default() && fee_to_account.owner == factory.fee_to)
+            @ DexError::InvalidTokenOwner,
+    )]
+    pub fee_to_account: Option<Box<InterfaceAccount<'info, TokenAccount>>>,
+
+    #[account(
+        mut,
+        constraint = user_token0.mint == dest_pair.token0 @ DexError::InvalidTokenAccount,
         constraint = user_token0.owner == sender.key() @ DexError::InvalidTokenOwner,
     )]
-    pub user_token0: InterfaceAccount<'info, TokenAccount>,
-    
+    pub user_token0: Box<InterfaceAccount<'info, TokenAccount>>,
+
     #[account(
         mut,
-        constraint = user_token1.mint == pair.token1 @ DexError::InvalidTokenAccount,
+        constraint = user_token1.mint == dest_pair.token1 @ DexError::InvalidTokenAccount,
         constraint = user_token1.owner == sender.key() @ DexError::InvalidTokenOwner,
     )]
-    pub user_token1: InterfaceAccount<'info, TokenAccount>,
-    
+    pub user_token1: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = dest_pair.token0 @ DexError::InvalidTokenAccount)]
+    pub token0_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = dest_pair.token1 @ DexError::InvalidTokenAccount)]
+    pub token1_mint: Box<InterfaceAccount<'info, Mint>>,
+
     #[account(mut)]
-    pub lp_mint: InterfaceAccount<'info, Mint>,
-    
+    pub sender: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[event]
+pub struct LiquidityMigratedEvent {
+    pub source_pairs: Vec<Pubkey>,
+    pub dest_pair: Pubkey,
+    pub sender: Pubkey,
+    pub liquidity_burned: Vec<u64>,
+    pub amount0_removed: u64,
+    pub amount1_removed: u64,
+    pub amount0_added: u64,
+    pub amount1_added: u64,
+    pub liquidity_minted: u64,
+}
+
+#[derive(Accounts)]
+pub struct InitRewardPool<'info> {
+    #[account(has_one = owner @ DexError::NotFactoryOwner)]
+    pub factory: Box<Account<'info, Factory>>,
+
     #[account(
-        mut,
-        constraint = liquidity_to.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
-        constraint = liquidity_to.owner == sender.key() @ DexError::InvalidTokenOwner,
+        constraint = pair.is_initialized @ DexError::PairNotInitialized,
+        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
+        constraint = pair.lp_mint == lp_mint.key() @ DexError::InvalidLpMint,
     )]
-    pub liquidity_to: InterfaceAccount<'info, TokenAccount>,
-    
+    pub pair: Box<Account<'info, PairAccount>>,
+
+    pub lp_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub reward_mint: Box<InterfaceAccount<'info, Mint>>,
+
     #[account(
-        mut,
-        constraint = burn_account.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
+        init,
+        payer = owner,
+        space = RewardPool::LEN,
+        seeds = [b"reward_pool".as_ref(), pair.key().as_ref()],
+        bump,
     )]
-    pub burn_account: InterfaceAccount<'info, TokenAccount>,
-    
+    pub reward_pool: Box<Account<'info, RewardPool>>,
+
+    // Both vaults are held by the pair authority
+    #[account(
+        init,
+        payer = owner,
+        seeds = [b"reward_lp_vault".as_ref(), pair.key().as_ref()],
+        bump,
+        token::mint = lp_mint,
+        token::authority = authority,
+    )]
+    pub staked_lp_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init,
+        payer = owner,
+        seeds = [b"reward_vault".as_ref(), pair.key().as_ref()],
+        bump,
+        token::mint = reward_mint,
+        token::authority = authority,
+    )]
+    pub reward_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
     /// CHECK: This is the PDA authority for the pair
     #[account(
         seeds = [
@@ -772,74 +5799,89 @@ pub struct AddLiquidity<'info> {
         bump = pair.authority_bump
     )]
     pub authority: UncheckedAccount<'info>,
-    
+
     #[account(mut)]
-    pub sender: Signer<'info>,
-    
-    /// CHECK: Factory owner required for authorization
-    pub owner: UncheckedAccount<'info>,
-    
-    pub token_program: Interface<'info, TokenInterface>,
-}
+    pub owner: Signer<'info>,
 
-// Add this event
-#[event]
-pub struct LiquidityAddedEvent {
-    pub sender: Pubkey,
-    pub amount0: u64,
-    pub amount1: u64,
-    pub liquidity: u64,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
 }
 
-// Add this accounts struct
 #[derive(Accounts)]
-pub struct RemoveLiquidity<'info> {
+pub struct StakeLp<'info> {
     #[account(
         mut,
-        has_one = owner @ DexError::NotFactoryOwner,
+        seeds = [b"reward_pool".as_ref(), reward_pool.pair.as_ref()],
+        bump,
     )]
-    pub factory: Account<'info, Factory>,
-    
+    pub reward_pool: Box<Account<'info, RewardPool>>,
+
     #[account(
         mut,
-        constraint = pair.is_initialized @ DexError::PairNotInitialized,
-        constraint = pair.factory == factory.key() @ DexError::InvalidPairFactory,
-        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
-        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
-        constraint = pair.lp_mint == lp_mint.key() @ DexError::InvalidLpMint,
+        address = reward_pool.staked_lp_vault @ DexError::InvalidTokenAccount,
     )]
-    pub pair: Account<'info, PairAccount>,
-    
-    #[account(mut)]
-    pub token0_account: InterfaceAccount<'info, TokenAccount>,
-    
+    pub staked_lp_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = staked_lp_vault.mint @ DexError::InvalidLpMint)]
+    pub lp_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init_if_needed,
+        payer = sender,
+        space = StakePosition::LEN,
+        seeds = [b"stake".as_ref(), reward_pool.key().as_ref(), sender.key().as_ref()],
+        bump,
+    )]
+    pub position: Box<Account<'info, StakePosition>>,
+
+    #[account(
+        mut,
+        constraint = user_lp.mint == staked_lp_vault.mint @ DexError::InvalidTokenAccount,
+        constraint = user_lp.owner == sender.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub user_lp: Box<InterfaceAccount<'info, TokenAccount>>,
+
     #[account(mut)]
-    pub token1_account: InterfaceAccount<'info, TokenAccount>,
-    
+    pub sender: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnstakeLp<'info> {
+    pub pair: Box<Account<'info, PairAccount>>,
+
     #[account(
         mut,
-        constraint = token0_to.mint == pair.token0 @ DexError::InvalidTokenAccount,
-        constraint = token0_to.owner == sender.key() @ DexError::InvalidTokenOwner,
+        seeds = [b"reward_pool".as_ref(), pair.key().as_ref()],
+        bump,
     )]
-    pub token0_to: InterfaceAccount<'info, TokenAccount>,
-    
+    pub reward_pool: Box<Account<'info, RewardPool>>,
+
     #[account(
         mut,
-        constraint = token1_to.mint == pair.token1 @ DexError::InvalidTokenAccount,
-        constraint = token1_to.owner == sender.key() @ DexError::InvalidTokenOwner,
+        address = reward_pool.staked_lp_vault @ DexError::InvalidTokenAccount,
     )]
-    pub token1_to: InterfaceAccount<'info, TokenAccount>,
-    
-    #[account(mut)]
-    pub lp_mint: InterfaceAccount<'info, Mint>,
-    
+    pub staked_lp_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = staked_lp_vault.mint @ DexError::InvalidLpMint)]
+    pub lp_mint: Box<InterfaceAccount<'info, Mint>>,
+
     #[account(
         mut,
-        constraint = liquidity_from.mint == lp_mint.key() @ DexError::InvalidTokenAccount,
-        constraint = liquidity_from.owner == sender.key() @ DexError::InvalidTokenOwner,
+        seeds = [b"stake".as_ref(), reward_pool.key().as_ref(), sender.key().as_ref()],
+        bump,
+    )]
+    pub position: Box<Account<'info, StakePosition>>,
+
+    #[account(
+        mut,
+        constraint = user_lp.mint == staked_lp_vault.mint @ DexError::InvalidTokenAccount,
+        constraint = user_lp.owner == sender.key() @ DexError::InvalidTokenOwner,
     )]
-    pub liquidity_from: InterfaceAccount<'info, TokenAccount>,
-    
+    pub user_lp: Box<InterfaceAccount<'info, TokenAccount>>,
+
     /// CHECK: This is the PDA authority for the pair
     #[account(
         seeds = [
@@ -849,57 +5891,46 @@ pub struct RemoveLiquidity<'info> {
         bump = pair.authority_bump
     )]
     pub authority: UncheckedAccount<'info>,
-    
-    #[account(mut)]
+
     pub sender: Signer<'info>,
-    
-    /// CHECK: Factory owner required for authorization
-    pub owner: UncheckedAccount<'info>,
-    
-    pub token_program: Interface<'info, TokenInterface>,
-}
 
-// Add this event
-#[event]
-pub struct LiquidityRemovedEvent {
-    pub sender: Pubkey,
-    pub amount0: u64,
-    pub amount1: u64,
-    pub liquidity: u64,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
-// Add this accounts struct
 #[derive(Accounts)]
-pub struct Swap<'info> {
+pub struct ClaimRewards<'info> {
+    pub pair: Box<Account<'info, PairAccount>>,
+
     #[account(
         mut,
-        constraint = pair.is_initialized @ DexError::PairNotInitialized,
-        constraint = pair.token0_account == token0_account.key() @ DexError::InvalidTokenAccount,
-        constraint = pair.token1_account == token1_account.key() @ DexError::InvalidTokenAccount,
+        seeds = [b"reward_pool".as_ref(), pair.key().as_ref()],
+        bump,
     )]
-    pub pair: Account<'info, PairAccount>,
-    
-    #[account(mut)]
-    pub token0_account: InterfaceAccount<'info, TokenAccount>,
-    
-    #[account(mut)]
-    pub token1_account: InterfaceAccount<'info, TokenAccount>,
-    
+    pub reward_pool: Box<Account<'info, RewardPool>>,
+
     #[account(
         mut,
-        constraint = token_in.owner == sender.key() @ DexError::InvalidTokenOwner,
-        constraint = (token_in.mint == pair.token0 || token_in.mint == pair.token1) @ DexError::InvalidTokenAccount,
+        address = reward_pool.reward_vault @ DexError::InvalidTokenAccount,
     )]
-    pub token_in: InterfaceAccount<'info, TokenAccount>,
-    
+    pub reward_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = reward_pool.reward_mint @ DexError::InvalidTokenAccount)]
+    pub reward_mint: Box<InterfaceAccount<'info, Mint>>,
+
     #[account(
         mut,
-        constraint = token_out.owner == sender.key() @ DexError::InvalidTokenOwner,
-        constraint = (token_out.mint == pair.token0 || token_out.mint == pair.token1) @ DexError::InvalidTokenAccount,
-        constraint = token_out.mint != token_in.mint @ DexError::IdenticalTokens,
+        seeds = [b"stake".as_ref(), reward_pool.key().as_ref(), sender.key().as_ref()],
+        bump,
     )]
-    pub token_out: InterfaceAccount<'info, TokenAccount>,
-    
+    pub position: Box<Account<'info, StakePosition>>,
+
+    #[account(
+        mut,
+        constraint = user_reward.mint == reward_pool.reward_mint @ DexError::InvalidTokenAccount,
+        constraint = user_reward.owner == sender.key() @ DexError::InvalidTokenOwner,
+    )]
+    pub user_reward: Box<InterfaceAccount<'info, TokenAccount>>,
+
     /// CHECK: This is the PDA authority for the pair
     #[account(
         seeds = [
@@ -909,20 +5940,126 @@ pub struct Swap<'info> {
         bump = pair.authority_bump
     )]
     pub authority: UncheckedAccount<'info>,
-    
-    #[account(mut)]
+
     pub sender: Signer<'info>,
-    
+
     pub token_program: Interface<'info, TokenInterface>,
 }
 
-// Add this event
+#[account]
+pub struct RewardPool {
+    pub pair: Pubkey,
+    pub reward_mint: Pubkey,
+    pub staked_lp_vault: Pubkey,
+    pub reward_vault: Pubkey,
+    pub reward_rate: u64,
+    pub reward_per_share: u128,
+    pub last_update_at: i64,
+    pub total_staked: u64,
+}
+
+impl RewardPool {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // pair
+        32 + // reward_mint
+        32 + // staked_lp_vault
+        32 + // reward_vault
+        8 + // reward_rate
+        16 + // reward_per_share
+        8 + // last_update_at
+        8; // total_staked
+
+    // Accrues emissions since the last update into reward_per_share. Nothing
+    // accrues while nothing is staked.
+    fn update(&mut self, now: i64) -> Result<()> {
+        if now <= self.last_update_at {
+            return Ok(());
+        }
+        if self.total_staked > 0 {
+            let elapsed = (now - self.last_update_at) as u128;
+            let increment = elapsed
+                .checked_mul(self.reward_rate as u128)
+                .and_then(|reward| reward.checked_mul(REWARD_PRECISION))
+                .ok_or(DexError::AmountOverflow)?
+                / self.total_staked as u128;
+            self.reward_per_share = self
+                .reward_per_share
+                .checked_add(increment)
+                .ok_or(DexError::AmountOverflow)?;
+        }
+        self.last_update_at = now;
+        Ok(())
+    }
+}
+
+#[account]
+pub struct StakePosition {
+    pub owner: Pubkey,
+    pub reward_pool: Pubkey,
+    pub amount: u64,
+    pub reward_debt: u128,
+    pub pending_rewards: u64,
+}
+
+impl StakePosition {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // owner
+        32 + // reward_pool
+        8 + // amount
+        16 + // reward_debt
+        8; // pending_rewards
+
+    fn accumulated(&self, reward_pool: &RewardPool) -> Result<u128> {
+        Ok((self.amount as u128)
+            .checked_mul(reward_pool.reward_per_share)
+            .ok_or(DexError::AmountOverflow)?
+            / REWARD_PRECISION)
+    }
+
+    // Moves rewards earned since the last checkpoint into pending_rewards
+    fn accrue(&mut self, reward_pool: &RewardPool) -> Result<()> {
+        let earned = self.accumulated(reward_pool)?.saturating_sub(self.reward_debt);
+        self.pending_rewards = u64::try_from(earned)
+            .ok()
+            .and_then(|earned| self.pending_rewards.checked_add(earned))
+            .ok_or(DexError::AmountOverflow)?;
+        Ok(())
+    }
+
+    fn reset_reward_debt(&mut self, reward_pool: &RewardPool) -> Result<()> {
+        self.reward_debt = self.accumulated(reward_pool)?;
+        Ok(())
+    }
+}
+
 #[event]
-pub struct SwapEvent {
-    pub sender: Pubkey,
-    pub amount_in: u64,
-    pub amount_out: u64,
-    pub is_token0_in: bool,
+pub struct RewardPoolInitializedEvent {
+    pub pair: Pubkey,
+    pub reward_mint: Pubkey,
+    pub reward_rate: u64,
+}
+
+#[event]
+pub struct LpStakedEvent {
+    pub pair: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub total_staked: u64,
+}
+
+#[event]
+pub struct LpUnstakedEvent {
+    pub pair: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub total_staked: u64,
+}
+
+#[event]
+pub struct RewardsClaimedEvent {
+    pub pair: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
 }
 
 #[error_code]
@@ -958,6 +6095,1034 @@ pub enum DexError {
     InsufficientLiquidity,
     #[msg("K value decreased - this shouldn't happen")]
     K,
+    #[msg("Protocol fee account is already set for this pair")]
+    ProtocolFeeAccountAlreadySet,
+    #[msg("Protocol fee account is not set for this pair")]
+    ProtocolFeeAccountNotSet,
+    #[msg("Protocol fee withdrawal is still timelocked")]
+    FeeWithdrawalLocked,
+    #[msg("Pair reserves are out of sync with its LP supply or vaults")]
+    ReserveDesync,
+    #[msg("Callback program account missing or does not match the pair")]
+    InvalidCallbackProgram,
+    #[msg("Pair stores the same mint for both tokens")]
+    InvalidPairMints,
+    #[msg("Fee exceeds the maximum allowed")]
+    InvalidFee,
+    #[msg("Too many fee tiers")]
+    TooManyFeeTiers,
+    #[msg("Fee tier thresholds must be strictly increasing")]
+    FeeTiersNotSorted,
+    #[msg("Swap would push the reserve ratio out of bounds")]
+    RatioOutOfBounds,
+    #[msg("Source and destination pairs must differ")]
+    IdenticalPairs,
+    #[msg("Pairs do not trade the same tokens")]
+    MismatchedPairTokens,
+    #[msg("No ownership transfer is pending")]
+    NoPendingOwner,
+    #[msg("Signer is not the pending owner")]
+    NotPendingOwner,
+    #[msg("Pair settings must be changed through its controller program")]
+    NotController,
+    #[msg("Price must be greater than zero")]
+    InvalidPrice,
+    #[msg("Swap cooldown for this signer has not elapsed")]
+    CooldownActive,
+    #[msg("Pair has a swap cooldown; pass the signer's trader state")]
+    TraderStateRequired,
+    #[msg("Unstake amount exceeds the staked balance")]
+    InsufficientStake,
+    #[msg("Reward vault cannot cover the claim")]
+    InsufficientRewards,
+    #[msg("Pair tokens cannot be another pair's LP mint")]
+    LpMintAsPairToken,
+    #[msg("Pool holds only the locked minimum liquidity")]
+    PoolDrained,
+    #[msg("Too many pairs for one batch")]
+    TooManyPairs,
+    #[msg("Minimum output is at or above the output reserve")]
+    ImpossibleOutputMinimum,
+    #[msg("Output cap must be at most 10000 bps")]
+    InvalidMaxOutput,
+    #[msg("Swap output exceeds the pair's per-swap cap")]
+    MaxOutputExceeded,
+    #[msg("Swap input is below the pair's minimum trade amount")]
+    TradeTooSmall,
+    #[msg("Protocol fee is due; pass the fee_to LP account")]
+    FeeToAccountRequired,
+    #[msg("Keeper bounty exceeds the maximum")]
+    InvalidKeeperBounty,
+    #[msg("No protocol fee has accrued since the last collection")]
+    NoProtocolFeeAccrued,
+    #[msg("Set fee_to before enabling the protocol fee")]
+    FeeToNotSet,
+    #[msg("Transaction deadline has passed")]
+    Expired,
+    #[msg("Required input exceeds the maximum")]
+    ExcessiveInputAmount,
+    #[msg("Not enough compute budget left to complete the swap")]
+    InsufficientComputeBudget,
+    #[msg("Pool price deviates too far from the external oracle")]
+    OracleDeviationTooHigh,
+    #[msg("Missing or invalid external oracle account")]
+    InvalidOracleAccount,
+    #[msg("Oracle deviation must be between 1 and 10000 bps")]
+    InvalidOracleDeviation,
+    #[msg("Pair is locked by an operation in progress")]
+    Locked,
+    #[msg("Route accounts don't form a valid path")]
+    InvalidRoute,
+    #[msg("Pair account is not the PDA of its tokens")]
+    InvalidPairAddress,
+    #[msg("Stored bumps already match the canonical ones")]
+    BumpsAlreadyValid,
+    #[msg("Swap fraction must be at most 10000 bps")]
+    InvalidSwapFraction,
+    #[msg("The factory is paused")]
+    Paused,
+    #[msg("Deposit mints LP outside the pair's bounds")]
+    DepositOutOfBounds,
+    #[msg("Minimum deposit exceeds the maximum")]
+    InvalidDepositBounds,
+    #[msg("The pair is paused")]
+    PairPaused,
+    #[msg("Only the migration authority can call this")]
+    NotMigrationAuthority,
+    #[msg("Withdrawal rounding is off by more than one unit")]
+    WithdrawalRounding,
+    #[msg("Input amount must be greater than zero")]
+    InsufficientInputAmount,
+    #[msg("A fee-charging swap left k unchanged")]
+    KNotIncreased,
+    #[msg("The pair's snapshot archive is full")]
+    ArchiveFull,
+    #[msg("The pair still holds reserves or LP supply")]
+    PairNotEmpty,
+    #[msg("Only the factory's fee_to can call this")]
+    NotFeeTo,
+    #[msg("A route hop broke its pair's invariants")]
+    RouteInvariantViolated,
+    #[msg("Flash swap was not repaid with enough to cover the fee")]
+    FlashRepaymentFailed,
+    #[msg("Pair was created from unsorted token seeds")]
+    TokensNotSorted,
+    #[msg("LP mint must be minted only by the pair authority and have no freeze authority")]
+    InvalidLpMintAuthority,
+    #[msg("Summary interval must be non-zero while swap events are off")]
+    InvalidSummaryInterval,
+    #[msg("Trade is too small for the fee to be charged")]
+    FeeRoundsToZero,
+    #[msg("Token transfer fee would break the pool invariant")]
+    TransferFeeUnsupported,
+    #[msg("Pair is frozen for deposits after reaching a reserve cap")]
+    PairFrozen,
+    #[msg("Price impact exceeds the allowed maximum")]
+    ExcessivePriceImpact,
+    #[msg("Batch accounts don't match the listed swaps")]
+    InvalidBatchSwap,
+    #[msg("Migration accounts don't match the listed sources")]
+    InvalidMigration,
+    #[msg("Pool price deviates too far from its TWAP to deposit")]
+    PriceDeviationTooHigh,
+    #[msg("Deposit deviation must be at most 10000 bps")]
+    InvalidDepositDeviation,
+    #[msg("Not enough oracle history for the requested window")]
+    OracleHistoryTooShort,
+    #[msg("Oracle already has price history")]
+    OracleAlreadyStarted,
+    #[msg("Seed price must be greater than zero")]
+    InvalidSeedPrice,
+    #[msg("Destination doesn't match the queued fee withdrawal")]
+    InvalidFeeWithdrawalDestination,
+}
+
+// Deposit amounts and LP to mint for `add_liquidity`, shared with the quote
+// instruction so previews use the exact same integer math. On the first
+// deposit the returned liquidity excludes the locked minimum.
+#[allow(clippy::too_many_arguments)]
+fn compute_liquidity(
+    reserve0: u64,
+    reserve1: u64,
+    total_supply: u64,
+    minimum_liquidity: u64,
+    normalized_decimals: Option<(u8, u8)>,
+    amount0_desired: u128,
+    amount1_desired: u128,
+    amount0_min: u128,
+    amount1_min: u128,
+) -> Result<(u64, u64, u64)> {
+    let (amount0, amount1, liquidity) = if reserve0 == 0 && reserve1 == 0 {
+        // First liquidity provision
+        // Use the full amounts provided but ensure they don't exceed u64::MAX
+        let amount0 = u64::try_from(amount0_desired)
+            .map_err(|_| error!(DexError::AmountOverflow))?;
+        let amount1 = u64::try_from(amount1_desired)
+            .map_err(|_| error!(DexError::AmountOverflow))?;
+    
+        // Initial liquidity is the geometric mean of the amounts, optionally
+        // taken in whole-token terms at the smaller of the two decimals
+        let (mean0, mean1) = match normalized_decimals {
+            Some((decimals0, decimals1)) => (
+                scale_down_decimals(amount0, decimals0, decimals1),
+                scale_down_decimals(amount1, decimals1, decimals0),
+            ),
+            None => (amount0 as u128, amount1 as u128),
+        };
+        let initial_liquidity = sqrt(
+            mean0.checked_mul(mean1).ok_or(DexError::AmountOverflow)?
+        ) as u64;
+    
+        // Enforce minimum liquidity
+        let liquidity = initial_liquidity.saturating_sub(minimum_liquidity);
+    
+        // Minimum liquidity check
+        require!(liquidity > 0, DexError::InsufficientLiquidityMinted);
+    
+        (amount0, amount1, liquidity)
+    } else {
+        // Not the first provision, calculate based on existing reserves.
+        // A single empty side would make the ratio undefined.
+        require!(reserve0 > 0 && reserve1 > 0, DexError::InsufficientLiquidity);
+        let amount1_optimal = amount0_desired
+            .checked_mul(reserve1 as u128)
+            .ok_or(DexError::AmountOverflow)?
+            .checked_div(reserve0 as u128)
+            .ok_or(DexError::InsufficientLiquidity)?;
+    
+        if amount1_optimal <= amount1_desired {
+            // amount1_optimal is the binding amount
+            require!(
+                amount1_optimal >= amount1_min,
+                DexError::InsufficientAmount
+            );
+    
+            let liquidity = amount0_desired
+                .checked_mul(total_supply as u128)
+                .ok_or(DexError::AmountOverflow)?
+                .checked_div(reserve0 as u128)
+                .ok_or(DexError::InsufficientLiquidity)?;
+    
+            // Convert to u64 for actual token transfers
+            let amount0_u64 = u64::try_from(amount0_desired)
+                .map_err(|_| error!(DexError::AmountOverflow))?;
+            let amount1_u64 = u64::try_from(amount1_optimal)
+                .map_err(|_| error!(DexError::AmountOverflow))?;
+            let liquidity_u64 = u64::try_from(liquidity)
+                .map_err(|_| error!(DexError::AmountOverflow))?;
+    
+            (amount0_u64, amount1_u64, liquidity_u64)
+        } else {
+            // amount0_optimal is the binding amount
+            let amount0_optimal = amount1_desired
+                .checked_mul(reserve0 as u128)
+                .ok_or(DexError::AmountOverflow)?
+                .checked_div(reserve1 as u128)
+                .ok_or(DexError::InsufficientLiquidity)?;
+    
+            require!(
+                amount0_optimal >= amount0_min,
+                DexError::InsufficientAmount
+            );
+    
+            let liquidity = amount1_desired
+                .checked_mul(total_supply as u128)
+                .ok_or(DexError::AmountOverflow)?
+                .checked_div(reserve1 as u128)
+                .ok_or(DexError::InsufficientLiquidity)?;
+    
+            // Convert to u64 for actual token transfers
+            let amount0_u64 = u64::try_from(amount0_optimal)
+                .map_err(|_| error!(DexError::AmountOverflow))?;
+            let amount1_u64 = u64::try_from(amount1_desired)
+                .map_err(|_| error!(DexError::AmountOverflow))?;
+            let liquidity_u64 = u64::try_from(liquidity)
+                .map_err(|_| error!(DexError::AmountOverflow))?;
+    
+            (amount0_u64, amount1_u64, liquidity_u64)
+        }
+    };
+    
+    // Ensure minimum liquidity amounts
+    require!(
+        amount0 as u128 >= amount0_min && amount1 as u128 >= amount1_min,
+        DexError::InsufficientAmount
+    );
+
+    Ok((amount0, amount1, liquidity))
+}
+
+// Notifies the pair's callback program, if one is set, about a liquidity
+// change. The callback program must be the first remaining account; the rest
+// are forwarded after the pair. A failing callback reverts the whole operation.
+fn invoke_liquidity_callback<'info>(
+    pair: &Account<'info, PairAccount>,
+    remaining_accounts: &[AccountInfo<'info>],
+    data: LiquidityCallbackData,
+) -> Result<()> {
+    let Some(callback_program) = pair.callback_program else {
+        return Ok(());
+    };
+
+    let (program_info, forwarded) = remaining_accounts
+        .split_first()
+        .ok_or(DexError::InvalidCallbackProgram)?;
+    require_keys_eq!(
+        program_info.key(),
+        callback_program,
+        DexError::InvalidCallbackProgram
+    );
+
+    let mut accounts = vec![AccountMeta::new_readonly(pair.key(), false)];
+    accounts.extend(forwarded.iter().map(|account| {
+        if account.is_writable {
+            AccountMeta::new(account.key(), account.is_signer)
+        } else {
+            AccountMeta::new_readonly(account.key(), account.is_signer)
+        }
+    }));
+
+    let mut ix_data = anchor_lang::solana_program::hash::hash(b"global:on_liquidity_event")
+        .to_bytes()[..8]
+        .to_vec();
+    data.serialize(&mut ix_data)?;
+
+    let mut account_infos = vec![pair.to_account_info()];
+    account_infos.extend_from_slice(forwarded);
+    account_infos.push(program_info.clone());
+
+    invoke(
+        &Instruction {
+            program_id: callback_program,
+            accounts,
+            data: ix_data,
+        },
+        &account_infos,
+    )?;
+
+    Ok(())
+}
+
+// PDA a pair's controller program signs with when managing that pair
+fn controller_signer(pair: &Pubkey, controller: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"controller", pair.as_ref()], controller).0
+}
+
+// Shared body of `swap_and_add_liquidity` and `zap_in`: swaps
+// `swap_fraction_bps` of the one-sided `amount_in`, or without one the share
+// `zap_swap_amount` balances, then deposits the rest with the swap output
+#[allow(clippy::too_many_arguments)]
+fn swap_and_deposit<'info>(
+    ctx: Context<'_, '_, '_, 'info, AddLiquidity<'info>>,
+    amount_in: u128,
+    is_token0_in: bool,
+    swap_fraction_bps: Option<u16>,
+    amount_out_min: u128,
+    amount0_min: u128,
+    amount1_min: u128,
+    min_liquidity: u128,
+    deadline: i64,
+) -> Result<()> {
+    require!(ctx.accounts.pair.is_initialized, DexError::PairNotInitialized);
+    require!(amount_in > 0, DexError::InsufficientInputAmount);
+    require!(!ctx.accounts.factory.paused, DexError::Paused);
+    require!(!ctx.accounts.pair.pair_paused, DexError::PairPaused);
+    require!(!ctx.accounts.pair.frozen, DexError::PairFrozen);
+    let now = Clock::get()?.unix_timestamp;
+    require!(now <= deadline, DexError::Expired);
+    let amount_in = u64::try_from(amount_in).map_err(|_| error!(DexError::AmountOverflow))?;
+
+    let pair = &ctx.accounts.pair;
+    require!(pair.swap_cooldown_seconds == 0, DexError::TraderStateRequired);
+    require!(pair.external_oracle.is_none(), DexError::InvalidOracleAccount);
+    // The split needs a price, so the pool must already be seeded
+    require!(pair.reserve0 > 0 && pair.reserve1 > 0, DexError::InsufficientLiquidity);
+
+    lock_pair(&mut ctx.accounts.pair)?;
+    update_cumulative_prices(&mut ctx.accounts.pair)?;
+    ctx.accounts.mint_fee()?;
+
+    let swap_amount = match swap_fraction_bps {
+        Some(swap_fraction_bps) => (amount_in as u128 * swap_fraction_bps as u128 / FEE_DENOMINATOR) as u64,
+        None => ctx.accounts.zap_swap_amount(amount_in, is_token0_in, now)?,
+    };
+    let amount_out = ctx.accounts.swap_for_deposit(swap_amount, is_token0_in, now)?;
+    require!(amount_out as u128 >= amount_out_min, DexError::InsufficientOutputAmount);
+
+    let kept_in = (amount_in - swap_amount) as u128;
+    let (amount0_desired, amount1_desired) = if is_token0_in {
+        (kept_in, amount_out as u128)
+    } else {
+        (amount_out as u128, kept_in)
+    };
+    let (amount0, amount1, liquidity) = compute_liquidity(
+        ctx.accounts.pair.reserve0,
+        ctx.accounts.pair.reserve1,
+        ctx.accounts.pair.total_supply,
+        ctx.accounts.pair.minimum_liquidity,
+        ctx.accounts.pair.normalized_decimals(),
+        amount0_desired,
+        amount1_desired,
+        amount0_min,
+        amount1_min,
+    )?;
+    require!(liquidity > 0, DexError::InsufficientLiquidityMinted);
+
+    let (amount0, amount1, liquidity) = ctx.accounts.deposit_liquidity(amount0, amount1, liquidity)?;
+    require!(liquidity as u128 >= min_liquidity, DexError::InsufficientLiquidityMinted);
+
+    emit!(LiquidityAddedEvent {
+        sender: ctx.accounts.sender.key(),
+        amount0,
+        amount1,
+        liquidity,
+        reserve0_after: ctx.accounts.pair.reserve0,
+        reserve1_after: ctx.accounts.pair.reserve1,
+    });
+
+    invoke_liquidity_callback(
+        &ctx.accounts.pair,
+        ctx.remaining_accounts,
+        LiquidityCallbackData {
+            pair: ctx.accounts.pair.key(),
+            sender: ctx.accounts.sender.key(),
+            amount0,
+            amount1,
+            liquidity,
+            is_add: true,
+        },
+    )?;
+
+    ctx.accounts.pair.locked = false;
+    Ok(())
+}
+
+// Shared body of the routed swaps; see `swap_exact_tokens_for_tokens`
+fn route_exact_in<'info>(
+    ctx: Context<'_, '_, 'info, 'info, SwapExactTokensForTokens<'info>>,
+    amount_in: u128,
+    amount_out_min: u128,
+    deadline: i64,
+    verify_hops: bool,
+) -> Result<()> {
+    require!(!ctx.accounts.factory.paused, DexError::Paused);
+    require!(amount_in > 0, DexError::InsufficientInputAmount);
+    let now = Clock::get()?.unix_timestamp;
+    require!(now <= deadline, DexError::Expired);
+
+    let hop_accounts = ctx.remaining_accounts.chunks_exact(ROUTE_HOP_ACCOUNTS);
+    require!(
+        hop_accounts.remainder().is_empty() && (1..=MAX_ROUTE_HOPS).contains(&hop_accounts.len()),
+        DexError::InvalidRoute
+    );
+
+    let accounts = &ctx.accounts;
+    let factory = &accounts.factory;
+    let promo_fee_bps = (now < factory.promo_fee_until).then_some(factory.promo_fee_bps);
+
+    // Validate and lock every hop up front. Locking writes through, so a
+    // pair listed twice fails when its second copy is loaded.
+    let mut pairs = Vec::with_capacity(MAX_ROUTE_HOPS);
+    let mut k_before = Vec::with_capacity(MAX_ROUTE_HOPS);
+    let mut vaults = Vec::with_capacity(MAX_ROUTE_HOPS);
+    let mut mint = accounts.token_in.mint;
+    for hop in hop_accounts {
+        let (vault0, vault1, authority) = (&hop[1], &hop[2], &hop[3]);
+        let mut pair = load_hop_pair(hop, &factory.key(), ctx.program_id)?;
+
+        // Each hop must take the previous hop's output mint
+        let is_token0_in = mint == pair.token0;
+        require!(is_token0_in || mint == pair.token1, DexError::InvalidRoute);
+        mint = if is_token0_in { pair.token1 } else { pair.token0 };
+
+        lock_pair(&mut pair)?;
+        update_cumulative_prices(&mut pair)?;
+        let (vault_in, vault_out) = if is_token0_in { (vault0, vault1) } else { (vault1, vault0) };
+        let (mint_in, mint_out) = if is_token0_in { (&hop[4], &hop[5]) } else { (&hop[5], &hop[4]) };
+        pairs.push((pair, is_token0_in));
+        vaults.push((
+            vault_in,
+            vault_out,
+            authority,
+            InterfaceAccount::<Mint>::try_from(mint_in)?,
+            InterfaceAccount::<Mint>::try_from(mint_out)?,
+        ));
+    }
+    require_keys_eq!(mint, accounts.token_out.mint, DexError::InvalidRoute);
+
+    // Each hop is priced on what actually reached its vault, so a mint with
+    // a transfer fee shrinks the amount carried forward instead of leaving
+    // a reserve its vault doesn't hold
+    let amount_in = u64::try_from(amount_in).map_err(|_| error!(DexError::AmountOverflow))?;
+    let vault_before = accessor::amount(vaults[0].0)?;
+    transfer_from_user(
+        accounts.token_program.to_account_info(),
+        accounts.token_in.to_account_info(),
+        &vaults[0].3,
+        vaults[0].0.clone(),
+        accounts.sender.to_account_info(),
+        amount_in,
+    )?;
+    let mut amount = accessor::amount(vaults[0].0)?
+        .checked_sub(vault_before)
+        .ok_or(DexError::AmountOverflow)?;
+
+    for hop in 0..pairs.len() {
+        let (pair, is_token0_in) = &mut pairs[hop];
+        k_before.push(pair.reserve0 as u128 * pair.reserve1 as u128);
+        let amount_out = swap_hop(pair, *is_token0_in, amount, promo_fee_bps, accounts.sender.key())?;
+
+        let next_vault_in = vaults.get(hop + 1).map(|(vault_in, ..)| *vault_in);
+        let to = match next_vault_in {
+            Some(next_vault_in) => next_vault_in.clone(),
+            None => accounts.token_out.to_account_info(),
+        };
+        let next_before = next_vault_in.map(accessor::amount).transpose()?;
+        let (_, vault_out, authority, _, mint_out) = &vaults[hop];
+        transfer_from_vault(
+            accounts.token_program.to_account_info(),
+            (*vault_out).clone(),
+            mint_out,
+            to,
+            (*authority).clone(),
+            pair.key(),
+            pair.authority_bump,
+            amount_out,
+        )?;
+        amount = match (next_vault_in, next_before) {
+            (Some(next_vault_in), Some(before)) => accessor::amount(next_vault_in)?
+                .checked_sub(before)
+                .ok_or(DexError::AmountOverflow)?,
+            _ => amount_out,
+        };
+    }
+
+    require!(
+        amount as u128 >= amount_out_min,
+        DexError::InsufficientOutputAmount
+    );
+
+    // A malformed hop (e.g. a vault drained behind its pair's back) shows as
+    // reserves its vaults no longer cover
+    if verify_hops {
+        for (((pair, is_token0_in), (vault_in, vault_out, ..)), k_before) in
+            pairs.iter().zip(&vaults).zip(&k_before)
+        {
+            let (reserve_in, reserve_out) = if *is_token0_in {
+                (pair.reserve0, pair.reserve1)
+            } else {
+                (pair.reserve1, pair.reserve0)
+            };
+            require!(
+                reserve_in as u128 * reserve_out as u128 >= *k_before
+                    && accessor::amount(vault_in)? >= reserve_in
+                    && accessor::amount(vault_out)? >= reserve_out,
+                DexError::RouteInvariantViolated
+            );
+        }
+    }
+
+    for (pair, _) in pairs.iter_mut() {
+        pair.locked = false;
+        pair.exit(ctx.program_id)?;
+    }
+
+    Ok(())
+}
+
+// Loads a pair passed as [pair, token0_account, token1_account, authority,
+// token0_mint, token1_mint] in `remaining_accounts` and checks it can be swapped through without the
+// extra accounts a cooldown or an external oracle would need
+fn load_hop_pair<'info>(
+    hop: &'info [AccountInfo<'info>],
+    factory: &Pubkey,
+    program_id: &Pubkey,
+) -> Result<Account<'info, PairAccount>> {
+    let (pair_info, vault0, vault1, authority) = (&hop[0], &hop[1], &hop[2], &hop[3]);
+    let pair = Account::<PairAccount>::try_from(pair_info)?;
+    require!(pair.is_initialized, DexError::PairNotInitialized);
+    require!(!pair.pair_paused, DexError::PairPaused);
+    require_keys_eq!(pair.factory, *factory, DexError::InvalidPairFactory);
+    require_keys_eq!(pair.token0_account, vault0.key(), DexError::InvalidTokenAccount);
+    require_keys_eq!(pair.token1_account, vault1.key(), DexError::InvalidTokenAccount);
+    require_keys_eq!(pair.token0, hop[4].key(), DexError::InvalidTokenAccount);
+    require_keys_eq!(pair.token1, hop[5].key(), DexError::InvalidTokenAccount);
+    let expected_authority = Pubkey::create_program_address(
+        &[b"authority", pair_info.key.as_ref(), &[pair.authority_bump]],
+        program_id,
+    )
+    .map_err(|_| error!(DexError::InvalidRoute))?;
+    require_keys_eq!(authority.key(), expected_authority, DexError::InvalidRoute);
+    require!(pair.swap_cooldown_seconds == 0, DexError::TraderStateRequired);
+    require!(pair.external_oracle.is_none(), DexError::InvalidOracleAccount);
+    require!(pair.total_supply > pair.minimum_liquidity, DexError::PoolDrained);
+    Ok(pair)
+}
+
+// Loads a migration source passed as [pair, token0_account, token1_account,
+// lp_mint, liquidity_from, authority, fee_to_account] in `remaining_accounts`,
+// checked like the typed accounts of `remove_liquidity`. The program id in
+// the fee_to_account slot stands for no account.
+fn load_migration_source<'info>(
+    source: &'info [AccountInfo<'info>],
+    factory: &Factory,
+    dest_pair: &Account<'info, PairAccount>,
+    sender: Pubkey,
+    program_id: &Pubkey,
+) -> Result<(Account<'info, PairAccount>, Option<InterfaceAccount<'info, TokenAccount>>)> {
+    let (pair_info, vault0, vault1, lp_mint, authority) =
+        (&source[0], &source[1], &source[2], &source[3], &source[5]);
+    require_keys_neq!(pair_info.key(), dest_pair.key(), DexError::IdenticalPairs);
+    let pair = Account::<PairAccount>::try_from(pair_info)?;
+    require!(pair.is_initialized, DexError::PairNotInitialized);
+    require!(!pair.pair_paused, DexError::PairPaused);
+    require_keys_eq!(pair.factory, dest_pair.factory, DexError::InvalidPairFactory);
+    require!(
+        pair.token0 == dest_pair.token0 && pair.token1 == dest_pair.token1,
+        DexError::MismatchedPairTokens
+    );
+    require_keys_eq!(pair.token0_account, vault0.key(), DexError::InvalidTokenAccount);
+    require_keys_eq!(pair.token1_account, vault1.key(), DexError::InvalidTokenAccount);
+    require_keys_eq!(pair.lp_mint, lp_mint.key(), DexError::InvalidLpMint);
+
+    let liquidity_from = InterfaceAccount::<TokenAccount>::try_from(&source[4])?;
+    require_keys_eq!(liquidity_from.mint, lp_mint.key(), DexError::InvalidTokenAccount);
+    require_keys_eq!(liquidity_from.owner, sender, DexError::InvalidTokenOwner);
+
+    let expected_authority = Pubkey::create_program_address(
+        &[b"authority", pair_info.key.as_ref(), &[pair.authority_bump]],
+        program_id,
+    )
+    .map_err(|_| error!(DexError::InvalidMigration))?;
+    require_keys_eq!(authority.key(), expected_authority, DexError::InvalidMigration);
+
+    let fee_to_account = if source[6].key == program_id {
+        None
+    } else {
+        let fee_to_account = InterfaceAccount::<TokenAccount>::try_from(&source[6])?;
+        require_keys_eq!(fee_to_account.mint, lp_mint.key(), DexError::InvalidTokenAccount);
+        require!(
+            fee_to_account.key() == pair.protocol_fee_account
+                || (pair.protocol_fee_account == Pubkey::default() && fee_to_account.owner == factory.fee_to),
+            DexError::InvalidTokenOwner
+        );
+        Some(fee_to_account)
+    };
+    Ok((pair, fee_to_account))
+}
+
+// Prices `amount_in` on a pair loaded by `load_hop_pair` and books the trade:
+// reserves, swap event and reserve caps. Moving the tokens is up to the caller.
+fn swap_hop(
+    pair: &mut Account<PairAccount>,
+    is_token0_in: bool,
+    amount_in: u64,
+    promo_fee_bps: Option<u16>,
+    sender: Pubkey,
+) -> Result<u64> {
+    let (reserve_in, reserve_out) = if is_token0_in {
+        (pair.reserve0, pair.reserve1)
+    } else {
+        (pair.reserve1, pair.reserve0)
+    };
+    let fee_bps = promo_fee_bps.unwrap_or(pair.swap_fee_bps(amount_in));
+    let amount_out = u64::try_from(amount_out_for(amount_in as u128, reserve_in, reserve_out, fee_bps)?)
+        .map_err(|_| error!(DexError::AmountOverflow))?;
+    pair.check_trade_limits(reserve_in, reserve_out, amount_in, amount_out)?;
+    check_fee_charged(reserve_in, reserve_out, amount_in, amount_out, fee_bps)?;
+
+    let new_reserve_in = reserve_in.checked_add(amount_in).ok_or(DexError::AmountOverflow)?;
+    let new_reserve_out = reserve_out - amount_out;
+    if is_token0_in {
+        pair.reserve0 = new_reserve_in;
+        pair.reserve1 = new_reserve_out;
+    } else {
+        pair.reserve1 = new_reserve_in;
+        pair.reserve0 = new_reserve_out;
+    }
+
+    let (pair_key, reserve0_after, reserve1_after) = (pair.key(), pair.reserve0, pair.reserve1);
+    pair.record_swap(
+        pair_key,
+        SwapEvent {
+            sender,
+            amount_in,
+            amount_out,
+            is_token0_in,
+            reserve0_after,
+            reserve1_after,
+        },
+    );
+    pair.check_reserve_caps(pair_key);
+    Ok(amount_out)
+}
+
+// Hands control to a flash swap borrower. The pair goes first, then every
+// remaining account as passed, so the borrower can sign its repayment with
+// the sender's authority. The pair stays locked for the whole call.
+fn invoke_flash_swap_callback<'info>(
+    pair: &Account<'info, PairAccount>,
+    callback_program: &UncheckedAccount<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+    data: FlashSwapCallbackData,
+) -> Result<()> {
+    let mut accounts = vec![AccountMeta::new_readonly(pair.key(), false)];
+    accounts.extend(remaining_accounts.iter().map(|account| {
+        if account.is_writable {
+            AccountMeta::new(account.key(), account.is_signer)
+        } else {
+            AccountMeta::new_readonly(account.key(), account.is_signer)
+        }
+    }));
+
+    let mut ix_data = anchor_lang::solana_program::hash::hash(b"global:on_flash_swap")
+        .to_bytes()[..8]
+        .to_vec();
+    data.serialize(&mut ix_data)?;
+
+    let mut account_infos = vec![pair.to_account_info()];
+    account_infos.extend_from_slice(remaining_accounts);
+    account_infos.push(callback_program.to_account_info());
+
+    invoke(
+        &Instruction {
+            program_id: callback_program.key(),
+            accounts,
+            data: ix_data,
+        },
+        &account_infos,
+    )?;
+
+    Ok(())
+}
+
+// Moves `amount` out of a pair vault, signed by the pair's authority PDA.
+// `transfer_checked` is the only transfer Token-2022 accepts for mints with
+// extensions, and works the same under the legacy token program.
+#[allow(clippy::too_many_arguments)]
+fn transfer_from_vault<'info>(
+    token_program: AccountInfo<'info>,
+    vault: AccountInfo<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    to: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    pair: Pubkey,
+    authority_bump: u8,
+    amount: u64,
+) -> Result<()> {
+    let authority_seeds = &[b"authority".as_ref(), pair.as_ref(), &[authority_bump]];
+    token_interface::transfer_checked(
+        CpiContext::new_with_signer(
+            token_program,
+            token_interface::TransferChecked {
+                from: vault,
+                mint: mint.to_account_info(),
+                to,
+                authority,
+            },
+            &[authority_seeds],
+        ),
+        amount,
+        mint.decimals,
+    )
+}
+
+// Moves `amount` from a signer's token account into `to`
+fn transfer_from_user<'info>(
+    token_program: AccountInfo<'info>,
+    from: AccountInfo<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    to: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    token_interface::transfer_checked(
+        CpiContext::new(
+            token_program,
+            token_interface::TransferChecked {
+                from,
+                mint: mint.to_account_info(),
+                to,
+                authority,
+            },
+        ),
+        amount,
+        mint.decimals,
+    )
+}
+
+// Mints the accrued protocol fee to the fee_to account, or to the pair's
+// protocol fee account once one is set, ahead of a liquidity event. A zero
+// `k_last` (first deposit, or the fee was off) mints nothing.
+fn mint_fee<'info>(
+    factory: &Factory,
+    pair: &mut Account<'info, PairAccount>,
+    fee_to_account: Option<&InterfaceAccount<'info, TokenAccount>>,
+    lp_mint: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+) -> Result<()> {
+    let liquidity = pair.accrued_protocol_fee(factory)?;
+    if liquidity == 0 {
+        return Ok(());
+    }
+
+    let fee_to_account = fee_to_account.ok_or(DexError::FeeToAccountRequired)?;
+    if pair.protocol_fee_account != Pubkey::default() {
+        require_keys_eq!(
+            fee_to_account.key(),
+            pair.protocol_fee_account,
+            DexError::InvalidTokenOwner
+        );
+    }
+    mint_lp(
+        token_program,
+        lp_mint,
+        fee_to_account.to_account_info(),
+        authority,
+        pair.key(),
+        pair.authority_bump,
+        liquidity,
+    )?;
+    pair.total_supply = pair
+        .total_supply
+        .checked_add(liquidity)
+        .ok_or(DexError::AmountOverflow)?;
+
+    emit!(ProtocolFeeMintedEvent {
+        pair: pair.key(),
+        fee_to_account: fee_to_account.key(),
+        liquidity,
+        k_last: pair.k_last,
+        k: pair.reserve0 as u128 * pair.reserve1 as u128,
+    });
+
+    Ok(())
+}
+
+// Overwrites the reserves with the vault balances, closing the TWAP interval
+// at the old reserves first
+fn sync_reserves(
+    pair: &mut Account<PairAccount>,
+    token0_account: &InterfaceAccount<TokenAccount>,
+    token1_account: &InterfaceAccount<TokenAccount>,
+) -> Result<()> {
+    update_cumulative_prices(pair)?;
+    let (reserve0_before, reserve1_before) = (pair.reserve0, pair.reserve1);
+    pair.reserve0 = token0_account.amount;
+    pair.reserve1 = token1_account.amount;
+
+    emit!(SyncEvent {
+        reserve0_before,
+        reserve1_before,
+        reserve0_after: pair.reserve0,
+        reserve1_after: pair.reserve1,
+        delta0_sign: pair.reserve0.cmp(&reserve0_before) as i8,
+        delta1_sign: pair.reserve1.cmp(&reserve1_before) as i8,
+    });
+
+    Ok(())
+}
+
+// Flooring a withdrawal may only favour the LPs who stay, and by less than
+// one unit: amount * supply <= liquidity * reserve < (amount + 1) * supply
+fn check_withdrawal_rounding(liquidity: u128, reserve: u64, total_supply: u64, amount: u128) -> Result<()> {
+    let share = liquidity
+        .checked_mul(reserve as u128)
+        .ok_or(DexError::AmountOverflow)?;
+    let paid = amount
+        .checked_mul(total_supply as u128)
+        .ok_or(DexError::AmountOverflow)?;
+    require!(
+        paid <= share && share - paid < total_supply as u128,
+        DexError::WithdrawalRounding
+    );
+    Ok(())
+}
+
+// Uniswap's `lock` modifier. The flag is written to the account data right
+// away, so a call re-entering through a token hook already sees it; callers
+// clear it as their last step.
+fn lock_pair(pair: &mut Account<PairAccount>) -> Result<()> {
+    require!(!pair.locked, DexError::Locked);
+    pair.locked = true;
+    pair.exit(&crate::ID)
+}
+
+// TWAP accumulators, advanced before the reserves change: each price (Q64.64,
+// token1 per token0 and the inverse) times the seconds it held. Both the
+// accumulators and the u32 timestamp wrap, so consumers difference two
+// snapshots with wrapping arithmetic. The first call only starts the clock.
+// Every update that advances the clock is also kept as an observation.
+fn update_cumulative_prices(pair: &mut Account<PairAccount>) -> Result<()> {
+    let block_timestamp = Clock::get()?.unix_timestamp as u32;
+    if pair.block_timestamp_last != 0 && block_timestamp == pair.block_timestamp_last {
+        return Ok(());
+    }
+
+    let (price0_cumulative, price1_cumulative) = pair.cumulative_prices_at(block_timestamp);
+    pair.price0_cumulative_last = price0_cumulative;
+    pair.price1_cumulative_last = price1_cumulative;
+    pair.liquidity_cumulative_last = pair.liquidity_cumulative_at(block_timestamp);
+    pair.block_timestamp_last = block_timestamp;
+    pair.record_observation();
+
+    emit!(OracleUpdatedEvent {
+        pair: pair.key(),
+        price0_cumulative_last: pair.price0_cumulative_last,
+        price1_cumulative_last: pair.price1_cumulative_last,
+        liquidity_cumulative_last: pair.liquidity_cumulative_last,
+        block_timestamp_last: block_timestamp,
+    });
+
+    Ok(())
+}
+
+// Oracle accounts carry an 8-byte header followed by the price of token0 in
+// token1 as a little-endian Q64.64 u128
+fn external_oracle_price_x64(oracle: &AccountInfo) -> Result<u128> {
+    let data = oracle.try_borrow_data()?;
+    let price_bytes: [u8; 16] = data
+        .get(8..24)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(DexError::InvalidOracleAccount)?;
+    let price_x64 = u128::from_le_bytes(price_bytes);
+    require!(price_x64 > 0, DexError::InvalidOracleAccount);
+    Ok(price_x64)
+}
+
+// The fee is taken in the scaled swap math, so for a trade that is tiny next
+// to the reserves it can vanish in the rounding of the output: the trader
+// gets exactly what a fee-free swap would pay. Such trades are refused.
+fn check_fee_charged(
+    reserve_in: u64,
+    reserve_out: u64,
+    amount_in: u64,
+    amount_out: u64,
+    fee_bps: u16,
+) -> Result<()> {
+    if fee_bps > 0 {
+        let fee_free_out = amount_out_for(amount_in as u128, reserve_in, reserve_out, 0)?;
+        require!(
+            (amount_out as u128) < fee_free_out,
+            DexError::FeeRoundsToZero
+        );
+    }
+    Ok(())
+}
+
+// Rejects a trade whose price, amount_out / amount_in, is more than
+// `max_impact_bps` below the mid price reserve_out / reserve_in. Compared as
+// amount_out * reserve_in >= amount_in * reserve_out * (1 - max_impact), with
+// the right side floored to whole bps so it stays within u128.
+fn check_price_impact(
+    reserve_in: u64,
+    reserve_out: u64,
+    amount_in: u128,
+    amount_out: u128,
+    max_impact_bps: u16,
+) -> Result<()> {
+    if max_impact_bps == 0 {
+        return Ok(());
+    }
+    let mid_value = amount_in
+        .checked_mul(reserve_out as u128)
+        .ok_or(DexError::AmountOverflow)?;
+    let execution_value = amount_out
+        .checked_mul(reserve_in as u128)
+        .ok_or(DexError::AmountOverflow)?;
+    let floor = (mid_value / FEE_DENOMINATOR)
+        .checked_mul(FEE_DENOMINATOR.saturating_sub(max_impact_bps as u128))
+        .ok_or(DexError::AmountOverflow)?;
+    require!(execution_value >= floor, DexError::ExcessivePriceImpact);
+    Ok(())
+}
+
+// Constant product output for `amount_in` after the fee (e.g. 0.3% fee =
+// multiply the input by 9970 / 10000), rounded down
+fn amount_out_for(amount_in: u128, reserve_in: u64, reserve_out: u64, fee_bps: u16) -> Result<u128> {
+    let amount_in_with_fee = amount_in
+        .checked_mul(FEE_DENOMINATOR - fee_bps as u128)
+        .ok_or(DexError::AmountOverflow)?;
+    let numerator = amount_in_with_fee
+        .checked_mul(reserve_out as u128)
+        .ok_or(DexError::AmountOverflow)?;
+    let denominator = (reserve_in as u128)
+        .checked_mul(FEE_DENOMINATOR)
+        .and_then(|value| value.checked_add(amount_in_with_fee))
+        .ok_or(DexError::AmountOverflow)?;
+    numerator
+        .checked_div(denominator)
+        .ok_or(error!(DexError::InsufficientLiquidity))
+}
+
+// Inverse of the swap formula, rounded up: the least `amount_in` for which
+// amount_in * (D - fee) * reserve_out / (reserve_in * D + amount_in * (D - fee))
+// is at least `amount_out`
+fn amount_in_for(amount_out: u64, reserve_in: u64, reserve_out: u64, fee_bps: u16) -> Result<u64> {
+    let numerator = (reserve_in as u128)
+        .checked_mul(amount_out as u128)
+        .and_then(|value| value.checked_mul(FEE_DENOMINATOR))
+        .ok_or(DexError::AmountOverflow)?;
+    let denominator = ((reserve_out - amount_out) as u128)
+        .checked_mul(FEE_DENOMINATOR - fee_bps as u128)
+        .ok_or(DexError::AmountOverflow)?;
+    u64::try_from(numerator.div_ceil(denominator)).map_err(|_| error!(DexError::AmountOverflow))
+}
+
+// Mints `amount` of a pair's LP token, signed by the pair's authority PDA
+fn mint_lp<'info>(
+    token_program: AccountInfo<'info>,
+    lp_mint: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    pair: Pubkey,
+    authority_bump: u8,
+    amount: u64,
+) -> Result<()> {
+    let authority_seeds = &[b"authority".as_ref(), pair.as_ref(), &[authority_bump]];
+    token_interface::mint_to(
+        CpiContext::new_with_signer(
+            token_program,
+            token_interface::MintTo {
+                mint: lp_mint,
+                to,
+                authority,
+            },
+            &[authority_seeds],
+        ),
+        amount,
+    )
+}
+
+// LP locked on a pair's first deposit. Initial LP is the geometric mean of
+// the deposited amounts, so it carries (decimals0 + decimals1) / 2 decimals.
+// Scaling MINIMUM_LIQUIDITY by that keeps the lock at 0.001 of a whole
+// geometric-mean token for every pair:
+//
+//     minimum = 1000 * 10^((decimals0 + decimals1) / 2 - 6), at least 1
+fn minimum_liquidity_for(decimals0: u8, decimals1: u8) -> Result<u64> {
+    let lp_decimals = ((decimals0 as u32 + decimals1 as u32) / 2) as i32;
+    let shift = lp_decimals - MINIMUM_LIQUIDITY_BASE_DECIMALS as i32;
+
+    if shift >= 0 {
+        10u64
+            .checked_pow(shift as u32)
+            .and_then(|scale| MINIMUM_LIQUIDITY.checked_mul(scale))
+            .ok_or(error!(DexError::AmountOverflow))
+    } else {
+        let scale = 10u64.pow(shift.unsigned_abs());
+        Ok((MINIMUM_LIQUIDITY / scale).max(1))
+    }
+}
+
+// `amount` at `decimals` re-expressed at `other_decimals` when that is the
+// smaller of the two; a shift too large for u128 leaves nothing
+fn scale_down_decimals(amount: u64, decimals: u8, other_decimals: u8) -> u128 {
+    let shift = decimals.saturating_sub(other_decimals) as u32;
+    10u128
+        .checked_pow(shift)
+        .map_or(0, |scale| amount as u128 / scale)
 }
 
 fn sqrt(value: u128) -> u128 {
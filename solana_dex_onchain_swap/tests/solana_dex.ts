@@ -1,16 +1,26 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { SolanaDex } from "../target/types/solana_dex";
+import { MockIntegrations } from "../target/types/mock_integrations";
 import { 
   TOKEN_PROGRAM_ID, 
+  TOKEN_2022_PROGRAM_ID,
+  ASSOCIATED_TOKEN_PROGRAM_ID,
+  ExtensionType,
+  getMintLen,
+  createInitializeMintInstruction,
+  createInitializeTransferFeeConfigInstruction,
   createMint, 
   getAssociatedTokenAddressSync,
   createAssociatedTokenAccount,
+  createAccount,
+  getOrCreateAssociatedTokenAccount,
   getMint,
   getAccount,
-  createMintToInstruction
+  createMintToInstruction,
+  transfer
 } from "@solana/spl-token";
-import { PublicKey, Keypair, SystemProgram, SYSVAR_RENT_PUBKEY } from "@solana/web3.js";
+import { ComputeBudgetProgram, PublicKey, Keypair, SystemProgram, SYSVAR_RENT_PUBKEY } from "@solana/web3.js";
 import { assert } from "chai";
 
 describe("solana_dex", () => {
@@ -19,11 +29,11 @@ describe("solana_dex", () => {
   anchor.setProvider(provider);
 
   const program = anchor.workspace.SolanaDex as Program<SolanaDex>;
+  const mockProgram = anchor.workspace.MockIntegrations as Program<MockIntegrations>;
   const wallet = provider.wallet as anchor.Wallet;
   console.log("Admin Wallet address", wallet.publicKey.toString());
   // Create keypairs for test accounts
   const factoryKeypair = anchor.web3.Keypair.generate();
-  const lpMintKeypair = anchor.web3.Keypair.generate();
   const token0AccountKeypair = anchor.web3.Keypair.generate();
   const token1AccountKeypair = anchor.web3.Keypair.generate();
   
@@ -33,6 +43,7 @@ describe("solana_dex", () => {
   let pairBump: number;
   let authorityPDA: PublicKey;
   let authorityBump: number;
+  let lpMintPDA: PublicKey;
 
   before(async () => {
     // Create two test tokens
@@ -53,9 +64,11 @@ describe("solana_dex", () => {
     );
 
     // Sort tokens to ensure deterministic pair address
-    const [token0Key, token1Key] = token0.toString() < token1.toString() 
+    const [token0Key, token1Key] = Buffer.compare(token0.toBuffer(), token1.toBuffer()) < 0 
       ? [token0, token1] 
       : [token1, token0];
+    // configure_pair expects each vault to hold the mint of its sorted side
+    [token0, token1] = [token0Key, token1Key];
 
     // Derive pair address
     [pairAddress, pairBump] = PublicKey.findProgramAddressSync(
@@ -76,6 +89,15 @@ describe("solana_dex", () => {
       program.programId
     );
 
+    // Derive LP mint PDA
+    [lpMintPDA] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("lp_mint"),
+        pairAddress.toBuffer(),
+      ],
+      program.programId
+    );
+
     console.log("Token0:", token0.toString());
     console.log("Token1:", token1.toString());
     console.log("Pair Address:", pairAddress.toString());
@@ -85,7 +107,7 @@ describe("solana_dex", () => {
   it("Initializes the factory", async () => {
     try {
       const tx = await program.methods
-        .initialize()
+        .initialize(wallet.publicKey)
         .accounts({
           factory: factoryKeypair.publicKey,
           owner: wallet.publicKey,
@@ -102,6 +124,7 @@ describe("solana_dex", () => {
       assert.equal(factoryAccount.owner.toString(), wallet.publicKey.toString());
       assert.equal(factoryAccount.pairCount.toString(), "0");
       assert.equal(factoryAccount.feeOn, false);
+      assert.equal(factoryAccount.migrationAuthority.toString(), wallet.publicKey.toString());
     } catch (error) {
       console.error("Error initializing factory:", error);
       throw error;
@@ -152,7 +175,7 @@ describe("solana_dex", () => {
           pair: pairAddress,
           token0: token0,
           token1: token1,
-          lpMint: lpMintKeypair.publicKey,
+          lpMint: lpMintPDA,
           authority: authorityPDA,
           sender: wallet.publicKey,
           owner: wallet.publicKey,
@@ -160,7 +183,6 @@ describe("solana_dex", () => {
           systemProgram: SystemProgram.programId,
           rent: SYSVAR_RENT_PUBKEY,
         })
-        .signers([lpMintKeypair])
         .rpc({ commitment: 'confirmed' });
       
       console.log("Pair account created transaction signature:", tx);
@@ -170,7 +192,7 @@ describe("solana_dex", () => {
       assert.equal(pairAccount.isInitialized, false);
       
       // Verify LP mint was created correctly
-      const lpMintInfo = await getMint(provider.connection, lpMintKeypair.publicKey);
+      const lpMintInfo = await getMint(provider.connection, lpMintPDA);
       assert.equal(lpMintInfo.mintAuthority.toString(), authorityPDA.toString());
       assert.equal(lpMintInfo.decimals, 8);
     } catch (error) {
@@ -182,13 +204,14 @@ describe("solana_dex", () => {
   it("Configures the pair", async () => {
     try {
       const tx = await program.methods
-        .configurePair()
+        .configurePair(new anchor.BN(0), new anchor.BN(0), new anchor.BN(0))
         .accounts({
           factory: factoryKeypair.publicKey,
           pair: pairAddress,
           token0: token0,
           token1: token1,
-          lpMint: lpMintKeypair.publicKey,
+          lpMint: lpMintPDA,
+          authority: authorityPDA,
           token0Account: token0AccountKeypair.publicKey,
           token1Account: token1AccountKeypair.publicKey,
           sender: wallet.publicKey,
@@ -202,7 +225,7 @@ describe("solana_dex", () => {
       const pairAccount = await program.account.pairAccount.fetch(pairAddress);
       
       assert.equal(pairAccount.factory.toString(), factoryKeypair.publicKey.toString());
-      assert.equal(pairAccount.lpMint.toString(), lpMintKeypair.publicKey.toString());
+      assert.equal(pairAccount.lpMint.toString(), lpMintPDA.toString());
       assert.equal(pairAccount.token0Account.toString(), token0AccountKeypair.publicKey.toString());
       assert.equal(pairAccount.token1Account.toString(), token1AccountKeypair.publicKey.toString());
       assert.equal(pairAccount.reserve0.toString(), "0");
@@ -243,7 +266,7 @@ describe("solana_dex", () => {
       const userLpTokenAccount = await createAssociatedTokenAccount(
         provider.connection,
         wallet.payer,
-        lpMintKeypair.publicKey,
+        lpMintPDA,
         wallet.publicKey
       );
       
@@ -252,7 +275,7 @@ describe("solana_dex", () => {
       const burnLpTokenAccount = await createAssociatedTokenAccount(
         provider.connection,
         wallet.payer,
-        lpMintKeypair.publicKey,
+        lpMintPDA,
         burnAddress
       );
       
@@ -295,18 +318,22 @@ describe("solana_dex", () => {
           amount0Desired,
           amount1Desired,
           amount0Min,
-          amount1Min
+          amount1Min,
+          deadline()
         )
         .accounts({
           factory: factoryKeypair.publicKey,
           pair: pairAddress,
           token0Account: token0AccountKeypair.publicKey,
           token1Account: token1AccountKeypair.publicKey,
+          token0Mint: token0,
+          token1Mint: token1,
           userToken0: userToken0Account,
           userToken1: userToken1Account,
-          lpMint: lpMintKeypair.publicKey,
+          lpMint: lpMintPDA,
           liquidityTo: userLpTokenAccount,
           burnAccount: burnLpTokenAccount,
+          feeToAccount: null,
           authority: authorityPDA,
           sender: wallet.publicKey,
           owner: wallet.publicKey,
@@ -355,6 +382,8 @@ describe("solana_dex", () => {
         assert.isTrue(lpTokenBalance > 0, "User should have received LP tokens");
       }
       
+      await assertReservesMatchVaults(pairAddress, token0AccountKeypair.publicKey, token1AccountKeypair.publicKey);
+      
     } catch (error) {
       console.error("Error adding liquidity:", error);
       throw error;
@@ -375,7 +404,7 @@ describe("solana_dex", () => {
       );
       
       const userLpTokenAccount = getAssociatedTokenAddressSync(
-        lpMintKeypair.publicKey,
+        lpMintPDA,
         wallet.publicKey
       );
       
@@ -424,17 +453,21 @@ describe("solana_dex", () => {
         .removeLiquidity(
           liquidityToRemove,
           amount0Min,
-          amount1Min
+          amount1Min,
+          deadline()
         )
         .accounts({
           factory: factoryKeypair.publicKey,
           pair: pairAddress,
           token0Account: token0AccountKeypair.publicKey,
           token1Account: token1AccountKeypair.publicKey,
+          token0Mint: token0,
+          token1Mint: token1,
           token0To: userToken0Account,
           token1To: userToken1Account,
-          lpMint: lpMintKeypair.publicKey,
+          lpMint: lpMintPDA,
           liquidityFrom: userLpTokenAccount,
+          feeToAccount: null,
           authority: authorityPDA,
           sender: wallet.publicKey,
           owner: wallet.publicKey,
@@ -518,6 +551,8 @@ describe("solana_dex", () => {
         "LP tokens burned should match requested amount"
       );
       
+      await assertReservesMatchVaults(pairAddress, token0AccountKeypair.publicKey, token1AccountKeypair.publicKey);
+      
     } catch (error) {
       console.error("Error removing liquidity:", error);
       throw error;
@@ -576,14 +611,22 @@ describe("solana_dex", () => {
       const tx = await program.methods
         .swap(
           amountIn,
-          amountOutMin
+          amountOutMin,
+          deadline(),
+          0
         )
         .accounts({
+          factory: factoryKeypair.publicKey,
           pair: pairAddress,
           token0Account: token0AccountKeypair.publicKey,
           token1Account: token1AccountKeypair.publicKey,
+          token0Mint: token0,
+          token1Mint: token1,
           tokenIn: userToken0Account,
           tokenOut: userToken1Account,
+          discountToken: null,
+          traderState: null,
+          externalOracle: null,
           authority: authorityPDA,
           sender: wallet.publicKey,
           tokenProgram: TOKEN_PROGRAM_ID,
@@ -607,6 +650,8 @@ describe("solana_dex", () => {
       const userToken0BalanceAfter = await getTokenBalance(provider.connection, userToken0Account);
       const userToken1BalanceAfter = await getTokenBalance(provider.connection, userToken1Account);
       
+      await assertReservesMatchVaults(pairAddress, token0AccountKeypair.publicKey, token1AccountKeypair.publicKey);
+      
       console.log("=== POOL STATE AFTER SWAP ===");
       console.log("Pool reserve0:", pairAfterSwap.reserve0.toString());
       console.log("Pool reserve1:", pairAfterSwap.reserve1.toString());
@@ -686,14 +731,22 @@ describe("solana_dex", () => {
       const txReverse = await program.methods
         .swap(
           amountInReverse,
-          amountOutMinReverse
+          amountOutMinReverse,
+          deadline(),
+          0
         )
         .accounts({
+          factory: factoryKeypair.publicKey,
           pair: pairAddress,
           token0Account: token0AccountKeypair.publicKey,
           token1Account: token1AccountKeypair.publicKey,
+          token0Mint: token0,
+          token1Mint: token1,
           tokenIn: userToken1Account,
           tokenOut: userToken0Account,
+          discountToken: null,
+          traderState: null,
+          externalOracle: null,
           authority: authorityPDA,
           sender: wallet.publicKey,
           tokenProgram: TOKEN_PROGRAM_ID,
@@ -754,29 +807,3813 @@ describe("solana_dex", () => {
         "User should have less token0 after round-trip swap due to fees"
       );
       
+      await assertReservesMatchVaults(pairAddress, token0AccountKeypair.publicKey, token1AccountKeypair.publicKey);
+      
     } catch (error) {
       console.error("Error swapping tokens:", error);
       throw error;
     }
   });
 
-  // Helper functions
-  async function mintToWallet(connection, payer, mint, destination, authority, amount) {
-    const tx = new anchor.web3.Transaction();
-    tx.add(
-      createMintToInstruction(
-        mint,
-        destination,
+  it("Emergency removes liquidity at the proportional share", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 4_000_000_000);
+
+    const pairBefore = await program.account.pairAccount.fetch(pool.pair);
+    const lpBalance = await getTokenBalance(provider.connection, pool.userLp);
+    const liquidity = new anchor.BN(lpBalance / 2);
+
+    const expected0 = liquidity.mul(pairBefore.reserve0).div(pairBefore.totalSupply);
+    const expected1 = liquidity.mul(pairBefore.reserve1).div(pairBefore.totalSupply);
+
+    const user0Before = await getTokenBalance(provider.connection, pool.userToken0);
+    const user1Before = await getTokenBalance(provider.connection, pool.userToken1);
+
+    // The caller's own slippage bounds and deadline still hold
+    const emergencyRemove = (amount0Min, expiry = deadline()) =>
+      program.methods
+        .emergencyRemoveLiquidity(liquidity, amount0Min, new anchor.BN(0), expiry)
+        .accounts(removeLiquidityAccounts(pool));
+    await expectError(emergencyRemove(expected0.addn(1)).rpc(), "InsufficientAmount");
+    await expectError(emergencyRemove(new anchor.BN(0), new anchor.BN(1)).rpc(), "Expired");
+
+    const tx = await emergencyRemove(expected0).rpc({ commitment: 'confirmed' });
+
+    const pairAfter = await program.account.pairAccount.fetch(pool.pair);
+    const user0After = await getTokenBalance(provider.connection, pool.userToken0);
+    const user1After = await getTokenBalance(provider.connection, pool.userToken1);
+
+    assert.equal((user0After - user0Before).toString(), expected0.toString());
+    assert.equal((user1After - user1Before).toString(), expected1.toString());
+    assert.equal(pairAfter.reserve0.toString(), pairBefore.reserve0.sub(expected0).toString());
+    assert.equal(pairAfter.reserve1.toString(), pairBefore.reserve1.sub(expected1).toString());
+    assert.equal(pairAfter.totalSupply.toString(), pairBefore.totalSupply.sub(liquidity).toString());
+
+    const events = await getEvents(tx);
+    const event = events.find((e) => e.name === "emergencyLiquidityRemovedEvent");
+    assert.isDefined(event, "EmergencyLiquidityRemovedEvent should be emitted");
+    assert.equal(event.data.liquidity.toString(), liquidity.toString());
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Emergency removes liquidity from a frozen, capped pool", async () => {
+    const pool = await createPool({ reserveCaps: [2_000_000_000, 0] });
+    await addLiquidity(pool, 2_000_000_000, 1_000_000_000);
+    assert.isTrue((await program.account.pairAccount.fetch(pool.pair)).frozen);
+    await expectError(addLiquidity(pool, 1_000_000, 1_000_000), "PairFrozen");
+
+    // A callback program that can't be reached blocks the normal exit
+    await program.methods
+      .setCallbackProgram(Keypair.generate().publicKey)
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey })
+      .rpc();
+    const lpBalance = await getTokenBalance(provider.connection, pool.userLp);
+    const liquidity = new anchor.BN(lpBalance);
+    await expectError(
+      program.methods
+        .removeLiquidity(liquidity, new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts(removeLiquidityAccounts(pool))
+        .rpc(),
+      "InvalidCallbackProgram"
+    );
+
+    const pairBefore = await program.account.pairAccount.fetch(pool.pair);
+    const expected0 = liquidity.mul(pairBefore.reserve0).div(pairBefore.totalSupply);
+    const expected1 = liquidity.mul(pairBefore.reserve1).div(pairBefore.totalSupply);
+    const user0Before = await getTokenBalance(provider.connection, pool.userToken0);
+    const user1Before = await getTokenBalance(provider.connection, pool.userToken1);
+    await program.methods
+      .emergencyRemoveLiquidity(liquidity, expected0, expected1, deadline())
+      .accounts(removeLiquidityAccounts(pool))
+      .rpc({ commitment: 'confirmed' });
+
+    assert.equal(await getTokenBalance(provider.connection, pool.userLp), 0);
+    assert.equal((await getTokenBalance(provider.connection, pool.userToken0) - user0Before).toString(), expected0.toString());
+    assert.equal((await getTokenBalance(provider.connection, pool.userToken1) - user1Before).toString(), expected1.toString());
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Previews the LP minted by add_liquidity", async () => {
+    const pool = await createPool();
+
+    // First deposit (geometric mean branch), then a second unbalanced one
+    for (const [amount0, amount1] of [[2_000_000_000, 500_000_000], [300_000_000, 900_000_000]]) {
+      const quote = program.methods
+        .quoteAddLiquidity(new anchor.BN(amount0), new anchor.BN(amount1))
+        .accounts({ pair: pool.pair });
+      const preview = await quote.view();
+      const event = (await quote.simulate()).events.find((e) => e.name === "addLiquidityPreviewEvent");
+      assert.equal(event.data.liquidity.toString(), preview.liquidity.toString());
+
+      const lpBefore = await getTokenBalance(provider.connection, pool.userLp);
+      await addLiquidity(pool, amount0, amount1);
+      const lpAfter = await getTokenBalance(provider.connection, pool.userLp);
+
+      assert.equal(preview.liquidity.toString(), (lpAfter - lpBefore).toString());
+      await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+    }
+  });
+
+  it("Locks the protocol fee account and timelocks withdrawals", async () => {
+    const pool = await createPool();
+    const [protocolFeeAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("protocol_fee"), pool.pair.toBuffer()],
+      program.programId
+    );
+    const initAccounts = {
+      factory: factoryKeypair.publicKey,
+      pair: pool.pair,
+      lpMint: pool.lpMint,
+      protocolFeeAccount,
+      authority: pool.authority,
+      owner: wallet.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+
+    await program.methods.initProtocolFeeAccount().accounts(initAccounts).rpc({ commitment: 'confirmed' });
+    let pairAccount = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pairAccount.protocolFeeAccount.toString(), protocolFeeAccount.toString());
+
+    // The fee destination can't be set a second time
+    await expectError(program.methods.initProtocolFeeAccount().accounts(initAccounts).rpc());
+
+    const tx = await program.methods
+      .queueProtocolFeeWithdrawal(new anchor.BN(1))
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, destination: pool.userLp, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    pairAccount = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pairAccount.feeWithdrawalAmount.toString(), "1");
+    assert.equal(pairAccount.feeWithdrawalDestination.toString(), pool.userLp.toString());
+    assert.isTrue(pairAccount.feeWithdrawalUnlockAt.toNumber() > Date.now() / 1000);
+    const event = (await getEvents(tx)).find((e) => e.name === "protocolFeeWithdrawalQueuedEvent");
+    assert.equal(event.data.destination.toString(), pool.userLp.toString());
+
+    const withdrawAccounts = {
+      factory: factoryKeypair.publicKey,
+      pair: pool.pair,
+      protocolFeeAccount,
+      lpMint: pool.lpMint,
+      destination: pool.userLp,
+      authority: pool.authority,
+      owner: wallet.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+    await expectError(program.methods.withdrawProtocolFees().accounts(withdrawAccounts).rpc(), "FeeWithdrawalLocked");
+
+    // The timelock covers the destination too: any other LP account is refused
+    const otherLp = await createAccount(provider.connection, wallet.payer, pool.lpMint, wallet.publicKey, Keypair.generate());
+    await expectError(
+      program.methods
+        .withdrawProtocolFees()
+        .accounts({ ...withdrawAccounts, destination: otherLp })
+        .rpc(),
+      "InvalidFeeWithdrawalDestination"
+    );
+
+    // Destinations that can't hold the pair's LP token can't be queued
+    await expectError(
+      program.methods
+        .queueProtocolFeeWithdrawal(new anchor.BN(1))
+        .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, destination: pool.userToken0, owner: wallet.publicKey })
+        .rpc(),
+      "InvalidTokenAccount"
+    );
+  });
+
+  it("Adds liquidity by total value at the reserve ratio", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 3_000_000_000);
+
+    const pairBefore = await program.account.pairAccount.fetch(pool.pair);
+    const user0Before = await getTokenBalance(provider.connection, pool.userToken0);
+    const user1Before = await getTokenBalance(provider.connection, pool.userToken1);
+
+    const totalValue = new anchor.BN(200_000_000);
+    const addByValue = (expiry) =>
+      program.methods
+        .addLiquidityByValue(totalValue, new anchor.BN(0), new anchor.BN(0), expiry)
+        .accounts(addLiquidityAccounts(pool));
+    await expectError(addByValue(new anchor.BN(1)).rpc(), "Expired");
+    await addByValue(deadline()).rpc({ commitment: 'confirmed' });
+
+    const spent0 = user0Before - (await getTokenBalance(provider.connection, pool.userToken0));
+    const spent1 = user1Before - (await getTokenBalance(provider.connection, pool.userToken1));
+
+    const expected0 = totalValue.divn(2);
+    const expected1 = expected0.mul(pairBefore.reserve1).div(pairBefore.reserve0);
+    assert.equal(spent0.toString(), expected0.toString());
+    assert.equal(spent1.toString(), expected1.toString());
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Rejects a withdrawal larger than the reserves with a clean error", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+
+    // More LP than exists makes the proportional payout exceed the reserves
+    const pairAccount = await program.account.pairAccount.fetch(pool.pair);
+    const liquidity = pairAccount.totalSupply.muln(2);
+
+    await expectError(
+      program.methods
+        .removeLiquidity(liquidity, new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts(removeLiquidityAccounts(pool))
+        .rpc(),
+      "ReserveDesync"
+    );
+
+    const pairAfter = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pairAfter.reserve0.toString(), pairAccount.reserve0.toString());
+    assert.equal(pairAfter.reserve1.toString(), pairAccount.reserve1.toString());
+  });
+
+  it("Notifies the pair's callback program on liquidity changes", async () => {
+    const pool = await createPool();
+    const record = Keypair.generate();
+    await mockProgram.methods
+      .initRecord()
+      .accounts({ record: record.publicKey, payer: wallet.publicKey, systemProgram: SystemProgram.programId })
+      .signers([record])
+      .rpc({ commitment: 'confirmed' });
+
+    await program.methods
+      .setCallbackProgram(mockProgram.programId)
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+
+    const callbackAccounts = [
+      { pubkey: mockProgram.programId, isSigner: false, isWritable: false },
+      { pubkey: record.publicKey, isSigner: false, isWritable: true },
+    ];
+
+    // Without the callback program the deposit is rejected
+    await expectError(addLiquidity(pool, 1_000_000_000, 1_000_000_000), "InvalidCallbackProgram");
+
+    await program.methods
+      .addLiquidity(new anchor.BN(1_000_000_000), new anchor.BN(1_000_000_000), new anchor.BN(0), new anchor.BN(0), deadline())
+      .accounts(addLiquidityAccounts(pool))
+      .remainingAccounts(callbackAccounts)
+      .rpc({ commitment: 'confirmed' });
+
+    let recorded = await mockProgram.account.callbackRecord.fetch(record.publicKey);
+    const lpBalance = await getTokenBalance(provider.connection, pool.userLp);
+    assert.equal(recorded.pair.toString(), pool.pair.toString());
+    assert.equal(recorded.sender.toString(), wallet.publicKey.toString());
+    assert.equal(recorded.liquidity.toString(), lpBalance.toString());
+    assert.equal(recorded.isAdd, true);
+
+    await program.methods
+      .removeLiquidity(new anchor.BN(lpBalance), new anchor.BN(0), new anchor.BN(0), deadline())
+      .accounts(removeLiquidityAccounts(pool))
+      .remainingAccounts(callbackAccounts)
+      .rpc({ commitment: 'confirmed' });
+
+    recorded = await mockProgram.account.callbackRecord.fetch(record.publicKey);
+    assert.equal(recorded.isAdd, false);
+    assert.equal(recorded.count.toString(), "2");
+  });
+
+  it("Rejects swaps against a pair storing the same mint twice", async () => {
+    const mint = await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6);
+    const [pair] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pair"), mint.toBuffer(), mint.toBuffer()],
+      program.programId
+    );
+    const [authority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("authority"), pair.toBuffer()],
+      program.programId
+    );
+    const [lpMint] = PublicKey.findProgramAddressSync(
+      [Buffer.from("lp_mint"), pair.toBuffer()],
+      program.programId
+    );
+    const vault0 = await createAccount(provider.connection, wallet.payer, mint, authority, Keypair.generate());
+    const vault1 = await createAccount(provider.connection, wallet.payer, mint, authority, Keypair.generate());
+
+    await program.methods
+      .createPairAccount()
+      .accounts({
+        factory: factoryKeypair.publicKey,
+        pair,
+        token0: mint,
+        token1: mint,
+        lpMint,
         authority,
-        amount
-      )
+        sender: wallet.publicKey,
+        owner: wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .rpc({ commitment: 'confirmed' });
+    await program.methods
+      .configurePair(new anchor.BN(0), new anchor.BN(0), new anchor.BN(0))
+      .accounts({
+        factory: factoryKeypair.publicKey,
+        pair,
+        token0: mint,
+        token1: mint,
+        lpMint,
+        authority,
+        token0Account: vault0,
+        token1Account: vault1,
+        sender: wallet.publicKey,
+        owner: wallet.publicKey,
+      })
+      .rpc({ commitment: 'confirmed' });
+
+    const userIn = await createAssociatedTokenAccount(provider.connection, wallet.payer, mint, wallet.publicKey);
+    const userOut = await createAccount(provider.connection, wallet.payer, mint, wallet.publicKey, Keypair.generate());
+    await mintToWallet(provider.connection, wallet.payer, mint, userIn, wallet.publicKey, 1_000_000);
+
+    await expectError(
+      program.methods
+        .swap(new anchor.BN(1_000), new anchor.BN(0), deadline(), 0)
+        .accounts({
+          factory: factoryKeypair.publicKey,
+          pair,
+          token0Account: vault0,
+          token1Account: vault1,
+          token0Mint: mint,
+          token1Mint: mint,
+          tokenIn: userIn,
+          tokenOut: userOut,
+          discountToken: null,
+          traderState: null,
+          externalOracle: null,
+          authority,
+          sender: wallet.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc()
     );
-    
-    await provider.sendAndConfirm(tx, [payer]);
-  }
-  
-  async function getTokenBalance(connection, tokenAccount) {
-    const accountInfo = await getAccount(connection, tokenAccount);
-    return parseInt(accountInfo.amount.toString());
+    assert.equal(await getTokenBalance(provider.connection, userIn), 1_000_000);
+  });
+
+  it("Charges the tiered fee matching the swap size", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 10_000_000_000, 10_000_000_000);
+
+    await program.methods
+      .setFeeTiers([{ threshold: new anchor.BN(1_000_000), feeBps: 10 }])
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+
+    // Unsorted tiers are rejected
+    await expectError(
+      program.methods
+        .setFeeTiers([
+          { threshold: new anchor.BN(5_000_000), feeBps: 10 },
+          { threshold: new anchor.BN(1_000_000), feeBps: 20 },
+        ])
+        .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey })
+        .rpc(),
+      "FeeTiersNotSorted"
+    );
+
+    // Below the threshold the base 30 bps applies, at or above it 10 bps
+    for (const [amountIn, feeBps] of [[500_000, 30], [2_000_000, 10]]) {
+      const pairBefore = await program.account.pairAccount.fetch(pool.pair);
+      const expected = getAmountOut(new anchor.BN(amountIn), pairBefore.reserve0, pairBefore.reserve1, feeBps);
+      const before = await getTokenBalance(provider.connection, pool.userToken1);
+
+      await program.methods
+        .swap(new anchor.BN(amountIn), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc({ commitment: 'confirmed' });
+
+      const received = (await getTokenBalance(provider.connection, pool.userToken1)) - before;
+      assert.equal(received.toString(), expected.toString());
+      await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+    }
+  });
+
+  it("Rejects swaps that push the reserve ratio past max_ratio", async () => {
+    const pool = await createPool({ maxRatio: 4 });
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+
+    // Moving to roughly 1.5 : 0.67 stays within a 4x ratio
+    await program.methods
+      .swap(new anchor.BN(500_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+
+    // Another large sell would leave reserve0 more than 4x reserve1
+    const pairBefore = await program.account.pairAccount.fetch(pool.pair);
+    await expectError(
+      program.methods
+        .swap(new anchor.BN(2_000_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc(),
+      "RatioOutOfBounds"
+    );
+    const pairAfter = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pairAfter.reserve0.toString(), pairBefore.reserve0.toString());
+  });
+
+  // Only one pair per token set can be configured, so a destination of the
+  // same tokens can't be staged; this covers the pairing and argument guards.
+  it("Refuses to migrate liquidity into a pair of other tokens", async () => {
+    const source = await createPool();
+    const other = await createPool();
+    await addLiquidity(source, 1_000_000_000, 2_000_000_000);
+    await addLiquidity(other, 1_000_000_000, 1_000_000_000);
+    const liquidity = new anchor.BN(await getTokenBalance(provider.connection, source.userLp));
+
+    const migrate = (dest, amounts = [liquidity], expiry = deadline()) =>
+      program.methods
+        .migrateLiquidity(amounts, new anchor.BN(1), expiry)
+        .accounts({
+          factory: factoryKeypair.publicKey,
+          destPair: dest.pair,
+          destToken0Account: dest.vault0,
+          destToken1Account: dest.vault1,
+          destLpMint: dest.lpMint,
+          liquidityTo: dest.userLp,
+          destAuthority: dest.authority,
+          feeToAccount: null,
+          userToken0: dest.userToken0,
+          userToken1: dest.userToken1,
+          token0Mint: dest.token0,
+          token1Mint: dest.token1,
+          sender: wallet.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts([
+          { pubkey: source.pair, isSigner: false, isWritable: true },
+          { pubkey: source.vault0, isSigner: false, isWritable: true },
+          { pubkey: source.vault1, isSigner: false, isWritable: true },
+          { pubkey: source.lpMint, isSigner: false, isWritable: true },
+          { pubkey: source.userLp, isSigner: false, isWritable: true },
+          { pubkey: source.authority, isSigner: false, isWritable: false },
+          { pubkey: program.programId, isSigner: false, isWritable: false },
+        ])
+        .rpc();
+
+    await expectError(migrate(other), "MismatchedPairTokens");
+    await expectError(migrate(source), "IdenticalPairs");
+    await expectError(migrate(other, [liquidity, liquidity]), "InvalidMigration");
+    await expectError(migrate(other, [liquidity], new anchor.BN(1)), "Expired");
+    assert.equal((await getTokenBalance(provider.connection, source.userLp)).toString(), liquidity.toString());
+    await assertReservesMatchVaults(source.pair, source.vault0, source.vault1);
+  });
+  it("Scales the locked minimum liquidity with token decimals", async () => {
+    const small = await createPool({ decimals0: 6, decimals1: 6 });
+    const large = await createPool({ decimals0: 18, decimals1: 18 });
+    // 18-decimal pools need more than the default funding to clear the minimum
+    await mintToWallet(provider.connection, wallet.payer, large.token0, large.userToken0, wallet.publicKey, 4_000_000_000_000_000);
+    await mintToWallet(provider.connection, wallet.payer, large.token1, large.userToken1, wallet.publicKey, 4_000_000_000_000_000);
+
+    await addLiquidity(small, 1_000_000, 1_000_000);
+    await addLiquidity(large, 4_000_000_000_000_000, 4_000_000_000_000_000);
+
+    const smallPair = await program.account.pairAccount.fetch(small.pair);
+    const largePair = await program.account.pairAccount.fetch(large.pair);
+    assert.equal(smallPair.minimumLiquidity.toString(), "1000");
+    assert.equal(largePair.minimumLiquidity.toString(), "1000000000000000");
+    assert.equal(largePair.token0Decimals, 18);
+    assert.equal(largePair.token1Decimals, 18);
+
+    assert.equal((await getAccount(provider.connection, small.burnLp)).amount.toString(), "1000");
+    assert.equal((await getAccount(provider.connection, large.burnLp)).amount.toString(), "1000000000000000");
+    assert.equal((await getAccount(provider.connection, large.userLp)).amount.toString(), "3000000000000000");
+  });
+
+  it("Dumps the full pair state with live vault balances", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 3_000_000_000);
+
+    const dumpCall = program.methods
+      .dumpPairState()
+      .accounts({
+        pair: pool.pair,
+        token0Account: pool.vault0,
+        token1Account: pool.vault1,
+        lpMint: pool.lpMint,
+      });
+    const dump = await dumpCall.view();
+    const event = (await dumpCall.simulate()).events.find((e) => e.name === "pairStateDumpEvent");
+    assert.equal(event.data.lpMintSupply.toString(), dump.lpMintSupply.toString());
+    assert.equal(JSON.stringify(event.data.state), JSON.stringify(dump.state));
+    const pairAccount = await program.account.pairAccount.fetch(pool.pair);
+
+    for (const field of Object.keys(pairAccount)) {
+      assert.equal(
+        JSON.stringify(dump.state[field]),
+        JSON.stringify(pairAccount[field]),
+        `state.${field} differs from the stored account`
+      );
+    }
+    assert.equal(dump.vault0Balance.toString(), (await getTokenBalance(provider.connection, pool.vault0)).toString());
+    assert.equal(dump.vault1Balance.toString(), (await getTokenBalance(provider.connection, pool.vault1)).toString());
+    assert.equal(dump.lpMintSupply.toString(), (await getMint(provider.connection, pool.lpMint)).supply.toString());
+  });
+
+  it("Charges the discounted fee to holders of the discount token", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 10_000_000_000, 10_000_000_000);
+
+    const discountMint = await createMint(provider.connection, wallet.payer, wallet.publicKey, null, 0);
+    const discountToken = await createAssociatedTokenAccount(provider.connection, wallet.payer, discountMint, wallet.publicKey);
+    await mintToWallet(provider.connection, wallet.payer, discountMint, discountToken, wallet.publicKey, 99);
+    await program.methods
+      .setFeeDiscount(discountMint, new anchor.BN(100), 10)
+      .accounts({ factory: factoryKeypair.publicKey, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+
+    const amountIn = 100_000_000;
+    const swapWithDiscountAccount = async () => {
+      const pair = await program.account.pairAccount.fetch(pool.pair);
+      const before = await getTokenBalance(provider.connection, pool.userToken1);
+      await program.methods
+        .swap(new anchor.BN(amountIn), new anchor.BN(0), deadline(), 0)
+        .accounts({ ...swapAccounts(pool, true), discountToken })
+        .rpc({ commitment: 'confirmed' });
+      const received = (await getTokenBalance(provider.connection, pool.userToken1)) - before;
+      return { pair, received };
+    };
+
+    // Below the threshold: base fee
+    const nonHolder = await swapWithDiscountAccount();
+    assert.equal(
+      nonHolder.received.toString(),
+      getAmountOut(new anchor.BN(amountIn), nonHolder.pair.reserve0, nonHolder.pair.reserve1, 30).toString()
+    );
+
+    // At the threshold: discounted fee, and k still does not decrease
+    await mintToWallet(provider.connection, wallet.payer, discountMint, discountToken, wallet.publicKey, 1);
+    const holder = await swapWithDiscountAccount();
+    assert.equal(
+      holder.received.toString(),
+      getAmountOut(new anchor.BN(amountIn), holder.pair.reserve0, holder.pair.reserve1, 10).toString()
+    );
+    const pairAfter = await program.account.pairAccount.fetch(pool.pair);
+    assert.isTrue(
+      pairAfter.reserve0.mul(pairAfter.reserve1).gte(holder.pair.reserve0.mul(holder.pair.reserve1))
+    );
+
+    await program.methods
+      .setFeeDiscount(PublicKey.default, new anchor.BN(0), 0)
+      .accounts({ factory: factoryKeypair.publicKey, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+  });
+
+  it("Cancels a pending ownership transfer", async () => {
+    const newOwner = Keypair.generate();
+    await program.methods
+      .transferOwnership(newOwner.publicKey)
+      .accounts({ factory: factoryKeypair.publicKey, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    assert.equal(
+      (await program.account.factory.fetch(factoryKeypair.publicKey)).pendingOwner.toString(),
+      newOwner.publicKey.toString()
+    );
+
+    const tx = await program.methods
+      .cancelOwnershipTransfer()
+      .accounts({ factory: factoryKeypair.publicKey, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "ownershipTransferCancelledEvent");
+    assert.equal(event.data.pendingOwner.toString(), newOwner.publicKey.toString());
+
+    await expectError(
+      program.methods
+        .acceptOwnership()
+        .accounts({ factory: factoryKeypair.publicKey, pendingOwner: newOwner.publicKey })
+        .signers([newOwner])
+        .rpc(),
+      "NoPendingOwner"
+    );
+    const factory = await program.account.factory.fetch(factoryKeypair.publicKey);
+    assert.equal(factory.owner.toString(), wallet.publicKey.toString());
+    assert.equal(factory.pendingOwner.toString(), PublicKey.default.toString());
+  });
+
+  it("Hands factory ownership over in two steps", async () => {
+    const newOwner = Keypair.generate();
+    const startTx = await program.methods
+      .transferOwnership(newOwner.publicKey)
+      .accounts({ factory: factoryKeypair.publicKey, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    const started = (await getEvents(startTx)).find((e) => e.name === "ownershipTransferStartedEvent");
+    assert.equal(started.data.owner.toString(), wallet.publicKey.toString());
+    assert.equal(started.data.pendingOwner.toString(), newOwner.publicKey.toString());
+
+    // Only the pending owner can accept
+    await expectError(
+      program.methods
+        .acceptOwnership()
+        .accounts({ factory: factoryKeypair.publicKey, pendingOwner: wallet.publicKey })
+        .rpc(),
+      "NotPendingOwner"
+    );
+
+    const acceptTx = await program.methods
+      .acceptOwnership()
+      .accounts({ factory: factoryKeypair.publicKey, pendingOwner: newOwner.publicKey })
+      .signers([newOwner])
+      .rpc({ commitment: 'confirmed' });
+    const accepted = (await getEvents(acceptTx)).find((e) => e.name === "ownershipTransferAcceptedEvent");
+    assert.equal(accepted.data.previousOwner.toString(), wallet.publicKey.toString());
+    assert.equal(accepted.data.newOwner.toString(), newOwner.publicKey.toString());
+    let factory = await program.account.factory.fetch(factoryKeypair.publicKey);
+    assert.equal(factory.owner.toString(), newOwner.publicKey.toString());
+    assert.equal(factory.pendingOwner.toString(), PublicKey.default.toString());
+
+    // Hand it back so the remaining tests keep administering the factory
+    await program.methods
+      .transferOwnership(wallet.publicKey)
+      .accounts({ factory: factoryKeypair.publicKey, owner: newOwner.publicKey })
+      .signers([newOwner])
+      .rpc({ commitment: 'confirmed' });
+    await program.methods
+      .acceptOwnership()
+      .accounts({ factory: factoryKeypair.publicKey, pendingOwner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    factory = await program.account.factory.fetch(factoryKeypair.publicKey);
+    assert.equal(factory.owner.toString(), wallet.publicKey.toString());
+  });
+
+  it("Lets a controller program manage the pair and rejects direct calls", async () => {
+    const pool = await createPool();
+    const [controllerSigner] = PublicKey.findProgramAddressSync(
+      [Buffer.from("controller"), pool.pair.toBuffer()],
+      mockProgram.programId
+    );
+    await program.methods
+      .setController(mockProgram.programId)
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+
+    const tiers = [{ threshold: new anchor.BN(1_000_000), feeBps: 5 }];
+
+    // The factory owner can no longer change the pair directly
+    await expectError(
+      program.methods
+        .setFeeTiers(tiers)
+        .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey })
+        .rpc(),
+      "NotController"
+    );
+
+    // The controller's PDA signs the same instruction through a CPI
+    const ix = await program.methods
+      .setFeeTiers(tiers)
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: controllerSigner })
+      .instruction();
+    await mockProgram.methods
+      .controllerInvoke(ix.data)
+      .accounts({ pair: pool.pair, dexProgram: program.programId })
+      .remainingAccounts(ix.keys.map((key) => ({ ...key, isSigner: false })))
+      .rpc({ commitment: 'confirmed' });
+
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.feeTierCount, 1);
+    assert.equal(pair.feeTiers[0].feeBps, 5);
+    assert.equal(pair.controller.toString(), mockProgram.programId.toString());
+  });
+
+  it("Computes the swap that aligns the pool with an external price", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 10_000_000_000, 10_000_000_000);
+
+    // Token0 is worth 1.1 token1 externally, 1.0 in the pool
+    const targetPriceX64 = new anchor.BN(2).pow(new anchor.BN(64)).muln(11).divn(10);
+    const quoteCall = program.methods
+      .priceAfterExternalMove(targetPriceX64)
+      .accounts({ pair: pool.pair });
+    const quote = await quoteCall.view();
+    assert.isFalse(quote.zeroForOne);
+    const event = (await quoteCall.simulate()).events.find((e) => e.name === "arbitrageQuoteEvent");
+    assert.equal(event.data.amountIn.toString(), quote.amountIn.toString());
+
+    await program.methods
+      .swap(quote.amountIn, new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, false))
+      .rpc({ commitment: 'confirmed' });
+
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    const price = pair.reserve1.toNumber() / pair.reserve0.toNumber();
+    assert.approximately(price, 1.1, 1.1 * 0.001);
+  });
+
+  it("Reports the resulting reserves in liquidity and swap events", async () => {
+    const pool = await createPool();
+    const assertReservesAfter = async (tx: string, eventName: string) => {
+      const event = (await getEvents(tx)).find((e) => e.name === eventName);
+      const pair = await program.account.pairAccount.fetch(pool.pair);
+      assert.equal(event.data.reserve0After.toString(), pair.reserve0.toString(), `${eventName} reserve0After`);
+      assert.equal(event.data.reserve1After.toString(), pair.reserve1.toString(), `${eventName} reserve1After`);
+      await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+    };
+
+    await assertReservesAfter(await addLiquidity(pool, 1_000_000_000, 2_000_000_000), "liquidityAddedEvent");
+
+    const swapTx = await program.methods
+      .swap(new anchor.BN(10_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+    await assertReservesAfter(swapTx, "swapEvent");
+
+    const lpBalance = await getTokenBalance(provider.connection, pool.userLp);
+    const removeTx = await program.methods
+      .removeLiquidity(new anchor.BN(lpBalance / 2), new anchor.BN(0), new anchor.BN(0), deadline())
+      .accounts(removeLiquidityAccounts(pool))
+      .rpc({ commitment: 'confirmed' });
+    await assertReservesAfter(removeTx, "liquidityRemovedEvent");
+  });
+
+  it("Enforces the per-signer swap cooldown", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    await program.methods
+      .setSwapCooldown(2)
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+
+    const [traderState] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), pool.pair.toBuffer(), wallet.publicKey.toBuffer()],
+      program.programId
+    );
+    const swap = () =>
+      program.methods
+        .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts({ ...swapAccounts(pool, true), traderState })
+        .rpc({ commitment: 'confirmed' });
+
+    // Without the trader state the cooldown can't be tracked
+    await expectError(
+      program.methods
+        .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc(),
+      "TraderStateRequired"
+    );
+
+    await program.methods
+      .initTraderState()
+      .accounts({ pair: pool.pair, sender: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    await swap();
+    await expectError(swap(), "CooldownActive");
+
+    await new Promise((resolve) => setTimeout(resolve, 3_000));
+    await swap();
+  });
+
+  it("Applies the promotional fee only inside its window", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 10_000_000_000, 10_000_000_000);
+
+    const chainNow = async () => provider.connection.getBlockTime(await provider.connection.getSlot());
+    const promoUntil = (await chainNow()) + 5;
+    await program.methods
+      .startPromo(0, new anchor.BN(promoUntil))
+      .accounts({ factory: factoryKeypair.publicKey, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+
+    const swapAndCheck = async (feeBps: number) => {
+      const amountIn = new anchor.BN(100_000_000);
+      const pairBefore = await program.account.pairAccount.fetch(pool.pair);
+      const before = await getTokenBalance(provider.connection, pool.userToken1);
+      await program.methods
+        .swap(amountIn, new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc({ commitment: 'confirmed' });
+      const received = (await getTokenBalance(provider.connection, pool.userToken1)) - before;
+      assert.equal(
+        received.toString(),
+        getAmountOut(amountIn, pairBefore.reserve0, pairBefore.reserve1, feeBps).toString()
+      );
+      const pairAfter = await program.account.pairAccount.fetch(pool.pair);
+      assert.isTrue(pairAfter.reserve0.mul(pairAfter.reserve1).gte(pairBefore.reserve0.mul(pairBefore.reserve1)));
+      await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+    };
+
+    await swapAndCheck(0);
+    while ((await chainNow()) <= promoUntil) {
+      await new Promise((resolve) => setTimeout(resolve, 1_000));
+    }
+    await swapAndCheck(30);
+  });
+
+  it("Refuses to configure a pair with a foreign LP mint", async () => {
+    // Only the pair's own LP mint PDA is accepted, whatever its authority
+    const foreignLpMint = await createMint(provider.connection, wallet.payer, wallet.publicKey, null, 8);
+    await expectError(createPool({ lpMint: foreignLpMint }), "ConstraintSeeds");
+  });
+
+  it("Distributes LP staking rewards over time", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+
+    const rewardMint = await createMint(provider.connection, wallet.payer, wallet.publicKey, null, 6);
+    const userReward = await createAssociatedTokenAccount(provider.connection, wallet.payer, rewardMint, wallet.publicKey);
+    const [rewardPool] = PublicKey.findProgramAddressSync([Buffer.from("reward_pool"), pool.pair.toBuffer()], program.programId);
+    const [stakedLpVault] = PublicKey.findProgramAddressSync([Buffer.from("reward_lp_vault"), pool.pair.toBuffer()], program.programId);
+    const [rewardVault] = PublicKey.findProgramAddressSync([Buffer.from("reward_vault"), pool.pair.toBuffer()], program.programId);
+    const [position] = PublicKey.findProgramAddressSync(
+      [Buffer.from("stake"), rewardPool.toBuffer(), wallet.publicKey.toBuffer()],
+      program.programId
+    );
+
+    const rewardRate = 1_000;
+    await program.methods
+      .initRewardPool(new anchor.BN(rewardRate))
+      .accounts({
+        factory: factoryKeypair.publicKey,
+        pair: pool.pair,
+        lpMint: pool.lpMint,
+        rewardMint,
+        rewardPool,
+        stakedLpVault,
+        rewardVault,
+        authority: pool.authority,
+        owner: wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc({ commitment: 'confirmed' });
+    await mintToWallet(provider.connection, wallet.payer, rewardMint, rewardVault, wallet.publicKey, 1_000_000_000);
+
+    const staked = Math.floor((await getTokenBalance(provider.connection, pool.userLp)) / 2);
+    await program.methods
+      .stakeLp(new anchor.BN(staked))
+      .accounts({
+        rewardPool,
+        stakedLpVault,
+        lpMint: pool.lpMint,
+        position,
+        userLp: pool.userLp,
+        sender: wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc({ commitment: 'confirmed' });
+    const stakedAt = (await program.account.rewardPool.fetch(rewardPool)).lastUpdateAt.toNumber();
+
+    await new Promise((resolve) => setTimeout(resolve, 3_000));
+
+    await program.methods
+      .claimRewards()
+      .accounts({
+        pair: pool.pair,
+        rewardPool,
+        rewardVault,
+        rewardMint,
+        position,
+        userReward,
+        authority: pool.authority,
+        sender: wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc({ commitment: 'confirmed' });
+    const claimedAt = (await program.account.rewardPool.fetch(rewardPool)).lastUpdateAt.toNumber();
+    assert.isAbove(claimedAt, stakedAt);
+
+    // Sole staker earns the full emission, less at most one unit of rounding
+    const claimed = await getTokenBalance(provider.connection, userReward);
+    const expected = rewardRate * (claimedAt - stakedAt);
+    assert.isAtMost(claimed, expected);
+    assert.isAtLeast(claimed, expected - 1);
+
+    const lpBefore = await getTokenBalance(provider.connection, pool.userLp);
+    await program.methods
+      .unstakeLp(new anchor.BN(staked))
+      .accounts({
+        pair: pool.pair,
+        rewardPool,
+        stakedLpVault,
+        lpMint: pool.lpMint,
+        position,
+        userLp: pool.userLp,
+        authority: pool.authority,
+        sender: wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc({ commitment: 'confirmed' });
+    assert.equal((await getTokenBalance(provider.connection, pool.userLp)) - lpBefore, staked);
+    assert.equal((await program.account.rewardPool.fetch(rewardPool)).totalStaked.toString(), "0");
+  });
+
+  it("Rejects pairs built on another pair's LP mint", async () => {
+    const pool = await createPool();
+    const other = await createMint(provider.connection, wallet.payer, wallet.publicKey, null, 6);
+
+    assert.isTrue((await program.account.factory.fetch(factoryKeypair.publicKey)).rejectLpPairTokens);
+    await expectError(createPool({ mints: [pool.lpMint, other] }), "LpMintAsPairToken");
+  });
+
+  it("Classifies a pair's canonical accounts", async () => {
+    const pool = await createPool();
+    const cases: [PublicKey, number][] = [
+      [pool.vault0, 1],
+      [pool.vault1, 2],
+      [pool.authority, 3],
+      [pool.lpMint, 4],
+      [pool.userToken0, 0],
+      [Keypair.generate().publicKey, 0],
+    ];
+
+    for (const [candidate, role] of cases) {
+      const verify = program.methods
+        .verifyPairAccount(candidate)
+        .accounts({ pair: pool.pair });
+      const result = await verify.view();
+      assert.equal(result, role, `role of ${candidate.toString()}`);
+      const event = (await verify.simulate()).events.find((e) => e.name === "accountRoleEvent");
+      assert.equal(event.data.role, role, `emitted role of ${candidate.toString()}`);
+    }
+  });
+
+  it("Rejects swaps once only the locked minimum liquidity remains", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+
+    const lpBalance = await getTokenBalance(provider.connection, pool.userLp);
+    await program.methods
+      .removeLiquidity(new anchor.BN(lpBalance), new anchor.BN(0), new anchor.BN(0), deadline())
+      .accounts(removeLiquidityAccounts(pool))
+      .rpc({ commitment: 'confirmed' });
+
+    const drained = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(drained.totalSupply.toString(), drained.minimumLiquidity.toString());
+    assert.isTrue(drained.reserve0.gtn(0) && drained.reserve1.gtn(0));
+
+    const userBefore = await getTokenBalance(provider.connection, pool.userToken0);
+    await expectError(
+      program.methods
+        .swap(new anchor.BN(1_000), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc(),
+      "PoolDrained"
+    );
+    assert.equal(await getTokenBalance(provider.connection, pool.userToken0), userBefore);
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Updates the base fee on several pairs in one transaction", async () => {
+    const pools = [await createPool(), await createPool(), await createPool()];
+
+    const tx = await program.methods
+      .setFeesBatch(25)
+      .accounts({ factory: factoryKeypair.publicKey, owner: wallet.publicKey })
+      .remainingAccounts(pools.map((pool) => ({ pubkey: pool.pair, isSigner: false, isWritable: true })))
+      .rpc({ commitment: 'confirmed' });
+
+    const events = (await getEvents(tx)).filter((e) => e.name === "pairFeeChangedEvent");
+    assert.equal(events.length, pools.length);
+    for (const [i, pool] of pools.entries()) {
+      assert.equal((await program.account.pairAccount.fetch(pool.pair)).feeBps, 25);
+      assert.equal(events[i].data.pair.toString(), pool.pair.toString());
+      assert.equal(events[i].data.oldFeeBps, 30);
+    }
+  });
+
+  it("Rejects an output minimum the reserves can never satisfy", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const pairBefore = await program.account.pairAccount.fetch(pool.pair);
+    const userBefore = await getTokenBalance(provider.connection, pool.userToken0);
+
+    await expectError(
+      program.methods
+        .swap(new anchor.BN(1_000_000), pairBefore.reserve1, deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc(),
+      "ImpossibleOutputMinimum"
+    );
+
+    const pairAfter = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pairAfter.reserve0.toString(), pairBefore.reserve0.toString());
+    assert.equal(pairAfter.reserve1.toString(), pairBefore.reserve1.toString());
+    assert.equal(await getTokenBalance(provider.connection, pool.userToken0), userBefore);
+  });
+
+  it("Configures the pair's fee parameters in one instruction", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey };
+
+    await expectError(
+      program.methods.configurePairFees(1_001, 0, new anchor.BN(0)).accounts(configAccounts).rpc(),
+      "InvalidFee"
+    );
+    await expectError(
+      program.methods.configurePairFees(30, 10_001, new anchor.BN(0)).accounts(configAccounts).rpc(),
+      "InvalidMaxOutput"
+    );
+
+    await program.methods
+      .configurePairFees(50, 100, new anchor.BN(10_000))
+      .accounts(configAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.feeBps, 50);
+    assert.equal(pair.maxOutBps, 100);
+    assert.equal(pair.minTradeAmount.toString(), "10000");
+
+    // Below the minimum input, then above the 1% output cap
+    await expectError(
+      program.methods.swap(new anchor.BN(9_999), new anchor.BN(0), deadline(), 0).accounts(swapAccounts(pool, true)).rpc(),
+      "TradeTooSmall"
+    );
+    await expectError(
+      program.methods.swap(new anchor.BN(20_000_000), new anchor.BN(0), deadline(), 0).accounts(swapAccounts(pool, true)).rpc(),
+      "MaxOutputExceeded"
+    );
+  });
+
+  it("Mints no protocol fee while the fee switch is off", async () => {
+    const pool = await createPool();
+    const factoryAccounts = { factory: factoryKeypair.publicKey, owner: wallet.publicKey };
+
+    const setTx = await program.methods
+      .setFeeTo(wallet.publicKey)
+      .accounts(factoryAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const setEvents = await getEvents(setTx);
+    assert.equal(setEvents[0].name, "feeToUpdatedEvent");
+    assert.equal(setEvents[0].data.feeTo.toString(), wallet.publicKey.toString());
+    assert.equal((await program.account.factory.fetch(factoryKeypair.publicKey)).feeTo.toString(), wallet.publicKey.toString());
+
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    await program.methods
+      .swap(new anchor.BN(50_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+    const supplyBefore = (await program.account.pairAccount.fetch(pool.pair)).totalSupply;
+    const tx = await addLiquidity(pool, 1_000_000, 1_000_000);
+
+    const events = await getEvents(tx);
+    assert.isUndefined(events.find((e) => e.name === "protocolFeeMintedEvent"));
+    const added = events.find((e) => e.name === "liquidityAddedEvent");
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.kLast.toString(), "0");
+    assert.equal(pair.totalSupply.toString(), supplyBefore.add(added.data.liquidity).toString());
+  });
+
+  it("Quotes the exact input needed for a target output", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const amountOut = new anchor.BN(10_000_000);
+
+    const quote = program.methods
+      .getAmountIn(amountOut, pool.token1)
+      .accounts({ pair: pool.pair });
+    const amountIn = await quote.view();
+    const event = (await quote.simulate()).events.find((e) => e.name === "amountInEvent");
+    assert.equal(event.data.amountIn.toString(), amountIn.toString());
+
+    // One unit less falls short of the target
+    await expectError(
+      program.methods
+        .swap(amountIn.subn(1), amountOut, deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc(),
+      "InsufficientOutputAmount"
+    );
+
+    // The quoted input clears the target as the minimum and pays exactly it
+    const outBefore = await getTokenBalance(provider.connection, pool.userToken1);
+    await program.methods
+      .swap(amountIn, amountOut, deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+    const received = (await getTokenBalance(provider.connection, pool.userToken1)) - outBefore;
+    assert.equal(received.toString(), amountOut.toString());
+
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    await expectError(
+      program.methods.getAmountIn(pair.reserve1, pool.token1).accounts({ pair: pool.pair }).simulate(),
+      "InsufficientLiquidity"
+    );
+  });
+
+  it("Caps the keeper bounty and refuses to collect an unaccrued protocol fee", async () => {
+    const factoryAccounts = { factory: factoryKeypair.publicKey, owner: wallet.publicKey };
+    await expectError(
+      program.methods.setKeeperBounty(101).accounts(factoryAccounts).rpc(),
+      "InvalidKeeperBounty"
+    );
+    const tx = await program.methods
+      .setKeeperBounty(50)
+      .accounts(factoryAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const events = await getEvents(tx);
+    assert.equal(events[0].name, "keeperBountyUpdatedEvent");
+    assert.equal(events[0].data.keeperBountyBps, 50);
+    assert.equal((await program.account.factory.fetch(factoryKeypair.publicKey)).keeperBountyBps, 50);
+
+    // With the fee switch off nothing accrues, whoever calls
+    await program.methods.setFeeTo(wallet.publicKey).accounts(factoryAccounts).rpc();
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    await program.methods
+      .swap(new anchor.BN(50_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+    const keeper = Keypair.generate();
+    const keeperLp = await createAssociatedTokenAccount(provider.connection, wallet.payer, pool.lpMint, keeper.publicKey);
+    await expectError(
+      program.methods
+        .collectProtocolFees()
+        .accounts(collectProtocolFeesAccounts(pool, keeper.publicKey, keeperLp))
+        .signers([keeper])
+        .rpc(),
+      "NoProtocolFeeAccrued"
+    );
+  });
+
+  it("Accrues the protocol fee once the fee switch is on", async () => {
+    const factoryAccounts = { factory: factoryKeypair.publicKey, owner: wallet.publicKey };
+
+    // No recipient, no switch
+    await program.methods.setFeeTo(PublicKey.default).accounts(factoryAccounts).rpc();
+    await expectError(
+      program.methods.setFeeOn(true).accounts(factoryAccounts).rpc(),
+      "FeeToNotSet"
+    );
+
+    await program.methods.setFeeTo(wallet.publicKey).accounts(factoryAccounts).rpc();
+    await program.methods.setKeeperBounty(100).accounts(factoryAccounts).rpc();
+    const tx = await program.methods
+      .setFeeOn(true)
+      .accounts(factoryAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const events = await getEvents(tx);
+    assert.equal(events[0].name, "feeSwitchToggledEvent");
+    assert.equal(events[0].data.enabled, true);
+
+    try {
+      const pool = await createPool();
+      await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+      assert.equal((await program.account.pairAccount.fetch(pool.pair)).kLast.toString(), "1000000000000000000");
+
+      for (const zeroForOne of [true, false, true]) {
+        await program.methods
+          .swap(new anchor.BN(100_000_000), new anchor.BN(0), deadline(), 0)
+          .accounts(swapAccounts(pool, zeroForOne))
+          .rpc({ commitment: 'confirmed' });
+      }
+
+      // Liquidity events can't skip the fee once it is due
+      await expectError(
+        program.methods
+          .removeLiquidity(new anchor.BN(1_000), new anchor.BN(0), new anchor.BN(0), deadline())
+          .accounts(removeLiquidityAccounts(pool))
+          .rpc(),
+        "FeeToAccountRequired"
+      );
+
+      // An arbitrary keeper collects and keeps 1% of the minted LP
+      const keeper = Keypair.generate();
+      const keeperLp = await createAssociatedTokenAccount(provider.connection, wallet.payer, pool.lpMint, keeper.publicKey);
+      const feeToBefore = await getTokenBalance(provider.connection, pool.userLp);
+      const pairBefore = await program.account.pairAccount.fetch(pool.pair);
+      const collectTx = await program.methods
+        .collectProtocolFees()
+        .accounts(collectProtocolFeesAccounts(pool, keeper.publicKey, keeperLp))
+        .signers([keeper])
+        .rpc({ commitment: 'confirmed' });
+
+      const collected = (await getEvents(collectTx)).find((e) => e.name === "protocolFeesCollectedEvent");
+      const minted = collected.data.feeToAmount.add(collected.data.bounty);
+      assert.isTrue(collected.data.feeToAmount.gtn(0));
+      assert.equal(collected.data.bounty.toString(), minted.muln(100).divn(10_000).toString());
+      assert.equal(collected.data.keeper.toString(), keeper.publicKey.toString());
+      assert.equal(
+        (await getTokenBalance(provider.connection, pool.userLp)) - feeToBefore,
+        collected.data.feeToAmount.toNumber()
+      );
+      assert.equal(await getTokenBalance(provider.connection, keeperLp), collected.data.bounty.toNumber());
+
+      const pairAfter = await program.account.pairAccount.fetch(pool.pair);
+      assert.equal(pairAfter.totalSupply.toString(), pairBefore.totalSupply.add(minted).toString());
+      assert.equal(pairAfter.kLast.toString(), pairAfter.reserve0.mul(pairAfter.reserve1).toString());
+
+      await expectError(
+        program.methods
+          .collectProtocolFees()
+          .accounts(collectProtocolFeesAccounts(pool, keeper.publicKey, keeperLp))
+          .signers([keeper])
+          .rpc(),
+        "NoProtocolFeeAccrued"
+      );
+    } finally {
+      // The other tests add and remove liquidity without a fee_to account
+      await program.methods.setFeeOn(false).accounts(factoryAccounts).rpc({ commitment: 'confirmed' });
+    }
+  });
+
+  it("Derives the LP mint from the pair address", async () => {
+    const pool = await createPool();
+    const [expected] = PublicKey.findProgramAddressSync(
+      [Buffer.from("lp_mint"), pool.pair.toBuffer()],
+      program.programId
+    );
+
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.lpMint.toString(), expected.toString());
+    const lpMintInfo = await getMint(provider.connection, expected);
+    assert.equal(lpMintInfo.mintAuthority.toString(), pool.authority.toString());
+    assert.equal(lpMintInfo.decimals, 8);
+  });
+
+  it("Rejects trades and liquidity changes past their deadline", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const expired = deadline(-60);
+
+    await expectError(
+      program.methods
+        .swap(new anchor.BN(1_000_000), new anchor.BN(0), expired, 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc(),
+      "Expired"
+    );
+    await expectError(
+      program.methods
+        .addLiquidity(new anchor.BN(1_000_000), new anchor.BN(1_000_000), new anchor.BN(0), new anchor.BN(0), expired)
+        .accounts(addLiquidityAccounts(pool))
+        .rpc(),
+      "Expired"
+    );
+    await expectError(
+      program.methods
+        .removeLiquidity(new anchor.BN(1_000), new anchor.BN(0), new anchor.BN(0), expired)
+        .accounts(removeLiquidityAccounts(pool))
+        .rpc(),
+      "Expired"
+    );
+
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.reserve0.toString(), "1000000000");
+    assert.equal(pair.reserve1.toString(), "1000000000");
+  });
+
+  it("Creates a missing output ATA when removing liquidity", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+
+    // A second LP with SOL for rent, an LP balance and only a token0 ATA
+    const user = Keypair.generate();
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        SystemProgram.transfer({ fromPubkey: wallet.publicKey, toPubkey: user.publicKey, lamports: 100_000_000 })
+      )
+    );
+    const userLp = await createAssociatedTokenAccount(provider.connection, wallet.payer, pool.lpMint, user.publicKey);
+    await transfer(provider.connection, wallet.payer, pool.userLp, userLp, wallet.publicKey, 10_000_000);
+    const userToken0 = await createAssociatedTokenAccount(provider.connection, wallet.payer, pool.token0, user.publicKey);
+    const userToken1 = getAssociatedTokenAddressSync(pool.token1, user.publicKey);
+    assert.isNull(await provider.connection.getAccountInfo(userToken1));
+
+    const tx = await program.methods
+      .removeLiquidityInitAccounts(new anchor.BN(10_000_000), new anchor.BN(0), new anchor.BN(0), deadline())
+      .accounts({
+        outputs: {
+          token0Mint: pool.token0,
+          token1Mint: pool.token1,
+          sender: user.publicKey,
+          token0To: userToken0,
+          token1To: userToken1,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        },
+        remove: {
+          ...removeLiquidityAccounts(pool),
+          token0To: userToken0,
+          token1To: userToken1,
+          liquidityFrom: userLp,
+          sender: user.publicKey,
+        },
+      })
+      .signers([user])
+      .rpc({ commitment: 'confirmed' });
+
+    const removed = (await getEvents(tx)).find((e) => e.name === "liquidityRemovedEvent");
+    const created = await getAccount(provider.connection, userToken1);
+    assert.equal(created.owner.toString(), user.publicKey.toString());
+    assert.equal(created.amount.toString(), removed.data.amount1.toString());
+    assert.equal(await getTokenBalance(provider.connection, userToken0), removed.data.amount0.toNumber());
+    assert.equal(await getTokenBalance(provider.connection, userLp), 0);
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Floors the spot price to the pair's tick size", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_234_567_891);
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    const rawPrice = pair.reserve1.shln(64).div(pair.reserve0);
+    const tickedPrice = async () => {
+      const call = program.methods.spotPriceTicked().accounts({ pair: pool.pair });
+      const ticked = await call.view();
+      const event = (await call.simulate()).events.find((e) => e.name === "tickedPriceEvent");
+      assert.equal(event.data.priceX64.toString(), ticked.priceX64.toString());
+      return ticked;
+    };
+
+    // Default tick of 1 leaves the price untouched
+    assert.equal(pair.tickSizeX64.toString(), "1");
+    assert.equal((await tickedPrice()).priceX64.toString(), rawPrice.toString());
+
+    const tick = new anchor.BN(1).shln(56);
+    await program.methods
+      .setTickSize(tick)
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    const ticked = await tickedPrice();
+    assert.equal(ticked.tick.toString(), tick.toString());
+    assert.equal(ticked.priceX64.toString(), rawPrice.sub(rawPrice.mod(tick)).toString());
+    assert.isTrue(ticked.priceX64.lt(rawPrice));
+  });
+
+  it("Returns the same amount out as the executed swap", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 3_000_000_007);
+
+    for (const zeroForOne of [true, false]) {
+      const amountIn = new anchor.BN(123_456_789);
+      const quoted = await program.methods
+        .getAmountOut(amountIn, zeroForOne ? pool.token0 : pool.token1)
+        .accounts({ pair: pool.pair })
+        .view();
+
+      const outAccount = zeroForOne ? pool.userToken1 : pool.userToken0;
+      const before = await getTokenBalance(provider.connection, outAccount);
+      await program.methods
+        .swap(amountIn, quoted, deadline(), 0)
+        .accounts(swapAccounts(pool, zeroForOne))
+        .rpc({ commitment: 'confirmed' });
+      const received = (await getTokenBalance(provider.connection, outAccount)) - before;
+      assert.equal(received.toString(), quoted.toString());
+    }
+  });
+
+  it("Force-closes a pair that was never configured", async () => {
+    const mintA = await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6);
+    const mintB = await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6);
+    const [mint0, mint1] = Buffer.compare(mintA.toBuffer(), mintB.toBuffer()) < 0 ? [mintA, mintB] : [mintB, mintA];
+    const [pair] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pair"), mint0.toBuffer(), mint1.toBuffer()],
+      program.programId
+    );
+    const [authority] = PublicKey.findProgramAddressSync([Buffer.from("authority"), pair.toBuffer()], program.programId);
+    const [lpMint] = PublicKey.findProgramAddressSync([Buffer.from("lp_mint"), pair.toBuffer()], program.programId);
+    const [lpMintMarker] = PublicKey.findProgramAddressSync([Buffer.from("lp_mint"), lpMint.toBuffer()], program.programId);
+
+    await program.methods
+      .createPairAccount()
+      .accounts({
+        factory: factoryKeypair.publicKey,
+        pair,
+        token0: mint0,
+        token1: mint1,
+        lpMint,
+        authority,
+        sender: wallet.publicKey,
+        owner: wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .rpc({ commitment: 'confirmed' });
+    const rent = (await provider.connection.getAccountInfo(pair)).lamports
+      + (await provider.connection.getAccountInfo(lpMintMarker)).lamports;
+
+    const balanceBefore = await provider.connection.getBalance(wallet.publicKey, 'confirmed');
+    const tx = await program.methods
+      .forceCloseUnconfiguredPair()
+      .accounts({ factory: factoryKeypair.publicKey, pair, lpMint, lpMintMarker, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    const balanceAfter = await provider.connection.getBalance(wallet.publicKey, 'confirmed');
+    const txDetails = await provider.connection.getTransaction(tx, { commitment: 'confirmed', maxSupportedTransactionVersion: 0 });
+
+    assert.isNull(await provider.connection.getAccountInfo(pair));
+    assert.isNull(await provider.connection.getAccountInfo(lpMintMarker));
+    assert.equal(balanceAfter - balanceBefore, rent - txDetails.meta.fee);
+    const event = (await getEvents(tx)).find((e) => e.name === "unconfiguredPairClosedEvent");
+    assert.equal(event.data.pair.toString(), pair.toString());
+
+    // Configured pairs can't be closed this way
+    const pool = await createPool();
+    await expectError(
+      program.methods
+        .forceCloseUnconfiguredPair()
+        .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, lpMint: pool.lpMint, owner: wallet.publicKey })
+        .rpc(),
+      "PairAlreadyInitialized"
+    );
+  });
+
+  it("Swaps for an exact output amount", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 2_000_000_000);
+    const amountOut = new anchor.BN(25_000_000);
+    const quotedIn = await program.methods
+      .getAmountIn(amountOut, pool.token1)
+      .accounts({ pair: pool.pair })
+      .view();
+
+    await expectError(
+      program.methods
+        .swapTokensForExactTokens(amountOut, quotedIn.subn(1), deadline())
+        .accounts(swapAccounts(pool, true))
+        .rpc(),
+      "ExcessiveInputAmount"
+    );
+
+    const in0Before = await getTokenBalance(provider.connection, pool.userToken0);
+    const out1Before = await getTokenBalance(provider.connection, pool.userToken1);
+    const tx = await program.methods
+      .swapTokensForExactTokens(amountOut, quotedIn, deadline())
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+
+    assert.equal((await getTokenBalance(provider.connection, pool.userToken1)) - out1Before, amountOut.toNumber());
+    assert.equal(in0Before - (await getTokenBalance(provider.connection, pool.userToken0)), quotedIn.toNumber());
+    const event = (await getEvents(tx)).find((e) => e.name === "swapEvent");
+    assert.equal(event.data.amountIn.toString(), quotedIn.toString());
+    assert.equal(event.data.amountOut.toString(), amountOut.toString());
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Rejects a swap that lacks the compute budget to finish", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const in0Before = await getTokenBalance(provider.connection, pool.userToken0);
+
+    await expectError(
+      program.methods
+        .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .preInstructions([ComputeBudgetProgram.setComputeUnitLimit({ units: 50_000 })])
+        .rpc(),
+      "InsufficientComputeBudget"
+    );
+    assert.equal(await getTokenBalance(provider.connection, pool.userToken0), in0Before);
+
+    await program.methods
+      .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc();
+    assert.equal(await getTokenBalance(provider.connection, pool.userToken0), in0Before - 1_000_000);
+  });
+
+  it("Syncs reserves to tokens donated to the vaults", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    await transfer(provider.connection, wallet.payer, pool.userToken0, pool.vault0, wallet.publicKey, 250_000_000);
+
+    const before = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(before.reserve0.toString(), "1000000000");
+
+    const tx = await program.methods
+      .sync()
+      .accounts({ pair: pool.pair, token0Account: pool.vault0, token1Account: pool.vault1 })
+      .rpc({ commitment: 'confirmed' });
+
+    const event = (await getEvents(tx)).find((e) => e.name === "syncEvent");
+    assert.equal(event.data.reserve0Before.toString(), "1000000000");
+    assert.equal(event.data.reserve0After.toString(), "1250000000");
+    assert.equal(event.data.reserve1After.toString(), "1000000000");
+    assert.equal(event.data.delta0Sign, 1);
+    assert.equal(event.data.delta1Sign, 0);
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Skims donated tokens to a recipient without touching reserves", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    await transfer(provider.connection, wallet.payer, pool.userToken0, pool.vault0, wallet.publicKey, 250_000_000);
+
+    const recipient = Keypair.generate();
+    const to0 = await createAssociatedTokenAccount(provider.connection, wallet.payer, pool.token0, recipient.publicKey);
+    const to1 = await createAssociatedTokenAccount(provider.connection, wallet.payer, pool.token1, recipient.publicKey);
+    const skimAccounts = {
+      pair: pool.pair,
+      token0Account: pool.vault0,
+      token1Account: pool.vault1,
+      token0Mint: pool.token0,
+      token1Mint: pool.token1,
+      token0To: to0,
+      token1To: to1,
+      authority: pool.authority,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    await expectError(
+      program.methods.skim(wallet.publicKey).accounts(skimAccounts).rpc(),
+      "InvalidTokenOwner"
+    );
+
+    const tx = await program.methods
+      .skim(recipient.publicKey)
+      .accounts(skimAccounts)
+      .rpc({ commitment: 'confirmed' });
+
+    assert.equal(await getTokenBalance(provider.connection, to0), 250_000_000);
+    assert.equal(await getTokenBalance(provider.connection, to1), 0);
+    const event = (await getEvents(tx)).find((e) => e.name === "skimEvent");
+    assert.equal(event.data.amount0.toString(), "250000000");
+    assert.equal(event.data.amount1.toString(), "0");
+    const pairAccount = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pairAccount.reserve0.toString(), "1000000000");
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Rejects swaps that move the pool price away from the external oracle", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+
+    const oracle = Keypair.generate();
+    await mockProgram.methods
+      .initMockOracle(new anchor.BN(1).shln(64))
+      .accounts({ oracle: oracle.publicKey, payer: wallet.publicKey, systemProgram: SystemProgram.programId })
+      .signers([oracle])
+      .rpc({ commitment: 'confirmed' });
+
+    const tx = await program.methods
+      .setExternalOracle(oracle.publicKey, 100)
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "externalOracleUpdatedEvent");
+    assert.equal(event.data.externalOracle.toBase58(), oracle.publicKey.toBase58());
+    assert.equal(event.data.maxOracleDeviationBps, 100);
+
+    await expectError(
+      program.methods
+        .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc(),
+      "InvalidOracleAccount"
+    );
+
+    // ~10% price move against a 1% band
+    await expectError(
+      program.methods
+        .swap(new anchor.BN(50_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts({ ...swapAccounts(pool, true), externalOracle: oracle.publicKey })
+        .rpc(),
+      "OracleDeviationTooHigh"
+    );
+
+    const out1Before = await getTokenBalance(provider.connection, pool.userToken1);
+    await program.methods
+      .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts({ ...swapAccounts(pool, true), externalOracle: oracle.publicKey })
+      .rpc();
+    assert.isAbove(await getTokenBalance(provider.connection, pool.userToken1), out1Before);
+  });
+
+  it("Runs a pair through its full lifecycle", async () => {
+    const factory = Keypair.generate();
+    await program.methods
+      .initialize(wallet.publicKey)
+      .accounts({
+        factory: factory.publicKey,
+        owner: wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([factory])
+      .rpc({ commitment: 'confirmed' });
+    const pool = await createPool({ factory: factory.publicKey });
+    assert.equal((await program.account.factory.fetch(factory.publicKey)).pairCount.toString(), "1");
+
+    // Reserves track the vaults, the stored supply tracks the mint, and k never shrinks
+    let lastK = BigInt(0);
+    const checkInvariants = async () => {
+      await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+      const pair = await program.account.pairAccount.fetch(pool.pair);
+      const lpSupply = (await getMint(provider.connection, pool.lpMint)).supply;
+      assert.equal(pair.totalSupply.toString(), lpSupply.toString());
+      assert.isFalse(pair.locked);
+      const k = BigInt(pair.reserve0.toString()) * BigInt(pair.reserve1.toString());
+      return { pair, k };
+    };
+
+    // First deposit: sqrt(amount0 * amount1), with the minimum locked away
+    let events = await getEvents(await addLiquidity(pool, 1_000_000_000, 4_000_000_000));
+    let added = events.find((e) => e.name === "liquidityAddedEvent");
+    let { pair, k } = await checkInvariants();
+    const minimumLiquidity = pair.minimumLiquidity.toNumber();
+    assert.equal(pair.totalSupply.toString(), "2000000000");
+    assert.equal(added.data.liquidity.toNumber(), 2_000_000_000 - minimumLiquidity);
+    assert.equal(added.data.reserve0After.toString(), "1000000000");
+    assert.equal(added.data.reserve1After.toString(), "4000000000");
+    assert.equal(await getTokenBalance(provider.connection, pool.userLp), 2_000_000_000 - minimumLiquidity);
+
+    // A proportional second deposit mints a proportional share
+    events = await getEvents(await addLiquidity(pool, 500_000_000, 2_000_000_000));
+    added = events.find((e) => e.name === "liquidityAddedEvent");
+    ({ pair, k } = await checkInvariants());
+    assert.equal(added.data.liquidity.toString(), "1000000000");
+    assert.equal(pair.totalSupply.toString(), "3000000000");
+    assert.equal(pair.reserve0.toString(), "1500000000");
+    assert.equal(pair.reserve1.toString(), "6000000000");
+
+    // Swaps in both directions pay out exactly the quoted amount and never shrink k
+    const swaps: [boolean, number][] = [[true, 10_000_000], [false, 80_000_000], [true, 3_000_000], [false, 5_000_000]];
+    for (const [zeroForOne, amountIn] of swaps) {
+      const before = pair;
+      const kBefore = k;
+      const tokenOut = zeroForOne ? pool.userToken1 : pool.userToken0;
+      const outBefore = await getTokenBalance(provider.connection, tokenOut);
+      const tx = await program.methods
+        .swap(new anchor.BN(amountIn), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, zeroForOne))
+        .rpc({ commitment: 'confirmed' });
+      const swapped = (await getEvents(tx)).find((e) => e.name === "swapEvent");
+      ({ pair, k } = await checkInvariants());
+
+      const [reserveIn, reserveOut] = zeroForOne ? [before.reserve0, before.reserve1] : [before.reserve1, before.reserve0];
+      assert.equal(swapped.data.isToken0In, zeroForOne);
+      assert.equal(swapped.data.amountIn.toNumber(), amountIn);
+      assert.equal(swapped.data.amountOut.toString(), getAmountOut(new anchor.BN(amountIn), reserveIn, reserveOut).toString());
+      assert.equal((await getTokenBalance(provider.connection, tokenOut)) - outBefore, swapped.data.amountOut.toNumber());
+      assert.equal(swapped.data.reserve0After.toString(), pair.reserve0.toString());
+      assert.equal(swapped.data.reserve1After.toString(), pair.reserve1.toString());
+      assert.equal(pair.totalSupply.toString(), "3000000000");
+      assert.isTrue(k >= kBefore, "k decreased");
+    }
+
+    // A partial withdrawal pays a pro-rata share of the current reserves
+    const beforePartial = pair;
+    const partial = new anchor.BN(1_000_000_000);
+    events = await getEvents(
+      await program.methods
+        .removeLiquidity(partial, new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts(removeLiquidityAccounts(pool))
+        .rpc({ commitment: 'confirmed' })
+    );
+    let removed = events.find((e) => e.name === "liquidityRemovedEvent");
+    ({ pair } = await checkInvariants());
+    assert.equal(removed.data.amount0.toString(), beforePartial.reserve0.mul(partial).div(beforePartial.totalSupply).toString());
+    assert.equal(removed.data.amount1.toString(), beforePartial.reserve1.mul(partial).div(beforePartial.totalSupply).toString());
+    assert.equal(removed.data.reserve0After.toString(), pair.reserve0.toString());
+    assert.equal(pair.totalSupply.toString(), "2000000000");
+
+    // Withdrawing the rest leaves only the locked minimum behind
+    const rest = await getTokenBalance(provider.connection, pool.userLp);
+    events = await getEvents(
+      await program.methods
+        .removeLiquidity(new anchor.BN(rest), new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts(removeLiquidityAccounts(pool))
+        .rpc({ commitment: 'confirmed' })
+    );
+    removed = events.find((e) => e.name === "liquidityRemovedEvent");
+    ({ pair } = await checkInvariants());
+    assert.equal(removed.data.liquidity.toNumber(), rest);
+    assert.equal(pair.totalSupply.toNumber(), minimumLiquidity);
+    assert.equal(await getTokenBalance(provider.connection, pool.userLp), 0);
+    assert.isAbove(pair.reserve0.toNumber(), 0);
+    assert.isAbove(pair.reserve1.toNumber(), 0);
+  });
+
+  it("Prices a deposit against the supply diluted by the protocol fee", async () => {
+    const factoryAccounts = { factory: factoryKeypair.publicKey, owner: wallet.publicKey };
+    await program.methods.setFeeTo(wallet.publicKey).accounts(factoryAccounts).rpc();
+    await program.methods.setFeeOn(true).accounts(factoryAccounts).rpc();
+
+    try {
+      const pool = await createPool();
+      await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+      for (const zeroForOne of [true, false]) {
+        await program.methods
+          .swap(new anchor.BN(200_000_000), new anchor.BN(0), deadline(), 0)
+          .accounts(swapAccounts(pool, zeroForOne))
+          .rpc({ commitment: 'confirmed' });
+      }
+
+      // token0 binds, so the deposit mints amount0 * supply / reserve0
+      const before = await program.account.pairAccount.fetch(pool.pair);
+      const amount0 = new anchor.BN(100_000_000);
+      const tx = await program.methods
+        .addLiquidity(amount0, amount0.muln(10), new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts({ ...addLiquidityAccounts(pool), feeToAccount: pool.userLp })
+        .rpc({ commitment: 'confirmed' });
+      const events = await getEvents(tx);
+      const feeMinted = events.find((e) => e.name === "protocolFeeMintedEvent");
+      const added = events.find((e) => e.name === "liquidityAddedEvent");
+      assert.isTrue(feeMinted.data.liquidity.gtn(0));
+
+      const dilutedSupply = before.totalSupply.add(feeMinted.data.liquidity);
+      assert.equal(added.data.liquidity.toString(), amount0.mul(dilutedSupply).div(before.reserve0).toString());
+      assert.isTrue(added.data.liquidity.gt(amount0.mul(before.totalSupply).div(before.reserve0)));
+
+      const after = await program.account.pairAccount.fetch(pool.pair);
+      assert.equal(after.totalSupply.toString(), dilutedSupply.add(added.data.liquidity).toString());
+    } finally {
+      await program.methods.setFeeOn(false).accounts(factoryAccounts).rpc();
+    }
+  });
+
+  it("Accumulates time-weighted prices for a TWAP oracle", async () => {
+    const pool = await createPool();
+
+    // The first update only starts the clock
+    let events = await getEvents(await addLiquidity(pool, 1_000_000_000, 4_000_000_000));
+    const first = events.find((e) => e.name === "oracleUpdatedEvent");
+    assert.equal(first.data.price0CumulativeLast.toString(), "0");
+    assert.equal(first.data.price1CumulativeLast.toString(), "0");
+    assert.isAbove(first.data.blockTimestampLast, 0);
+
+    await new Promise((resolve) => setTimeout(resolve, 2_000));
+    const tx = await program.methods
+      .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+    events = await getEvents(tx);
+    const updated = events.find((e) => e.name === "oracleUpdatedEvent");
+
+    // Pre-swap prices: 4 token1 per token0 and 0.25 token0 per token1
+    const elapsed = updated.data.blockTimestampLast - first.data.blockTimestampLast;
+    assert.isAbove(elapsed, 0);
+    assert.equal(updated.data.price0CumulativeLast.toString(), new anchor.BN(4).shln(64).muln(elapsed).toString());
+    assert.equal(updated.data.price1CumulativeLast.toString(), new anchor.BN(1).shln(62).muln(elapsed).toString());
+
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.price0CumulativeLast.toString(), updated.data.price0CumulativeLast.toString());
+    assert.equal(pair.blockTimestampLast, updated.data.blockTimestampLast);
+  });
+
+  it("Rejects deposits while the spot price is far from the TWAP", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey };
+
+    await expectError(
+      program.methods.setDepositDeviation(10_001).accounts(configAccounts).rpc(),
+      "InvalidDepositDeviation"
+    );
+    const tx = await program.methods.setDepositDeviation(1_000).accounts(configAccounts).rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "depositDeviationUpdatedEvent");
+    assert.equal(event.data.maxDepositDeviationBps, 1_000);
+
+    // History is a single observation of the 1:1 price, so the check is off
+    await addLiquidity(pool, 1_000_000, 1_000_000);
+
+    // Skew the pool to a quarter of its price; the TWAP still mostly reflects 1:1
+    await new Promise((resolve) => setTimeout(resolve, 2_000));
+    await program.methods
+      .swap(new anchor.BN(1_000_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+    await expectError(addLiquidity(pool, 1_000_000, 1_000_000), "PriceDeviationTooHigh");
+
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.isAbove(pair.observationCount, 1);
+
+    await program.methods.setDepositDeviation(0).accounts(configAccounts).rpc();
+    await addLiquidity(pool, 1_000_000, 1_000_000);
+  });
+
+  it("Seeds a new pair's oracle from an external price", async () => {
+    const pool = await createPool();
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey };
+    const seedPriceX64 = new anchor.BN(2).shln(64);
+
+    await expectError(
+      program.methods.configurePairWithOracleSeed(seedPriceX64).accounts(configAccounts).rpc(),
+      "InsufficientLiquidity"
+    );
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    await expectError(
+      program.methods.configurePairWithOracleSeed(new anchor.BN(0)).accounts(configAccounts).rpc(),
+      "InvalidSeedPrice"
+    );
+    const tx = await program.methods.configurePairWithOracleSeed(seedPriceX64).accounts(configAccounts).rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "oracleSeededEvent");
+    assert.equal(event.data.price0CumulativeLast.toString(), seedPriceX64.muln(3_600).toString());
+
+    // The hour of seeded history outweighs the 1:1 spot since
+    const consult = await program.methods.consult(60).accounts({ pair: pool.pair }).view();
+    assert.isAtLeast(consult.window, 3_600);
+    const price0Pct = consult.price0AverageX64.muln(100).div(seedPriceX64).toNumber();
+    assert.isAtLeast(price0Pct, 99);
+    assert.isAtMost(price0Pct, 100);
+    const price1Pct = consult.price1AverageX64.muln(200).shrn(64).toNumber();
+    assert.isAtLeast(price1Pct, 100);
+    assert.isAtMost(price1Pct, 101);
+
+    await expectError(
+      program.methods.consult(7_200).accounts({ pair: pool.pair }).simulate(),
+      "OracleHistoryTooShort"
+    );
+    await expectError(
+      program.methods.configurePairWithOracleSeed(seedPriceX64).accounts(configAccounts).rpc(),
+      "OracleAlreadyStarted"
+    );
+  });
+
+  it("Tracks time-weighted liquidity depth alongside prices", async () => {
+    const pool = await createPool();
+
+    // sqrt(k) steps from 1e9 to 2e9 to 3e9, one observation per step
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    await new Promise((resolve) => setTimeout(resolve, 2_000));
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    await new Promise((resolve) => setTimeout(resolve, 2_000));
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.observationCount, 3);
+    const [t0, t1, t2] = pair.observations.slice(0, 3).map((observation) => observation.timestamp);
+    assert.equal(
+      pair.liquidityCumulativeLast.toString(),
+      new anchor.BN(1_000_000_000).muln(t1 - t0).add(new anchor.BN(2_000_000_000).muln(t2 - t1)).toString()
+    );
+
+    // Reaches back to the first observation; anything since the last one held 3e9
+    const consult = await program.methods.consultLiquidity(t2 - t0).accounts({ pair: pool.pair }).view();
+    const sinceLast = consult.window - (t2 - t0);
+    assert.isAtLeast(sinceLast, 0);
+    const expected = new anchor.BN(1_000_000_000).muln(t1 - t0)
+      .add(new anchor.BN(2_000_000_000).muln(t2 - t1))
+      .add(new anchor.BN(3_000_000_000).muln(sinceLast))
+      .divn(consult.window);
+    assert.equal(consult.liquidityAverage.toString(), expected.toString());
+    assert.isAbove(consult.liquidityAverage.toNumber(), 1_000_000_000);
+    assert.isBelow(consult.liquidityAverage.toNumber(), 3_000_000_000);
+  });
+
+  it("Routes a swap through two pairs", async () => {
+    const [mintA, mintB, mintC] = await Promise.all(
+      [0, 1, 2].map(() => createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6))
+    );
+    const poolAB = await createPool({ mints: [mintA, mintB] });
+    const poolBC = await createPool({ mints: [mintB, mintC] });
+    await addLiquidity(poolAB, 1_000_000_000, 1_000_000_000);
+    await addLiquidity(poolBC, 1_000_000_000, 2_000_000_000);
+
+    const hopAccounts = (pools) =>
+      pools.flatMap((pool) => [
+        { pubkey: pool.pair, isSigner: false, isWritable: true },
+        { pubkey: pool.vault0, isSigner: false, isWritable: true },
+        { pubkey: pool.vault1, isSigner: false, isWritable: true },
+        { pubkey: pool.authority, isSigner: false, isWritable: false },
+        { pubkey: pool.token0, isSigner: false, isWritable: false },
+        { pubkey: pool.token1, isSigner: false, isWritable: false },
+      ]);
+    const reservesFor = async (pool, mintIn: PublicKey) => {
+      const pair = await program.account.pairAccount.fetch(pool.pair);
+      return pair.token0.equals(mintIn) ? [pair.reserve0, pair.reserve1] : [pair.reserve1, pair.reserve0];
+    };
+    const userA = getAssociatedTokenAddressSync(mintA, wallet.publicKey);
+    const userC = getAssociatedTokenAddressSync(mintC, wallet.publicKey);
+    const routeAccounts = {
+      factory: factoryKeypair.publicKey,
+      tokenIn: userA,
+      tokenOut: userC,
+      sender: wallet.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    const amountIn = new anchor.BN(10_000_000);
+    const [reserveA, reserveB1] = await reservesFor(poolAB, mintA);
+    const [reserveB2, reserveC] = await reservesFor(poolBC, mintB);
+    const amountB = getAmountOut(amountIn, reserveA, reserveB1);
+    const amountC = getAmountOut(amountB, reserveB2, reserveC);
+
+    // Hops out of order don't chain A->B->C
+    await expectError(
+      program.methods
+        .swapExactTokensForTokens(amountIn, new anchor.BN(0), deadline())
+        .accounts(routeAccounts)
+        .remainingAccounts(hopAccounts([poolBC, poolAB]))
+        .rpc(),
+      "InvalidRoute"
+    );
+
+    // Only the final output is held to the minimum
+    await expectError(
+      program.methods
+        .swapExactTokensForTokens(amountIn, amountC.addn(1), deadline())
+        .accounts(routeAccounts)
+        .remainingAccounts(hopAccounts([poolAB, poolBC]))
+        .rpc(),
+      "InsufficientOutputAmount"
+    );
+
+    const aBefore = await getTokenBalance(provider.connection, userA);
+    const bBefore = await getTokenBalance(provider.connection, getAssociatedTokenAddressSync(mintB, wallet.publicKey));
+    const cBefore = await getTokenBalance(provider.connection, userC);
+    const tx = await program.methods
+      .swapExactTokensForTokens(amountIn, amountC, deadline())
+      .accounts(routeAccounts)
+      .remainingAccounts(hopAccounts([poolAB, poolBC]))
+      .rpc({ commitment: 'confirmed' });
+
+    assert.equal(aBefore - (await getTokenBalance(provider.connection, userA)), amountIn.toNumber());
+    assert.equal(await getTokenBalance(provider.connection, getAssociatedTokenAddressSync(mintB, wallet.publicKey)), bBefore);
+    assert.equal((await getTokenBalance(provider.connection, userC)) - cBefore, amountC.toNumber());
+    const swaps = (await getEvents(tx)).filter((e) => e.name === "swapEvent");
+    assert.equal(swaps.length, 2);
+    assert.equal(swaps[0].data.amountOut.toString(), amountB.toString());
+    assert.equal(swaps[1].data.amountIn.toString(), amountB.toString());
+    await assertReservesMatchVaults(poolAB.pair, poolAB.vault0, poolAB.vault1);
+    await assertReservesMatchVaults(poolBC.pair, poolBC.vault0, poolBC.vault1);
+  });
+
+  it("Reports a pair's settings as a flag bitmask", async () => {
+    const pool = await createPool();
+    const readFlags = async () => {
+      const call = program.methods.getPairFlags().accounts({ pair: pool.pair });
+      const flags = await call.view();
+      const event = (await call.simulate()).events.find((e) => e.name === "pairFlagsEvent");
+      assert.equal(event.data.flags, flags);
+      return flags;
+    };
+    // PAIR_FLAG_* bit positions
+    const INITIALIZED = 1 << 0;
+    const ORACLE_ENABLED = 1 << 2;
+    const COOLDOWN = 1 << 6;
+
+    assert.equal(await readFlags(), INITIALIZED);
+
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey };
+    await program.methods.setExternalOracle(Keypair.generate().publicKey, 100).accounts(configAccounts).rpc();
+    await program.methods.setSwapCooldown(30).accounts(configAccounts).rpc();
+    assert.equal(await readFlags(), INITIALIZED | ORACLE_ENABLED | COOLDOWN);
+
+    await program.methods.setExternalOracle(null, 0).accounts(configAccounts).rpc();
+    assert.equal(await readFlags(), INITIALIZED | COOLDOWN);
+  });
+
+  it("Donates tokens to a pool's LPs without minting LP", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const before = await program.account.pairAccount.fetch(pool.pair);
+    const lpSupplyBefore = (await getMint(provider.connection, pool.lpMint)).supply;
+
+    const tx = await program.methods
+      .donate(new anchor.BN(100_000_000), new anchor.BN(50_000_000))
+      .accounts({
+        pair: pool.pair,
+        token0Account: pool.vault0,
+        token1Account: pool.vault1,
+        token0Mint: pool.token0,
+        token1Mint: pool.token1,
+        donorToken0: pool.userToken0,
+        donorToken1: pool.userToken1,
+        donor: wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc({ commitment: 'confirmed' });
+
+    const event = (await getEvents(tx)).find((e) => e.name === "donationEvent");
+    assert.equal(event.data.amount0.toString(), "100000000");
+    assert.equal(event.data.reserve0After.toString(), "1100000000");
+    assert.equal(event.data.reserve1After.toString(), "1050000000");
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+
+    // Same LP supply backed by more of both tokens
+    const after = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(after.totalSupply.toString(), before.totalSupply.toString());
+    assert.equal((await getMint(provider.connection, pool.lpMint)).supply, lpSupplyBefore);
+    assert.isTrue(after.reserve0.mul(after.reserve1).gt(before.reserve0.mul(before.reserve1)));
+  });
+
+  it("Lets the factory owner retune a pair's fee", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey };
+
+    await expectError(
+      program.methods.setPairFee(1_001).accounts(configAccounts).rpc(),
+      "InvalidFee"
+    );
+
+    const stranger = Keypair.generate();
+    await expectError(
+      program.methods
+        .setPairFee(5)
+        .accounts({ ...configAccounts, owner: stranger.publicKey })
+        .signers([stranger])
+        .rpc(),
+      "NotFactoryOwner"
+    );
+
+    const tx = await program.methods.setPairFee(5).accounts(configAccounts).rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "pairFeeChangedEvent");
+    assert.equal(event.data.oldFeeBps, 30);
+    assert.equal(event.data.newFeeBps, 5);
+
+    const amountIn = new anchor.BN(1_000_000);
+    const before = await program.account.pairAccount.fetch(pool.pair);
+    const out1Before = await getTokenBalance(provider.connection, pool.userToken1);
+    await program.methods
+      .swap(amountIn, new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+    assert.equal(
+      (await getTokenBalance(provider.connection, pool.userToken1)) - out1Before,
+      getAmountOut(amountIn, before.reserve0, before.reserve1, 5).toNumber()
+    );
+  });
+
+  // No instruction writes a wrong bump, so a corrupted pair can't be staged on
+  // a live validator; this covers the access and healthy-pair guards.
+  it("Refuses to rewrite the bumps of a healthy pair", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const repairAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, migrationAuthority: wallet.publicKey };
+
+    const stranger = Keypair.generate();
+    await expectError(
+      program.methods
+        .repairBumps()
+        .accounts({ ...repairAccounts, migrationAuthority: stranger.publicKey })
+        .signers([stranger])
+        .rpc(),
+      "NotMigrationAuthority"
+    );
+    await expectError(program.methods.repairBumps().accounts(repairAccounts).rpc(), "BumpsAlreadyValid");
+
+    const [, authorityBump] = PublicKey.findProgramAddressSync(
+      [Buffer.from("authority"), pool.pair.toBuffer()],
+      program.programId
+    );
+    assert.equal((await program.account.pairAccount.fetch(pool.pair)).authorityBump, authorityBump);
+    await program.methods
+      .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc();
+  });
+
+  it("Swaps part of the input and deposits the rest in one transaction", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const amountIn = new anchor.BN(100_000_000);
+    const swapAndAdd = (fractionBps: number) =>
+      program.methods
+        .swapAndAddLiquidity(amountIn, pool.token0, fractionBps, new anchor.BN(0), new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts(addLiquidityAccounts(pool));
+
+    await expectError(swapAndAdd(10_001).rpc(), "InvalidSwapFraction");
+
+    const before = await program.account.pairAccount.fetch(pool.pair);
+    const in0Before = await getTokenBalance(provider.connection, pool.userToken0);
+    const lpBefore = await getTokenBalance(provider.connection, pool.userLp);
+    const tx = await swapAndAdd(5_000).rpc({ commitment: 'confirmed' });
+    const events = await getEvents(tx);
+    const swapped = events.find((e) => e.name === "swapEvent");
+    const added = events.find((e) => e.name === "liquidityAddedEvent");
+
+    // Half is swapped at the starting reserves...
+    const swapAmount = amountIn.divn(2);
+    const amountOut = getAmountOut(swapAmount, before.reserve0, before.reserve1);
+    assert.equal(swapped.data.amountIn.toString(), swapAmount.toString());
+    assert.equal(swapped.data.amountOut.toString(), amountOut.toString());
+
+    // ...and the kept half plus the output are deposited at the post-swap ones
+    const reserve0 = before.reserve0.add(swapAmount);
+    const reserve1 = before.reserve1.sub(amountOut);
+    const kept = amountIn.sub(swapAmount);
+    const amount1Optimal = kept.mul(reserve1).div(reserve0);
+    const liquidity = amount1Optimal.lte(amountOut)
+      ? kept.mul(before.totalSupply).div(reserve0)
+      : amountOut.mul(before.totalSupply).div(reserve1);
+    assert.equal(added.data.liquidity.toString(), liquidity.toString());
+    assert.equal(await getTokenBalance(provider.connection, pool.userLp) - lpBefore, liquidity.toNumber());
+    assert.equal(
+      in0Before - (await getTokenBalance(provider.connection, pool.userToken0)),
+      swapAmount.add(added.data.amount0).toNumber()
+    );
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Pauses swaps and deposits but keeps withdrawals open", async () => {
+    const factoryAccounts = { factory: factoryKeypair.publicKey, owner: wallet.publicKey };
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+
+    const tx = await program.methods
+      .setPaused(true)
+      .accounts(factoryAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const events = await getEvents(tx);
+    assert.equal(events[0].name, "pausedEvent");
+    assert.equal(events[0].data.paused, true);
+
+    try {
+      await expectError(
+        program.methods
+          .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+          .accounts(swapAccounts(pool, true))
+          .rpc(),
+        "Paused"
+      );
+      await expectError(
+        program.methods
+          .addLiquidity(new anchor.BN(1_000_000), new anchor.BN(1_000_000), new anchor.BN(0), new anchor.BN(0), deadline())
+          .accounts(addLiquidityAccounts(pool))
+          .rpc(),
+        "Paused"
+      );
+
+      const lpBefore = await getTokenBalance(provider.connection, pool.userLp);
+      await program.methods
+        .removeLiquidity(new anchor.BN(1_000_000), new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts(removeLiquidityAccounts(pool))
+        .rpc({ commitment: 'confirmed' });
+      assert.equal(lpBefore - (await getTokenBalance(provider.connection, pool.userLp)), 1_000_000);
+    } finally {
+      // The factory is shared with the other tests
+      await program.methods.setPaused(false).accounts(factoryAccounts).rpc({ commitment: 'confirmed' });
+    }
+
+    await program.methods
+      .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc();
+  });
+
+  it("Bounds the LP a single deposit may mint", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey };
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.minDepositLiquidity.toString(), "0");
+    assert.equal(pair.maxDepositLiquidity.toString(), "18446744073709551615");
+
+    await expectError(
+      program.methods
+        .setDepositBounds(new anchor.BN(2), new anchor.BN(1))
+        .accounts(configAccounts)
+        .rpc(),
+      "InvalidDepositBounds"
+    );
+    const tx = await program.methods
+      .setDepositBounds(new anchor.BN(1_000_000), new anchor.BN(10_000_000))
+      .accounts(configAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "depositBoundsUpdatedEvent");
+    assert.equal(event.data.minDepositLiquidity.toString(), "1000000");
+    assert.equal(event.data.maxDepositLiquidity.toString(), "10000000");
+
+    // At a 1:1 pool with 1:1 supply, liquidity equals the amount deposited
+    const deposit = (amount: number) =>
+      program.methods
+        .addLiquidity(new anchor.BN(amount), new anchor.BN(amount), new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts(addLiquidityAccounts(pool))
+        .rpc({ commitment: 'confirmed' });
+
+    await expectError(deposit(100_000), "DepositOutOfBounds");
+    await expectError(deposit(50_000_000), "DepositOutOfBounds");
+
+    const lpBefore = await getTokenBalance(provider.connection, pool.userLp);
+    const added = (await getEvents(await deposit(5_000_000))).find((e) => e.name === "liquidityAddedEvent");
+    assert.equal(added.data.liquidity.toString(), "5000000");
+    assert.equal(await getTokenBalance(provider.connection, pool.userLp) - lpBefore, 5_000_000);
+  });
+
+  it("Pauses a single pair independently of the factory", async () => {
+    const paused = await createPool();
+    const other = await createPool();
+    await addLiquidity(paused, 1_000_000_000, 1_000_000_000);
+    await addLiquidity(other, 1_000_000_000, 1_000_000_000);
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: paused.pair, owner: wallet.publicKey };
+
+    const stranger = Keypair.generate();
+    await expectError(
+      program.methods
+        .togglePairPaused(true)
+        .accounts({ ...configAccounts, owner: stranger.publicKey })
+        .signers([stranger])
+        .rpc(),
+      "NotFactoryOwner"
+    );
+
+    const tx = await program.methods
+      .togglePairPaused(true)
+      .accounts(configAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "pairPausedEvent");
+    assert.equal(event.data.pair.toString(), paused.pair.toString());
+    assert.equal(event.data.paused, true);
+
+    const swap = (pool) =>
+      program.methods
+        .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc({ commitment: 'confirmed' });
+
+    // Distinct from the factory-wide "Paused"
+    await expectError(swap(paused), "PairPaused");
+    await expectError(
+      program.methods
+        .addLiquidity(new anchor.BN(1_000_000), new anchor.BN(1_000_000), new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts(addLiquidityAccounts(paused))
+        .rpc(),
+      "PairPaused"
+    );
+    await program.methods
+      .removeLiquidity(new anchor.BN(1_000_000), new anchor.BN(0), new anchor.BN(0), deadline())
+      .accounts(removeLiquidityAccounts(paused))
+      .rpc({ commitment: 'confirmed' });
+
+    // The rest of the DEX keeps trading
+    await swap(other);
+
+    await program.methods.togglePairPaused(false).accounts(configAccounts).rpc({ commitment: 'confirmed' });
+    await swap(paused);
+  });
+
+  it("Fails an overflowing deposit with a decodable error", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+
+    // amount0_desired * reserve1 overflows u128 while sizing the deposit
+    const huge = new anchor.BN(2).pow(new anchor.BN(127));
+    await expectError(
+      program.methods
+        .addLiquidity(huge, huge, new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts(addLiquidityAccounts(pool))
+        .rpc(),
+      "AmountOverflow"
+    );
+  });
+
+  it("Reserves migration instructions for the migration authority", async () => {
+    const factory = Keypair.generate();
+    const migrationAuthority = Keypair.generate();
+    await program.methods
+      .initialize(migrationAuthority.publicKey)
+      .accounts({
+        factory: factory.publicKey,
+        owner: wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([factory])
+      .rpc({ commitment: 'confirmed' });
+    const pool = await createPool({ factory: factory.publicKey });
+
+    // The owner runs the factory but can't repair bumps or hand over migration
+    // rights
+    await expectError(
+      program.methods
+        .repairBumps()
+        .accounts({ factory: factory.publicKey, pair: pool.pair, migrationAuthority: wallet.publicKey })
+        .rpc(),
+      "NotMigrationAuthority"
+    );
+    await expectError(
+      program.methods
+        .setMigrationAuthority(wallet.publicKey)
+        .accounts({ factory: factory.publicKey, migrationAuthority: wallet.publicKey })
+        .rpc(),
+      "NotMigrationAuthority"
+    );
+
+    // The migration authority passes the access check and reaches the pair checks
+    await expectError(
+      program.methods
+        .repairBumps()
+        .accounts({ factory: factory.publicKey, pair: pool.pair, migrationAuthority: migrationAuthority.publicKey })
+        .signers([migrationAuthority])
+        .rpc(),
+      "BumpsAlreadyValid"
+    );
+
+    const next = Keypair.generate();
+    const tx = await program.methods
+      .setMigrationAuthority(next.publicKey)
+      .accounts({ factory: factory.publicKey, migrationAuthority: migrationAuthority.publicKey })
+      .signers([migrationAuthority])
+      .rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "migrationAuthorityUpdatedEvent");
+    assert.equal(event.data.oldMigrationAuthority.toString(), migrationAuthority.publicKey.toString());
+    assert.equal(event.data.newMigrationAuthority.toString(), next.publicKey.toString());
+    const factoryAccount = await program.account.factory.fetch(factory.publicKey);
+    assert.equal(factoryAccount.migrationAuthority.toString(), next.publicKey.toString());
+    assert.equal(factoryAccount.owner.toString(), wallet.publicKey.toString());
+  });
+
+  it("Never lowers the remaining LPs' share value on partial removals", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_007, 3_000_000_011);
+    for (const zeroForOne of [true, false]) {
+      await program.methods
+        .swap(new anchor.BN(123_456_789), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, zeroForOne))
+        .rpc({ commitment: 'confirmed' });
+    }
+
+    // Fixed-seed Park-Miller generator so a failure reproduces
+    let seed = 20_240_517;
+    const nextRandom = () => (seed = (seed * 16_807) % 2_147_483_647) / 2_147_483_647;
+
+    for (let i = 0; i < 8; i++) {
+      const before = await program.account.pairAccount.fetch(pool.pair);
+      const balance = await getTokenBalance(provider.connection, pool.userLp);
+      const liquidity = new anchor.BN(Math.max(1, Math.floor(balance * nextRandom() * 0.2)));
+
+      const tx = await program.methods
+        .removeLiquidity(liquidity, new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts(removeLiquidityAccounts(pool))
+        .rpc({ commitment: 'confirmed' });
+      const removed = (await getEvents(tx)).find((e) => e.name === "liquidityRemovedEvent");
+
+      // Paid out the floored proportional share, never more
+      assert.equal(removed.data.amount0.toString(), liquidity.mul(before.reserve0).div(before.totalSupply).toString());
+      assert.equal(removed.data.amount1.toString(), liquidity.mul(before.reserve1).div(before.totalSupply).toString());
+
+      // reserve / supply never decreases: after * supplyBefore >= before * supplyAfter
+      const after = await program.account.pairAccount.fetch(pool.pair);
+      assert.isTrue(after.reserve0.mul(before.totalSupply).gte(before.reserve0.mul(after.totalSupply)));
+      assert.isTrue(after.reserve1.mul(before.totalSupply).gte(before.reserve1.mul(after.totalSupply)));
+    }
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Rejects a zero-input swap before touching the pair", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const before = await program.account.pairAccount.fetch(pool.pair);
+
+    await expectError(
+      program.methods
+        .swap(new anchor.BN(0), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc(),
+      "InsufficientInputAmount"
+    );
+
+    const after = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(after.reserve0.toString(), before.reserve0.toString());
+    assert.equal(after.reserve1.toString(), before.reserve1.toString());
+  });
+
+  it("Rejects zero amounts with a single input error", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const zero = new anchor.BN(0);
+
+    await expectError(
+      program.methods
+        .addLiquidity(zero, new anchor.BN(1_000_000), zero, zero, deadline())
+        .accounts(addLiquidityAccounts(pool))
+        .rpc(),
+      "InsufficientInputAmount"
+    );
+    await expectError(
+      program.methods
+        .removeLiquidity(zero, zero, zero, deadline())
+        .accounts(removeLiquidityAccounts(pool))
+        .rpc(),
+      "InsufficientInputAmount"
+    );
+    await expectError(
+      program.methods
+        .swap(zero, zero, deadline(), 0)
+        .accounts(swapAccounts(pool, false))
+        .rpc(),
+      "InsufficientInputAmount"
+    );
+  });
+
+  it("Requires k to grow strictly on fee-charging swaps only", async () => {
+    const k = (pair) => pair.reserve0.mul(pair.reserve1).toString();
+    const swapAll = async (pool) => {
+      const before = await program.account.pairAccount.fetch(pool.pair);
+      await program.methods
+        .swap(new anchor.BN(1_000_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, true))
+        .rpc({ commitment: 'confirmed' });
+      return [before, await program.account.pairAccount.fetch(pool.pair)];
+    };
+
+    // With the fee, 1e9 against 1e9:1e9 leaves some of it in the reserves
+    const feePool = await createPool();
+    await addLiquidity(feePool, 1_000_000_000, 1_000_000_000);
+    const [feeBefore, feeAfter] = await swapAll(feePool);
+    assert.isTrue(feeAfter.reserve0.mul(feeAfter.reserve1).gt(feeBefore.reserve0.mul(feeBefore.reserve1)));
+
+    // Without it the same swap pays out exactly 5e8 and k stays put
+    const freePool = await createPool();
+    await program.methods
+      .setPairFee(0)
+      .accounts({ factory: factoryKeypair.publicKey, pair: freePool.pair, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    await addLiquidity(freePool, 1_000_000_000, 1_000_000_000);
+    const [freeBefore, freeAfter] = await swapAll(freePool);
+    assert.equal(freeAfter.reserve1.toString(), "500000000");
+    assert.equal(k(freeAfter), k(freeBefore));
+  });
+
+  it("Archives pair snapshots on-chain", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const [archive] = PublicKey.findProgramAddressSync(
+      [Buffer.from("archive"), pool.pair.toBuffer(), new anchor.BN(0).toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    const snapshot = () =>
+      program.methods
+        .archiveSnapshot()
+        .accounts({ pair: pool.pair, archive, keeper: wallet.publicKey })
+        .rpc({ commitment: 'confirmed' });
+
+    const captured = [];
+    for (const zeroForOne of [true, false]) {
+      const tx = await snapshot();
+      captured.push(await program.account.pairAccount.fetch(pool.pair));
+      const event = (await getEvents(tx)).find((e) => e.name === "pairSnapshotArchivedEvent");
+      assert.equal(event.data.index.toNumber(), captured.length - 1);
+
+      await program.methods
+        .swap(new anchor.BN(100_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, zeroForOne))
+        .rpc({ commitment: 'confirmed' });
+    }
+
+    const stored = await program.account.pairArchive.fetch(archive);
+    assert.equal(stored.pair.toString(), pool.pair.toString());
+    assert.equal(stored.chunkIndex.toNumber(), 0);
+    assert.equal(stored.count, 2);
+    for (const [i, pair] of captured.entries()) {
+      assert.equal(stored.snapshots[i].reserve0.toString(), pair.reserve0.toString());
+      assert.equal(stored.snapshots[i].reserve1.toString(), pair.reserve1.toString());
+      assert.equal(stored.snapshots[i].totalSupply.toString(), pair.totalSupply.toString());
+      assert.isTrue(stored.snapshots[i].timestamp.gtn(0));
+    }
+    assert.notEqual(stored.snapshots[0].reserve0.toString(), stored.snapshots[1].reserve0.toString());
+    assert.equal((await program.account.pairAccount.fetch(pool.pair)).archiveSnapshotCount.toNumber(), 2);
+  });
+
+  it("Closes an empty pair and returns its rent to the owner", async () => {
+    const factory = Keypair.generate();
+    await program.methods
+      .initialize(wallet.publicKey)
+      .accounts({
+        factory: factory.publicKey,
+        owner: wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([factory])
+      .rpc({ commitment: 'confirmed' });
+    const empty = await createPool({ factory: factory.publicKey });
+    const funded = await createPool({ factory: factory.publicKey });
+    await addLiquidity(funded, 1_000_000_000, 1_000_000_000);
+    const closeAccounts = (pool) => ({
+      factory: factory.publicKey,
+      pair: pool.pair,
+      lpMint: pool.lpMint,
+      lpMintMarker: PublicKey.findProgramAddressSync([Buffer.from("lp_mint"), pool.lpMint.toBuffer()], program.programId)[0],
+      owner: wallet.publicKey,
+    });
+
+    await expectError(
+      program.methods.closePair().accounts(closeAccounts(funded)).rpc(),
+      "PairNotEmpty"
+    );
+    const stranger = Keypair.generate();
+    await expectError(
+      program.methods
+        .closePair()
+        .accounts({ ...closeAccounts(empty), owner: stranger.publicKey })
+        .signers([stranger])
+        .rpc(),
+      "NotFactoryOwner"
+    );
+
+    const rent = (await provider.connection.getAccountInfo(empty.pair)).lamports;
+    const balanceBefore = await provider.connection.getBalance(wallet.publicKey, 'confirmed');
+    const tx = await program.methods.closePair().accounts(closeAccounts(empty)).rpc({ commitment: 'confirmed' });
+    const balanceAfter = await provider.connection.getBalance(wallet.publicKey, 'confirmed');
+    const txDetails = await provider.connection.getTransaction(tx, { commitment: 'confirmed', maxSupportedTransactionVersion: 0 });
+
+    assert.isNull(await provider.connection.getAccountInfo(empty.pair));
+    assert.isAbove(balanceAfter - balanceBefore + txDetails.meta.fee, rent);
+    const event = (await getEvents(tx)).find((e) => e.name === "pairClosedEvent");
+    assert.equal(event.data.pair.toString(), empty.pair.toString());
+    assert.equal(event.data.pairCount.toString(), "1");
+    assert.equal((await program.account.factory.fetch(factory.publicKey)).pairCount.toString(), "1");
+  });
+
+  it("Reinvests protocol fees as permanent protocol-owned liquidity", async () => {
+    // A dedicated factory and fee_to, so the shared fee switch stays off
+    const factory = Keypair.generate();
+    const feeTo = Keypair.generate();
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        SystemProgram.transfer({ fromPubkey: wallet.publicKey, toPubkey: feeTo.publicKey, lamports: 100_000_000 })
+      )
+    );
+    await program.methods
+      .initialize(wallet.publicKey)
+      .accounts({
+        factory: factory.publicKey,
+        owner: wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([factory])
+      .rpc({ commitment: 'confirmed' });
+    const factoryAccounts = { factory: factory.publicKey, owner: wallet.publicKey };
+    await program.methods.setFeeTo(feeTo.publicKey).accounts(factoryAccounts).rpc();
+    await program.methods.setFeeOn(true).accounts(factoryAccounts).rpc();
+
+    const pool = await createPool({ factory: factory.publicKey });
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const feeToLp = await createAssociatedTokenAccount(provider.connection, wallet.payer, pool.lpMint, feeTo.publicKey);
+    const [vault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("protocol_liquidity"), pool.pair.toBuffer()],
+      program.programId
+    );
+    const reinvestAccounts = {
+      factory: factory.publicKey,
+      pair: pool.pair,
+      lpMint: pool.lpMint,
+      feeToAccount: feeToLp,
+      protocolLiquidityVault: vault,
+      authority: pool.authority,
+      feeTo: feeTo.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    let protocolLiquidity = 0;
+    for (const zeroForOne of [true, false]) {
+      await program.methods
+        .swap(new anchor.BN(200_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, zeroForOne))
+        .rpc({ commitment: 'confirmed' });
+      await program.methods
+        .collectProtocolFees()
+        .accounts({ ...collectProtocolFeesAccounts(pool, wallet.publicKey, pool.userLp), feeToAccount: feeToLp })
+        .rpc({ commitment: 'confirmed' });
+      const collected = await getTokenBalance(provider.connection, feeToLp);
+      assert.isAbove(collected, 0);
+
+      const tx = await program.methods
+        .reinvestProtocolFees()
+        .accounts(reinvestAccounts)
+        .signers([feeTo])
+        .rpc({ commitment: 'confirmed' });
+      protocolLiquidity += collected;
+
+      const event = (await getEvents(tx)).find((e) => e.name === "protocolLiquidityReinvestedEvent");
+      assert.equal(event.data.amount.toNumber(), collected);
+      assert.equal(event.data.protocolLiquidity.toNumber(), protocolLiquidity);
+      assert.equal(await getTokenBalance(provider.connection, feeToLp), 0);
+    }
+
+    // Kept apart from the burned minimum, and out of anyone's reach but the pair's
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.protocolLiquidity.toNumber(), protocolLiquidity);
+    assert.equal(await getTokenBalance(provider.connection, vault), protocolLiquidity);
+    assert.equal(await getTokenBalance(provider.connection, pool.burnLp), pair.minimumLiquidity.toNumber());
+    const vaultAccount = await getAccount(provider.connection, vault);
+    assert.equal(vaultAccount.owner.toString(), pool.authority.toString());
+
+    await expectError(
+      program.methods
+        .reinvestProtocolFees()
+        .accounts({ ...reinvestAccounts, feeToAccount: pool.userLp, feeTo: wallet.publicKey })
+        .rpc(),
+      "NotFeeTo"
+    );
+  });
+
+  it("Verifies every hop of a relayed route", async () => {
+    const [mintA, mintB, mintC, mintD] = await Promise.all(
+      [0, 1, 2, 3].map(() => createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6))
+    );
+    const poolAB = await createPool({ mints: [mintA, mintB] });
+    const poolBC = await createPool({ mints: [mintB, mintC] });
+    const poolCD = await createPool({ mints: [mintC, mintD] });
+    for (const pool of [poolAB, poolBC, poolCD]) {
+      await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    }
+
+    const userD = getAssociatedTokenAddressSync(mintD, wallet.publicKey);
+    const route = (verifyHops: boolean) =>
+      program.methods
+        .swapRouteChecked(new anchor.BN(10_000_000), new anchor.BN(0), deadline(), verifyHops)
+        .accounts({
+          factory: factoryKeypair.publicKey,
+          tokenIn: getAssociatedTokenAddressSync(mintA, wallet.publicKey),
+          tokenOut: userD,
+          sender: wallet.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts(
+          [poolAB, poolBC, poolCD].flatMap((pool) => [
+            { pubkey: pool.pair, isSigner: false, isWritable: true },
+            { pubkey: pool.vault0, isSigner: false, isWritable: true },
+            { pubkey: pool.vault1, isSigner: false, isWritable: true },
+            { pubkey: pool.authority, isSigner: false, isWritable: false },
+            { pubkey: pool.token0, isSigner: false, isWritable: false },
+            { pubkey: pool.token1, isSigner: false, isWritable: false },
+          ])
+        )
+        .rpc({ commitment: 'confirmed' });
+
+    // Honest pairs pass every per-hop check, verified or not
+    for (const verifyHops of [true, false]) {
+      const dBefore = await getTokenBalance(provider.connection, userD);
+      const tx = await route(verifyHops);
+      assert.equal((await getEvents(tx)).filter((e) => e.name === "swapEvent").length, 3);
+      assert.isAbove(await getTokenBalance(provider.connection, userD), dBefore);
+    }
+    for (const pool of [poolAB, poolBC, poolCD]) {
+      await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+    }
+  });
+  it("Lends through a flash swap and checks the repayment covers the fee", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const pairBefore = await program.account.pairAccount.fetch(pool.pair);
+    const borrowed = 100_000_000;
+
+    const flashSwap = (repay: number) => flashBorrowToken0(pool, borrowed, repay);
+
+    // Handing back exactly what was borrowed leaves the fee unpaid
+    await expectError(flashSwap(borrowed), "FlashRepaymentFailed");
+
+    const fee = Math.ceil((borrowed * pairBefore.feeBps) / (10_000 - pairBefore.feeBps));
+    const tx = await flashSwap(borrowed + fee);
+    const event = (await getEvents(tx)).find((e) => e.name === "flashSwapEvent");
+    assert.equal(event.data.amount0Out.toNumber(), borrowed);
+    assert.equal(event.data.amount0In.toNumber(), borrowed + fee);
+    assert.equal(event.data.amount1In.toNumber(), 0);
+
+    const pairAfter = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pairAfter.reserve0.toNumber(), pairBefore.reserve0.toNumber() + fee);
+    assert.equal(pairAfter.reserve1.toNumber(), pairBefore.reserve1.toNumber());
+    assert.isFalse(pairAfter.locked);
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+
+    // The repayment is charged the tier fee a swap of its size would pay
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey };
+    await program.methods
+      .setFeeTiers([{ threshold: new anchor.BN(borrowed), feeBps: 10 }])
+      .accounts(configAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const tierFee = Math.ceil((borrowed * 10) / (10_000 - 10));
+    await flashSwap(borrowed + tierFee);
+
+    // Pairs with an external oracle need the feed to check the repaid price
+    await program.methods.setExternalOracle(Keypair.generate().publicKey, 100).accounts(configAccounts).rpc();
+    await expectError(flashSwap(borrowed + fee), "InvalidOracleAccount");
+  });
+
+  it("Holds flash swaps to the per-swap limits", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey };
+    await program.methods.configurePairFees(30, 1_000, new anchor.BN(0)).accounts(configAccounts).rpc();
+
+    // Repaid in token1, 20% of reserve0 leaves the pool: over the 10% output cap
+    await expectError(
+      flashBorrowToken0(pool, 200_000_000, 300_000_000, { repayToken1: true }),
+      "MaxOutputExceeded"
+    );
+    await flashBorrowToken0(pool, 50_000_000, 60_000_000, { repayToken1: true });
+
+    // Repaying the borrowed side is a loan, not a trade, so the cap doesn't apply
+    const loan = 200_000_000;
+    await flashBorrowToken0(pool, loan, loan + Math.ceil((loan * 30) / (10_000 - 30)));
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+
+    // A cooldown needs the sender's trader state, as for swaps
+    await program.methods.setSwapCooldown(30).accounts(configAccounts).rpc();
+    await expectError(flashBorrowToken0(pool, 50_000_000, 60_000_000, { repayToken1: true }), "TraderStateRequired");
+  });
+
+  it("Finds a pair from its mints in either order", async () => {
+    const pool = await createPool();
+    for (const [tokenA, tokenB] of [[pool.token0, pool.token1], [pool.token1, pool.token0]]) {
+      const found = await program.methods
+        .findPair(tokenA, tokenB)
+        .accounts({ pair: pool.pair })
+        .view();
+      assert.equal(found.pair.toString(), pool.pair.toString());
+      assert.isTrue(found.isInitialized);
+    }
+
+    // A pair that was never created still resolves, just not initialized
+    const mint = await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6);
+    const [token0, token1] = Buffer.compare(mint.toBuffer(), pool.token0.toBuffer()) < 0 ? [mint, pool.token0] : [pool.token0, mint];
+    const [missing] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pair"), token0.toBuffer(), token1.toBuffer()],
+      program.programId
+    );
+    const found = await program.methods
+      .findPair(pool.token0, mint)
+      .accounts({ pair: missing })
+      .view();
+    assert.equal(found.pair.toString(), missing.toString());
+    assert.isFalse(found.isInitialized);
+
+    await expectError(
+      program.methods.findPair(pool.token0, mint).accounts({ pair: pool.pair }).rpc(),
+      "InvalidPairAddress"
+    );
+  });
+
+  it("Reports how close the reserves are to the pair's caps", async () => {
+    const pool = await createPool({ maxRatio: 4 });
+    await addLiquidity(pool, 2_000_000_000, 1_000_000_000);
+    const utilization = async () => {
+      const call = program.methods.pairUtilization().accounts({ pair: pool.pair });
+      const report = await call.view();
+      const event = (await call.simulate()).events.find((e) => e.name === "utilizationEvent");
+      assert.equal(event.data.ratioBps.toString(), report.ratioBps.toString());
+      assert.equal(event.data.maxAmount0Out.toString(), report.maxAmount0Out.toString());
+      return report;
+    };
+
+    // Output isn't capped yet, so a swap may take all but one unit
+    let report = await utilization();
+    assert.equal(report.ratioBps.toNumber(), 20_000);
+    assert.equal(report.maxRatio.toNumber(), 4);
+    assert.equal(report.ratioHeadroomBps.toNumber(), 20_000);
+    assert.equal(report.maxAmount0Out.toNumber(), 2_000_000_000 - 1);
+
+    await program.methods
+      .configurePairFees(30, 1_000, new anchor.BN(0))
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    await program.methods
+      .swap(new anchor.BN(100_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+
+    // Tracks the reserves the swap left behind
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    const ratioBps = pair.reserve0.muln(10_000).div(pair.reserve1).toNumber();
+    report = await utilization();
+    assert.equal(report.ratioBps.toNumber(), ratioBps);
+    assert.equal(report.ratioHeadroomBps.toNumber(), 40_000 - ratioBps);
+    assert.equal(report.maxOutBps, 1_000);
+    assert.equal(report.maxAmount0Out.toString(), pair.reserve0.divn(10).toString());
+    assert.equal(report.maxAmount1Out.toString(), pair.reserve1.divn(10).toString());
+  });
+
+  // A second pair of the same tokens can't be configured, so a live
+  // replacement can't be staged; this covers the access and pairing guards.
+  it("Refuses to evacuate a pair into one that can't replace it", async () => {
+    const pool = await createPool();
+    const other = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const pairBefore = await program.account.pairAccount.fetch(pool.pair);
+
+    const evacuate = (newPool, owner = wallet.payer) =>
+      program.methods
+        .evacuatePair()
+        .accounts({
+          factory: factoryKeypair.publicKey,
+          oldPair: pool.pair,
+          oldToken0Account: pool.vault0,
+          oldToken1Account: pool.vault1,
+          oldAuthority: pool.authority,
+          newPair: newPool.pair,
+          newToken0Account: newPool.vault0,
+          newToken1Account: newPool.vault1,
+          newAuthority: newPool.authority,
+          lpMint: pool.lpMint,
+          lpMintMarker: PublicKey.findProgramAddressSync([Buffer.from("lp_mint"), pool.lpMint.toBuffer()], program.programId)[0],
+          token0Mint: pool.token0,
+          token1Mint: pool.token1,
+          owner: owner.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([owner])
+        .rpc();
+
+    await expectError(evacuate(other, Keypair.generate()), "NotFactoryOwner");
+    await expectError(evacuate(other), "MismatchedPairTokens");
+    await expectError(evacuate(pool), "IdenticalPairs");
+
+    const pairAfter = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pairAfter.reserve0.toString(), pairBefore.reserve0.toString());
+    assert.equal(pairAfter.totalSupply.toString(), pairBefore.totalSupply.toString());
+    assert.isFalse(pairAfter.pairPaused);
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+  it("Rejects configuring a pair with vaults it doesn't control", async () => {
+    const mintA = await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6);
+    const mintB = await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6);
+    const [mint0, mint1] = Buffer.compare(mintA.toBuffer(), mintB.toBuffer()) < 0 ? [mintA, mintB] : [mintB, mintA];
+    const [pair] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pair"), mint0.toBuffer(), mint1.toBuffer()],
+      program.programId
+    );
+
+    // Vaults the caller owns
+    const walletVault0 = await createAccount(provider.connection, wallet.payer, mint0, wallet.publicKey, Keypair.generate());
+    const walletVault1 = await createAccount(provider.connection, wallet.payer, mint1, wallet.publicKey, Keypair.generate());
+    await expectError(createPool({ mints: [mint0, mint1], vaults: [walletVault0, walletVault1] }), "InvalidTokenAccount");
+
+    // The pair's own vaults, but for the wrong sides
+    const mintC = await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6);
+    const mintD = await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6);
+    const [mint2, mint3] = Buffer.compare(mintC.toBuffer(), mintD.toBuffer()) < 0 ? [mintC, mintD] : [mintD, mintC];
+    const [swappedPair] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pair"), mint2.toBuffer(), mint3.toBuffer()],
+      program.programId
+    );
+    const [swappedAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("authority"), swappedPair.toBuffer()],
+      program.programId
+    );
+    const vault2 = await createAccount(provider.connection, wallet.payer, mint2, swappedAuthority, Keypair.generate());
+    const vault3 = await createAccount(provider.connection, wallet.payer, mint3, swappedAuthority, Keypair.generate());
+    await expectError(createPool({ mints: [mint2, mint3], vaults: [vault3, vault2] }), "InvalidTokenAccount");
+
+    // Both pairs stay unconfigured
+    for (const unconfigured of [pair, swappedPair]) {
+      assert.isFalse((await program.account.pairAccount.fetch(unconfigured)).isInitialized);
+    }
+  });
+
+  it("Rejects configuring a pair created from unsorted token seeds", async () => {
+    await expectError(createPool({ reverseSeeds: true }), "TokensNotSorted");
+
+    // Mints passed to configure_pair in either order are fine
+    const mintA = await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6);
+    const mintB = await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, 6);
+    const pool = await createPool({ mints: [mintB, mintA] });
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.token0.toString(), pool.token0.toString());
+    assert.isTrue(Buffer.compare(pair.token0.toBuffer(), pair.token1.toBuffer()) < 0);
+  });
+
+  it("Replaces per-swap events with periodic summaries when events are off", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey };
+    await expectError(program.methods.setEventMode(false, 0).accounts(configAccounts).rpc(), "InvalidSummaryInterval");
+    await program.methods.setEventMode(false, 3).accounts(configAccounts).rpc({ commitment: 'confirmed' });
+
+    const amounts = [10_000_000, 20_000_000, 30_000_000];
+    let amount0Out = 0;
+    for (const [i, amount] of amounts.entries()) {
+      const before = await program.account.pairAccount.fetch(pool.pair);
+      const zeroForOne = i !== 1;
+      const tx = await program.methods
+        .swap(new anchor.BN(amount), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, zeroForOne))
+        .rpc({ commitment: 'confirmed' });
+      const events = await getEvents(tx);
+      assert.isUndefined(events.find((e) => e.name === "swapEvent"));
+
+      // State still moves on every swap
+      const after = await program.account.pairAccount.fetch(pool.pair);
+      assert.notEqual(after.reserve0.toString(), before.reserve0.toString());
+      await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+      if (!zeroForOne) {
+        amount0Out = before.reserve0.sub(after.reserve0).toNumber();
+      }
+
+      const summary = events.find((e) => e.name === "swapSummaryEvent");
+      if (i < amounts.length - 1) {
+        assert.isUndefined(summary);
+      } else {
+        assert.equal(summary.data.swapCount, 3);
+        assert.equal(summary.data.amount0In.toNumber(), amounts[0] + amounts[2]);
+        assert.equal(summary.data.amount1In.toNumber(), amounts[1]);
+        assert.equal(summary.data.amount0Out.toNumber(), amount0Out);
+        assert.equal(summary.data.reserve0After.toString(), after.reserve0.toString());
+      }
+    }
+    assert.equal((await program.account.pairAccount.fetch(pool.pair)).summarySwapCount, 0);
+
+    await program.methods.setEventMode(true, 0).accounts(configAccounts).rpc({ commitment: 'confirmed' });
+    const tx = await program.methods
+      .swap(new anchor.BN(1_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+    assert.isDefined((await getEvents(tx)).find((e) => e.name === "swapEvent"));
+  });
+
+  it("Normalizes decimals for the first deposit's LP when enabled", async () => {
+    const pool = await createPool({ decimals0: 9, decimals1: 6 });
+    const configAccounts = { factory: factoryKeypair.publicKey, pair: pool.pair, owner: wallet.publicKey };
+    const tx = await program.methods
+      .setDecimalsNormalization(true)
+      .accounts(configAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "decimalsNormalizationUpdatedEvent");
+    // Locked minimum for 6-decimal LP
+    assert.equal(event.data.minimumLiquidity.toNumber(), 1_000);
+
+    // One whole token of each side mints like a 6/6 pair would: sqrt(1e6 * 1e6)
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    await addLiquidity(pool, 10 ** pair.token0Decimals, 10 ** pair.token1Decimals);
+    assert.equal(await getTokenBalance(provider.connection, pool.userLp), 1_000_000 - 1_000);
+    assert.equal(await getTokenBalance(provider.connection, pool.burnLp), 1_000);
+
+    // Raw units would have given sqrt(1e9 * 1e6) instead
+    const raw = await createPool({ decimals0: 9, decimals1: 6 });
+    const rawPair = await program.account.pairAccount.fetch(raw.pair);
+    await addLiquidity(raw, 10 ** rawPair.token0Decimals, 10 ** rawPair.token1Decimals);
+    assert.equal(
+      await getTokenBalance(provider.connection, raw.userLp),
+      Math.floor(Math.sqrt(1e15)) - rawPair.minimumLiquidity.toNumber()
+    );
+
+    await expectError(program.methods.setDecimalsNormalization(false).accounts(configAccounts).rpc(), "PairNotEmpty");
+  });
+
+  it("Refuses a swap too small for the fee to change its output", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+
+    // 100 in pays 99 out with or without the 0.3% fee
+    const amountIn = new anchor.BN(100);
+    assert.equal(
+      getAmountOut(amountIn, pair.reserve0, pair.reserve1, pair.feeBps).toString(),
+      getAmountOut(amountIn, pair.reserve0, pair.reserve1, 0).toString()
+    );
+    await expectError(
+      program.methods.swap(amountIn, new anchor.BN(0), deadline(), 0).accounts(swapAccounts(pool, true)).rpc(),
+      "FeeRoundsToZero"
+    );
+
+    // Large enough for the fee to cost the trader at least one unit
+    const largerIn = new anchor.BN(1_000_000);
+    assert.isTrue(
+      getAmountOut(largerIn, pair.reserve0, pair.reserve1, pair.feeBps).lt(
+        getAmountOut(largerIn, pair.reserve0, pair.reserve1, 0)
+      )
+    );
+    await program.methods
+      .swap(largerIn, new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Freezes deposits once a reserve reaches its cap", async () => {
+    const cap = 2_000_000_000;
+    const pool = await createPool({ reserveCaps: [cap, 0] });
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    let pairAccount = await program.account.pairAccount.fetch(pool.pair);
+    assert.isFalse(pairAccount.frozen);
+
+    // Filling reserve0 up to the cap is still accepted, and freezes the pair
+    const tx = await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const event = (await getEvents(tx)).find((e) => e.name === "reserveCapReachedEvent");
+    assert.equal(event.data.pair.toString(), pool.pair.toString());
+    assert.equal(event.data.reserve0.toString(), cap.toString());
+    assert.equal(event.data.reserveCap0.toString(), cap.toString());
+    pairAccount = await program.account.pairAccount.fetch(pool.pair);
+    assert.isTrue(pairAccount.frozen);
+
+    await expectError(addLiquidity(pool, 1_000_000, 1_000_000), "PairFrozen");
+
+    // Outflows stay open
+    const lpBefore = await getTokenBalance(provider.connection, pool.userLp);
+    const token0Before = await getTokenBalance(provider.connection, pool.userToken0);
+    await program.methods
+      .removeLiquidity(new anchor.BN(Math.floor(lpBefore / 2)), new anchor.BN(0), new anchor.BN(0), deadline())
+      .accounts(removeLiquidityAccounts(pool))
+      .rpc({ commitment: 'confirmed' });
+    assert.isAbove(await getTokenBalance(provider.connection, pool.userToken0), token0Before);
+    assert.isBelow(await getTokenBalance(provider.connection, pool.userLp), lpBefore);
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Locks the minimum liquidity with the factory's burn address", async () => {
+    const factory = Keypair.generate();
+    await program.methods
+      .initialize(wallet.publicKey)
+      .accounts({
+        factory: factory.publicKey,
+        owner: wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([factory])
+      .rpc({ commitment: 'confirmed' });
+    const pool = await createPool({ factory: factory.publicKey });
+
+    const burnAddress = Keypair.generate().publicKey;
+    const tx = await program.methods
+      .setLpBurnAddress(burnAddress)
+      .accounts({ factory: factory.publicKey, owner: wallet.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "lpBurnAddressUpdatedEvent");
+    assert.equal(event.data.lpBurnAddress.toString(), burnAddress.toString());
+
+    // The pool's burn account belongs to the old address now
+    await expectError(addLiquidity(pool, 1_000_000_000, 1_000_000_000), "InvalidTokenOwner");
+
+    const burnLp = await createAssociatedTokenAccount(provider.connection, wallet.payer, pool.lpMint, burnAddress);
+    await program.methods
+      .addLiquidity(new anchor.BN(1_000_000_000), new anchor.BN(1_000_000_000), new anchor.BN(0), new anchor.BN(0), deadline())
+      .accounts({ ...addLiquidityAccounts(pool), burnAccount: burnLp })
+      .rpc({ commitment: 'confirmed' });
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(await getTokenBalance(provider.connection, burnLp), pair.minimumLiquidity.toNumber());
+  });
+
+  it("Quotes a position's redeemable value net of the pending protocol fee", async () => {
+    const factoryAccounts = { factory: factoryKeypair.publicKey, owner: wallet.publicKey };
+    await program.methods.setFeeTo(wallet.publicKey).accounts(factoryAccounts).rpc();
+    await program.methods.setFeeOn(true).accounts(factoryAccounts).rpc();
+
+    try {
+      const pool = await createPool();
+      await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+      for (const zeroForOne of [true, false]) {
+        await program.methods
+          .swap(new anchor.BN(200_000_000), new anchor.BN(0), deadline(), 0)
+          .accounts(swapAccounts(pool, zeroForOne))
+          .rpc({ commitment: 'confirmed' });
+      }
+
+      const liquidity = new anchor.BN(await getTokenBalance(provider.connection, pool.userLp));
+      const redeemable = await program.methods
+        .myRedeemable(liquidity)
+        .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair })
+        .view();
+      assert.isTrue(redeemable.protocolFeeLiquidity.gtn(0));
+
+      // Slightly below the naive share of the stored supply
+      const pair = await program.account.pairAccount.fetch(pool.pair);
+      const naive0 = liquidity.mul(pair.reserve0).div(pair.totalSupply);
+      const naive1 = liquidity.mul(pair.reserve1).div(pair.totalSupply);
+      assert.isTrue(redeemable.amount0.lt(naive0));
+      assert.isTrue(redeemable.amount1.lt(naive1));
+
+      // Matches what burning the position actually pays
+      const tx = await program.methods
+        .removeLiquidity(liquidity, new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts({ ...removeLiquidityAccounts(pool), feeToAccount: pool.userLp })
+        .rpc({ commitment: 'confirmed' });
+      const removed = (await getEvents(tx)).find((e) => e.name === "liquidityRemovedEvent");
+      assert.equal(removed.data.amount0.toString(), redeemable.amount0.toString());
+      assert.equal(removed.data.amount1.toString(), redeemable.amount1.toString());
+    } finally {
+      await program.methods.setFeeOn(false).accounts(factoryAccounts).rpc();
+    }
+  });
+
+  it("Zaps a single token into liquidity", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const amountIn = 100_000_000;
+    const token0Before = await getTokenBalance(provider.connection, pool.userToken0);
+    const token1Before = await getTokenBalance(provider.connection, pool.userToken1);
+    const lpBefore = await getTokenBalance(provider.connection, pool.userLp);
+
+    const zapIn = (amountOutMin, minLiquidity = new anchor.BN(0), expiry = deadline()) =>
+      program.methods
+        .zapIn(new anchor.BN(amountIn), true, amountOutMin, new anchor.BN(0), new anchor.BN(0), minLiquidity, expiry)
+        .accounts(addLiquidityAccounts(pool));
+
+    // The swap can't pay out more than the pool's own quote for half the input
+    const halfOut = getAmountOut(new anchor.BN(amountIn / 2), new anchor.BN(1_000_000_000), new anchor.BN(1_000_000_000));
+    await expectError(zapIn(halfOut.addn(1)).rpc(), "InsufficientOutputAmount");
+    await expectError(zapIn(new anchor.BN(0), new anchor.BN(amountIn)).rpc(), "InsufficientLiquidityMinted");
+    await expectError(zapIn(new anchor.BN(0), new anchor.BN(0), new anchor.BN(1)).rpc(), "Expired");
+
+    const tx = await zapIn(new anchor.BN(0)).rpc({ commitment: 'confirmed' });
+    const events = await getEvents(tx);
+    const swap = events.find((e) => e.name === "swapEvent");
+    const added = events.find((e) => e.name === "liquidityAddedEvent");
+    assert.isTrue(swap.data.isToken0In);
+
+    // Roughly half is swapped, and the output goes straight back in
+    assert.isAbove(swap.data.amountIn.toNumber(), amountIn * 0.45);
+    assert.isBelow(swap.data.amountIn.toNumber(), amountIn / 2);
+    assert.isAtMost(added.data.amount1.toNumber(), swap.data.amountOut.toNumber());
+    assert.isAtMost(swap.data.amountOut.toNumber() - added.data.amount1.toNumber(), 1);
+
+    // Only dust stays behind in the wallet
+    const spent0 = token0Before - (await getTokenBalance(provider.connection, pool.userToken0));
+    assert.equal(spent0, swap.data.amountIn.toNumber() + added.data.amount0.toNumber());
+    assert.isAtMost(amountIn - spent0, 1);
+    const gained1 = (await getTokenBalance(provider.connection, pool.userToken1)) - token1Before;
+    assert.isAtLeast(gained1, 0);
+    assert.isAtMost(gained1, 1);
+    assert.equal((await getTokenBalance(provider.connection, pool.userLp)) - lpBefore, added.data.liquidity.toNumber());
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Caps a swap's price impact when asked to", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const swap = (amountIn: number, maxImpactBps: number) =>
+      program.methods
+        .swap(new anchor.BN(amountIn), new anchor.BN(0), deadline(), maxImpactBps)
+        .accounts(swapAccounts(pool, true))
+        .rpc({ commitment: 'confirmed' });
+
+    // A tenth of the reserve executes about 9.3% below mid, fee included
+    await expectError(swap(100_000_000, 900), "ExcessivePriceImpact");
+
+    // Small trades fit under a tight cap, and 0 leaves large ones unchecked
+    await swap(1_000_000, 50);
+    await swap(100_000_000, 0);
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1);
+  });
+
+  it("Executes several independent swaps atomically", async () => {
+    const poolA = await createPool();
+    const poolB = await createPool();
+    await addLiquidity(poolA, 1_000_000_000, 1_000_000_000);
+    await addLiquidity(poolB, 1_000_000_000, 2_000_000_000);
+    const legAccounts = (pool, zeroForOne: boolean) => [
+      { pubkey: pool.pair, isSigner: false, isWritable: true },
+      { pubkey: pool.vault0, isSigner: false, isWritable: true },
+      { pubkey: pool.vault1, isSigner: false, isWritable: true },
+      { pubkey: pool.authority, isSigner: false, isWritable: false },
+      { pubkey: pool.token0, isSigner: false, isWritable: false },
+      { pubkey: pool.token1, isSigner: false, isWritable: false },
+      { pubkey: zeroForOne ? pool.userToken0 : pool.userToken1, isSigner: false, isWritable: true },
+      { pubkey: zeroForOne ? pool.userToken1 : pool.userToken0, isSigner: false, isWritable: true },
+    ];
+    const remainingAccounts = [...legAccounts(poolA, true), ...legAccounts(poolB, false)];
+    const batchAccounts = { factory: factoryKeypair.publicKey, sender: wallet.publicKey, tokenProgram: TOKEN_PROGRAM_ID };
+    const amountIn = new anchor.BN(10_000_000);
+    const outA = getAmountOut(amountIn, new anchor.BN(1_000_000_000), new anchor.BN(1_000_000_000));
+    const outB = getAmountOut(amountIn, new anchor.BN(2_000_000_000), new anchor.BN(1_000_000_000));
+
+    // One leg that misses its minimum reverts the other
+    await expectError(
+      program.methods
+        .batchSwap([
+          { amountIn, amountOutMin: outA },
+          { amountIn, amountOutMin: outB.addn(1) },
+        ])
+        .accounts(batchAccounts)
+        .remainingAccounts(remainingAccounts)
+        .rpc(),
+      "InsufficientOutputAmount"
+    );
+    assert.equal((await program.account.pairAccount.fetch(poolA.pair)).reserve0.toString(), "1000000000");
+
+    await expectError(
+      program.methods
+        .batchSwap([{ amountIn, amountOutMin: new anchor.BN(0) }])
+        .accounts(batchAccounts)
+        .remainingAccounts(remainingAccounts)
+        .rpc(),
+      "InvalidBatchSwap"
+    );
+
+    const tx = await program.methods
+      .batchSwap([
+        { amountIn, amountOutMin: outA },
+        { amountIn, amountOutMin: outB },
+      ])
+      .accounts(batchAccounts)
+      .remainingAccounts(remainingAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const swaps = (await getEvents(tx)).filter((e) => e.name === "swapEvent");
+    assert.equal(swaps.length, 2);
+    assert.equal(swaps[0].data.amountOut.toString(), outA.toString());
+    assert.isTrue(swaps[0].data.isToken0In);
+    assert.equal(swaps[1].data.amountOut.toString(), outB.toString());
+    assert.isFalse(swaps[1].data.isToken0In);
+    await assertReservesMatchVaults(poolA.pair, poolA.vault0, poolA.vault1);
+    await assertReservesMatchVaults(poolB.pair, poolB.vault0, poolB.vault1);
+  });
+
+  it("Quotes the underlying amounts of an LP position", async () => {
+    const pool = await createPool();
+    await addLiquidity(pool, 1_000_000_000, 3_000_000_000);
+    await program.methods
+      .swap(new anchor.BN(70_000_000), new anchor.BN(0), deadline(), 0)
+      .accounts(swapAccounts(pool, true))
+      .rpc({ commitment: 'confirmed' });
+
+    const liquidity = new anchor.BN(123_456_789);
+    const quote = await program.methods
+      .quoteRemoveLiquidity(liquidity)
+      .accounts({ factory: factoryKeypair.publicKey, pair: pool.pair })
+      .view();
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(quote.amount0.toString(), liquidity.mul(pair.reserve0).div(pair.totalSupply).toString());
+    assert.equal(quote.amount1.toString(), liquidity.mul(pair.reserve1).div(pair.totalSupply).toString());
+
+    // Nothing changed, and the real withdrawal pays exactly the quote
+    const after = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(after.reserve0.toString(), pair.reserve0.toString());
+    const tx = await program.methods
+      .removeLiquidity(liquidity, new anchor.BN(0), new anchor.BN(0), deadline())
+      .accounts(removeLiquidityAccounts(pool))
+      .rpc({ commitment: 'confirmed' });
+    const removed = (await getEvents(tx)).find((e) => e.name === "liquidityRemovedEvent");
+    assert.equal(removed.data.amount0.toString(), quote.amount0.toString());
+    assert.equal(removed.data.amount1.toString(), quote.amount1.toString());
+  });
+
+  it("Records when and by whom a pair was created", async () => {
+    const before = Math.floor(Date.now() / 1000);
+    const pool = await createPool();
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    assert.equal(pair.creator.toString(), wallet.publicKey.toString());
+    // The validator clock can drift a little from the local one
+    assert.isAtLeast(pair.createdAt.toNumber(), before - 60);
+    assert.isAtMost(pair.createdAt.toNumber(), Math.floor(Date.now() / 1000) + 60);
+  });
+
+  it("Sizes the protocol fee by the factory's fee fraction", async () => {
+    const factory = Keypair.generate();
+    await program.methods
+      .initialize(wallet.publicKey)
+      .accounts({
+        factory: factory.publicKey,
+        owner: wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([factory])
+      .rpc({ commitment: 'confirmed' });
+    const factoryAccounts = { factory: factory.publicKey, owner: wallet.publicKey };
+    let factoryAccount = await program.account.factory.fetch(factory.publicKey);
+    assert.equal(factoryAccount.feeNumerator, 1);
+    assert.equal(factoryAccount.feeDenominator, 6);
+
+    await program.methods.setFeeTo(wallet.publicKey).accounts(factoryAccounts).rpc();
+    await program.methods.setFeeOn(true).accounts(factoryAccounts).rpc();
+    const pool = await createPool({ factory: factory.publicKey });
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    for (const zeroForOne of [true, false]) {
+      await program.methods
+        .swap(new anchor.BN(200_000_000), new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, zeroForOne))
+        .rpc({ commitment: 'confirmed' });
+    }
+    const pendingFee = async () =>
+      (
+        await program.methods
+          .myRedeemable(new anchor.BN(1))
+          .accounts({ factory: factory.publicKey, pair: pool.pair })
+          .view()
+      ).protocolFeeLiquidity.toNumber();
+
+    for (const [numerator, denominator] of [[1, 0], [6, 6], [7, 6]]) {
+      await expectError(
+        program.methods.setProtocolFeeFraction(numerator, denominator).accounts(factoryAccounts).rpc(),
+        "InvalidFee"
+      );
+    }
+
+    const sixth = await pendingFee();
+    assert.isAbove(sixth, 0);
+    const tx = await program.methods
+      .setProtocolFeeFraction(1, 2)
+      .accounts(factoryAccounts)
+      .rpc({ commitment: 'confirmed' });
+    const event = (await getEvents(tx)).find((e) => e.name === "protocolFeeFractionUpdatedEvent");
+    assert.equal(event.data.feeNumerator, 1);
+    assert.equal(event.data.feeDenominator, 2);
+
+    // For small growth the fee is close to proportional to the fraction
+    const half = await pendingFee();
+    assert.isAbove(half, sixth * 2.5);
+    assert.isBelow(half, sixth * 3.5);
+
+    await program.methods.setProtocolFeeFraction(0, 1).accounts(factoryAccounts).rpc();
+    assert.equal(await pendingFee(), 0);
+  });
+
+  it("Pools Token-2022 mints, including one with a transfer fee", async () => {
+    // 1% of every transfer of feeMint is withheld at the destination
+    const feeMint = await createTransferFeeMint(100);
+    const plainMint = await createMint(
+      provider.connection, wallet.payer, wallet.publicKey, null, 6, undefined, undefined, TOKEN_2022_PROGRAM_ID
+    );
+    const pool = await createPool({ mints: [feeMint, plainMint], tokenProgram: TOKEN_2022_PROGRAM_ID });
+    const feeIsToken0 = pool.token0.equals(feeMint);
+    const [userFee, userPlain] = feeIsToken0 ? [pool.userToken0, pool.userToken1] : [pool.userToken1, pool.userToken0];
+
+    // Only what reached the vaults is booked as reserves
+    await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+    const pair = await program.account.pairAccount.fetch(pool.pair);
+    const [feeReserve, plainReserve] = feeIsToken0 ? [pair.reserve0, pair.reserve1] : [pair.reserve1, pair.reserve0];
+    assert.equal(feeReserve.toString(), "990000000");
+    assert.equal(plainReserve.toString(), "1000000000");
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1, TOKEN_2022_PROGRAM_ID);
+
+    // Buying the fee mint pays it out with transfer_checked; the fee comes out of what the buyer receives
+    const amountIn = new anchor.BN(10_000_000);
+    const amountOut = getAmountOut(amountIn, plainReserve, feeReserve);
+    const feeBefore = await getTokenBalance(provider.connection, userFee, TOKEN_2022_PROGRAM_ID);
+    await program.methods
+      .swap(amountIn, amountOut, deadline(), 0)
+      .accounts(swapAccounts(pool, !feeIsToken0))
+      .rpc({ commitment: 'confirmed' });
+    const received = (await getTokenBalance(provider.connection, userFee, TOKEN_2022_PROGRAM_ID)) - feeBefore;
+    assert.equal(received, amountOut.toNumber() - Math.ceil(amountOut.toNumber() / 100));
+    await assertReservesMatchVaults(pool.pair, pool.vault0, pool.vault1, TOKEN_2022_PROGRAM_ID);
+
+    // Selling it is priced on the full input, and a 1% cut outgrows the 0.3% swap fee
+    const plainBefore = await getTokenBalance(provider.connection, userPlain, TOKEN_2022_PROGRAM_ID);
+    await expectError(
+      program.methods
+        .swap(amountIn, new anchor.BN(0), deadline(), 0)
+        .accounts(swapAccounts(pool, feeIsToken0))
+        .rpc(),
+      "TransferFeeUnsupported"
+    );
+    assert.equal(await getTokenBalance(provider.connection, userPlain, TOKEN_2022_PROGRAM_ID), plainBefore);
+  });
+
+  it("Keeps the accrued protocol fee through an emergency exit", async () => {
+    const factoryAccounts = { factory: factoryKeypair.publicKey, owner: wallet.publicKey };
+    await program.methods.setFeeTo(wallet.publicKey).accounts(factoryAccounts).rpc();
+    await program.methods.setFeeOn(true).accounts(factoryAccounts).rpc();
+
+    try {
+      // Two identical pools with fees accrued; only the first sees an exit
+      const [pool, control] = [await createPool(), await createPool()];
+      for (const p of [pool, control]) {
+        await addLiquidity(p, 1_000_000_000, 1_000_000_000);
+        for (const zeroForOne of [true, false]) {
+          await program.methods
+            .swap(new anchor.BN(200_000_000), new anchor.BN(0), deadline(), 0)
+            .accounts(swapAccounts(p, zeroForOne))
+            .rpc({ commitment: 'confirmed' });
+        }
+      }
+      const liquidity = new anchor.BN(Math.floor((await getTokenBalance(provider.connection, pool.userLp)) / 4));
+
+      // The fee is due, so the exit needs the fee account like any other
+      await expectError(
+        program.methods
+          .emergencyRemoveLiquidity(liquidity, new anchor.BN(0), new anchor.BN(0), deadline())
+          .accounts(removeLiquidityAccounts(pool))
+          .rpc(),
+        "FeeToAccountRequired"
+      );
+      const tx = await program.methods
+        .emergencyRemoveLiquidity(liquidity, new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts({ ...removeLiquidityAccounts(pool), feeToAccount: pool.userLp })
+        .rpc({ commitment: 'confirmed' });
+      const feeMinted = (await getEvents(tx)).find((e) => e.name === "protocolFeeMintedEvent");
+      const after = await program.account.pairAccount.fetch(pool.pair);
+      assert.equal(after.kLast.toString(), after.reserve0.mul(after.reserve1).toString());
+
+      // The control pool's next liquidity event mints exactly the same fee
+      const controlTx = await program.methods
+        .addLiquidity(new anchor.BN(1_000_000), new anchor.BN(1_000_000), new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts({ ...addLiquidityAccounts(control), feeToAccount: control.userLp })
+        .rpc({ commitment: 'confirmed' });
+      const controlFee = (await getEvents(controlTx)).find((e) => e.name === "protocolFeeMintedEvent");
+      assert.isTrue(controlFee.data.liquidity.gtn(0));
+      assert.equal(feeMinted.data.liquidity.toString(), controlFee.data.liquidity.toString());
+
+      // Once the pair has a protocol fee account, fee_to's own LP account is refused
+      const [protocolFeeAccount] = PublicKey.findProgramAddressSync(
+        [Buffer.from("protocol_fee"), pool.pair.toBuffer()],
+        program.programId
+      );
+      await program.methods
+        .initProtocolFeeAccount()
+        .accounts({
+          factory: factoryKeypair.publicKey,
+          pair: pool.pair,
+          lpMint: pool.lpMint,
+          protocolFeeAccount,
+          authority: pool.authority,
+          owner: wallet.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc({ commitment: 'confirmed' });
+      for (const zeroForOne of [true, false]) {
+        await program.methods
+          .swap(new anchor.BN(200_000_000), new anchor.BN(0), deadline(), 0)
+          .accounts(swapAccounts(pool, zeroForOne))
+          .rpc({ commitment: 'confirmed' });
+      }
+      await expectError(
+        program.methods
+          .emergencyRemoveLiquidity(liquidity, new anchor.BN(0), new anchor.BN(0), deadline())
+          .accounts({ ...removeLiquidityAccounts(pool), feeToAccount: pool.userLp })
+          .rpc(),
+        "InvalidTokenOwner"
+      );
+    } finally {
+      await program.methods.setFeeOn(false).accounts(factoryAccounts).rpc();
+    }
+  });
+
+  it("Routes protocol fees into the pair's protocol fee account", async () => {
+    const factoryAccounts = { factory: factoryKeypair.publicKey, owner: wallet.publicKey };
+    await program.methods.setFeeTo(wallet.publicKey).accounts(factoryAccounts).rpc();
+    await program.methods.setFeeOn(true).accounts(factoryAccounts).rpc();
+
+    try {
+      const pool = await createPool();
+      const [protocolFeeAccount] = PublicKey.findProgramAddressSync(
+        [Buffer.from("protocol_fee"), pool.pair.toBuffer()],
+        program.programId
+      );
+      await program.methods
+        .initProtocolFeeAccount()
+        .accounts({
+          factory: factoryKeypair.publicKey,
+          pair: pool.pair,
+          lpMint: pool.lpMint,
+          protocolFeeAccount,
+          authority: pool.authority,
+          owner: wallet.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc({ commitment: 'confirmed' });
+      await addLiquidity(pool, 1_000_000_000, 1_000_000_000);
+      const swapBothWays = async () => {
+        for (const zeroForOne of [true, false]) {
+          await program.methods
+            .swap(new anchor.BN(200_000_000), new anchor.BN(0), deadline(), 0)
+            .accounts(swapAccounts(pool, zeroForOne))
+            .rpc({ commitment: 'confirmed' });
+        }
+      };
+
+      // A liquidity event mints the fee into the protocol fee account
+      await swapBothWays();
+      const tx = await program.methods
+        .addLiquidity(new anchor.BN(100_000_000), new anchor.BN(100_000_000), new anchor.BN(0), new anchor.BN(0), deadline())
+        .accounts({ ...addLiquidityAccounts(pool), feeToAccount: protocolFeeAccount })
+        .rpc({ commitment: 'confirmed' });
+      const minted = (await getEvents(tx)).find((e) => e.name === "protocolFeeMintedEvent");
+      assert.equal(minted.data.feeToAccount.toString(), protocolFeeAccount.toString());
+      const afterMint = await getTokenBalance(provider.connection, protocolFeeAccount);
+      assert.equal(afterMint, minted.data.liquidity.toNumber());
+
+      // Pointing fee_to elsewhere doesn't redirect the pair's fees
+      const newFeeTo = Keypair.generate();
+      const newFeeToLp = await createAssociatedTokenAccount(provider.connection, wallet.payer, pool.lpMint, newFeeTo.publicKey);
+      await program.methods.setFeeTo(newFeeTo.publicKey).accounts(factoryAccounts).rpc();
+      await swapBothWays();
+      await expectError(
+        program.methods
+          .collectProtocolFees()
+          .accounts({ ...collectProtocolFeesAccounts(pool, wallet.publicKey, pool.userLp), feeToAccount: newFeeToLp })
+          .rpc(),
+        "InvalidTokenOwner"
+      );
+      await program.methods
+        .collectProtocolFees()
+        .accounts({ ...collectProtocolFeesAccounts(pool, wallet.publicKey, pool.userLp), feeToAccount: protocolFeeAccount })
+        .rpc({ commitment: 'confirmed' });
+      assert.isAbove(await getTokenBalance(provider.connection, protocolFeeAccount), afterMint);
+      assert.equal(await getTokenBalance(provider.connection, newFeeToLp), 0);
+    } finally {
+      await program.methods.setFeeTo(wallet.publicKey).accounts(factoryAccounts).rpc();
+      await program.methods.setFeeOn(false).accounts(factoryAccounts).rpc();
+    }
+  });
+
+  // Helper functions
+  async function mintToWallet(connection, payer, mint, destination, authority, amount, tokenProgram = TOKEN_PROGRAM_ID) {
+    const tx = new anchor.web3.Transaction();
+    tx.add(
+      createMintToInstruction(
+        mint,
+        destination,
+        authority,
+        amount,
+        [],
+        tokenProgram
+      )
+    );
+    
+    await provider.sendAndConfirm(tx, [payer]);
+  }
+  
+  // Vault balances must equal the stored reserves after every mutating
+  // instruction.
+  async function assertReservesMatchVaults(pair: PublicKey, vault0: PublicKey, vault1: PublicKey, tokenProgram = TOKEN_PROGRAM_ID) {
+    const pairAccount = await program.account.pairAccount.fetch(pair);
+    const vault0Balance = (await getAccount(provider.connection, vault0, undefined, tokenProgram)).amount;
+    const vault1Balance = (await getAccount(provider.connection, vault1, undefined, tokenProgram)).amount;
+    assert.equal(vault0Balance.toString(), pairAccount.reserve0.toString(), "vault0 balance differs from reserve0");
+    assert.equal(vault1Balance.toString(), pairAccount.reserve1.toString(), "vault1 balance differs from reserve1");
+  }
+
+  async function getTokenBalance(connection, tokenAccount, tokenProgram = TOKEN_PROGRAM_ID) {
+    const accountInfo = await getAccount(connection, tokenAccount, undefined, tokenProgram);
+    return parseInt(accountInfo.amount.toString());
+  }
+
+  // Token-2022 mint that withholds `feeBps` of every transfer, uncapped
+  async function createTransferFeeMint(feeBps: number, decimals = 6) {
+    const mint = Keypair.generate();
+    const space = getMintLen([ExtensionType.TransferFeeConfig]);
+    const tx = new anchor.web3.Transaction().add(
+      SystemProgram.createAccount({
+        fromPubkey: wallet.publicKey,
+        newAccountPubkey: mint.publicKey,
+        space,
+        lamports: await provider.connection.getMinimumBalanceForRentExemption(space),
+        programId: TOKEN_2022_PROGRAM_ID,
+      }),
+      createInitializeTransferFeeConfigInstruction(
+        mint.publicKey,
+        wallet.publicKey,
+        wallet.publicKey,
+        feeBps,
+        BigInt("18446744073709551615"),
+        TOKEN_2022_PROGRAM_ID
+      ),
+      createInitializeMintInstruction(mint.publicKey, decimals, wallet.publicKey, null, TOKEN_2022_PROGRAM_ID)
+    );
+    await provider.sendAndConfirm(tx, [mint]);
+    return mint.publicKey;
+  }
+
+  // Creates two fresh mints (or reuses `mints`) and runs the three-step pair
+  // creation against the shared factory. Seeds use the sorted token order
+  // unless `reverseSeeds` is set, which configure_pair rejects.
+  async function createPool(
+    options: {
+      decimals0?: number;
+      decimals1?: number;
+      maxRatio?: number;
+      reserveCaps?: [number, number];
+      mints?: [PublicKey, PublicKey];
+      reverseSeeds?: boolean;
+      // Configure the pair with this mint instead of the LP mint it created
+      lpMint?: PublicKey;
+      // Configure the pair with these (token0, token1) accounts as its vaults
+      vaults?: [PublicKey, PublicKey];
+      // Register the pair with this factory instead of the shared one
+      factory?: PublicKey;
+      // Token program owning both mints; the LP mint is created under it too
+      tokenProgram?: PublicKey;
+    } = {}
+  ) {
+    const factory = options.factory ?? factoryKeypair.publicKey;
+    const tokenProgram = options.tokenProgram ?? TOKEN_PROGRAM_ID;
+    const [mintA, mintB] = options.mints ?? [
+      await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, options.decimals0 ?? 6, undefined, undefined, tokenProgram),
+      await createMint(provider.connection, wallet.payer, wallet.publicKey, wallet.publicKey, options.decimals1 ?? 6, undefined, undefined, tokenProgram),
+    ];
+    const [poolToken0, poolToken1] = Buffer.compare(mintA.toBuffer(), mintB.toBuffer()) < 0 ? [mintA, mintB] : [mintB, mintA];
+    const [seedToken0, seedToken1] = options.reverseSeeds ? [poolToken1, poolToken0] : [poolToken0, poolToken1];
+
+    const [pair] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pair"), seedToken0.toBuffer(), seedToken1.toBuffer()],
+      program.programId
+    );
+    const [authority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("authority"), pair.toBuffer()],
+      program.programId
+    );
+    const [lpMint] = PublicKey.findProgramAddressSync(
+      [Buffer.from("lp_mint"), pair.toBuffer()],
+      program.programId
+    );
+    const seedVault0 = Keypair.generate();
+    const seedVault1 = Keypair.generate();
+    const [vault0, vault1] = options.reverseSeeds ? [seedVault1, seedVault0] : [seedVault0, seedVault1];
+    const poolLpMint = options.lpMint ?? lpMint;
+    const [poolVault0, poolVault1] = options.vaults ?? [vault0.publicKey, vault1.publicKey];
+
+    await program.methods
+      .createTokenAccounts()
+      .accounts({
+        token0: seedToken0,
+        token1: seedToken1,
+        pairPda: pair,
+        authority,
+        token0Account: seedVault0.publicKey,
+        token1Account: seedVault1.publicKey,
+        sender: wallet.publicKey,
+        tokenProgram,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([seedVault0, seedVault1])
+      .rpc({ commitment: 'confirmed' });
+
+    await program.methods
+      .createPairAccount()
+      .accounts({
+        factory,
+        pair,
+        token0: seedToken0,
+        token1: seedToken1,
+        lpMint,
+        authority,
+        sender: wallet.publicKey,
+        owner: wallet.publicKey,
+        tokenProgram,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .rpc({ commitment: 'confirmed' });
+
+    await program.methods
+      .configurePair(
+        new anchor.BN(options.maxRatio ?? 0),
+        new anchor.BN(options.reserveCaps?.[0] ?? 0),
+        new anchor.BN(options.reserveCaps?.[1] ?? 0)
+      )
+      .accounts({
+        factory,
+        pair,
+        token0: poolToken0,
+        token1: poolToken1,
+        lpMint: poolLpMint,
+        authority,
+        token0Account: poolVault0,
+        token1Account: poolVault1,
+        sender: wallet.publicKey,
+        owner: wallet.publicKey,
+      })
+      .rpc({ commitment: 'confirmed' });
+
+    const userToken0 = (
+      await getOrCreateAssociatedTokenAccount(provider.connection, wallet.payer, poolToken0, wallet.publicKey, false, undefined, undefined, tokenProgram)
+    ).address;
+    const userToken1 = (
+      await getOrCreateAssociatedTokenAccount(provider.connection, wallet.payer, poolToken1, wallet.publicKey, false, undefined, undefined, tokenProgram)
+    ).address;
+    const userLp = await createAssociatedTokenAccount(provider.connection, wallet.payer, poolLpMint, wallet.publicKey, undefined, tokenProgram);
+    const burnLp = await createAssociatedTokenAccount(
+      provider.connection,
+      wallet.payer,
+      poolLpMint,
+      new PublicKey("11111111111111111111111111111111"),
+      undefined,
+      tokenProgram
+    );
+
+    const fundAmount = 1_000_000_000_000_000;
+    await mintToWallet(provider.connection, wallet.payer, poolToken0, userToken0, wallet.publicKey, fundAmount, tokenProgram);
+    await mintToWallet(provider.connection, wallet.payer, poolToken1, userToken1, wallet.publicKey, fundAmount, tokenProgram);
+
+    return {
+      factory,
+      token0: poolToken0,
+      token1: poolToken1,
+      pair,
+      authority,
+      lpMint: poolLpMint,
+      vault0: poolVault0,
+      vault1: poolVault1,
+      userToken0,
+      userToken1,
+      userLp,
+      burnLp,
+      tokenProgram,
+    };
+  }
+
+  async function addLiquidity(pool, amount0, amount1) {
+    return program.methods
+      .addLiquidity(new anchor.BN(amount0), new anchor.BN(amount1), new anchor.BN(0), new anchor.BN(0), deadline())
+      .accounts(addLiquidityAccounts(pool))
+      .rpc({ commitment: 'confirmed' });
+  }
+
+  // Flash-borrows token0 into the user's account; the mock borrower pays back
+  // `repay` from the user's token0 account, or token1 with `repayToken1`
+  function flashBorrowToken0(pool, borrowed: number, repay: number, options: { repayToken1?: boolean } = {}) {
+    const data = Buffer.alloc(8);
+    data.writeBigUInt64LE(BigInt(repay));
+    const [repayFrom, vault] = options.repayToken1 ? [pool.userToken1, pool.vault1] : [pool.userToken0, pool.vault0];
+    return program.methods
+      .flashSwap(new anchor.BN(borrowed), data)
+      .accounts({
+        factory: factoryKeypair.publicKey,
+        pair: pool.pair,
+        token0Account: pool.vault0,
+        token1Account: pool.vault1,
+        token0Mint: pool.token0,
+        token1Mint: pool.token1,
+        recipient: pool.userToken0,
+        authority: pool.authority,
+        callbackProgram: mockProgram.programId,
+        discountToken: null,
+        traderState: null,
+        externalOracle: null,
+        sender: wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .remainingAccounts([
+        { pubkey: repayFrom, isSigner: false, isWritable: true },
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: wallet.publicKey, isSigner: true, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ])
+      .rpc({ commitment: 'confirmed' });
+  }
+
+  function addLiquidityAccounts(pool) {
+    return {
+      factory: pool.factory,
+      pair: pool.pair,
+      token0Account: pool.vault0,
+      token1Account: pool.vault1,
+      token0Mint: pool.token0,
+      token1Mint: pool.token1,
+      userToken0: pool.userToken0,
+      userToken1: pool.userToken1,
+      lpMint: pool.lpMint,
+      liquidityTo: pool.userLp,
+      burnAccount: pool.burnLp,
+      feeToAccount: null,
+      authority: pool.authority,
+      sender: wallet.publicKey,
+      owner: wallet.publicKey,
+      tokenProgram: pool.tokenProgram,
+    };
+  }
+
+  function removeLiquidityAccounts(pool) {
+    return {
+      factory: pool.factory,
+      pair: pool.pair,
+      token0Account: pool.vault0,
+      token1Account: pool.vault1,
+      token0Mint: pool.token0,
+      token1Mint: pool.token1,
+      token0To: pool.userToken0,
+      token1To: pool.userToken1,
+      lpMint: pool.lpMint,
+      liquidityFrom: pool.userLp,
+      feeToAccount: null,
+      authority: pool.authority,
+      sender: wallet.publicKey,
+      owner: wallet.publicKey,
+      tokenProgram: pool.tokenProgram,
+    };
+  }
+
+  // fee_to is the wallet, so the fee lands in the pool's user LP account
+  function collectProtocolFeesAccounts(pool, keeper: PublicKey, keeperLp: PublicKey) {
+    return {
+      factory: pool.factory,
+      pair: pool.pair,
+      lpMint: pool.lpMint,
+      feeToAccount: pool.userLp,
+      keeperLp,
+      authority: pool.authority,
+      keeper,
+      tokenProgram: pool.tokenProgram,
+    };
+  }
+
+  function swapAccounts(pool, zeroForOne: boolean) {
+    return {
+      factory: pool.factory,
+      pair: pool.pair,
+      token0Account: pool.vault0,
+      token1Account: pool.vault1,
+      token0Mint: pool.token0,
+      token1Mint: pool.token1,
+      tokenIn: zeroForOne ? pool.userToken0 : pool.userToken1,
+      tokenOut: zeroForOne ? pool.userToken1 : pool.userToken0,
+      discountToken: null,
+      traderState: null,
+      externalOracle: null,
+      authority: pool.authority,
+      sender: wallet.publicKey,
+      tokenProgram: pool.tokenProgram,
+    };
+  }
+
+  async function expectError(promise: Promise<any>, code?: string) {
+    try {
+      await promise;
+    } catch (error) {
+      if (code) {
+        assert.include(error.toString() + JSON.stringify(error.logs ?? []), code);
+      }
+      return;
+    }
+    assert.fail("Expected the transaction to fail");
+  }
+
+  // Constant-product output with the fee expressed in basis points
+  function getAmountOut(amountIn: anchor.BN, reserveIn: anchor.BN, reserveOut: anchor.BN, feeBps = 30) {
+    const amountInWithFee = amountIn.muln(10_000 - feeBps);
+    return amountInWithFee.mul(reserveOut).div(reserveIn.muln(10_000).add(amountInWithFee));
+  }
+
+  // Frontends pass now + 20 minutes
+  function deadline(seconds = 20 * 60) {
+    return new anchor.BN(Math.floor(Date.now() / 1000) + seconds);
+  }
+
+  // Decodes the Anchor events emitted by a confirmed transaction.
+  async function getEvents(signature: string) {
+    const txDetails = await provider.connection.getTransaction(signature, {
+      commitment: 'confirmed',
+      maxSupportedTransactionVersion: 0
+    });
+    const parser = new anchor.EventParser(program.programId, program.coder);
+    return Array.from(parser.parseLogs(txDetails.meta.logMessages));
   }
 });
\ No newline at end of file